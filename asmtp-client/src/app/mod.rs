@@ -13,7 +13,10 @@ pub use self::{
 };
 use anyhow::{anyhow, ensure, Context as _, Result};
 use asmtp_lib::PassportImporter;
-use asmtp_network::Message;
+use asmtp_network::{
+    net::{RemoteAddress, Socks5Proxy},
+    CoverTraffic, Message,
+};
 use asmtp_storage::{Storage, StorageOptions};
 use directories::ProjectDirs;
 use keynesis::{
@@ -26,7 +29,7 @@ use keynesis::{
 };
 use poldercast::{GossipSlice, Topic};
 use rand_chacha::ChaChaRng;
-use std::{net::SocketAddr, path::PathBuf};
+use std::{convert::TryFrom as _, path::PathBuf};
 
 /// Application settings
 ///
@@ -45,9 +48,25 @@ pub struct Config {
     /// However it is possible to set a specific path that will be used instead
     pub directory: Option<PathBuf>,
 
-    pub remote_address: SocketAddr,
+    /// the address of the remote node, the scheme selects the transport
+    /// (`tcp://` or `ws://`/`wss://` for WebSocket)
+    pub remote_address: RemoteAddress,
+
+    /// optional SOCKS5 proxy to connect to the remote node through,
+    /// the remote address is then resolved by the proxy
+    pub proxy: Option<Socks5Proxy>,
 
-    pub remote_id: PublicKey,
+    /// the expected identity of the remote node
+    ///
+    /// if not set, the identity presented by the node the first time we
+    /// connect to it is pinned in the storage and expected from then on
+    /// (trust on first use). Setting it replaces the pinned identity.
+    pub remote_id: Option<PublicKey>,
+
+    /// send dummy messages to the remote node at random times so an
+    /// observer of the connection cannot tell when we actually send
+    /// messages
+    pub cover_traffic: Option<CoverTraffic>,
 }
 
 pub struct App {
@@ -149,7 +168,8 @@ impl App {
             passports.insert(passport);
         }
 
-        let network = Network::default();
+        let mut network = Network::default();
+        network.set_cover_traffic(config.cover_traffic);
 
         Ok(Self {
             rng,
@@ -220,14 +240,68 @@ impl App {
 
         if self.current_key != Some(index) {
             if let Some(key) = key.key() {
+                let address = self.config.remote_address.to_string();
+                let pinned = self
+                    .storage
+                    .known_node(&address)
+                    .await?
+                    .map(|node| PublicKey::try_from(node.key.as_slice()))
+                    .transpose()
+                    .context("Invalid public key pinned for the remote node")?;
+                let expected = self.config.remote_id.or(pinned);
+
                 self.network
                     .connect(
                         &mut self.rng,
-                        self.config.remote_address,
-                        self.config.remote_id,
+                        &self.config.remote_address,
+                        self.config.proxy.as_ref(),
+                        expected,
                         key,
                     )
                     .await;
+
+                if self.network.connection_failure().is_none() {
+                    if let Some(stats) = self.network.stats() {
+                        let peer_id = stats.lock().expect("valid lock").peer_id;
+                        if pinned != Some(peer_id) {
+                            self.storage.pin_known_node(&address, &peer_id).await?;
+                        }
+                    }
+                } else if let (Some(pinned), None) = (pinned, self.config.remote_id) {
+                    // the handshake with the pinned identity failed, check
+                    // if it is because the remote node's identity changed
+                    let probe = Network::probe_identity(
+                        &mut self.rng,
+                        &self.config.remote_address,
+                        self.config.proxy.as_ref(),
+                    )
+                    .await;
+                    match probe {
+                        Ok(id) if id != pinned => {
+                            self.network.set_connection_failure(anyhow!(
+                                "WARNING: THE IDENTITY OF THE REMOTE NODE {} HAS CHANGED! \
+                                 expected {} but the node presented {}. Someone may be \
+                                 impersonating the node. If the change is expected, restart \
+                                 with `--remote-id {}`",
+                                address,
+                                pinned,
+                                id,
+                                id,
+                            ));
+                        }
+                        Ok(_) => {}
+                        Err(error) => {
+                            self.network.set_connection_failure(anyhow!(
+                                "Could not verify the identity of the remote node {}: the \
+                                 handshake with the pinned identity {} failed and the node \
+                                 could not be asked for its identity ({:#})",
+                                address,
+                                pinned,
+                                error,
+                            ));
+                        }
+                    }
+                }
             }
         }
 
@@ -316,6 +390,32 @@ impl App {
                             .expect("already know it is a query topic"),
                     );
                 }
+                MessageType::Goodbye => {
+                    // the network runtime closes the connection on goodbye
+                    // and reports the reason in the network stats
+                }
+                MessageType::Cover => {
+                    // dummy messages are discarded by the network runtime
+                }
+                MessageType::QueryTopicMessagesNext => {
+                    // the client does not query paginated topic messages
+                }
+                MessageType::Reconcile => {
+                    // the client does not reconcile the messages of the topics
+                }
+                MessageType::GetPassportBlocks | MessageType::PutPassportBlocks => {
+                    // the client does not query the passports incrementally
+                }
+                MessageType::Batch => {
+                    // batches are unpacked by the connection
+                }
+                MessageType::Addresses => {
+                    // the client only connects to the node it is configured with
+                }
+                MessageType::Rejected => {
+                    // the network runtime reports the rejections in the
+                    // network stats
+                }
             }
         }
 
@@ -1,11 +1,13 @@
 use anyhow::{Context, Result};
 use asmtp_network::{
-    net::{Connection, ConnectionReader, ConnectionWriter},
-    Message, SessionId,
+    net::{
+        Connection, ConnectionReader, ConnectionWriter, RemoteAddress, Socks5Proxy, TrafficMeter,
+    },
+    CoverTraffic, GoodbyeReason, Message, MessageType, Puzzle, SessionId,
 };
 use futures::prelude::*;
 use keynesis::key::{curve25519::PublicKey, ed25519::SecretKey};
-use rand::{CryptoRng, RngCore};
+use rand::{rngs::OsRng, CryptoRng, RngCore};
 use std::{
     net::SocketAddr,
     sync::{mpsc as std_mpsc, Arc, Mutex},
@@ -13,6 +15,10 @@ use std::{
 };
 use tokio::sync::{mpsc, oneshot};
 
+/// the time to establish a connection with the remote node, the handshake
+/// included
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(1);
+
 #[derive(Clone)]
 pub struct NetworkStats {
     pub peer_id: PublicKey,
@@ -20,10 +26,8 @@ pub struct NetworkStats {
     pub current_id: PublicKey,
     pub session_id: SessionId,
     pub connection_established_since: Instant,
-    pub number_message_sent: usize,
-    pub last_message_sent: Instant,
-    pub number_message_received: usize,
-    pub last_message_received: Instant,
+    pub sent: TrafficMeter,
+    pub received: TrafficMeter,
     pub error: Option<Arc<anyhow::Error>>,
     pub last_error_received: Option<Instant>,
 }
@@ -32,6 +36,8 @@ pub struct NetworkStats {
 pub struct Network {
     inner: Option<Inner>,
     connection_failure: Option<anyhow::Error>,
+    /// send dummy messages to the remote to hide when we actually send
+    cover: Option<CoverTraffic>,
 }
 
 struct Inner {
@@ -48,9 +54,15 @@ struct Runtime {
     outbound_messages: mpsc::Receiver<Message>,
     inbound_messages: std_mpsc::Sender<Message>,
     shutdown_condition: oneshot::Receiver<()>,
+    cover: Option<CoverTraffic>,
 }
 
 impl Network {
+    /// set the cover traffic to use for the next connections
+    pub fn set_cover_traffic(&mut self, cover: Option<CoverTraffic>) {
+        self.cover = cover;
+    }
+
     pub fn disconnect(&mut self) {
         let _previous = self.inner.take();
     }
@@ -83,13 +95,14 @@ impl Network {
     pub async fn connect<RNG>(
         &mut self,
         rng: RNG,
-        remote_address: SocketAddr,
-        remote_identity: PublicKey,
+        remote_address: &RemoteAddress,
+        proxy: Option<&Socks5Proxy>,
+        remote_identity: Option<PublicKey>,
         sk: &SecretKey,
     ) where
         RNG: CryptoRng + RngCore,
     {
-        let new = Inner::new(rng, remote_address, remote_identity, sk).await;
+        let new = Inner::new(rng, remote_address, proxy, remote_identity, sk, self.cover).await;
         match new {
             Ok(new) => {
                 // no error
@@ -102,36 +115,86 @@ impl Network {
             }
         }
     }
+
+    /// replace the error of the last connection attempt
+    pub fn set_connection_failure(&mut self, error: anyhow::Error) {
+        self.connection_failure.replace(error);
+    }
+
+    /// retrieve the identity of the remote node without disclosing ours
+    ///
+    /// a throw away key is used to perform a first contact handshake
+    /// with the remote, the connection is closed straight away. The remote
+    /// has the time of any connection to answer (see [`CONNECTION_TIMEOUT`]).
+    pub async fn probe_identity<RNG>(
+        mut rng: RNG,
+        remote_address: &RemoteAddress,
+        proxy: Option<&Socks5Proxy>,
+    ) -> Result<PublicKey>
+    where
+        RNG: CryptoRng + RngCore,
+    {
+        let sk = SecretKey::new(&mut rng);
+        let connection = tokio::time::timeout(
+            CONNECTION_TIMEOUT,
+            Connection::connect_remote(
+                rng,
+                &sk,
+                remote_address,
+                proxy,
+                None,
+                Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY,
+            ),
+        )
+        .await
+        .context("Cannot connect to remote peer")?
+        .context("Failed to establish secure connection to peer")?;
+
+        Ok(*connection.remote_public_identity())
+    }
 }
 
 impl Inner {
     async fn new<RNG>(
         rng: RNG,
-        remote_address: SocketAddr,
-        remote_identity: PublicKey,
+        remote_address: &RemoteAddress,
+        proxy: Option<&Socks5Proxy>,
+        remote_identity: Option<PublicKey>,
         sk: &SecretKey,
+        cover: Option<CoverTraffic>,
     ) -> Result<Self>
     where
         RNG: CryptoRng + RngCore,
     {
         let current_id = sk.public_key();
         let connection = tokio::time::timeout(
-            Duration::from_secs(1),
-            Connection::connect_to(rng, sk, remote_address, remote_identity),
+            CONNECTION_TIMEOUT,
+            Connection::connect_remote(
+                rng,
+                sk,
+                remote_address,
+                proxy,
+                remote_identity,
+                Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY,
+            ),
         )
         .await
         .context("Cannot connect to remote peer")?
         .context("Failed to establish secure connection to peer")?;
+        let peer_id = *connection.remote_public_identity();
+        let peer_address = connection.remote_address();
+        let session_id = *connection.session_id();
+        // the older nodes would drop the connection on the cover messages
+        let cover = cover.filter(|_| connection.capabilities().supports_cover_traffic());
+        let (inbound, outbound) = connection.into_parts();
         let stats = Arc::new(Mutex::new(NetworkStats {
-            peer_id: *connection.remote_public_identity(),
-            peer_address: connection.remote_address(),
+            peer_id,
+            peer_address,
             current_id,
-            session_id: *connection.session_id(),
+            session_id,
             connection_established_since: Instant::now(),
-            number_message_sent: 1,
-            last_message_sent: Instant::now(),
-            number_message_received: 1,
-            last_message_received: Instant::now(),
+            sent: outbound.traffic_meter(),
+            received: inbound.traffic_meter(),
             error: None,
             last_error_received: None,
         }));
@@ -141,11 +204,13 @@ impl Inner {
         let (_condition, shutdown_condition) = oneshot::channel();
 
         let runtime = Runtime::new(
-            connection,
+            inbound,
+            outbound,
             Arc::clone(&stats),
             outbound_receiver,
             inbound_sender,
             shutdown_condition,
+            cover,
         );
 
         tokio::task::spawn(async move { runtime.run().await });
@@ -176,13 +241,14 @@ impl Inner {
 
 impl Runtime {
     fn new(
-        connection: Connection,
+        inbound: ConnectionReader,
+        outbound: ConnectionWriter,
         stats: Arc<Mutex<NetworkStats>>,
         outbound_messages: mpsc::Receiver<Message>,
         inbound_messages: std_mpsc::Sender<Message>,
         shutdown_condition: oneshot::Receiver<()>,
+        cover: Option<CoverTraffic>,
     ) -> Self {
-        let (inbound, outbound) = connection.into_parts();
         Self {
             stats: Arc::clone(&stats),
             outbound,
@@ -190,12 +256,29 @@ impl Runtime {
             outbound_messages,
             inbound_messages,
             shutdown_condition,
+            cover,
         }
     }
 
     async fn run(mut self) {
+        let next_cover = |cover: &Option<CoverTraffic>| {
+            let delay = cover
+                .map(|cover| cover.next_delay(OsRng))
+                .unwrap_or(Duration::from_secs(3_600));
+            tokio::time::Instant::now() + delay
+        };
+        let cover_timer = tokio::time::sleep_until(next_cover(&self.cover));
+        tokio::pin!(cover_timer);
+
         loop {
             tokio::select! {
+                () = &mut cover_timer, if self.cover.is_some() => {
+                    let message = self.cover.expect("cover traffic enabled").message(OsRng);
+                    if self.outbound.send(message).await.is_err() {
+                        break;
+                    }
+                    cover_timer.as_mut().reset(next_cover(&self.cover));
+                }
                 _ = &mut self.shutdown_condition => {
                     // here we check the shutdown condition is actually
                     // either alive or not triggered.
@@ -203,6 +286,8 @@ impl Runtime {
                     // may it be an error or an successful delivery
                     // it means the `network::Inner` has been dropped and
                     // it is time to disconnect
+                    let goodbye = Message::new_goodbye(GoodbyeReason::Shutdown, None);
+                    let _ = self.outbound.send(goodbye).await;
                     break;
                 }
                 inbound = self.inbound.next() => {
@@ -224,13 +309,11 @@ impl Runtime {
     async fn handle_outbound(&mut self, outbound: Option<Message>) -> bool {
         if let Some(outbound) = outbound {
             let result = self.outbound.send(outbound).await;
-            if let Ok(mut stats) = self.stats.lock() {
-                if let Err(error) = result {
+            if let Err(error) = result {
+                if let Ok(mut stats) = self.stats.lock() {
                     stats.last_error_received = Some(Instant::now());
                     stats.error = Some(Arc::new(error));
                 }
-                stats.last_message_sent = Instant::now();
-                stats.number_message_sent += 1;
             }
             false
         } else {
@@ -253,17 +336,43 @@ impl Runtime {
             }
             Some((_peer, Err(error))) => {
                 if let Ok(mut stats) = self.stats.lock() {
-                    stats.last_message_received = Instant::now();
-                    stats.number_message_received += 1;
                     stats.last_error_received = Some(Instant::now());
                     stats.error = Some(Arc::new(error));
                 }
                 false
             }
+            Some((_peer, Ok(message))) if message.message_type() == MessageType::Cover => {
+                // dummy message, discarded
+                false
+            }
             Some((_peer, Ok(message))) => {
-                if let Ok(mut stats) = self.stats.lock() {
-                    stats.last_message_received = Instant::now();
-                    stats.number_message_received += 1;
+                if let Some((reason, retry_after)) = message.goodbye_checked() {
+                    let error = if let Some(retry_after) = retry_after {
+                        anyhow::anyhow!(
+                            "Disconnected by the peer ({}), retry after {}s",
+                            reason,
+                            retry_after.as_secs()
+                        )
+                    } else {
+                        anyhow::anyhow!("Disconnected by the peer ({})", reason)
+                    };
+                    if let Ok(mut stats) = self.stats.lock() {
+                        stats.last_error_received = Some(Instant::now());
+                        stats.error = Some(Arc::new(error));
+                    }
+                    return true;
+                }
+                if let Some((reason, topic)) = message.rejected_checked() {
+                    let error = anyhow::anyhow!(
+                        "Message to topic {} rejected by the node ({})",
+                        topic,
+                        reason
+                    );
+                    if let Ok(mut stats) = self.stats.lock() {
+                        stats.last_error_received = Some(Instant::now());
+                        stats.error = Some(Arc::new(error));
+                    }
+                    return false;
                 }
                 // if we cannot send the reply back to the mpsc
                 // it means there is no receiver to receive from
@@ -4,13 +4,17 @@ use asmtp_client::{
     event::{Event, Events, Key},
     ui,
 };
+use asmtp_network::{
+    net::{RemoteAddress, Socks5Proxy},
+    CoverTraffic,
+};
 use crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use keynesis::key::ed25519::PublicKey;
-use std::{io::stdout, net::SocketAddr, path::PathBuf, str::FromStr};
+use std::{io::stdout, path::PathBuf, str::FromStr, time::Duration};
 use structopt::StructOpt;
 use tui::{backend::CrosstermBackend, Terminal};
 
@@ -18,22 +22,41 @@ use tui::{backend::CrosstermBackend, Terminal};
 struct Options {
     /// the public remote address of the ASMTPD server
     ///
-    // currently we are expecting the socket address though on the longer
-    // run we can set a URL to resolve with DNS or other means.
+    /// use `ws://` or `wss://` URLs to connect with WebSocket (for example
+    /// when behind a proxy that only lets HTTP(S) through). Otherwise the
+    /// connection is established with TCP.
     #[structopt(long = "remote-address", default_value = "86.31.102.125:9800")]
-    remote_address: SocketAddr,
+    remote_address: RemoteAddress,
+
+    /// connect to the remote through the given SOCKS5 proxy
+    ///
+    /// of the form `socks5://[username:password@]host:port`. The remote
+    /// address is resolved by the proxy (allowing to use Tor for example).
+    #[structopt(long = "socks5-proxy")]
+    proxy: Option<Socks5Proxy>,
 
     /// the public remote public key (identity)
     ///
-    // while this is currently mandatory it should not remain for long. Indeed
-    // this value should only be useful when connecting for the first time to
-    // the ASMTPd server. Later we should be able to get the passport's updates
-    // of the remote servers and be able to connect to any
-    #[structopt(
-        long = "remote-id",
-        default_value = "7353f1e7fb03b2346638b4e2b93f810c84853787970be0844df63cdc9979a01d"
-    )]
-    remote_id: PublicKey,
+    /// if not set, the identity presented by the remote the first time
+    /// we connect to it is remembered and expected for the subsequent
+    /// connections (trust on first use). Use this option to set the
+    /// expected identity explicitly, or to accept a new identity after
+    /// the remote's key changed.
+    #[structopt(long = "remote-id")]
+    remote_id: Option<PublicKey>,
+
+    /// send dummy messages to the remote node every given number of
+    /// seconds on average
+    ///
+    /// this makes it harder for an observer of the connection to tell
+    /// when you are actually sending messages. The dummy messages are
+    /// sent at random times and discarded by the node.
+    #[structopt(long = "cover-traffic-interval")]
+    cover_traffic_interval: Option<u64>,
+
+    /// the maximum size of the dummy messages (in bytes)
+    #[structopt(long = "cover-traffic-max-size", default_value = "1024")]
+    cover_traffic_max_size: usize,
 
     /// directory to use to store all the persistent information
     ///
@@ -57,10 +80,15 @@ async fn main() {
 async fn main_() -> Result<()> {
     let options = Options::from_args();
 
+    let max_size = options.cover_traffic_max_size;
     let config = app::Config {
         directory: options.working_directory,
         remote_address: options.remote_address,
+        proxy: options.proxy,
         remote_id: options.remote_id,
+        cover_traffic: options
+            .cover_traffic_interval
+            .map(|interval| CoverTraffic::new(Duration::from_secs(interval), max_size)),
     };
 
     let app = if let Some(seed) = options.seed {
@@ -1,6 +1,6 @@
 use crate::{app::App, event, ui::Focus};
 use anyhow::Result;
-use asmtp_network::Version as NetworkVersion;
+use asmtp_network::{net::TrafficSnapshot, Version as NetworkVersion};
 use std::time::{Duration, Instant};
 use structopt::clap::crate_version;
 use tui::{
@@ -104,20 +104,12 @@ impl Settings {
                     format_duration_since(stats.connection_established_since),
                 ]));
                 self.items.push(Row::new(vec![
-                    "Network received messages".to_string(),
-                    format!(
-                        "{} ({})",
-                        stats.number_message_received,
-                        format_duration_since(stats.last_message_received)
-                    ),
+                    "Network received".to_string(),
+                    format_traffic(stats.received.snapshot()),
                 ]));
                 self.items.push(Row::new(vec![
-                    "Network sent messages".to_string(),
-                    format!(
-                        "{} ({})",
-                        stats.number_message_sent,
-                        format_duration_since(stats.last_message_sent)
-                    ),
+                    "Network sent".to_string(),
+                    format_traffic(stats.sent.snapshot()),
                 ]));
 
                 if let Some(error) = stats.error.as_ref() {
@@ -186,3 +178,14 @@ impl Settings {
 fn format_duration_since(since: Instant) -> String {
     format!("{:?} ago", Duration::from_secs(since.elapsed().as_secs()))
 }
+
+fn format_traffic(traffic: TrafficSnapshot) -> String {
+    let last = traffic
+        .last_frame
+        .map(|last| format_duration_since(last.into_std()))
+        .unwrap_or_else(|| "never".to_owned());
+    format!(
+        "{} frames, {} bytes, {} errors ({})",
+        traffic.frames, traffic.bytes, traffic.errors, last
+    )
+}
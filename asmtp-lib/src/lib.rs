@@ -8,5 +8,8 @@ mod passport_importer;
 mod topic;
 
 pub use self::{
-    entropy::Entropy, message_id::MessageId, passport_importer::PassportImporter, topic::mk_topic,
+    entropy::Entropy,
+    message_id::MessageId,
+    passport_importer::PassportImporter,
+    topic::{mk_topic, passport_id, passport_topic},
 };
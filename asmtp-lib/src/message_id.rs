@@ -33,7 +33,12 @@ impl MessageId {
     ///
     /// the time will be the current time
     pub fn new(bytes: impl AsRef<[u8]>) -> Self {
-        let mut message_id = Self::timed(Time::now());
+        Self::with_time(Time::now(), bytes)
+    }
+
+    /// create the [`MessageId`] of the given byte slice received at `time`
+    pub fn with_time(time: Time, bytes: impl AsRef<[u8]>) -> Self {
+        let mut message_id = Self::timed(time);
 
         Blake2b::blake2b(&mut message_id.0[Self::TIME_SIZE..], bytes.as_ref(), &[]);
 
@@ -22,7 +22,7 @@ impl PassportImporter<Block, Passport> {
         Ok(Self {
             current,
             pending: HashMap::new(),
-            added: HashSet::new(),
+            added: std::iter::once(block.header().hash()).collect(),
         })
     }
 
@@ -36,7 +36,7 @@ impl PassportImporter<Block, Passport> {
         };
 
         for block in blocks {
-            importer.put(block)?;
+            importer.load(block)?;
         }
 
         importer.finalize()
@@ -95,7 +95,7 @@ impl<'a> PassportImporter<BlockSlice<'a>, LightPassport> {
         Ok(Self {
             current,
             pending: HashMap::new(),
-            added: HashSet::new(),
+            added: std::iter::once(block.header().hash()).collect(),
         })
     }
 
@@ -109,7 +109,7 @@ impl<'a> PassportImporter<BlockSlice<'a>, LightPassport> {
         };
 
         for block in blocks {
-            importer.put(block)?;
+            importer.load(block)?;
         }
 
         importer.finalize()
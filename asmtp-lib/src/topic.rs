@@ -1,6 +1,7 @@
 use cryptoxide::{hmac::Hmac, pbkdf2::pbkdf2, sha2::Sha512};
-use keynesis::key::curve25519::PublicKey;
+use keynesis::{key::curve25519::PublicKey, passport::block::Hash};
 use poldercast::Topic;
+use std::convert::TryFrom as _;
 
 const ITERATIONS: u32 = 10 * 1_024;
 
@@ -42,3 +43,24 @@ pub fn mk_topic(key1: &PublicKey, key2: &PublicKey) -> Topic {
 
     Topic::new(bytes)
 }
+
+/// the `Topic` of the passport: the passport's id prefixed with zeros
+///
+/// the peers subscribed to this topic are sent the new blocks of the
+/// passport.
+pub fn passport_topic(id: &Hash) -> Topic {
+    let mut bytes = [0; Topic::SIZE];
+    bytes[(Topic::SIZE - Hash::SIZE)..].copy_from_slice(id.as_ref());
+    Topic::new(bytes)
+}
+
+/// the id of the passport if the `Topic` is a passport's topic (see
+/// [`passport_topic`])
+pub fn passport_id(topic: &Topic) -> Option<Hash> {
+    let (prefix, id) = topic.as_ref().split_at(Topic::SIZE - Hash::SIZE);
+    if prefix.iter().all(|b| *b == 0) {
+        Hash::try_from(id).ok()
+    } else {
+        None
+    }
+}
@@ -1,17 +1,23 @@
 use crate::{
-    codec::handshake::{HandshakeInitialize, HandshakeResponse},
-    Handle,
+    codec::handshake::{
+        has_capabilities, has_flags, is_first_contact, HandshakeInitialize, HandshakeResponse,
+        HandshakeSolution, HandshakeXxFinalize, HandshakeXxInitialize, HandshakeXxResponse,
+        HEADER_SIZE, VERSION_MISMATCH,
+    },
+    Capabilities, Handle, Puzzle, Version,
 };
-use anyhow::{bail, Context as _, Result};
+use anyhow::{bail, ensure, Context as _, Result};
 use keynesis::{
     hash::Blake2b,
     key::{
         ed25519::{self, PublicKey},
         Dh,
     },
-    noise::{ik::A, IK},
+    noise::{IK, XX},
+    passport::block::Time,
 };
 use rand_core::{CryptoRng, RngCore};
+use std::marker::PhantomData;
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 
 /// accept incoming handshake
@@ -22,10 +28,15 @@ use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 ///
 /// This object offers the necessary tooling to identify the initiator so it is
 /// possible to deny the connection early enough (see [Accepting::accept])
+///
+/// The initiator may not know our static key yet (first contact), in which
+/// case the Noise XX handshake is used instead of IK.
 pub struct Accepting<I, O, RNG, K = ed25519::SecretKey> {
     reader: I,
     writer: O,
-    state: IK<K, Blake2b, RNG, A>,
+    rng: RNG,
+    puzzle: Option<(Puzzle, Vec<u8>)>,
+    key: PhantomData<K>,
 }
 
 impl<I, O, K, RNG> Accepting<I, O, RNG, K>
@@ -37,7 +48,24 @@ where
         Self {
             reader,
             writer,
-            state: IK::new(rng, &[]),
+            rng,
+            puzzle: None,
+            key: PhantomData,
+        }
+    }
+
+    /// require the initiator to solve the [`Puzzle`] before processing
+    /// the handshake
+    ///
+    /// the `binding` identifies the initiator (its address for example)
+    /// so a solved challenge cannot be reused by another initiator.
+    ///
+    /// Initiators using a [`Version`](crate::Version) that does not
+    /// support the puzzle are rejected.
+    pub fn challenge(self, puzzle: Puzzle, binding: impl Into<Vec<u8>>) -> Self {
+        Self {
+            puzzle: Some((puzzle, binding.into())),
+            ..self
         }
     }
 }
@@ -56,7 +84,13 @@ where
     /// of unwelcome public keys.
     ///
     /// If the peer is accepted and is using a supported version of the protocol
-    /// then the functions replies the response handshake.
+    /// then the functions replies the response handshake. The peers using a
+    /// version we do not support are told so in clear, with the maximal
+    /// version we support, before any Diffie-Hellman operation (see
+    /// [`UnsupportedVersion`](crate::UnsupportedVersion)).
+    ///
+    /// On first contact the initiator only discloses its public key in the last
+    /// message of the handshake, `check_id` is then called once it is received.
     ///
     /// # Errors
     ///
@@ -64,29 +98,85 @@ where
     /// noise handshake.
     ///
     pub async fn accept<F>(self, k: &K, check_id: F) -> Result<Handle<I, O>>
+    where
+        F: Fn(&PublicKey) -> bool,
+    {
+        let mut header = [0; HEADER_SIZE];
+
+        let mut accepting = self;
+        accepting
+            .reader
+            .read_exact(&mut header[..Version::SIZE])
+            .await
+            .context("Cannot receive the initiate Handshake")?;
+
+        let version = Version::from_u8(header[0]);
+        if !version.is_supported() {
+            accepting
+                .writer
+                .write_all(&[Version::MAX.to_u8(), VERSION_MISMATCH])
+                .await
+                .context("Cannot send the version mismatch")?;
+            bail!("Unsupported version {:?}", version);
+        }
+
+        // the handshake of the version 1 does not have the flags byte
+        if has_flags(version) {
+            accepting
+                .reader
+                .read_exact(&mut header[Version::SIZE..])
+                .await
+                .context("Cannot receive the initiate Handshake")?;
+        }
+
+        if is_first_contact(header[1]) {
+            accepting.accept_xx(header, k, check_id).await
+        } else {
+            accepting.accept_ik(header, k, check_id).await
+        }
+    }
+
+    async fn accept_ik<F>(
+        self,
+        header: [u8; HEADER_SIZE],
+        k: &K,
+        check_id: F,
+    ) -> Result<Handle<I, O>>
     where
         F: Fn(&PublicKey) -> bool,
     {
         let Self {
             mut reader,
             mut writer,
-            state,
+            rng,
+            puzzle,
+            key: PhantomData,
         } = self;
 
         let mut bytes = [0; HandshakeInitialize::SIZE];
+        bytes[..HEADER_SIZE].copy_from_slice(&header);
 
         reader
-            .read_exact(&mut bytes)
+            .read_exact(&mut bytes[HEADER_SIZE..])
             .await
             .context("Cannot receive the Noise IK initiate Handshake")?;
 
         let message = HandshakeInitialize::from_bytes(bytes);
+        let capabilities = receive_capabilities(&mut reader, message.version()).await?;
 
-        if !message.version().is_supported() {
-            bail!("Unsupported version {:?}", message.version());
+        if let Some((puzzle, binding)) = puzzle {
+            challenge(
+                &mut reader,
+                &mut writer,
+                &puzzle,
+                &binding,
+                message.version(),
+                message.as_ref(),
+            )
+            .await?;
         }
 
-        let state = state
+        let state = IK::<K, Blake2b, RNG, _>::new(rng, &[])
             .receive(k, message.message())
             .context("Noise IK Handshake Initiate failed")?;
 
@@ -97,17 +187,183 @@ where
             )
         }
 
-        let mut message = HandshakeResponse::DEFAULT;
+        // reply with the version of the initiator so older versions
+        // still recognize the response
+        let mut message = HandshakeResponse::new(message.version());
 
         let state = state
-            .reply(&mut message.message_mut())
+            .reply(message.message_mut())
             .context("Cannot prep the Noise's Handshake Response message")?;
 
+        let mut reply = message.to_bytes();
+        if has_capabilities(message.version()) {
+            reply.extend_from_slice(&Capabilities::ALL.to_bytes());
+        }
+
         writer
-            .write_all(message.as_ref())
+            .write_all(&reply)
             .await
             .context("Cannot send the Noise IK response Handshake")?;
 
-        Ok(Handle::new(reader, writer, state))
+        let capabilities = Capabilities::ALL.intersection(capabilities);
+        Ok(Handle::new(
+            reader,
+            writer,
+            state,
+            message.version(),
+            capabilities,
+        ))
+    }
+
+    async fn accept_xx<F>(
+        self,
+        header: [u8; HEADER_SIZE],
+        k: &K,
+        check_id: F,
+    ) -> Result<Handle<I, O>>
+    where
+        F: Fn(&PublicKey) -> bool,
+    {
+        let Self {
+            mut reader,
+            mut writer,
+            rng,
+            puzzle,
+            key: PhantomData,
+        } = self;
+
+        let mut bytes = [0; HandshakeXxInitialize::SIZE];
+        bytes[..HEADER_SIZE].copy_from_slice(&header);
+
+        reader
+            .read_exact(&mut bytes[HEADER_SIZE..])
+            .await
+            .context("Cannot receive the Noise XX initiate Handshake")?;
+
+        let message = HandshakeXxInitialize::from_bytes(bytes);
+
+        if !message.version().supports_first_contact() {
+            bail!("Unsupported version {:?}", message.version());
+        }
+        let capabilities = receive_capabilities(&mut reader, message.version()).await?;
+
+        if let Some((puzzle, binding)) = puzzle {
+            challenge(
+                &mut reader,
+                &mut writer,
+                &puzzle,
+                &binding,
+                message.version(),
+                message.as_ref(),
+            )
+            .await?;
+        }
+
+        let state = XX::<K, Blake2b, RNG, _>::new(rng, &[])
+            .receive(message.message())
+            .context("Noise XX Handshake Initiate failed")?;
+
+        let version = message.version();
+        let mut message = HandshakeXxResponse::new(version);
+
+        let state = state
+            .reply(k, message.message_mut())
+            .context("Cannot prep the Noise's Handshake Response message")?;
+
+        let mut reply = message.as_ref().to_vec();
+        if has_capabilities(version) {
+            reply.extend_from_slice(&Capabilities::ALL.to_bytes());
+        }
+
+        writer
+            .write_all(&reply)
+            .await
+            .context("Cannot send the Noise XX response Handshake")?;
+
+        let mut bytes = [0; HandshakeXxFinalize::SIZE];
+        reader
+            .read_exact(&mut bytes)
+            .await
+            .context("Cannot receive the Noise XX final Handshake")?;
+        let message = HandshakeXxFinalize::from_bytes(bytes);
+
+        ensure!(
+            message.version() == version,
+            "Unexpected version {:?} in the final Handshake",
+            message.version()
+        );
+
+        let state = state
+            .receive(message.message())
+            .context("Noise XX Handshake final message failed")?;
+
+        if !check_id(state.remote_public_identity()) {
+            bail!(
+                "Rejecting connection with {}",
+                state.remote_public_identity()
+            )
+        }
+
+        let capabilities = Capabilities::ALL.intersection(capabilities);
+        Ok(Handle::new(reader, writer, state, version, capabilities))
     }
 }
+
+/// receive the [`Capabilities`] following the initial handshake message of
+/// the `version`
+///
+/// the initiators of the previous versions do not send them, they support
+/// all the features of their version.
+async fn receive_capabilities<I>(reader: &mut I, version: Version) -> Result<Capabilities>
+where
+    I: AsyncRead + Unpin,
+{
+    if !has_capabilities(version) {
+        return Ok(Capabilities::of(version));
+    }
+
+    let mut bytes = [0; Capabilities::SIZE];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .context("Cannot receive the initiator's capabilities")?;
+    Ok(Capabilities::from_bytes(bytes))
+}
+
+/// send the [`Puzzle`]'s challenge to the initiator and verify the solution
+/// for the given initial handshake message
+async fn challenge<I, O>(
+    reader: &mut I,
+    writer: &mut O,
+    puzzle: &Puzzle,
+    binding: &[u8],
+    version: Version,
+    handshake: &[u8],
+) -> Result<()>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + Unpin,
+{
+    ensure!(
+        version.supports_puzzle(),
+        "Peer's version ({}) does not support the puzzle",
+        version
+    );
+
+    let challenge = puzzle.challenge(version, binding, Time::now());
+    writer
+        .write_all(challenge.as_ref())
+        .await
+        .context("Cannot send the puzzle's challenge")?;
+
+    let mut bytes = [0; HandshakeSolution::SIZE];
+    reader
+        .read_exact(&mut bytes)
+        .await
+        .context("Cannot receive the puzzle's solution")?;
+    let solution = HandshakeSolution::from_bytes(bytes);
+
+    puzzle
+        .verify(&solution, binding, handshake, Time::now())
+        .context("Invalid puzzle's solution")
+}
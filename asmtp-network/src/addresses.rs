@@ -0,0 +1,213 @@
+/*!
+advertised addresses
+
+The poldercast [`Gossip`] of a node carries only one address. A node
+that can be reached at several addresses (IPv4 and IPv6, LAN and WAN...)
+advertises all of them with [`PeerAddresses`]. The record is signed with
+the node's key so it can be relayed along with the node's gossip without
+being tampered with.
+
+The addresses are listed in the node's order of preference. The peers
+connect to the first one reachable.
+
+[`Gossip`]: poldercast::Gossip
+*/
+
+use anyhow::{bail, ensure, Context as _, Result};
+use keynesis::{
+    key::ed25519::{PublicKey, SecretKey, Signature},
+    passport::block::Time,
+};
+use std::{
+    convert::{TryFrom, TryInto as _},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+const IPV4: u8 = 4;
+const IPV6: u8 = 6;
+
+/// the addresses a node can be reached at, signed by the node
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PeerAddresses(Vec<u8>);
+
+impl PeerAddresses {
+    /// maximum number of addresses a node can advertise
+    pub const MAX_ADDRESSES: usize = 8;
+
+    /// the node's id, the time and the number of addresses
+    const HEAD_SIZE: usize = PublicKey::SIZE + Time::SIZE + 1;
+
+    /// sign the `addresses` the node `id` can be reached at
+    ///
+    /// the `time` orders the records of the same node: only the most
+    /// recent one is kept.
+    pub fn new(id: &SecretKey, time: Time, addresses: &[SocketAddr]) -> Result<Self> {
+        ensure!(!addresses.is_empty(), "Expecting at least one address");
+        ensure!(
+            addresses.len() <= Self::MAX_ADDRESSES,
+            "Cannot advertise more than {} addresses",
+            Self::MAX_ADDRESSES
+        );
+
+        let mut bytes = Vec::with_capacity(Self::HEAD_SIZE + Signature::SIZE);
+        bytes.extend_from_slice(id.public_key().as_ref());
+        bytes.extend_from_slice(&time.to_be_bytes());
+        bytes.push(addresses.len() as u8);
+        for address in addresses {
+            match address.ip() {
+                IpAddr::V4(ip) => {
+                    bytes.push(IPV4);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    bytes.push(IPV6);
+                    bytes.extend_from_slice(&ip.octets());
+                }
+            }
+            bytes.extend_from_slice(&address.port().to_be_bytes());
+        }
+
+        let signature = id.sign(&bytes);
+        bytes.extend_from_slice(signature.as_ref());
+
+        Ok(Self(bytes))
+    }
+
+    /// read the record from the given bytes, checking the signature
+    pub fn try_from_slice(slice: &[u8]) -> Result<Self> {
+        ensure!(
+            slice.len() >= Self::HEAD_SIZE + Signature::SIZE,
+            "Not enough bytes for the peer addresses"
+        );
+
+        let (signed, signature) = slice.split_at(slice.len() - Signature::SIZE);
+        let signature = Signature::try_from(signature).context("Invalid signature")?;
+
+        let record = Self(slice.to_vec());
+        let addresses = record
+            .read_addresses(signed)
+            .context("Invalid peer addresses")?;
+        ensure!(!addresses.is_empty(), "Expecting at least one address");
+
+        ensure!(
+            record.id().verify(signed, &signature),
+            "The signature does not match the peer addresses"
+        );
+
+        Ok(record)
+    }
+
+    /// the node advertising the addresses
+    pub fn id(&self) -> PublicKey {
+        PublicKey::try_from(&self.0[..PublicKey::SIZE]).expect("valid public key")
+    }
+
+    /// when the addresses were signed
+    pub fn time(&self) -> Time {
+        let time = &self.0[PublicKey::SIZE..PublicKey::SIZE + Time::SIZE];
+        Time::from(u32::from_be_bytes(time.try_into().unwrap()))
+    }
+
+    /// the addresses, in the node's order of preference
+    pub fn addresses(&self) -> Vec<SocketAddr> {
+        let signed = &self.0[..self.0.len() - Signature::SIZE];
+        self.read_addresses(signed)
+            .expect("the addresses were checked already")
+    }
+
+    fn read_addresses(&self, signed: &[u8]) -> Result<Vec<SocketAddr>> {
+        let number = signed[Self::HEAD_SIZE - 1] as usize;
+        ensure!(
+            number <= Self::MAX_ADDRESSES,
+            "Too many addresses ({})",
+            number
+        );
+
+        let mut bytes = &signed[Self::HEAD_SIZE..];
+        let mut addresses = Vec::with_capacity(number);
+        for index in 0..number {
+            ensure!(!bytes.is_empty(), "Expecting the address {}", index);
+            let ip = match bytes[0] {
+                IPV4 if bytes.len() >= 1 + 4 + 2 => {
+                    let ip: [u8; 4] = bytes[1..5].try_into().unwrap();
+                    bytes = &bytes[5..];
+                    IpAddr::V4(Ipv4Addr::from(ip))
+                }
+                IPV6 if bytes.len() >= 1 + 16 + 2 => {
+                    let ip: [u8; 16] = bytes[1..17].try_into().unwrap();
+                    bytes = &bytes[17..];
+                    IpAddr::V6(Ipv6Addr::from(ip))
+                }
+                IPV4 | IPV6 => bail!("Not enough bytes for the address {}", index),
+                kind => bail!("Unknown kind of address ({}) for address {}", kind, index),
+            };
+            let port = u16::from_be_bytes(bytes[..2].try_into().unwrap());
+            bytes = &bytes[2..];
+
+            addresses.push(SocketAddr::new(ip, port));
+        }
+        ensure!(bytes.is_empty(), "Unexpected bytes after the addresses");
+
+        Ok(addresses)
+    }
+}
+
+impl AsRef<[u8]> for PeerAddresses {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::Seed;
+
+    fn addresses() -> Vec<SocketAddr> {
+        vec![
+            "192.168.1.2:9876".parse().unwrap(),
+            "[2001:db8::1]:9876".parse().unwrap(),
+            "203.0.113.7:443".parse().unwrap(),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let key = SecretKey::new(&mut rng);
+        let time = Time::from(1_234);
+
+        let record = PeerAddresses::new(&key, time, &addresses()).unwrap();
+        let decoded = PeerAddresses::try_from_slice(record.as_ref()).unwrap();
+
+        assert_eq!(decoded, record);
+        assert_eq!(decoded.id(), key.public_key());
+        assert_eq!(decoded.time(), time);
+        assert_eq!(decoded.addresses(), addresses());
+    }
+
+    #[test]
+    fn tampered_addresses_are_rejected() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let key = SecretKey::new(&mut rng);
+
+        let record = PeerAddresses::new(&key, Time::from(1_234), &addresses()).unwrap();
+        let mut bytes = record.as_ref().to_vec();
+        // change the port of the first address
+        bytes[PeerAddresses::HEAD_SIZE + 5] ^= 1;
+
+        assert!(PeerAddresses::try_from_slice(&bytes).is_err());
+        assert!(PeerAddresses::try_from_slice(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn number_of_addresses_is_bounded() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let key = SecretKey::new(&mut rng);
+        let time = Time::from(1_234);
+
+        assert!(PeerAddresses::new(&key, time, &[]).is_err());
+        let many = vec![addresses()[0]; PeerAddresses::MAX_ADDRESSES + 1];
+        assert!(PeerAddresses::new(&key, time, &many).is_err());
+    }
+}
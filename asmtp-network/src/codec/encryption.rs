@@ -11,6 +11,8 @@ use tokio_util::codec::{Decoder, Encoder};
 const MIN_FRAME_LENGTH: usize = 16; // the length and the 16 bytes of mac
 pub const MAX_FRAME_LENGTH: usize = u16::MAX as usize - HEAD_LENGTH;
 const HEAD_LENGTH: usize = std::mem::size_of::<u16>();
+/// bytes added on the wire to every frame: the length and the mac
+pub(crate) const FRAME_OVERHEAD: usize = HEAD_LENGTH + 16;
 
 /**
 # Decoder for encrypted connections
@@ -197,3 +199,63 @@ impl Encoder<Bytes> for NoiseEncryptedEncoder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::handshake::{HandshakeInitialize, HandshakeResponse};
+    use keynesis::{
+        key::ed25519::SecretKey,
+        noise::{TransportState, IK},
+        Seed,
+    };
+
+    /// the transport states of the initiator and of the responder of an
+    /// IK handshake, always the same ones
+    fn transports() -> (TransportState<Blake2b>, TransportState<Blake2b>) {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let initiator = SecretKey::new(&mut rng);
+        let responder = SecretKey::new(&mut rng);
+
+        let mut initialize = HandshakeInitialize::new(crate::Version::CURRENT);
+        let opening = IK::<_, Blake2b, _, _>::new(rng, &[])
+            .initiate(&initiator, responder.public_key(), initialize.message_mut())
+            .unwrap();
+        let mut response = HandshakeResponse::new(crate::Version::CURRENT);
+        let rng = Seed::from([1; Seed::SIZE]).into_rand_chacha();
+        let accepted = IK::<_, Blake2b, _, _>::new(rng, &[])
+            .receive(&responder, initialize.message())
+            .unwrap()
+            .reply(response.message_mut())
+            .unwrap();
+        let opened = opening.receive(&initiator, response.message()).unwrap();
+
+        (opened, accepted)
+    }
+
+    #[test]
+    fn every_frame_rekeys() {
+        let (opened, accepted) = transports();
+        let (send, _) = opened.split();
+        let (_, receive) = accepted.split();
+        let mut encoder = NoiseEncryptedEncoder::new(send);
+        let mut decoder = NoiseEncryptedDecoder::new(receive);
+
+        let frame = Bytes::from_static(b"hello");
+        let mut first = BytesMut::new();
+        let mut second = BytesMut::new();
+        encoder.encode(frame.clone(), &mut first).unwrap();
+        encoder.encode(frame.clone(), &mut second).unwrap();
+        // the same frame is encrypted with a different key
+        assert_ne!(first, second);
+
+        // a frame only decrypts with the cipher state it was sent with:
+        // not before the previous frame, not twice
+        assert!(decoder.decode(&mut second.clone()).is_err());
+        let (_, accepted) = transports();
+        let mut decoder = NoiseEncryptedDecoder::new(accepted.split().1);
+        assert_eq!(decoder.decode(&mut first.clone()).unwrap().unwrap(), frame);
+        assert_eq!(decoder.decode(&mut second).unwrap().unwrap(), frame);
+        assert!(decoder.decode(&mut first).is_err());
+    }
+}
@@ -1,9 +1,10 @@
 use crate::Version;
-use keynesis::key::ed25519;
+use keynesis::{key::ed25519, passport::block::Time};
 
 /// initial handshake message
 ///
-/// composed of the [`Version`] and the noise initiator handshake [`IK`]
+/// composed of the [`Version`], the flags (see [`HEADER_SIZE`]) and the
+/// noise initiator handshake [`IK`]
 ///
 /// [`IK`]: keynesis::noise::IK
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
@@ -11,44 +12,239 @@ pub struct HandshakeInitialize([u8; Self::SIZE]);
 
 /// handshake reply
 ///
-/// composed of the [`Version`] and the noise response handshake [`IK`]
+/// composed of the [`Version`], the flags and the noise response handshake
+/// [`IK`]
 ///
 /// [`IK`]: keynesis::noise::IK
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 pub struct HandshakeResponse([u8; Self::SIZE]);
 
+/// initial handshake message of the first contact
+///
+/// composed of the [`Version`], the flags (with [`FIRST_CONTACT`] set) and
+/// the noise initiator handshake [`XX`]. Used when the initiator does not
+/// know the static key of the responder yet.
+///
+/// [`XX`]: keynesis::noise::XX
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct HandshakeXxInitialize([u8; Self::SIZE]);
+
+/// first contact handshake reply
+///
+/// composed of the [`Version`], the flags and the noise responder handshake
+/// [`XX`] (including the responder's static key).
+///
+/// [`XX`]: keynesis::noise::XX
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct HandshakeXxResponse([u8; Self::SIZE]);
+
+/// last message of the first contact handshake
+///
+/// composed of the [`Version`], the flags and the last noise initiator
+/// handshake [`XX`] (including the initiator's static key).
+///
+/// [`XX`]: keynesis::noise::XX
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct HandshakeXxFinalize([u8; Self::SIZE]);
+
+/// challenge sent by the responder instead of the [`HandshakeResponse`]
+///
+/// composed of the [`Version`], the flags (with [`CHALLENGE`] set), the
+/// difficulty, the time the challenge was issued and the responder's MAC
+/// of these values (see [`Puzzle`]).
+///
+/// [`Puzzle`]: crate::Puzzle
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct HandshakeChallenge([u8; Self::SIZE]);
+
+/// the initiator's solution to the [`HandshakeChallenge`]
+///
+/// composed of the challenge as received and the nonce solving it.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+pub struct HandshakeSolution([u8; Self::SIZE]);
+
+/// size of the header of the handshake messages: the [`Version`] followed
+/// by one byte of flags
+///
+/// the flags byte is only present from [`Version::V2`], the handshake
+/// messages of [`Version::V1`] are the noise messages right after the
+/// version (see [`has_flags`]).
+pub const HEADER_SIZE: usize = Version::SIZE + 1;
+
+/// flag set when the responder sends a [`HandshakeChallenge`] instead of
+/// the [`HandshakeResponse`]
+pub const CHALLENGE: u8 = 0b0000_0001;
+
+/// flag set on the initial handshake message when the initiator uses the
+/// first contact handshake ([`HandshakeXxInitialize`])
+pub const FIRST_CONTACT: u8 = 0b0000_0010;
+
+/// flag set when the responder does not support the version of the
+/// initiator: the reply is the header alone, with the maximal version the
+/// responder supports (see [`Version::MAX`])
+pub const VERSION_MISMATCH: u8 = 0b0000_0100;
+
+/// returns if the handshake messages of the `version` have the flags byte
+pub fn has_flags(version: Version) -> bool {
+    version >= Version::V2
+}
+
+/// returns if the initial handshake message and the reply of the `version`
+/// are followed by the [`Capabilities`] of the peer
+///
+/// [`Capabilities`]: crate::Capabilities
+pub fn has_capabilities(version: Version) -> bool {
+    version >= Version::V3
+}
+
+/// returns if the `flags` have [`FIRST_CONTACT`] set
+pub fn is_first_contact(flags: u8) -> bool {
+    flags & FIRST_CONTACT == FIRST_CONTACT
+}
+
+/// returns if the `flags` have [`VERSION_MISMATCH`] set
+pub fn is_version_mismatch(flags: u8) -> bool {
+    flags & VERSION_MISMATCH == VERSION_MISMATCH
+}
+
+/// returns if the `flags` have [`CHALLENGE`] set
+pub fn is_challenge(flags: u8) -> bool {
+    flags & CHALLENGE == CHALLENGE
+}
+
 impl HandshakeInitialize {
     pub const SIZE: usize =
-        Version::SIZE + ed25519::PublicKey::SIZE + (ed25519::PublicKey::SIZE + 16) + 16;
-    pub const DEFAULT: Self = Self::new(Version::CURRENT);
-
-    const fn new(version: Version) -> Self {
-        Self::from_bytes([version.to_u8(); Self::SIZE])
+        HEADER_SIZE + ed25519::PublicKey::SIZE + (ed25519::PublicKey::SIZE + 16) + 16;
+    pub const fn new(version: Version) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        Self::from_bytes(bytes)
     }
 
     pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
         Self(bytes)
     }
 
+    /// the message as sent to the responder, without the flags byte
+    /// if the version does not have it (see [`has_flags`])
+    pub fn to_bytes(self) -> Vec<u8> {
+        if has_flags(self.version()) {
+            self.0.to_vec()
+        } else {
+            [&self.0[..Version::SIZE], self.message()].concat()
+        }
+    }
+
     pub fn version(&self) -> Version {
         Version::from_u8(self.0[0])
     }
 
     pub fn message(&self) -> &[u8] {
-        &self.0[1..]
+        &self.0[HEADER_SIZE..]
     }
 
     pub fn message_mut(&mut self) -> &mut [u8] {
-        &mut self.0[1..]
+        &mut self.0[HEADER_SIZE..]
     }
 }
 
 impl HandshakeResponse {
-    pub const SIZE: usize = Version::SIZE + ed25519::PublicKey::SIZE + 16;
-    pub const DEFAULT: Self = Self::new(Version::CURRENT);
+    pub const SIZE: usize = HEADER_SIZE + ed25519::PublicKey::SIZE + 16;
+
+    pub const fn new(version: Version) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        Self::from_bytes(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    /// the response as sent to the initiator, without the flags byte
+    /// if the version does not have it (see [`has_flags`])
+    pub fn to_bytes(self) -> Vec<u8> {
+        if has_flags(self.version()) {
+            self.0.to_vec()
+        } else {
+            [&self.0[..Version::SIZE], self.message()].concat()
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        Version::from_u8(self.0[0])
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.0[HEADER_SIZE..]
+    }
+
+    pub fn message_mut(&mut self) -> &mut [u8] {
+        &mut self.0[HEADER_SIZE..]
+    }
+}
+
+impl HandshakeXxInitialize {
+    pub const SIZE: usize = HEADER_SIZE + ed25519::PublicKey::SIZE;
+
+    pub const fn new(version: Version) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        bytes[1] = FIRST_CONTACT;
+        Self::from_bytes(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn version(&self) -> Version {
+        Version::from_u8(self.0[0])
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.0[HEADER_SIZE..]
+    }
+
+    pub fn message_mut(&mut self) -> &mut [u8] {
+        &mut self.0[HEADER_SIZE..]
+    }
+}
+
+impl HandshakeXxResponse {
+    pub const SIZE: usize =
+        HEADER_SIZE + ed25519::PublicKey::SIZE + (ed25519::PublicKey::SIZE + 16) + 16;
+
+    pub const fn new(version: Version) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        Self::from_bytes(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn version(&self) -> Version {
+        Version::from_u8(self.0[0])
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.0[HEADER_SIZE..]
+    }
+
+    pub fn message_mut(&mut self) -> &mut [u8] {
+        &mut self.0[HEADER_SIZE..]
+    }
+}
+
+impl HandshakeXxFinalize {
+    pub const SIZE: usize = HEADER_SIZE + (ed25519::PublicKey::SIZE + 16) + 16;
 
-    const fn new(version: Version) -> Self {
-        Self::from_bytes([version.to_u8(); Self::SIZE])
+    pub const fn new(version: Version) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        Self::from_bytes(bytes)
     }
 
     pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
@@ -60,11 +256,84 @@ impl HandshakeResponse {
     }
 
     pub fn message(&self) -> &[u8] {
-        &self.0[1..]
+        &self.0[HEADER_SIZE..]
     }
 
     pub fn message_mut(&mut self) -> &mut [u8] {
-        &mut self.0[1..]
+        &mut self.0[HEADER_SIZE..]
+    }
+}
+
+impl HandshakeChallenge {
+    pub const MAC_SIZE: usize = 16;
+    pub const SIZE: usize = HEADER_SIZE + 1 + 4 + Self::MAC_SIZE;
+    const MAC_OFFSET: usize = Self::SIZE - Self::MAC_SIZE;
+
+    pub fn new(version: Version, difficulty: u8, time: Time) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = version.to_u8();
+        bytes[1] = CHALLENGE;
+        bytes[HEADER_SIZE] = difficulty;
+        bytes[HEADER_SIZE + 1..Self::MAC_OFFSET].copy_from_slice(&time.to_be_bytes());
+        Self(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn version(&self) -> Version {
+        Version::from_u8(self.0[0])
+    }
+
+    pub fn difficulty(&self) -> u8 {
+        self.0[HEADER_SIZE]
+    }
+
+    pub fn time(&self) -> Time {
+        let mut time = [0; 4];
+        time.copy_from_slice(&self.0[HEADER_SIZE + 1..Self::MAC_OFFSET]);
+        Time::from(u32::from_be_bytes(time))
+    }
+
+    /// the part of the challenge that is authenticated by the MAC
+    pub fn header(&self) -> &[u8] {
+        &self.0[..Self::MAC_OFFSET]
+    }
+
+    pub fn mac(&self) -> &[u8] {
+        &self.0[Self::MAC_OFFSET..]
+    }
+
+    pub fn mac_mut(&mut self) -> &mut [u8] {
+        &mut self.0[Self::MAC_OFFSET..]
+    }
+}
+
+impl HandshakeSolution {
+    pub const SIZE: usize = HandshakeChallenge::SIZE + 8;
+
+    pub fn new(challenge: &HandshakeChallenge, nonce: u64) -> Self {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..HandshakeChallenge::SIZE].copy_from_slice(challenge.as_ref());
+        bytes[HandshakeChallenge::SIZE..].copy_from_slice(&nonce.to_be_bytes());
+        Self(bytes)
+    }
+
+    pub const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn challenge(&self) -> HandshakeChallenge {
+        let mut challenge = [0; HandshakeChallenge::SIZE];
+        challenge.copy_from_slice(&self.0[..HandshakeChallenge::SIZE]);
+        HandshakeChallenge::from_bytes(challenge)
+    }
+
+    pub fn nonce(&self) -> u64 {
+        let mut nonce = [0; 8];
+        nonce.copy_from_slice(&self.0[HandshakeChallenge::SIZE..]);
+        u64::from_be_bytes(nonce)
     }
 }
 
@@ -79,3 +348,33 @@ impl AsRef<[u8]> for HandshakeResponse {
         self.0.as_ref()
     }
 }
+
+impl AsRef<[u8]> for HandshakeChallenge {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for HandshakeSolution {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for HandshakeXxInitialize {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for HandshakeXxResponse {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl AsRef<[u8]> for HandshakeXxFinalize {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
@@ -0,0 +1,118 @@
+/*!
+cover traffic
+
+An observer of the link between 2 peers cannot read the messages (they are
+encrypted) but it can see _when_ messages are sent and how large they are.
+To make it harder to correlate the activity of a user with the traffic, the
+peers can send dummy messages ([`MessageType::Cover`]) at random times. The
+receivers discard them.
+
+The dummy messages are sent following a Poisson process: the delay between
+2 dummy messages is exponentially distributed so the dummy messages are not
+sent at regular (recognizable) intervals. Their size is uniformly distributed
+so they cannot be told apart from the actual messages by their size.
+
+[`MessageType::Cover`]: crate::MessageType::Cover
+*/
+
+use crate::Message;
+use rand_core::RngCore;
+use std::time::Duration;
+
+/// settings of the cover traffic of a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CoverTraffic {
+    interval: Duration,
+    max_size: usize,
+}
+
+impl CoverTraffic {
+    /// the maximum size of a dummy message
+    pub const MAX_SIZE: usize = 16 * 1024;
+
+    /// delays are capped to this number of times the mean interval so we
+    /// do not wait forever on unlucky draws
+    const MAX_INTERVALS: f64 = 10.0;
+
+    /// send dummy messages every `interval` on average, of at most
+    /// `max_size` bytes (bound to [`CoverTraffic::MAX_SIZE`])
+    pub fn new(interval: Duration, max_size: usize) -> Self {
+        Self {
+            interval,
+            max_size: max_size.min(Self::MAX_SIZE),
+        }
+    }
+
+    /// the mean time between 2 dummy messages
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// the maximum size of the dummy messages
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// the time to wait before sending the next dummy message
+    pub fn next_delay<RNG>(&self, mut rng: RNG) -> Duration
+    where
+        RNG: RngCore,
+    {
+        // uniformly distributed in `]0; 1]`
+        let uniform = ((rng.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64;
+        let intervals = (-uniform.ln()).min(Self::MAX_INTERVALS);
+
+        self.interval.mul_f64(intervals)
+    }
+
+    /// create the next dummy message
+    pub fn message<RNG>(&self, mut rng: RNG) -> Message
+    where
+        RNG: RngCore,
+    {
+        let size = if self.max_size == 0 {
+            0
+        } else {
+            rng.next_u64() as usize % (self.max_size + 1)
+        };
+
+        Message::new_cover(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageType;
+    use keynesis::Seed;
+
+    #[test]
+    fn delays_are_bounded() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let cover = CoverTraffic::new(Duration::from_secs(1), 1024);
+
+        let mut total = Duration::from_secs(0);
+        for _ in 0..1_000 {
+            let delay = cover.next_delay(&mut rng);
+            assert!(delay <= Duration::from_secs(10));
+            total += delay;
+        }
+
+        // the mean is close to the interval
+        assert!(total > Duration::from_secs(900));
+        assert!(total < Duration::from_secs(1_100));
+    }
+
+    #[test]
+    fn messages_are_cover() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let cover = CoverTraffic::new(Duration::from_secs(1), usize::MAX);
+        assert_eq!(cover.max_size(), CoverTraffic::MAX_SIZE);
+
+        for _ in 0..100 {
+            let message = cover.message(&mut rng);
+            assert_eq!(message.message_type(), MessageType::Cover);
+            assert!(message.as_ref().len() <= CoverTraffic::MAX_SIZE);
+        }
+    }
+}
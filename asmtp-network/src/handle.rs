@@ -1,7 +1,7 @@
 use crate::{
     codec::{NoiseEncryptedDecoder, NoiseEncryptedEncoder},
-    opening::Opening,
-    Accepting, SessionId,
+    opening::{FirstContact, Opening},
+    Accepting, Capabilities, GoodbyeReason, Message, Puzzle, SessionId, Version,
 };
 use anyhow::{Context as _, Result};
 use bytes::{Bytes, BytesMut};
@@ -15,6 +15,7 @@ use rand_core::{CryptoRng, RngCore};
 use std::{
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::codec::{FramedRead, FramedWrite};
@@ -36,6 +37,8 @@ pub struct Handle<I, O> {
 pub struct HandleReadHalf<I> {
     none: bool,
     stream: FramedRead<I, NoiseEncryptedDecoder>,
+    version: Version,
+    capabilities: Capabilities,
 }
 
 /// the writing half of the encrypted connection
@@ -43,17 +46,39 @@ pub struct HandleReadHalf<I> {
 /// see [`Handle::split`] for more information
 pub struct HandleWriteHalf<O> {
     sink: FramedWrite<O, NoiseEncryptedEncoder>,
+    version: Version,
+    capabilities: Capabilities,
 }
 
 impl<I> HandleReadHalf<I>
 where
     I: AsyncRead,
 {
-    fn new(stream: I, state: TransportReceiveHalf<Blake2b>) -> Self {
+    fn new(
+        stream: I,
+        state: TransportReceiveHalf<Blake2b>,
+        version: Version,
+        capabilities: Capabilities,
+    ) -> Self {
         let stream = FramedRead::new(stream, NoiseEncryptedDecoder::new(state));
         let none = false;
 
-        Self { stream, none }
+        Self {
+            stream,
+            none,
+            version,
+            capabilities,
+        }
+    }
+
+    /// the version of the protocol agreed with the peer during the handshake
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// the capabilities agreed with the peer during the handshake
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
     }
 
     /// retrieve the public identity of the peer
@@ -75,10 +100,29 @@ impl<O> HandleWriteHalf<O>
 where
     O: AsyncWrite,
 {
-    fn new(stream: O, state: TransportSendHalf<Blake2b>) -> Self {
+    fn new(
+        stream: O,
+        state: TransportSendHalf<Blake2b>,
+        version: Version,
+        capabilities: Capabilities,
+    ) -> Self {
         let sink = FramedWrite::new(stream, NoiseEncryptedEncoder::new(state));
 
-        Self { sink }
+        Self {
+            sink,
+            version,
+            capabilities,
+        }
+    }
+
+    /// the version of the protocol agreed with the peer during the handshake
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// the capabilities agreed with the peer during the handshake
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
     }
 
     /// retrieve the public identity of the peer
@@ -101,15 +145,34 @@ where
     I: AsyncRead + Unpin,
     O: AsyncWrite + Unpin,
 {
-    pub(crate) fn new(stream: I, sink: O, state: TransportState<Blake2b>) -> Self {
+    pub(crate) fn new(
+        stream: I,
+        sink: O,
+        state: TransportState<Blake2b>,
+        version: Version,
+        capabilities: Capabilities,
+    ) -> Self {
         let (tsh, trh) = state.split();
 
-        let stream = HandleReadHalf::new(stream, trh);
-        let sink = HandleWriteHalf::new(sink, tsh);
+        let stream = HandleReadHalf::new(stream, trh, version, capabilities);
+        let sink = HandleWriteHalf::new(sink, tsh, version, capabilities);
 
         Self { stream, sink }
     }
 
+    /// tell the peer we are closing the connection and why, before the
+    /// connection is handed over (see [`Message::new_goodbye`])
+    pub(crate) async fn goodbye(
+        &mut self,
+        reason: GoodbyeReason,
+        retry_after: Option<Duration>,
+    ) -> Result<()> {
+        let message = Message::new_goodbye(reason, retry_after);
+        self.send(message.to_bytes())
+            .await
+            .context("Cannot send the goodbye message")
+    }
+
     /// split the handle into 2 parts into 2 separate half
     ///
     /// One will contains the writing half and the other one the reading half. This is
@@ -145,8 +208,27 @@ where
         K: Dh,
         RNG: RngCore + CryptoRng,
     {
-        let opening = Opening::new(rng, k, rs, reader, writer).await?;
-        opening.wait(k).await
+        let opening = Opening::new(rng, k, rs, Version::CURRENT, reader, writer).await?;
+        opening.wait(k, Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY).await
+    }
+
+    /// open a new stream with the remote peer connected to the `stream`
+    /// without knowing the remote's public identity in advance (first
+    /// contact).
+    ///
+    /// The remote discloses its identity during the handshake, it is then
+    /// available with [`remote_public_identity`](Self::remote_public_identity).
+    /// It is up to the caller to decide whether this identity is to be trusted
+    /// (for example by pinning it the first time and checking it does not
+    /// change later).
+    ///
+    pub async fn first_contact<K, RNG>(rng: RNG, k: &K, reader: I, writer: O) -> Result<Self>
+    where
+        K: Dh,
+        RNG: RngCore + CryptoRng,
+    {
+        let opening = FirstContact::new(rng, Version::CURRENT, reader, writer).await?;
+        opening.wait(k, Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY).await
     }
 
     /// retrieve the public identity of the peer
@@ -163,6 +245,16 @@ where
     pub fn session_id(&self) -> &SessionId {
         self.stream.session_id()
     }
+
+    /// the version of the protocol agreed with the peer during the handshake
+    pub fn version(&self) -> Version {
+        self.stream.version()
+    }
+
+    /// the capabilities agreed with the peer during the handshake
+    pub fn capabilities(&self) -> Capabilities {
+        self.stream.capabilities()
+    }
 }
 
 impl<I, O> Stream for Handle<I, O>
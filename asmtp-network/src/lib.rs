@@ -33,18 +33,31 @@ ASMTP will use the [`Topic`] to relay messages to the appropriate peers.
 */
 
 mod accept;
+mod addresses;
 mod codec;
+mod cover;
 mod handle;
 mod message;
 pub mod net;
 mod opening;
+mod puzzle;
+mod query;
+mod reconcile;
 mod session_id;
 mod version;
 
 pub use self::{
     accept::Accepting,
+    addresses::PeerAddresses,
+    cover::CoverTraffic,
     handle::Handle,
-    message::{Message, MessageSlice, MessageType},
+    message::{
+        GoodbyeReason, InvalidMessage, Message, MessageSlice, MessageType, RejectReason,
+        UnknownMessageType,
+    },
+    puzzle::Puzzle,
+    query::TopicQuery,
+    reconcile::{MessageHash, Reconciliation},
     session_id::SessionId,
-    version::Version,
+    version::{Capabilities, UnsupportedVersion, Version},
 };
@@ -1,12 +1,17 @@
-use crate::codec::encryption::MAX_FRAME_LENGTH;
-use anyhow::{anyhow, ensure, Context as _, Result};
+use crate::{codec::encryption::MAX_FRAME_LENGTH, PeerAddresses, Reconciliation, TopicQuery};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use asmtp_lib::MessageId;
 use bytes::{BufMut as _, Bytes, BytesMut};
 use keynesis::passport::{
     block::{Hash, Time},
     PassportBlocksSlice,
 };
 use poldercast::{GossipSlice, Topic};
-use std::convert::{TryFrom, TryInto};
+use std::{
+    convert::{TryFrom, TryInto},
+    fmt,
+    time::Duration,
+};
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
 #[repr(u8)]
@@ -21,8 +26,105 @@ pub enum MessageType {
     DeregisterTopic = 6,
 
     QueryTopicMessages = 7,
+
+    Goodbye = 8,
+
+    /// dummy message (see [`CoverTraffic`](crate::CoverTraffic)), to be
+    /// discarded by the receiver
+    Cover = 9,
+
+    /// several messages packed in one frame (see [`Message::batches`])
+    ///
+    /// only sent to peers supporting it (see [`Capabilities::supports_batch`]).
+    ///
+    /// [`Capabilities::supports_batch`]: crate::Capabilities::supports_batch
+    Batch = 10,
+
+    /// more messages match the [`TopicQuery`], the message carries the cursor
+    /// to query the next page of messages (see [`TopicQuery::after`])
+    QueryTopicMessagesNext = 11,
+
+    /// a step of the set reconciliation of the messages of a topic (see
+    /// [`Reconciliation`])
+    ///
+    /// only sent to peers supporting it (see
+    /// [`Capabilities::supports_reconciliation`]).
+    ///
+    /// [`Capabilities::supports_reconciliation`]: crate::Capabilities::supports_reconciliation
+    Reconcile = 12,
+
+    /// ask for the blocks of a passport following the given block
+    ///
+    /// only sent to peers supporting it (see
+    /// [`Capabilities::supports_passport_sync`]).
+    ///
+    /// [`Capabilities::supports_passport_sync`]: crate::Capabilities::supports_passport_sync
+    GetPassportBlocks = 13,
+    /// the blocks of a passport following the given block
+    ///
+    /// only sent to peers supporting it (see
+    /// [`Capabilities::supports_passport_sync`]).
+    ///
+    /// [`Capabilities::supports_passport_sync`]: crate::Capabilities::supports_passport_sync
+    PutPassportBlocks = 14,
+
+    /// the addresses a node can be reached at (see [`PeerAddresses`]),
+    /// sent along with the node's gossip
+    ///
+    /// only sent to peers supporting it (see
+    /// [`Capabilities::supports_peer_addresses`]).
+    ///
+    /// [`Capabilities::supports_peer_addresses`]: crate::Capabilities::supports_peer_addresses
+    Addresses = 15,
+
+    /// a message of a topic was not kept (see [`RejectReason`])
+    ///
+    /// only sent to peers supporting it (see
+    /// [`Capabilities::supports_rejection`]).
+    ///
+    /// [`Capabilities::supports_rejection`]: crate::Capabilities::supports_rejection
+    Rejected = 16,
+}
+
+/// the reason a peer is closing the connection (see [`Message::new_goodbye`])
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+#[repr(u8)]
+pub enum GoodbyeReason {
+    /// the peer is shutting down
+    Shutdown = 1,
+    /// the peer has too many connections or too much work to do
+    Overloaded = 2,
+    /// the peer does not want to talk to us anymore
+    Banned = 3,
+    /// the peer does not support the version/features we are using
+    VersionMismatch = 4,
+    /// the connection was not used for a while
+    Idle = 5,
 }
 
+/// the reason a message of a topic was not accepted (see
+/// [`Message::new_rejected`])
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+#[repr(u8)]
+pub enum RejectReason {
+    /// the user the messages of the topic are kept for has no storage
+    /// left on the node
+    QuotaExceeded = 1,
+}
+
+/// the error decoding a message of a type this version of the protocol
+/// does not know of (see [`MessageSlice::try_from_slice`])
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub struct UnknownMessageType(pub u8);
+
+/// the error receiving a frame that is not a valid message of the
+/// protocol from a connection, the peer is to blame
+///
+/// the other errors receiving from a connection are failures of the
+/// connection itself.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub struct InvalidMessage;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct MessageSlice<'a>(&'a [u8]);
 
@@ -47,15 +149,76 @@ impl MessageType {
             5 => Some(Self::RegisterTopic),
             6 => Some(Self::DeregisterTopic),
             7 => Some(Self::QueryTopicMessages),
+            8 => Some(Self::Goodbye),
+            9 => Some(Self::Cover),
+            10 => Some(Self::Batch),
+            11 => Some(Self::QueryTopicMessagesNext),
+            12 => Some(Self::Reconcile),
+            13 => Some(Self::GetPassportBlocks),
+            14 => Some(Self::PutPassportBlocks),
+            15 => Some(Self::Addresses),
+            16 => Some(Self::Rejected),
+
+            0 | 17..=u8::MAX => None,
+        }
+    }
+}
+
+impl GoodbyeReason {
+    const SIZE: usize = 1;
+
+    #[inline]
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    #[inline]
+    fn try_from_u8(t: u8) -> Option<Self> {
+        match t {
+            1 => Some(Self::Shutdown),
+            2 => Some(Self::Overloaded),
+            3 => Some(Self::Banned),
+            4 => Some(Self::VersionMismatch),
+            5 => Some(Self::Idle),
 
-            0 | 8..=u8::MAX => None,
+            0 | 6..=u8::MAX => None,
+        }
+    }
+}
+
+impl RejectReason {
+    const SIZE: usize = 1;
+
+    #[inline]
+    fn to_u8(self) -> u8 {
+        self as u8
+    }
+
+    #[inline]
+    fn try_from_u8(t: u8) -> Option<Self> {
+        match t {
+            1 => Some(Self::QuotaExceeded),
+
+            0 | 2..=u8::MAX => None,
         }
     }
 }
 
 impl Message {
-    const MAX_SIZE: usize = MAX_FRAME_LENGTH - MessageType::SIZE;
-    const MIN_SIZE: usize = MessageType::SIZE + Hash::SIZE;
+    pub(crate) const MAX_SIZE: usize = MAX_FRAME_LENGTH - MessageType::SIZE;
+    /// the size of the smallest message: a goodbye
+    ///
+    /// this is only a first bound, every type of message checks the size
+    /// of its own content (see `messages_smaller_than_their_type`).
+    const MIN_SIZE: usize = MessageType::SIZE + GoodbyeReason::SIZE + 4;
+
+    /// the until time and the limit of a paginated topic query
+    const QUERY_RANGE_SIZE: usize = Time::SIZE + std::mem::size_of::<u16>();
+
+    /// the length prefix of every message packed in a batch
+    const BATCH_ENTRY_HEAD: usize = std::mem::size_of::<u16>();
+    /// a batch needs to fit in one frame, including the frame's MAC
+    const BATCH_MAX_SIZE: usize = MAX_FRAME_LENGTH - 16;
 
     /// create a new message from the given gossip
     pub fn new_gossip(gossip: GossipSlice<'_>) -> Self {
@@ -68,6 +231,17 @@ impl Message {
         Self(bytes.freeze())
     }
 
+    /// advertise the addresses a node can be reached at
+    pub fn new_addresses(addresses: &PeerAddresses) -> Self {
+        let size = MessageType::SIZE + addresses.as_ref().len();
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::Addresses.to_u8());
+        bytes.put_slice(addresses.as_ref());
+
+        Self(bytes.freeze())
+    }
+
     /// create a new message from the given topic and content
     pub fn new_topic(topic: Topic, message: impl AsRef<[u8]>) -> Self {
         let size = MessageType::SIZE + Topic::SIZE + message.as_ref().len();
@@ -104,6 +278,32 @@ impl Message {
         Self(bytes.freeze())
     }
 
+    /// ask for the blocks of the passport `id` following the block `head`
+    /// (the most recent block we know of)
+    pub fn new_get_passport_blocks(id: Hash, head: Hash) -> Self {
+        let size = MessageType::SIZE + Hash::SIZE + Hash::SIZE;
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::GetPassportBlocks.to_u8());
+        bytes.put_slice(id.as_ref());
+        bytes.put_slice(head.as_ref());
+
+        Self(bytes.freeze())
+    }
+
+    /// send the `blocks` of the passport `id` following the block `head`
+    pub fn new_put_passport_blocks(id: Hash, head: Hash, blocks: PassportBlocksSlice) -> Self {
+        let size = MessageType::SIZE + Hash::SIZE + Hash::SIZE + blocks.len();
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::PutPassportBlocks.to_u8());
+        bytes.put_slice(id.as_ref());
+        bytes.put_slice(head.as_ref());
+        bytes.put_slice(blocks.as_ref());
+
+        Self(bytes.freeze())
+    }
+
     pub fn new_register_topic(topic: Topic) -> Self {
         let size = MessageType::SIZE + Topic::SIZE;
         let mut bytes = BytesMut::with_capacity(size);
@@ -127,17 +327,165 @@ impl Message {
     }
 
     pub fn new_query_topic_messages(topic: Topic, time: Time) -> Self {
-        let size = MessageType::SIZE + Topic::SIZE + Time::SIZE;
+        Self::new_topic_query(&TopicQuery::new(topic, time))
+    }
+
+    /// query the messages of a topic
+    ///
+    /// unless the query only has the since time (see
+    /// [`new_query_topic_messages`](Self::new_query_topic_messages)) the
+    /// message can only be sent to peers supporting paginated queries (see
+    /// [`Capabilities::supports_paginated_query`](crate::Capabilities::supports_paginated_query))
+    pub fn new_topic_query(query: &TopicQuery) -> Self {
+        let mut size = MessageType::SIZE + Topic::SIZE + Time::SIZE;
+        if query.is_paginated() {
+            size += Self::QUERY_RANGE_SIZE;
+            if query.cursor().is_some() {
+                size += MessageId::SIZE;
+            }
+        }
         let mut bytes = BytesMut::with_capacity(size);
-        bytes.reserve(size);
 
         bytes.put_u8(MessageType::QueryTopicMessages.to_u8());
+        bytes.put_slice(query.topic().as_ref());
+        bytes.put_u32(*query.since());
+        if query.is_paginated() {
+            bytes.put_u32(query.until_time().map(|until| *until).unwrap_or(0));
+            bytes.put_u16(query.max_messages().unwrap_or(0));
+            if let Some(cursor) = query.cursor() {
+                bytes.put_slice(cursor.as_ref());
+            }
+        }
+
+        Self(bytes.freeze())
+    }
+
+    /// tell the peer more messages match its query of the `topic` and
+    /// they can be queried from the given `cursor`
+    pub fn new_query_topic_messages_next(topic: Topic, cursor: MessageId) -> Self {
+        let size = MessageType::SIZE + Topic::SIZE + MessageId::SIZE;
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::QueryTopicMessagesNext.to_u8());
+        bytes.put_slice(topic.as_ref());
+        bytes.put_slice(cursor.as_ref());
+
+        Self(bytes.freeze())
+    }
+
+    /// send the next step of the reconciliation of the messages of a topic
+    pub fn new_reconcile(reconciliation: &Reconciliation) -> Self {
+        let size = Reconciliation::HEAD_SIZE + reconciliation.ranges_size();
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::Reconcile.to_u8());
+        bytes.put_slice(reconciliation.topic().as_ref());
+        bytes.put_u32(*reconciliation.since());
+        reconciliation.put_ranges(&mut bytes);
+
+        Self(bytes.freeze())
+    }
+
+    /// tell the peer we are closing the connection and why
+    ///
+    /// `retry_after` is a hint of how long the peer should wait before
+    /// trying to connect again. It is sent with a precision of a second.
+    pub fn new_goodbye(reason: GoodbyeReason, retry_after: Option<Duration>) -> Self {
+        let size = MessageType::SIZE + GoodbyeReason::SIZE + 4;
+        let mut bytes = BytesMut::with_capacity(size);
+        bytes.reserve(size);
+
+        let retry_after = retry_after
+            .map(|duration| duration.as_secs().clamp(1, u32::MAX as u64) as u32)
+            .unwrap_or(0);
+
+        bytes.put_u8(MessageType::Goodbye.to_u8());
+        bytes.put_u8(reason.to_u8());
+        bytes.put_u32(retry_after);
+
+        Self(bytes.freeze())
+    }
+
+    /// tell the peer a message of the `topic` was not kept and why
+    pub fn new_rejected(reason: RejectReason, topic: Topic) -> Self {
+        let size = MessageType::SIZE + RejectReason::SIZE + Topic::SIZE;
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::Rejected.to_u8());
+        bytes.put_u8(reason.to_u8());
         bytes.put_slice(topic.as_ref());
-        bytes.put_u32(*time);
 
         Self(bytes.freeze())
     }
 
+    /// create a dummy message of `size` bytes (including the message type)
+    ///
+    /// the size is bound to the minimum and maximum size of a message. Since
+    /// the messages are encrypted the content of the message does not matter.
+    pub fn new_cover(size: usize) -> Self {
+        let size = size.clamp(Self::MIN_SIZE, Self::MAX_SIZE);
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::Cover.to_u8());
+        bytes.resize(size, 0);
+
+        Self(bytes.freeze())
+    }
+
+    /// pack the `messages` in as few [`MessageType::Batch`] messages as
+    /// possible, preserving their order
+    ///
+    /// a message that cannot be packed with any other (because it is alone
+    /// or too large) is returned as is.
+    pub fn batches<I>(messages: I) -> Vec<Self>
+    where
+        I: IntoIterator<Item = Self>,
+    {
+        let mut batches = Vec::new();
+        let mut pending: Vec<Self> = Vec::new();
+        let mut size = MessageType::SIZE;
+
+        for message in messages {
+            let entry = Self::BATCH_ENTRY_HEAD + message.0.len();
+
+            if size + entry > Self::BATCH_MAX_SIZE {
+                batches.extend(Self::new_batch(std::mem::take(&mut pending)));
+                size = MessageType::SIZE;
+            }
+
+            if size + entry > Self::BATCH_MAX_SIZE {
+                batches.push(message);
+            } else {
+                size += entry;
+                pending.push(message);
+            }
+        }
+        batches.extend(Self::new_batch(pending));
+
+        batches
+    }
+
+    fn new_batch(mut messages: Vec<Self>) -> Option<Self> {
+        if messages.len() <= 1 {
+            return messages.pop();
+        }
+
+        let size = MessageType::SIZE
+            + messages
+                .iter()
+                .map(|message| Self::BATCH_ENTRY_HEAD + message.0.len())
+                .sum::<usize>();
+        let mut bytes = BytesMut::with_capacity(size);
+
+        bytes.put_u8(MessageType::Batch.to_u8());
+        for message in messages {
+            bytes.put_u16(message.0.len() as u16);
+            bytes.put_slice(message.as_ref());
+        }
+
+        Some(Self(bytes.freeze()))
+    }
+
     #[inline(always)]
     pub fn as_slice(&self) -> MessageSlice<'_> {
         MessageSlice(self.0.as_ref())
@@ -153,6 +501,12 @@ impl Message {
             .expect("Expecting to have a valid Gossip message")
     }
 
+    pub fn addresses_checked(&self) -> Option<PeerAddresses> {
+        self.as_slice()
+            .addresses()
+            .expect("Expected a valid addresses message")
+    }
+
     pub fn topic_checked(&self) -> Option<(Topic, &[u8])> {
         self.as_slice()
             .topic_checked()
@@ -171,6 +525,18 @@ impl Message {
             .expect("Expected a valid put passport message")
     }
 
+    pub fn get_passport_blocks_checked(&self) -> Option<(Hash, Hash)> {
+        self.as_slice()
+            .get_passport_blocks()
+            .expect("Expected a valid get passport blocks message")
+    }
+
+    pub fn put_passport_blocks_checked(&self) -> Option<(Hash, Hash, PassportBlocksSlice<'_>)> {
+        self.as_slice()
+            .put_passport_blocks()
+            .expect("Expected a valid put passport blocks message")
+    }
+
     pub fn register_topic_checked(&self) -> Option<Topic> {
         self.as_slice()
             .register_topic()
@@ -189,6 +555,43 @@ impl Message {
             .expect("Expected a valid topic message query")
     }
 
+    pub fn topic_query_checked(&self) -> Option<TopicQuery> {
+        self.as_slice()
+            .topic_query()
+            .expect("Expected a valid topic message query")
+    }
+
+    pub fn query_topic_messages_next_checked(&self) -> Option<(Topic, MessageId)> {
+        self.as_slice()
+            .query_topic_messages_next()
+            .expect("Expected a valid topic message query cursor")
+    }
+
+    pub fn reconcile_checked(&self) -> Option<Reconciliation> {
+        self.as_slice()
+            .reconcile()
+            .expect("Expected a valid reconciliation message")
+    }
+
+    pub fn goodbye_checked(&self) -> Option<(GoodbyeReason, Option<Duration>)> {
+        self.as_slice()
+            .goodbye()
+            .expect("Expected a valid goodbye message")
+    }
+
+    pub fn rejected_checked(&self) -> Option<(RejectReason, Topic)> {
+        self.as_slice()
+            .rejected()
+            .expect("Expected a valid rejected message")
+    }
+
+    pub fn batch_checked(&self) -> Option<Vec<Message>> {
+        self.as_slice()
+            .batch()
+            .expect("Expected a valid batch message")
+            .map(|messages| messages.iter().map(|m| m.to_message()).collect())
+    }
+
     pub fn to_bytes(&self) -> Bytes {
         self.0.clone()
     }
@@ -201,8 +604,9 @@ impl<'a> MessageSlice<'a> {
             "Not enough bytes to complete the smallest message possible"
         );
 
-        let message_type =
-            MessageType::try_from_u8(slice[0]).context("Invalid message, unknown message type")?;
+        let message_type = MessageType::try_from_u8(slice[0])
+            .ok_or(UnknownMessageType(slice[0]))
+            .context("Invalid message")?;
         let message = Self::from_slice_unchecked(slice);
 
         match message_type {
@@ -211,6 +615,11 @@ impl<'a> MessageSlice<'a> {
                     .gossip_checked()?
                     .ok_or_else(|| anyhow!("Expected a gossip message"))?;
             }
+            MessageType::Addresses => {
+                message
+                    .addresses()?
+                    .ok_or_else(|| anyhow!("Expected an addresses message"))?;
+            }
             MessageType::Topic => {
                 message
                     .topic_checked()?
@@ -226,6 +635,16 @@ impl<'a> MessageSlice<'a> {
                     .put_passport()?
                     .ok_or_else(|| anyhow!("Expected a put passport message"))?;
             }
+            MessageType::GetPassportBlocks => {
+                message
+                    .get_passport_blocks()?
+                    .ok_or_else(|| anyhow!("Expected a get passport blocks message"))?;
+            }
+            MessageType::PutPassportBlocks => {
+                message
+                    .put_passport_blocks()?
+                    .ok_or_else(|| anyhow!("Expected a put passport blocks message"))?;
+            }
             MessageType::RegisterTopic => {
                 message
                     .register_topic()?
@@ -241,13 +660,41 @@ impl<'a> MessageSlice<'a> {
                     .query_topic_messages()?
                     .ok_or_else(|| anyhow!("Expected a query of topic message"))?;
             }
+            MessageType::Goodbye => {
+                message
+                    .goodbye()?
+                    .ok_or_else(|| anyhow!("Expected a goodbye message"))?;
+            }
+            MessageType::Rejected => {
+                message
+                    .rejected()?
+                    .ok_or_else(|| anyhow!("Expected a rejected message"))?;
+            }
+            MessageType::Cover => {
+                // the content of the dummy messages is ignored
+            }
+            MessageType::QueryTopicMessagesNext => {
+                message
+                    .query_topic_messages_next()?
+                    .ok_or_else(|| anyhow!("Expected a query of topic message cursor"))?;
+            }
+            MessageType::Reconcile => {
+                message
+                    .reconcile()?
+                    .ok_or_else(|| anyhow!("Expected a reconciliation message"))?;
+            }
+            MessageType::Batch => {
+                message
+                    .batch()?
+                    .ok_or_else(|| anyhow!("Expected a batch message"))?;
+            }
         }
 
         Ok(message)
     }
 
     #[inline(always)]
-    fn from_slice_unchecked(slice: &'a [u8]) -> Self {
+    pub(crate) fn from_slice_unchecked(slice: &'a [u8]) -> Self {
         assert!(
             slice.len() >= Message::MIN_SIZE,
             "Message cannot be smaller than the length of the type"
@@ -281,6 +728,16 @@ impl<'a> MessageSlice<'a> {
         }
     }
 
+    pub fn addresses(self) -> Result<Option<PeerAddresses>> {
+        if self.message_type() == MessageType::Addresses {
+            PeerAddresses::try_from_slice(&self.0[1..])
+                .context("Unable to read the addresses from the given message")
+                .map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn topic_checked(&self) -> Result<Option<(Topic, &'a [u8])>> {
         if self.message_type() == MessageType::Topic {
             ensure!(
@@ -300,6 +757,17 @@ impl<'a> MessageSlice<'a> {
         }
     }
 
+    /// check the message is at least `size` bytes long (including the
+    /// message type)
+    fn ensure_size(&self, size: usize) -> Result<()> {
+        ensure!(
+            self.0.len() >= MessageType::SIZE + size,
+            "Not enough bytes in the {:?} message",
+            self.message_type()
+        );
+        Ok(())
+    }
+
     pub fn get_passport(self) -> Result<Option<Hash>> {
         if self.message_type() == MessageType::GetPassport {
             let hash = &self.0[1..];
@@ -313,6 +781,7 @@ impl<'a> MessageSlice<'a> {
 
     pub fn put_passport(self) -> Result<Option<(Hash, PassportBlocksSlice<'a>)>> {
         if self.message_type() == MessageType::PutPassport {
+            self.ensure_size(Hash::SIZE)?;
             let hash = &self.0[1..1 + Hash::SIZE];
             let id = Hash::try_from(hash).context("Not enough bytes for a passport ID")?;
 
@@ -324,8 +793,38 @@ impl<'a> MessageSlice<'a> {
         }
     }
 
+    pub fn get_passport_blocks(self) -> Result<Option<(Hash, Hash)>> {
+        if self.message_type() == MessageType::GetPassportBlocks {
+            ensure!(
+                self.0.len() == MessageType::SIZE + Hash::SIZE + Hash::SIZE,
+                "Invalid size for a get passport blocks message"
+            );
+            let id = Hash::try_from(&self.0[1..1 + Hash::SIZE]).unwrap();
+            let head = Hash::try_from(&self.0[1 + Hash::SIZE..]).unwrap();
+
+            Ok(Some((id, head)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn put_passport_blocks(self) -> Result<Option<(Hash, Hash, PassportBlocksSlice<'a>)>> {
+        if self.message_type() == MessageType::PutPassportBlocks {
+            self.ensure_size(Hash::SIZE + Hash::SIZE)?;
+            let id = Hash::try_from(&self.0[1..1 + Hash::SIZE]).unwrap();
+            let head = Hash::try_from(&self.0[1 + Hash::SIZE..1 + 2 * Hash::SIZE]).unwrap();
+
+            let blocks = PassportBlocksSlice::try_from_slice(&self.0[1 + 2 * Hash::SIZE..])?;
+
+            Ok(Some((id, head, blocks)))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn register_topic(self) -> Result<Option<Topic>> {
         if self.message_type() == MessageType::RegisterTopic {
+            self.ensure_size(Topic::SIZE)?;
             let topic = &self.0[1..1 + Topic::SIZE];
             let topic = Topic::try_from(topic).context("Not enough bytes for a Topic")?;
 
@@ -337,6 +836,7 @@ impl<'a> MessageSlice<'a> {
 
     pub fn deregister_topic(self) -> Result<Option<Topic>> {
         if self.message_type() == MessageType::DeregisterTopic {
+            self.ensure_size(Topic::SIZE)?;
             let topic = &self.0[1..1 + Topic::SIZE];
             let topic = Topic::try_from(topic).context("Not enough bytes for a Topic")?;
 
@@ -347,19 +847,210 @@ impl<'a> MessageSlice<'a> {
     }
 
     pub fn query_topic_messages(self) -> Result<Option<(Topic, Time)>> {
-        if self.message_type() == MessageType::QueryTopicMessages {
+        Ok(self
+            .topic_query()?
+            .map(|query| (*query.topic(), query.since())))
+    }
+
+    pub fn topic_query(self) -> Result<Option<TopicQuery>> {
+        if self.message_type() != MessageType::QueryTopicMessages {
+            return Ok(None);
+        }
+
+        self.ensure_size(Topic::SIZE + Time::SIZE)?;
+        let topic = &self.0[1..1 + Topic::SIZE];
+        let topic = Topic::try_from(topic).context("Not enough bytes for a Topic")?;
+        let mut bytes = &self.0[1 + Topic::SIZE..];
+
+        let since = read_u32(&mut bytes).into();
+        let mut query = TopicQuery::new(topic, since);
+
+        if bytes.is_empty() {
+            return Ok(Some(query));
+        }
+
+        ensure!(
+            bytes.len() >= Message::QUERY_RANGE_SIZE,
+            "Expecting the until time and the limit of the query"
+        );
+        let until = read_u32(&mut bytes);
+        if until != 0 {
+            query = query.until(until.into());
+        }
+        let limit = u16::from_be_bytes(bytes[..2].try_into().unwrap());
+        query = query.limit(limit);
+        bytes = &bytes[2..];
+
+        match bytes.len() {
+            0 => {}
+            MessageId::SIZE => {
+                let cursor = MessageId::try_from(bytes).unwrap();
+                query = query.after(cursor);
+            }
+            _ => bail!("Invalid cursor in the topic message query"),
+        }
+
+        Ok(Some(query))
+    }
+
+    pub fn query_topic_messages_next(self) -> Result<Option<(Topic, MessageId)>> {
+        if self.message_type() == MessageType::QueryTopicMessagesNext {
+            ensure!(
+                self.0.len() == MessageType::SIZE + Topic::SIZE + MessageId::SIZE,
+                "Invalid size for a query of topic message cursor"
+            );
             let topic = &self.0[1..1 + Topic::SIZE];
             let topic = Topic::try_from(topic).context("Not enough bytes for a Topic")?;
+            let cursor = MessageId::try_from(&self.0[1 + Topic::SIZE..]).unwrap();
 
-            let time = u32::from_be_bytes(self.0[1 + Topic::SIZE..].try_into().unwrap()).into();
+            Ok(Some((topic, cursor)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn reconcile(self) -> Result<Option<Reconciliation>> {
+        if self.message_type() != MessageType::Reconcile {
+            return Ok(None);
+        }
+
+        self.ensure_size(Topic::SIZE + Time::SIZE)?;
+        let topic = &self.0[1..1 + Topic::SIZE];
+        let topic = Topic::try_from(topic).context("Not enough bytes for a Topic")?;
+        let mut bytes = &self.0[1 + Topic::SIZE..];
+        let since = read_u32(&mut bytes).into();
 
-            Ok(Some((topic, time)))
+        Reconciliation::read_ranges(topic, since, bytes)
+            .context("Invalid reconciliation message")
+            .map(Some)
+    }
+
+    pub fn goodbye(self) -> Result<Option<(GoodbyeReason, Option<Duration>)>> {
+        if self.message_type() == MessageType::Goodbye {
+            ensure!(
+                self.0.len() == MessageType::SIZE + GoodbyeReason::SIZE + 4,
+                "Invalid size for a goodbye message"
+            );
+            let reason = GoodbyeReason::try_from_u8(self.0[1]).context("Unknown goodbye reason")?;
+            let retry_after = u32::from_be_bytes(self.0[2..].try_into().unwrap());
+            let retry_after = if retry_after == 0 {
+                None
+            } else {
+                Some(Duration::from_secs(retry_after as u64))
+            };
+
+            Ok(Some((reason, retry_after)))
         } else {
             Ok(None)
         }
     }
+
+    pub fn rejected(self) -> Result<Option<(RejectReason, Topic)>> {
+        if self.message_type() == MessageType::Rejected {
+            ensure!(
+                self.0.len() == MessageType::SIZE + RejectReason::SIZE + Topic::SIZE,
+                "Invalid size for a rejected message"
+            );
+            let reason = RejectReason::try_from_u8(self.0[1]).context("Unknown reject reason")?;
+            let topic = Topic::try_from(&self.0[2..]).context("Not enough bytes for a Topic")?;
+
+            Ok(Some((reason, topic)))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// unpack the messages of a batch
+    ///
+    /// every message of the batch is validated. A batch cannot contain
+    /// another batch.
+    pub fn batch(self) -> Result<Option<Vec<MessageSlice<'a>>>> {
+        if self.message_type() != MessageType::Batch {
+            return Ok(None);
+        }
+
+        let mut messages = Vec::new();
+        let mut bytes = &self.0[MessageType::SIZE..];
+
+        while !bytes.is_empty() {
+            ensure!(
+                bytes.len() >= Message::BATCH_ENTRY_HEAD,
+                "Expecting the length of the next message of the batch"
+            );
+            let (head, tail) = bytes.split_at(Message::BATCH_ENTRY_HEAD);
+            let len = u16::from_be_bytes(head.try_into().unwrap()) as usize;
+            ensure!(
+                tail.len() >= len,
+                "Not enough bytes for the message {} of the batch",
+                messages.len()
+            );
+            let (message, tail) = tail.split_at(len);
+
+            // checked before the message is parsed: parsing a batch would
+            // parse its messages, a frame of nested batches would recurse
+            // as deep as the frame is long
+            ensure!(
+                message.first() != Some(&MessageType::Batch.to_u8()),
+                "A batch cannot contain another batch"
+            );
+            let message = Self::try_from_slice(message)
+                .with_context(|| format!("Invalid message {} of the batch", messages.len()))?;
+
+            messages.push(message);
+            bytes = tail;
+        }
+
+        ensure!(!messages.is_empty(), "Empty batch");
+
+        Ok(Some(messages))
+    }
+}
+
+/// read a big endian `u32` from the front of `bytes`
+///
+/// the caller needs to check there are enough bytes
+fn read_u32(bytes: &mut &[u8]) -> u32 {
+    let (head, tail) = bytes.split_at(4);
+    *bytes = tail;
+    u32::from_be_bytes(head.try_into().unwrap())
+}
+
+impl fmt::Display for GoodbyeReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Shutdown => f.write_str("shutdown"),
+            Self::Overloaded => f.write_str("overloaded"),
+            Self::Banned => f.write_str("banned"),
+            Self::VersionMismatch => f.write_str("version mismatch"),
+            Self::Idle => f.write_str("idle"),
+        }
+    }
 }
 
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::QuotaExceeded => f.write_str("quota exceeded"),
+        }
+    }
+}
+
+impl fmt::Display for UnknownMessageType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown message type {}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownMessageType {}
+
+impl fmt::Display for InvalidMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Invalid message from connection")
+    }
+}
+
+impl std::error::Error for InvalidMessage {}
+
 impl AsRef<[u8]> for Message {
     fn as_ref(&self) -> &[u8] {
         self.0.as_ref()
@@ -371,3 +1062,297 @@ impl<'a> AsRef<[u8]> for MessageSlice<'a> {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn goodbye_round_trip() {
+        let retry_after = Some(Duration::from_secs(30));
+        let message = Message::new_goodbye(GoodbyeReason::Overloaded, retry_after);
+
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(slice.message_type(), MessageType::Goodbye);
+        assert_eq!(
+            slice.goodbye().unwrap(),
+            Some((GoodbyeReason::Overloaded, retry_after))
+        );
+
+        let message = Message::new_goodbye(GoodbyeReason::Shutdown, None);
+        assert_eq!(
+            message.goodbye_checked(),
+            Some((GoodbyeReason::Shutdown, None))
+        );
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        let message = Message::new_goodbye(GoodbyeReason::Idle, None);
+        let mut bytes = message.as_ref().to_vec();
+        bytes[0] = 0;
+
+        let error = MessageSlice::try_from_slice(&bytes).err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<UnknownMessageType>(),
+            Some(&UnknownMessageType(0))
+        );
+    }
+
+    #[test]
+    fn goodbye_unknown_reason() {
+        let message = Message::new_goodbye(GoodbyeReason::Idle, None);
+        let mut bytes = message.as_ref().to_vec();
+        bytes[1] = 0;
+
+        assert!(MessageSlice::try_from_slice(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejected_round_trip() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let message = Message::new_rejected(RejectReason::QuotaExceeded, topic);
+
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(slice.message_type(), MessageType::Rejected);
+        assert_eq!(
+            message.rejected_checked(),
+            Some((RejectReason::QuotaExceeded, topic))
+        );
+
+        let mut bytes = message.as_ref().to_vec();
+        bytes[1] = 0;
+        assert!(MessageSlice::try_from_slice(&bytes).is_err());
+    }
+
+    #[test]
+    fn addresses_round_trip() {
+        let mut rng = keynesis::Seed::from([0; keynesis::Seed::SIZE]).into_rand_chacha();
+        let key = keynesis::key::ed25519::SecretKey::new(&mut rng);
+        let addresses = [
+            "127.0.0.1:9876".parse().unwrap(),
+            "[::1]:9876".parse().unwrap(),
+        ];
+        let record = PeerAddresses::new(&key, Time::from(42), &addresses).unwrap();
+        let message = Message::new_addresses(&record);
+
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(slice.message_type(), MessageType::Addresses);
+        assert_eq!(message.addresses_checked(), Some(record));
+    }
+
+    #[test]
+    fn cover_message_size() {
+        let message = Message::new_cover(128);
+        assert_eq!(message.as_ref().len(), 128);
+
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(slice.message_type(), MessageType::Cover);
+
+        assert_eq!(Message::new_cover(0).as_ref().len(), Message::MIN_SIZE);
+        assert_eq!(
+            Message::new_cover(usize::MAX).as_ref().len(),
+            Message::MAX_SIZE
+        );
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let messages: Vec<_> = (0..10)
+            .map(|i| Message::new_topic(topic, vec![i; 100]))
+            .collect();
+
+        let batches = Message::batches(messages.clone());
+        assert_eq!(batches.len(), 1);
+
+        let batch = MessageSlice::try_from_slice(batches[0].as_ref()).unwrap();
+        assert_eq!(batch.message_type(), MessageType::Batch);
+        assert!(batches[0].batch_checked() == Some(messages));
+    }
+
+    #[test]
+    fn batches_fit_in_a_frame() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let large = Message::new_topic(topic, vec![0; 40_000]);
+        let small = Message::new_register_topic(topic);
+        let messages = vec![
+            small.clone(),
+            large.clone(),
+            large.clone(),
+            small.clone(),
+            small.clone(),
+        ];
+
+        let batches = Message::batches(messages.clone());
+        assert_eq!(batches.len(), 2);
+        for batch in batches.iter() {
+            assert!(batch.as_ref().len() <= Message::BATCH_MAX_SIZE);
+        }
+
+        let unpacked: Vec<_> = batches
+            .into_iter()
+            .flat_map(|batch| batch.batch_checked().unwrap_or_else(|| vec![batch]))
+            .collect();
+        assert!(unpacked == messages);
+
+        assert!(Message::batches(vec![small.clone()]) == vec![small]);
+        assert!(Message::batches(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn nested_batch_is_rejected() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let message = Message::new_register_topic(topic);
+        let batch = Message::new_batch(vec![message.clone(), message]).unwrap();
+        let nested = Message::new_batch(vec![batch.clone(), batch]).unwrap();
+
+        assert!(MessageSlice::try_from_slice(nested.as_ref()).is_err());
+    }
+
+    #[test]
+    fn deeply_nested_batch_is_rejected() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut bytes = Message::new_register_topic(topic).as_ref().to_vec();
+        // as many levels of batches as fit in a frame
+        while bytes.len() + 3 <= Message::MAX_SIZE {
+            let mut nested = vec![MessageType::Batch.to_u8()];
+            nested.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+            nested.append(&mut bytes);
+            bytes = nested;
+        }
+
+        assert!(MessageSlice::try_from_slice(&bytes).is_err());
+    }
+
+    #[test]
+    fn truncated_batch_is_rejected() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let message = Message::new_register_topic(topic);
+        let batch = Message::new_batch(vec![message.clone(), message]).unwrap();
+        let bytes = &batch.as_ref()[..batch.as_ref().len() - 1];
+
+        assert!(MessageSlice::try_from_slice(bytes).is_err());
+    }
+
+    #[test]
+    fn legacy_topic_query() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let message = Message::new_query_topic_messages(topic, Time::from(42));
+        assert_eq!(
+            message.as_ref().len(),
+            MessageType::SIZE + Topic::SIZE + Time::SIZE
+        );
+
+        assert_eq!(
+            message.topic_query_checked(),
+            Some(TopicQuery::new(topic, Time::from(42)))
+        );
+        assert_eq!(
+            message.query_topic_messages_checked(),
+            Some((topic, Time::from(42)))
+        );
+    }
+
+    #[test]
+    fn paginated_topic_query() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let cursor = MessageId::with_time(Time::from(50), b"message");
+        let queries = [
+            TopicQuery::new(topic, Time::from(42)).limit(10),
+            TopicQuery::new(topic, Time::from(42)).until(Time::from(100)),
+            TopicQuery::new(topic, Time::from(42))
+                .until(Time::from(100))
+                .limit(10)
+                .after(cursor),
+        ];
+
+        for query in queries.iter() {
+            let message = Message::new_topic_query(query);
+            let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+            assert_eq!(slice.topic_query().unwrap().as_ref(), Some(query));
+        }
+
+        let message = Message::new_query_topic_messages_next(topic, cursor);
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(
+            slice.query_topic_messages_next().unwrap(),
+            Some((topic, cursor))
+        );
+    }
+
+    #[test]
+    fn invalid_topic_query_cursor() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let query = TopicQuery::new(topic, Time::from(42))
+            .limit(10)
+            .after(MessageId::with_time(Time::from(50), b"message"));
+        let message = Message::new_topic_query(&query);
+        let bytes = &message.as_ref()[..message.as_ref().len() - 1];
+
+        assert!(MessageSlice::try_from_slice(bytes).is_err());
+    }
+
+    #[test]
+    fn passport_blocks_round_trip() {
+        let id = Hash::from([1; Hash::SIZE]);
+        let head = Hash::from([2; Hash::SIZE]);
+
+        let message = Message::new_get_passport_blocks(id, head);
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        assert_eq!(slice.get_passport_blocks().unwrap(), Some((id, head)));
+
+        let blocks = PassportBlocksSlice::try_from_slice(&[]).unwrap();
+        let message = Message::new_put_passport_blocks(id, head, blocks);
+        let slice = MessageSlice::try_from_slice(message.as_ref()).unwrap();
+        let (i, h, b) = slice.put_passport_blocks().unwrap().unwrap();
+        assert_eq!((i, h), (id, head));
+        assert!(b.is_empty());
+
+        let bytes = &message.as_ref()[..message.as_ref().len() - 1];
+        assert!(MessageSlice::try_from_slice(bytes).is_err());
+    }
+
+    #[test]
+    fn messages_smaller_than_their_type() {
+        let goodbye = Message::new_goodbye(GoodbyeReason::Idle, None);
+        assert_eq!(goodbye.as_ref().len(), Message::MIN_SIZE);
+        assert!(MessageSlice::try_from_slice(goodbye.as_ref()).is_ok());
+
+        let topic = Topic::new([1; Topic::SIZE]);
+        let id = Hash::from([1; Hash::SIZE]);
+        let cursor = MessageId::with_time(Time::from(50), b"message");
+        let messages = vec![
+            goodbye,
+            Message::new_topic(topic, b""),
+            Message::new_get_passport(id),
+            Message::new_get_passport_blocks(id, id),
+            Message::new_register_topic(topic),
+            Message::new_deregister_topic(topic),
+            Message::new_query_topic_messages(topic, Time::from(0)),
+            Message::new_query_topic_messages_next(topic, cursor),
+            Message::new_rejected(RejectReason::QuotaExceeded, topic),
+        ];
+
+        for message in messages {
+            let bytes = message.as_ref();
+            for size in 0..bytes.len() {
+                assert!(
+                    MessageSlice::try_from_slice(&bytes[..size]).is_err(),
+                    "{:?} message of {} bytes",
+                    message.as_slice().message_type(),
+                    size
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let message = Message::new_register_topic(topic);
+        let bytes = &message.as_ref()[..Message::MIN_SIZE];
+
+        assert!(MessageSlice::try_from_slice(bytes).is_err());
+    }
+}
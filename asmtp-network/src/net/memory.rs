@@ -0,0 +1,231 @@
+/*!
+in-process transport
+
+The [`MemoryNetwork`] is a switchboard that lives within the process:
+listeners are registered at a [`SocketAddr`] and the connections are
+pairs of in-memory pipes. The network conditions (latency of the links,
+partitions between 2 addresses) can be changed at any time. This is
+meant to run multiple nodes within the same process (tests, simulations).
+
+The latency is applied with [`tokio::time`] so it plays well with
+a paused clock (see [`tokio::time::pause`]).
+*/
+
+use anyhow::{bail, Result};
+use bytes::BytesMut;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, DuplexStream},
+    sync::mpsc,
+    time::Instant,
+};
+
+/// size of the in-memory pipes' buffers
+const BUFFER_SIZE: usize = 64 * 1024;
+
+/// in-process network to connect peers without using the OS' network stack
+///
+/// this object can be cheaply cloned, all the clones share the same
+/// listeners and network conditions.
+///
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<(DuplexStream, SocketAddr)>>,
+    latency: Duration,
+    links_latency: HashMap<Link, Duration>,
+    partitions: HashSet<Link>,
+}
+
+/// a link between 2 addresses, regardless of the direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Link(SocketAddr, SocketAddr);
+
+pub(super) struct MemoryListener {
+    address: SocketAddr,
+    incoming: tokio::sync::Mutex<mpsc::UnboundedReceiver<(DuplexStream, SocketAddr)>>,
+}
+
+impl Link {
+    fn new(a: SocketAddr, b: SocketAddr) -> Self {
+        if a <= b {
+            Self(a, b)
+        } else {
+            Self(b, a)
+        }
+    }
+}
+
+impl MemoryNetwork {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// set the latency applied to every link without a specific latency
+    /// (see [`set_link_latency`](Self::set_link_latency))
+    pub fn set_latency(&self, latency: Duration) {
+        self.inner.lock().unwrap().latency = latency;
+    }
+
+    /// set the latency of the link between the addresses `a` and `b`
+    /// (in both directions)
+    pub fn set_link_latency(&self, a: SocketAddr, b: SocketAddr, latency: Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .links_latency
+            .insert(Link::new(a, b), latency);
+    }
+
+    /// partition the addresses `a` and `b`
+    ///
+    /// new connections between the two are refused and the established
+    /// connections are closed the next time they try to deliver data.
+    pub fn partition(&self, a: SocketAddr, b: SocketAddr) {
+        self.inner
+            .lock()
+            .unwrap()
+            .partitions
+            .insert(Link::new(a, b));
+    }
+
+    /// remove the partition between `a` and `b`
+    pub fn heal(&self, a: SocketAddr, b: SocketAddr) {
+        self.inner
+            .lock()
+            .unwrap()
+            .partitions
+            .remove(&Link::new(a, b));
+    }
+
+    /// remove all the partitions
+    pub fn heal_all(&self) {
+        self.inner.lock().unwrap().partitions.clear();
+    }
+
+    fn latency(&self, a: SocketAddr, b: SocketAddr) -> Duration {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .links_latency
+            .get(&Link::new(a, b))
+            .copied()
+            .unwrap_or(inner.latency)
+    }
+
+    fn is_partitioned(&self, a: SocketAddr, b: SocketAddr) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .partitions
+            .contains(&Link::new(a, b))
+    }
+
+    pub(super) fn bind(&self, address: SocketAddr) -> Result<MemoryListener> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(listener) = inner.listeners.get(&address) {
+            if !listener.is_closed() {
+                bail!("Cannot listen to {}, address already in use", address)
+            }
+        }
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        inner.listeners.insert(address, sender);
+
+        Ok(MemoryListener {
+            address,
+            incoming: tokio::sync::Mutex::new(receiver),
+        })
+    }
+
+    /// open a new connection from the address `from` to the listener at `to`
+    pub(super) fn connect(&self, from: SocketAddr, to: SocketAddr) -> Result<DuplexStream> {
+        if self.is_partitioned(from, to) {
+            bail!("Cannot connect to {}, network is partitioned", to)
+        }
+
+        let listener = if let Some(listener) = self.inner.lock().unwrap().listeners.get(&to) {
+            listener.clone()
+        } else {
+            bail!("Cannot connect to {}, connection refused", to)
+        };
+
+        let (local, local_inner) = tokio::io::duplex(BUFFER_SIZE);
+        let (remote, remote_inner) = tokio::io::duplex(BUFFER_SIZE);
+        let (local_reader, local_writer) = tokio::io::split(local_inner);
+        let (remote_reader, remote_writer) = tokio::io::split(remote_inner);
+
+        if listener.send((remote, from)).is_err() {
+            bail!("Cannot connect to {}, connection refused", to)
+        }
+
+        tokio::spawn(forward(self.clone(), from, to, local_reader, remote_writer));
+        tokio::spawn(forward(self.clone(), to, from, remote_reader, local_writer));
+
+        Ok(local)
+    }
+}
+
+impl MemoryListener {
+    pub(super) fn local_address(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub(super) async fn accept(&self) -> Result<(DuplexStream, SocketAddr)> {
+        if let Some(incoming) = self.incoming.lock().await.recv().await {
+            Ok(incoming)
+        } else {
+            bail!("The in-memory listener at {} is closed", self.address)
+        }
+    }
+}
+
+/// forward the data read from `from` to `to` applying the network conditions
+async fn forward<R, W>(
+    network: MemoryNetwork,
+    from: SocketAddr,
+    to: SocketAddr,
+    mut reader: R,
+    mut writer: W,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+
+    let reading = async move {
+        loop {
+            let mut buffer = BytesMut::with_capacity(BUFFER_SIZE);
+            match reader.read_buf(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if sender.send((Instant::now(), buffer.freeze())).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    };
+
+    let writing = async move {
+        while let Some((sent_at, bytes)) = receiver.recv().await {
+            tokio::time::sleep_until(sent_at + network.latency(from, to)).await;
+
+            if network.is_partitioned(from, to) || writer.write_all(&bytes).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+
+    futures::join!(reading, writing);
+}
@@ -0,0 +1,114 @@
+/*!
+per-connection traffic accounting
+
+Each half of a [`Connection`](super::Connection) counts the frames going
+through it with a [`TrafficMeter`]. The meter can be cloned and kept
+around (for example to report the traffic of a peer while the connection
+is being handled in another task) and a [`TrafficSnapshot`] can be taken
+at any time.
+
+The bytes are the bytes on the wire: the encrypted frames with their
+length and authentication tag. The bytes of the handshake are not
+accounted for.
+
+The timestamps use [`tokio::time`] so they play well with a paused clock
+(see [`tokio::time::pause`]).
+*/
+
+use std::sync::{Arc, Mutex};
+use tokio::time::Instant;
+
+/// the counters of one direction of a connection
+///
+/// this object can be cheaply cloned, all the clones share the same
+/// counters.
+#[derive(Debug, Clone)]
+pub struct TrafficMeter {
+    inner: Arc<Mutex<TrafficSnapshot>>,
+}
+
+/// the state of a [`TrafficMeter`] at a given time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrafficSnapshot {
+    /// when the connection was established
+    pub since: Instant,
+    /// number of bytes on the wire
+    pub bytes: u64,
+    /// number of frames, a [`MessageType::Batch`](crate::MessageType::Batch)
+    /// is one frame
+    pub frames: u64,
+    /// number of errors (invalid frames or messages, failure to send...)
+    pub errors: u64,
+    /// when the last frame went through
+    pub last_frame: Option<Instant>,
+    /// when the last error happened
+    pub last_error: Option<Instant>,
+}
+
+impl TrafficMeter {
+    pub(super) fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(TrafficSnapshot::new(Instant::now()))),
+        }
+    }
+
+    /// the current state of the counters
+    pub fn snapshot(&self) -> TrafficSnapshot {
+        *self.inner.lock().expect("traffic meter lock")
+    }
+
+    /// account for a frame of `bytes` on the wire
+    pub(super) fn frame(&self, bytes: usize) {
+        let mut traffic = self.inner.lock().expect("traffic meter lock");
+        traffic.bytes = traffic.bytes.saturating_add(bytes as u64);
+        traffic.frames = traffic.frames.saturating_add(1);
+        traffic.last_frame = Some(Instant::now());
+    }
+
+    /// account for an error
+    pub(super) fn error(&self) {
+        let mut traffic = self.inner.lock().expect("traffic meter lock");
+        traffic.errors = traffic.errors.saturating_add(1);
+        traffic.last_error = Some(Instant::now());
+    }
+}
+
+impl TrafficSnapshot {
+    fn new(since: Instant) -> Self {
+        Self {
+            since,
+            bytes: 0,
+            frames: 0,
+            errors: 0,
+            last_frame: None,
+            last_error: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_and_errors_are_counted() {
+        let meter = TrafficMeter::new();
+        let since = meter.snapshot().since;
+        assert_eq!(meter.snapshot(), TrafficSnapshot::new(since));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        meter.frame(42);
+        meter.clone().frame(18);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        meter.error();
+
+        let snapshot = meter.snapshot();
+        assert_eq!(snapshot.bytes, 60);
+        assert_eq!(snapshot.frames, 2);
+        assert_eq!(snapshot.errors, 1);
+        assert_eq!(snapshot.last_frame, Some(since + Duration::from_secs(1)));
+        assert_eq!(snapshot.last_error, Some(since + Duration::from_secs(2)));
+    }
+}
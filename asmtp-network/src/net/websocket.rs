@@ -0,0 +1,187 @@
+/*!
+WebSocket binding of the ASMTP protocol
+
+The handshake and the noise encrypted frames are carried as binary WebSocket
+messages. This allows peers sitting behind proxies that only let HTTP(S)
+through to still reach an ASMTP node.
+*/
+
+use super::{Io, Socks5Proxy, TransportReader, TransportWriter};
+use anyhow::{Context as _, Result};
+use bytes::{Buf as _, Bytes};
+use futures::prelude::*;
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_tungstenite::{
+    tungstenite::{http::Uri, Error as WsError, Message},
+    WebSocketStream,
+};
+
+/// byte stream adapter on top of a [`WebSocketStream`]
+///
+/// every write is sent as one binary message and the content of the
+/// received binary messages is returned by the reads. Control messages
+/// (ping, pong) are handled by the underlying [`WebSocketStream`].
+struct WebSocketIo<S> {
+    stream: WebSocketStream<S>,
+    buffer: Bytes,
+}
+
+impl<S> WebSocketIo<S> {
+    fn new(stream: WebSocketStream<S>) -> Self {
+        Self {
+            stream,
+            buffer: Bytes::new(),
+        }
+    }
+}
+
+/// upgrade the inbound TCP stream to a WebSocket and split it into
+/// the reading and writing halves of the transport
+pub(super) async fn accept(stream: Box<dyn Io>) -> Result<(TransportReader, TransportWriter)> {
+    let stream = tokio_tungstenite::accept_async(stream)
+        .await
+        .context("Cannot upgrade the inbound connection to WebSocket")?;
+
+    let (reader, writer) = tokio::io::split(WebSocketIo::new(stream));
+
+    Ok((Box::new(reader), Box::new(writer)))
+}
+
+/// open a WebSocket with the given `ws://` or `wss://` URI
+///
+/// returns the reading and writing halves of the transport as well as the
+/// address of the TCP peer we are connected to (the proxy's address if the
+/// connection is opened through a `proxy`).
+pub(super) async fn connect(
+    uri: &Uri,
+    proxy: Option<&Socks5Proxy>,
+) -> Result<(TransportReader, TransportWriter, SocketAddr)> {
+    let host = uri
+        .host()
+        .with_context(|| format!("No host to connect to in {}", uri))?;
+    let port = uri
+        .port_u16()
+        .unwrap_or_else(|| default_port(uri.scheme_str()));
+
+    let stream = if let Some(proxy) = proxy {
+        proxy.connect(format!("{}:{}", host, port)).await?
+    } else {
+        TcpStream::connect((unbracketed(host), port))
+            .await
+            .with_context(|| format!("Cannot connect to {}", uri))?
+    };
+    let peer_addr = stream
+        .peer_addr()
+        .context("Cannot retrieve the remote address of the connection")?;
+
+    let (stream, _response) = tokio_tungstenite::client_async_tls(uri, stream)
+        .await
+        .with_context(|| format!("Cannot upgrade the connection to {} to WebSocket", uri))?;
+
+    let (reader, writer) = tokio::io::split(WebSocketIo::new(stream));
+
+    Ok((Box::new(reader), Box::new(writer), peer_addr))
+}
+
+/// the host of an URI keeps the brackets around an IPv6 address
+/// (`[::1]`), they need to be removed before resolving the address
+fn unbracketed(host: &str) -> &str {
+    host.strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host)
+}
+
+fn default_port(scheme: Option<&str>) -> u16 {
+    match scheme {
+        Some("wss") => 443,
+        _ => 80,
+    }
+}
+
+fn into_io_error(error: WsError) -> io::Error {
+    match error {
+        WsError::Io(error) => error,
+        error => io::Error::other(error),
+    }
+}
+
+impl<S> AsyncRead for WebSocketIo<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let io = self.get_mut();
+
+        loop {
+            if !io.buffer.is_empty() {
+                let n = std::cmp::min(buf.remaining(), io.buffer.len());
+                buf.put_slice(&io.buffer[..n]);
+                io.buffer.advance(n);
+                return Poll::Ready(Ok(()));
+            }
+
+            match futures::ready!(Pin::new(&mut io.stream).poll_next(cx)) {
+                None
+                | Some(Ok(Message::Close(_)))
+                | Some(Err(WsError::ConnectionClosed))
+                | Some(Err(WsError::AlreadyClosed)) => return Poll::Ready(Ok(())),
+                Some(Ok(Message::Binary(bytes))) => io.buffer = Bytes::from(bytes),
+                Some(Ok(Message::Text(_))) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unexpected text WebSocket message",
+                    )))
+                }
+                Some(Ok(Message::Ping(_))) | Some(Ok(Message::Pong(_))) => continue,
+                Some(Err(error)) => return Poll::Ready(Err(into_io_error(error))),
+            }
+        }
+    }
+}
+
+impl<S> AsyncWrite for WebSocketIo<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let io = self.get_mut();
+
+        futures::ready!(Pin::new(&mut io.stream).poll_ready(cx)).map_err(into_io_error)?;
+        Pin::new(&mut io.stream)
+            .start_send(Message::Binary(buf.to_vec()))
+            .map_err(into_io_error)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let io = self.get_mut();
+        Pin::new(&mut io.stream)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let io = self.get_mut();
+        Pin::new(&mut io.stream)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
@@ -1,23 +1,43 @@
 use crate::{
-    codec::handshake::{HandshakeInitialize, HandshakeResponse},
-    Handle,
+    codec::handshake::{
+        has_capabilities, has_flags, is_challenge, is_version_mismatch, HandshakeChallenge,
+        HandshakeInitialize, HandshakeResponse, HandshakeXxFinalize, HandshakeXxInitialize,
+        HandshakeXxResponse, HEADER_SIZE,
+    },
+    puzzle, Capabilities, GoodbyeReason, Handle, UnsupportedVersion, Version,
 };
-use anyhow::{bail, Context as _, Result};
+use anyhow::{bail, ensure, Context as _, Result};
 use keynesis::{
     hash::Blake2b,
     key::{
         ed25519::{self, PublicKey},
         Dh,
     },
-    noise::{ik::WaitB, IK},
+    noise::{ik, xx, IK, XX},
 };
 use rand_core::{CryptoRng, RngCore};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
 
 pub struct Opening<I, O, RNG, K = ed25519::SecretKey> {
     reader: I,
     writer: O,
-    state: IK<K, Blake2b, RNG, WaitB>,
+    state: IK<K, Blake2b, RNG, ik::WaitB>,
+    handshake: HandshakeInitialize,
+}
+
+/// opening a connection without knowing the responder's static key
+///
+/// this uses the Noise XX handshake: the responder discloses its static
+/// key to us in its reply.
+pub struct FirstContact<I, O, RNG, K = ed25519::SecretKey> {
+    reader: I,
+    writer: O,
+    state: XX<K, Blake2b, RNG, xx::WaitB>,
+    handshake: HandshakeXxInitialize,
 }
 
 impl<I, O, RNG, K> Opening<I, O, RNG, K>
@@ -30,18 +50,24 @@ where
         rng: RNG,
         k: &K,
         rs: PublicKey,
+        version: Version,
         reader: I,
         mut writer: O,
     ) -> Result<Self> {
-        let mut message = HandshakeInitialize::DEFAULT;
+        let mut message = HandshakeInitialize::new(version);
         let ik = IK::new(rng, &[]);
 
         let state = ik
             .initiate(k, rs, message.message_mut())
             .context("Cannot initiate Noise IK handshake")?;
 
+        let mut bytes = message.to_bytes();
+        if has_capabilities(version) {
+            bytes.extend_from_slice(&Capabilities::ALL.to_bytes());
+        }
+
         writer
-            .write_all(message.as_ref())
+            .write_all(&bytes)
             .await
             .context("Cannot send the Noise IK initial Handshake")?;
 
@@ -49,6 +75,7 @@ where
             reader,
             writer,
             state,
+            handshake: message,
         })
     }
 }
@@ -60,30 +87,238 @@ where
     K: Dh,
     RNG: CryptoRng + RngCore,
 {
-    pub(crate) async fn wait(self, k: &K) -> Result<Handle<I, O>> {
+    /// wait for the responder's reply, solving its challenge if it is not
+    /// more difficult than `max_difficulty`
+    pub(crate) async fn wait(self, k: &K, max_difficulty: u8) -> Result<Handle<I, O>> {
         let Self {
             mut reader,
-            writer,
+            mut writer,
             state,
+            handshake,
         } = self;
 
         let mut bytes = [0; HandshakeResponse::SIZE];
 
-        reader
-            .read_exact(&mut bytes)
-            .await
-            .context("Cannot receive the Noise IK response Handshake")?;
+        let capabilities = receive_reply(
+            &mut reader,
+            &mut writer,
+            handshake.version(),
+            handshake.as_ref(),
+            &mut bytes,
+            max_difficulty,
+        )
+        .await
+        .context("Cannot receive the Noise IK response Handshake")?;
 
         let message = HandshakeResponse::from_bytes(bytes);
 
+        let state = state
+            .receive(k, message.message())
+            .context("Noise IK Handshake response failed")?;
+
+        let capabilities = Capabilities::ALL.intersection(capabilities);
+        let mut handle = Handle::new(reader, writer, state, message.version(), capabilities);
         if !message.version().is_supported() {
+            handle.goodbye(GoodbyeReason::VersionMismatch, None).await?;
             bail!("Unsupported version {:?}", message.version());
         }
 
+        Ok(handle)
+    }
+}
+
+impl<I, O, RNG, K> FirstContact<I, O, RNG, K>
+where
+    O: AsyncWrite + Unpin,
+    K: Dh,
+    RNG: CryptoRng + RngCore,
+{
+    pub(crate) async fn new(rng: RNG, version: Version, reader: I, mut writer: O) -> Result<Self> {
+        let mut message = HandshakeXxInitialize::new(version);
+        let xx = XX::new(rng, &[]);
+
+        let state = xx
+            .initiate(message.message_mut())
+            .context("Cannot initiate Noise XX handshake")?;
+
+        let mut bytes = message.as_ref().to_vec();
+        if has_capabilities(version) {
+            bytes.extend_from_slice(&Capabilities::ALL.to_bytes());
+        }
+
+        writer
+            .write_all(&bytes)
+            .await
+            .context("Cannot send the Noise XX initial Handshake")?;
+
+        Ok(Self {
+            reader,
+            writer,
+            state,
+            handshake: message,
+        })
+    }
+}
+
+impl<I, O, RNG, K> FirstContact<I, O, RNG, K>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + Unpin,
+    K: Dh,
+    RNG: CryptoRng + RngCore,
+{
+    /// wait for the responder's reply, solving its challenge if it is not
+    /// more difficult than `max_difficulty`
+    pub(crate) async fn wait(self, k: &K, max_difficulty: u8) -> Result<Handle<I, O>> {
+        let Self {
+            mut reader,
+            mut writer,
+            state,
+            handshake,
+        } = self;
+
+        let mut bytes = [0; HandshakeXxResponse::SIZE];
+
+        let capabilities = receive_reply(
+            &mut reader,
+            &mut writer,
+            handshake.version(),
+            handshake.as_ref(),
+            &mut bytes,
+            max_difficulty,
+        )
+        .await
+        .context("Cannot receive the Noise XX response Handshake")?;
+
+        let message = HandshakeXxResponse::from_bytes(bytes);
+
+        ensure!(
+            message.version() == handshake.version(),
+            "Unsupported version {:?}",
+            message.version()
+        );
+
         let state = state
-            .receive(k, message.message())
-            .context("Noise IK Handshake response failed")?;
+            .receive(message.message())
+            .context("Noise XX Handshake response failed")?;
+
+        let mut message = HandshakeXxFinalize::new(handshake.version());
+
+        let state = state
+            .reply(k, message.message_mut())
+            .context("Cannot prep the Noise's Handshake final message")?;
+
+        writer
+            .write_all(message.as_ref())
+            .await
+            .context("Cannot send the Noise XX final Handshake")?;
+
+        let capabilities = Capabilities::ALL.intersection(capabilities);
+        Ok(Handle::new(
+            reader,
+            writer,
+            state,
+            handshake.version(),
+            capabilities,
+        ))
+    }
+}
+
+/// receive the responder's reply to the initial `handshake` message
+///
+/// if the responder challenges us first, the challenge is solved (see
+/// [`Puzzle`](crate::Puzzle)) before receiving the actual reply. Fails with
+/// [`UnsupportedVersion`] if the responder does not support our version.
+///
+/// the reply of the version 1 does not have the flags byte: the responder
+/// cannot challenge us.
+///
+/// returns the [`Capabilities`] of the responder (see [`has_capabilities`]).
+async fn receive_reply<I, O>(
+    reader: &mut I,
+    writer: &mut O,
+    version: Version,
+    handshake: &[u8],
+    reply: &mut [u8],
+    max_difficulty: u8,
+) -> Result<Capabilities>
+where
+    I: AsyncRead + Unpin,
+    O: AsyncWrite + Unpin,
+{
+    if !has_flags(version) {
+        reader.read_exact(&mut reply[..Version::SIZE]).await?;
+        reader.read_exact(&mut reply[HEADER_SIZE..]).await?;
+        return Ok(Capabilities::of(version));
+    }
+
+    reader.read_exact(&mut reply[..HEADER_SIZE]).await?;
+
+    if is_version_mismatch(reply[1]) {
+        let supported = Version::from_u8(reply[0]);
+        return Err(UnsupportedVersion { supported }.into());
+    }
+
+    if is_challenge(reply[1]) {
+        let mut challenge = [0; HandshakeChallenge::SIZE];
+        challenge[..HEADER_SIZE].copy_from_slice(&reply[..HEADER_SIZE]);
+        reader
+            .read_exact(&mut challenge[HEADER_SIZE..])
+            .await
+            .context("Cannot receive the puzzle's challenge")?;
+        let challenge = HandshakeChallenge::from_bytes(challenge);
+
+        ensure!(
+            challenge.version() == version,
+            "Unexpected challenge for version {:?}",
+            challenge.version()
+        );
+
+        tracing::debug!(
+            difficulty = challenge.difficulty(),
+            "solving the remote's challenge"
+        );
+        let handshake = handshake.to_vec();
+        let cancel = Cancel::default();
+        let cancelled = Arc::clone(&cancel.0);
+        let solution = tokio::task::spawn_blocking(move || {
+            puzzle::solve(&challenge, &handshake, max_difficulty, &cancelled)
+        })
+        .await
+        .context("Cannot solve the puzzle's challenge")??;
+        drop(cancel);
+
+        writer
+            .write_all(solution.as_ref())
+            .await
+            .context("Cannot send the puzzle's solution")?;
+
+        reader.read_exact(&mut reply[..HEADER_SIZE]).await?;
+    }
+
+    reader.read_exact(&mut reply[HEADER_SIZE..]).await?;
+
+    if !has_capabilities(version) {
+        return Ok(Capabilities::of(version));
+    }
+
+    let mut capabilities = [0; Capabilities::SIZE];
+    reader
+        .read_exact(&mut capabilities)
+        .await
+        .context("Cannot receive the responder's capabilities")?;
+    Ok(Capabilities::from_bytes(capabilities))
+}
+
+/// gives up the solving of the puzzle when dropped
+///
+/// the solving runs on a blocking thread which would otherwise keep
+/// running after the handshake is dropped (timed out for example).
+#[derive(Default)]
+struct Cancel(Arc<AtomicBool>);
 
-        Ok(Handle::new(reader, writer, state))
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
     }
 }
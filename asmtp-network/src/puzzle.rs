@@ -0,0 +1,288 @@
+/*!
+stateless client puzzle
+
+Before spending any Diffie-Hellman operation on an inbound handshake, a
+responder under load can reply with a [`HandshakeChallenge`] instead of the
+handshake response. The initiator has to find a nonce such that the hash of
+the challenge, its initial handshake message and the nonce starts with the
+challenge's _difficulty_ zero bits.
+
+The responder does not keep any state about the challenges it sent: the
+challenge is authenticated with a MAC (keyed with the [`Puzzle`]'s secret)
+over the difficulty, the time it was issued and the address of the
+initiator. Only recent challenges are accepted.
+*/
+
+use crate::{
+    codec::handshake::{HandshakeChallenge, HandshakeSolution},
+    Version,
+};
+use anyhow::{bail, ensure, Result};
+use keynesis::{
+    hash::{Blake2b, Digest as _},
+    passport::block::Time,
+};
+use rand_core::{CryptoRng, RngCore};
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// the responder's side of the client puzzle
+///
+/// see [`Accepting::challenge`](crate::net::Accepting::challenge)
+#[derive(Clone)]
+pub struct Puzzle {
+    key: [u8; Self::KEY_SIZE],
+    difficulty: u8,
+}
+
+impl Puzzle {
+    const KEY_SIZE: usize = 32;
+
+    /// the maximum difficulty a responder may ask for
+    pub const MAX_DIFFICULTY: u8 = 32;
+
+    /// the default maximum difficulty an initiator is willing to solve
+    /// (about a million attempts)
+    ///
+    /// a responder asking for more will see its challenge refused.
+    pub const DEFAULT_MAX_SOLVED_DIFFICULTY: u8 = 20;
+
+    /// the number of attempts between two checks of the cancellation of
+    /// the solving (see [`solve`])
+    const CANCELLATION_CHECK: u64 = 1 << 12;
+
+    /// number of seconds a challenge remains valid
+    const VALIDITY: u32 = 30;
+
+    /// create a new puzzle with a random secret
+    ///
+    /// the `difficulty` is the number of leading zero bits the initiators
+    /// have to find, every extra bit doubles the work of the initiator.
+    ///
+    /// # Errors
+    ///
+    /// fails if the difficulty is greater than [`Puzzle::MAX_DIFFICULTY`].
+    pub fn new<RNG>(mut rng: RNG, difficulty: u8) -> Result<Self>
+    where
+        RNG: RngCore + CryptoRng,
+    {
+        ensure!(
+            difficulty <= Self::MAX_DIFFICULTY,
+            "Puzzle difficulty cannot be greater than {}",
+            Self::MAX_DIFFICULTY
+        );
+
+        let mut key = [0; Self::KEY_SIZE];
+        rng.fill_bytes(&mut key);
+
+        Ok(Self { key, difficulty })
+    }
+
+    pub fn difficulty(&self) -> u8 {
+        self.difficulty
+    }
+
+    fn mac(
+        &self,
+        challenge: &HandshakeChallenge,
+        binding: &[u8],
+    ) -> [u8; HandshakeChallenge::MAC_SIZE] {
+        let mut mac = [0; HandshakeChallenge::MAC_SIZE];
+        let mut hasher = Blake2b::new_keyed(HandshakeChallenge::MAC_SIZE, &self.key);
+        hasher.input(challenge.header());
+        hasher.input(binding);
+        hasher.result(&mut mac);
+        mac
+    }
+
+    /// issue a new challenge for the initiator identified by `binding`
+    /// (its address)
+    pub(crate) fn challenge(
+        &self,
+        version: Version,
+        binding: &[u8],
+        now: Time,
+    ) -> HandshakeChallenge {
+        let mut challenge = HandshakeChallenge::new(version, self.difficulty, now);
+        let mac = self.mac(&challenge, binding);
+        challenge.mac_mut().copy_from_slice(&mac);
+        challenge
+    }
+
+    /// verify the solution is valid for a challenge we have issued
+    /// recently to the initiator identified by `binding`
+    pub(crate) fn verify(
+        &self,
+        solution: &HandshakeSolution,
+        binding: &[u8],
+        handshake: &[u8],
+        now: Time,
+    ) -> Result<()> {
+        let challenge = solution.challenge();
+
+        let mac = self.mac(&challenge, binding);
+        let diff = mac
+            .iter()
+            .zip(challenge.mac())
+            .fold(0, |diff, (a, b)| diff | (a ^ b));
+        ensure!(diff == 0, "Invalid challenge");
+
+        let time = *challenge.time();
+        let now = *now;
+        if time > now || now - time > Self::VALIDITY {
+            bail!("Expired challenge")
+        }
+
+        ensure!(
+            leading_zeros(&challenge, handshake, solution.nonce()) >= challenge.difficulty() as u32,
+            "Invalid solution to the challenge"
+        );
+
+        Ok(())
+    }
+}
+
+/// find the nonce solving the challenge for the given initial handshake
+/// message
+///
+/// this is CPU intensive, the expected number of attempts is
+/// `2^difficulty`. The challenges more difficult than `max_difficulty` are
+/// refused and the solving gives up once `cancelled` is set (the handshake
+/// timed out for example).
+pub(crate) fn solve(
+    challenge: &HandshakeChallenge,
+    handshake: &[u8],
+    max_difficulty: u8,
+    cancelled: &AtomicBool,
+) -> Result<HandshakeSolution> {
+    ensure!(
+        challenge.difficulty() <= max_difficulty.min(Puzzle::MAX_DIFFICULTY),
+        "Challenge difficulty is too high ({})",
+        challenge.difficulty()
+    );
+
+    let difficulty = challenge.difficulty() as u32;
+    let mut nonce = 0;
+    while leading_zeros(challenge, handshake, nonce) < difficulty {
+        nonce += 1;
+        if nonce % Puzzle::CANCELLATION_CHECK == 0 && cancelled.load(Ordering::Relaxed) {
+            bail!("Gave up solving the challenge")
+        }
+    }
+
+    Ok(HandshakeSolution::new(challenge, nonce))
+}
+
+fn leading_zeros(challenge: &HandshakeChallenge, handshake: &[u8], nonce: u64) -> u32 {
+    let mut hash = [0; 32];
+    let mut hasher = Blake2b::new(hash.len());
+    hasher.input(challenge.as_ref());
+    hasher.input(handshake);
+    hasher.input(&nonce.to_be_bytes());
+    hasher.result(&mut hash);
+
+    let mut zeros = 0;
+    for byte in hash.iter() {
+        zeros += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    zeros
+}
+
+/// the secret is not displayed in the debug output
+impl fmt::Debug for Puzzle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Puzzle")
+            .field("difficulty", &self.difficulty)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::Seed;
+
+    const BINDING: &[u8] = b"10.0.0.1";
+    const HANDSHAKE: &[u8] = b"initial handshake";
+
+    fn puzzle(difficulty: u8) -> Puzzle {
+        Puzzle::new(Seed::from([0; Seed::SIZE]).into_rand_chacha(), difficulty).unwrap()
+    }
+
+    #[test]
+    fn solve_and_verify() {
+        let puzzle = puzzle(8);
+        let now = Time::from(1_000);
+
+        let challenge = puzzle.challenge(Version::CURRENT, BINDING, now);
+        assert_eq!(challenge.version(), Version::CURRENT);
+        let solution = solve(&challenge, HANDSHAKE, 8, &AtomicBool::new(false)).unwrap();
+
+        puzzle.verify(&solution, BINDING, HANDSHAKE, now).unwrap();
+        puzzle
+            .verify(&solution, BINDING, HANDSHAKE, Time::from(1_030))
+            .unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_solutions() {
+        let puzzle = puzzle(8);
+        let now = Time::from(1_000);
+
+        let challenge = puzzle.challenge(Version::CURRENT, BINDING, now);
+        let solution = solve(&challenge, HANDSHAKE, 8, &AtomicBool::new(false)).unwrap();
+
+        // another initiator's challenge
+        assert!(puzzle
+            .verify(&solution, b"10.0.0.2", HANDSHAKE, now)
+            .is_err());
+        // the solution is bound to the handshake message
+        assert!(puzzle
+            .verify(&solution, BINDING, b"other handshake", now)
+            .is_err());
+        // expired
+        assert!(puzzle
+            .verify(&solution, BINDING, HANDSHAKE, Time::from(1_031))
+            .is_err());
+        // not issued by us
+        let other = Puzzle::new(Seed::from([1; Seed::SIZE]).into_rand_chacha(), 8).unwrap();
+        assert!(other.verify(&solution, BINDING, HANDSHAKE, now).is_err());
+    }
+
+    #[test]
+    fn solving_is_bounded() {
+        let puzzle = puzzle(Puzzle::MAX_DIFFICULTY);
+        let challenge = puzzle.challenge(Version::CURRENT, BINDING, Time::from(1_000));
+
+        let max_difficulty = Puzzle::MAX_DIFFICULTY - 1;
+        assert!(solve(
+            &challenge,
+            HANDSHAKE,
+            max_difficulty,
+            &AtomicBool::new(false)
+        )
+        .is_err());
+        // gives up on the first check of the cancellation
+        assert!(solve(
+            &challenge,
+            HANDSHAKE,
+            Puzzle::MAX_DIFFICULTY,
+            &AtomicBool::new(true)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn difficulty_is_bounded() {
+        assert!(Puzzle::new(
+            Seed::from([0; Seed::SIZE]).into_rand_chacha(),
+            Puzzle::MAX_DIFFICULTY + 1
+        )
+        .is_err());
+    }
+}
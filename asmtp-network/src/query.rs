@@ -0,0 +1,101 @@
+/*!
+ranged and paginated queries of the messages of a topic
+
+A [`TopicQuery`] asks a node for the messages of a [`Topic`] received in a
+given time range. The node sends back at most [`TopicQuery::limit`]
+messages (and may use a lower limit of its own). If more messages match,
+the node follows the messages with a [`MessageType::QueryTopicMessagesNext`]
+carrying an opaque cursor: sending the same query again with this cursor
+(see [`TopicQuery::after`]) returns the next page. The cursor is sent in
+reply to any query to the peers supporting paginated queries, even if the
+query itself is not paginated (see [`TopicQuery::is_paginated`]).
+
+Only peers supporting it may be sent the bounded queries (see
+[`Capabilities::supports_paginated_query`]).
+
+[`MessageType::QueryTopicMessagesNext`]: crate::MessageType::QueryTopicMessagesNext
+[`Capabilities::supports_paginated_query`]: crate::Capabilities::supports_paginated_query
+*/
+
+use asmtp_lib::MessageId;
+use keynesis::passport::block::Time;
+use poldercast::Topic;
+
+/// query of the messages of a topic (see [`Message::new_topic_query`])
+///
+/// [`Message::new_topic_query`]: crate::Message::new_topic_query
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TopicQuery {
+    topic: Topic,
+    since: Time,
+    until: Option<Time>,
+    limit: Option<u16>,
+    cursor: Option<MessageId>,
+}
+
+impl TopicQuery {
+    /// query all the messages of the `topic` received since the given time
+    pub fn new(topic: Topic, since: Time) -> Self {
+        Self {
+            topic,
+            since,
+            until: None,
+            limit: None,
+            cursor: None,
+        }
+    }
+
+    /// only query the messages received before the given time
+    pub fn until(self, until: Time) -> Self {
+        Self {
+            until: Some(until),
+            ..self
+        }
+    }
+
+    /// query at most `limit` messages
+    ///
+    /// a limit of `0` means no limit: the node still applies its own
+    pub fn limit(self, limit: u16) -> Self {
+        Self {
+            limit: Some(limit).filter(|limit| *limit > 0),
+            ..self
+        }
+    }
+
+    /// query the messages following the `cursor` returned by the node with
+    /// the previous page of results
+    pub fn after(self, cursor: MessageId) -> Self {
+        Self {
+            cursor: Some(cursor),
+            ..self
+        }
+    }
+
+    pub fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    pub fn since(&self) -> Time {
+        self.since
+    }
+
+    pub fn until_time(&self) -> Option<Time> {
+        self.until
+    }
+
+    pub fn max_messages(&self) -> Option<u16> {
+        self.limit
+    }
+
+    pub fn cursor(&self) -> Option<&MessageId> {
+        self.cursor.as_ref()
+    }
+
+    /// returns true if the query uses any of the until time, the limit or
+    /// the cursor. Such queries can only be sent to the peers supporting
+    /// paginated queries.
+    pub fn is_paginated(&self) -> bool {
+        self.until.is_some() || self.limit.is_some() || self.cursor.is_some()
+    }
+}
@@ -0,0 +1,658 @@
+/*!
+set reconciliation of the messages of a topic
+
+Two peers storing the messages of the same [`Topic`] can find out which
+messages the other is missing without sending everything they have. The
+messages are identified by their [`MessageHash`] (the hash of their content,
+the same on every peer) and the peers run a range-based set reconciliation:
+
+1. the initiator sends a [`Reconciliation`] with the fingerprint of all its
+   messages received since a given time;
+2. the peer compares every range it receives with its own messages. The
+   ranges with matching fingerprints are done. The others are either split
+   in 2 smaller ranges with their fingerprints or, when there are only a
+   few messages left in the range, answered with the list of the hashes of
+   the messages;
+3. a peer receiving the list of hashes of a range sends the messages the
+   other is missing and asks for the messages it is missing itself.
+
+The exchange stops when all the ranges are done. The messages are sent with
+[`Message::new_topic`].
+
+Only peers supporting it may be sent a reconciliation (see
+[`Capabilities::supports_reconciliation`]).
+
+[`Message::new_topic`]: crate::Message::new_topic
+[`Capabilities::supports_reconciliation`]: crate::Capabilities::supports_reconciliation
+*/
+
+use crate::Message;
+use anyhow::{bail, ensure, Result};
+use bytes::{BufMut as _, BytesMut};
+use keynesis::{hash::Blake2b, passport::block::Time};
+use poldercast::Topic;
+use std::{
+    convert::{TryFrom, TryInto as _},
+    fmt,
+};
+
+/// the hash of the content of a message
+///
+/// unlike the [`MessageId`](asmtp_lib::MessageId), it does not depend on
+/// the time the message was received at so it is the same on every peer.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MessageHash([u8; Self::SIZE]);
+
+/// the fingerprint of the messages of a range: the XOR of their hashes
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+struct Fingerprint([u8; MessageHash::SIZE]);
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+enum Mode {
+    /// the range is reconciled
+    Skip,
+    /// the fingerprint of the sender's messages in the range
+    Fingerprint(Fingerprint),
+    /// the hashes of all the sender's messages in the range, the receiver
+    /// sends the messages the sender is missing and asks for the others
+    Items(Vec<MessageHash>),
+    /// the sender is missing these messages
+    Want(Vec<MessageHash>),
+}
+
+/// the ranges cover the whole space of hashes: a range starts where the
+/// previous one stops (the first starts at [`MessageHash::MIN`]) and stops
+/// before its `upper` bound. The last range includes [`MessageHash::MAX`].
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+struct Range {
+    upper: MessageHash,
+    mode: Mode,
+}
+
+/// one step of the reconciliation of the messages of a topic (see
+/// [`Message::new_reconcile`])
+///
+/// [`Message::new_reconcile`]: crate::Message::new_reconcile
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Reconciliation {
+    topic: Topic,
+    since: Time,
+    ranges: Vec<Range>,
+}
+
+impl MessageHash {
+    pub const SIZE: usize = 16;
+
+    pub const MIN: Self = Self([0x00; Self::SIZE]);
+    pub const MAX: Self = Self([0xFF; Self::SIZE]);
+
+    /// compute the hash of the content of a message
+    pub fn new(content: impl AsRef<[u8]>) -> Self {
+        let mut hash = [0; Self::SIZE];
+        Blake2b::blake2b(&mut hash, content.as_ref(), &[]);
+        Self(hash)
+    }
+}
+
+impl Fingerprint {
+    fn new(items: &[MessageHash]) -> Self {
+        let mut fingerprint = [0; MessageHash::SIZE];
+        for item in items {
+            for (f, b) in fingerprint.iter_mut().zip(item.0.iter()) {
+                *f ^= b;
+            }
+        }
+        Self(fingerprint)
+    }
+}
+
+impl Mode {
+    const SKIP: u8 = 0;
+    const FINGERPRINT: u8 = 1;
+    const ITEMS: u8 = 2;
+    const WANT: u8 = 3;
+
+    fn encoded_size(&self) -> usize {
+        match self {
+            Self::Skip => 0,
+            Self::Fingerprint(_) => MessageHash::SIZE,
+            Self::Items(items) | Self::Want(items) => 2 + items.len() * MessageHash::SIZE,
+        }
+    }
+}
+
+impl Range {
+    /// the mode and the upper bound
+    const HEAD_SIZE: usize = 1 + MessageHash::SIZE;
+
+    fn encoded_size(&self) -> usize {
+        Self::HEAD_SIZE + self.mode.encoded_size()
+    }
+}
+
+impl Reconciliation {
+    /// the number of messages in a range from which the peers exchange
+    /// the fingerprint of the range instead of the hashes of the messages
+    const MAX_ITEMS: usize = 32;
+
+    /// the message type, the topic and the time
+    pub(crate) const HEAD_SIZE: usize = 1 + Topic::SIZE + Time::SIZE;
+
+    /// start the reconciliation of the messages of the `topic` received
+    /// since the given time
+    ///
+    /// `items` are the hashes of our messages, sorted.
+    pub fn new(topic: Topic, since: Time, items: &[MessageHash]) -> Self {
+        debug_assert!(items.windows(2).all(|w| w[0] <= w[1]));
+
+        let mode = if items.len() <= Self::MAX_ITEMS {
+            Mode::Items(items.to_vec())
+        } else {
+            Mode::Fingerprint(Fingerprint::new(items))
+        };
+
+        Self {
+            topic,
+            since,
+            ranges: vec![Range {
+                upper: MessageHash::MAX,
+                mode,
+            }],
+        }
+    }
+
+    pub fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    /// the reconciliation is about the messages received since this time
+    pub fn since(&self) -> Time {
+        self.since
+    }
+
+    /// only reconcile the messages received since the given time, if the
+    /// peer asked for older messages
+    ///
+    /// the reply carries the new time so the peer follows.
+    pub fn not_before(self, since: Time) -> Self {
+        Self {
+            since: self.since.max(since),
+            ..self
+        }
+    }
+
+    /// compute our reply to the peer's reconciliation
+    ///
+    /// `items` are the hashes of our messages (received since
+    /// [`since`](Self::since)), sorted. Returns the reconciliation to send
+    /// back to the peer (if the reconciliation is not done) and the hashes
+    /// of the messages to send to the peer.
+    pub fn reply(&self, items: &[MessageHash]) -> (Option<Self>, Vec<MessageHash>) {
+        self.reply_bounded(items, usize::MAX, usize::MAX)
+    }
+
+    /// same as [`reply`](Self::reply) but only answers the first
+    /// `max_ranges` ranges of the peer and sends at most `max_messages`
+    /// messages (if the first range with messages to send has more, only
+    /// its first `max_messages` messages are sent)
+    ///
+    /// the ranges left are reconciled again from their overall fingerprint
+    /// in the next round.
+    pub fn reply_bounded(
+        &self,
+        items: &[MessageHash],
+        max_ranges: usize,
+        max_messages: usize,
+    ) -> (Option<Self>, Vec<MessageHash>) {
+        debug_assert!(items.windows(2).all(|w| w[0] <= w[1]));
+
+        // keep room for the range covering what did not fit
+        let max_size = Message::MAX_SIZE - Range::HEAD_SIZE - MessageHash::SIZE;
+        let mut size = Self::HEAD_SIZE;
+        let mut ranges: Vec<Range> = Vec::new();
+        let mut send = Vec::new();
+        let mut lower = MessageHash::MIN;
+
+        for (index, range) in self.ranges.iter().enumerate() {
+            let ours = range_items(items, &lower, &range.upper);
+
+            let mut reply = Vec::with_capacity(2);
+            let mut range_send = Vec::new();
+            match &range.mode {
+                Mode::Skip => {}
+                Mode::Fingerprint(fingerprint) => {
+                    if &Fingerprint::new(ours) == fingerprint {
+                        // already reconciled
+                    } else if ours.len() <= Self::MAX_ITEMS {
+                        reply.push(Range {
+                            upper: range.upper,
+                            mode: Mode::Items(ours.to_vec()),
+                        });
+                    } else {
+                        let (left, right) = ours.split_at(ours.len() / 2);
+                        reply.push(Range {
+                            upper: right[0],
+                            mode: Mode::Fingerprint(Fingerprint::new(left)),
+                        });
+                        reply.push(Range {
+                            upper: range.upper,
+                            mode: Mode::Fingerprint(Fingerprint::new(right)),
+                        });
+                    }
+                }
+                Mode::Items(theirs) => {
+                    range_send.extend(
+                        ours.iter()
+                            .filter(|item| theirs.binary_search(item).is_err()),
+                    );
+                    let want: Vec<_> = theirs
+                        .iter()
+                        .filter(|item| ours.binary_search(item).is_err())
+                        .copied()
+                        .collect();
+                    if !want.is_empty() {
+                        reply.push(Range {
+                            upper: range.upper,
+                            mode: Mode::Want(want),
+                        });
+                    }
+                }
+                Mode::Want(theirs) => {
+                    range_send.extend(
+                        theirs
+                            .iter()
+                            .filter(|item| ours.binary_search(item).is_ok()),
+                    );
+                }
+            }
+
+            if reply.is_empty() {
+                reply.push(Range {
+                    upper: range.upper,
+                    mode: Mode::Skip,
+                });
+            }
+            let merged = reply[0].mode == Mode::Skip
+                && matches!(
+                    ranges.last(),
+                    Some(Range {
+                        mode: Mode::Skip,
+                        ..
+                    })
+                );
+            let reply_size = if merged {
+                0
+            } else {
+                reply.iter().map(Range::encoded_size).sum()
+            };
+            let bounded = index >= max_ranges || send.len() + range_send.len() > max_messages;
+            if bounded || size + reply_size > max_size {
+                // the messages of a range asking for more than we may
+                // send are still sent in part, so the reconciliation
+                // progresses
+                if send.is_empty() && index < max_ranges {
+                    range_send.truncate(max_messages);
+                    send.append(&mut range_send);
+                }
+                // restart the reconciliation of the remaining ranges
+                // from their overall fingerprint
+                let remaining = range_items(items, &lower, &MessageHash::MAX);
+                push(
+                    &mut ranges,
+                    Range {
+                        upper: MessageHash::MAX,
+                        mode: Mode::Fingerprint(Fingerprint::new(remaining)),
+                    },
+                );
+                break;
+            }
+
+            size += reply_size;
+            send.append(&mut range_send);
+            for range in reply {
+                push(&mut ranges, range);
+            }
+
+            lower = range.upper;
+        }
+
+        let done = ranges.iter().all(|range| range.mode == Mode::Skip);
+        let reply = if done {
+            None
+        } else {
+            Some(Self {
+                topic: self.topic,
+                since: self.since,
+                ranges,
+            })
+        };
+
+        (reply, send)
+    }
+
+    /// the size of the ranges once encoded
+    pub(crate) fn ranges_size(&self) -> usize {
+        self.ranges.iter().map(Range::encoded_size).sum()
+    }
+
+    pub(crate) fn put_ranges(&self, bytes: &mut BytesMut) {
+        for range in self.ranges.iter() {
+            match &range.mode {
+                Mode::Skip => {
+                    bytes.put_u8(Mode::SKIP);
+                    bytes.put_slice(&range.upper.0);
+                }
+                Mode::Fingerprint(fingerprint) => {
+                    bytes.put_u8(Mode::FINGERPRINT);
+                    bytes.put_slice(&range.upper.0);
+                    bytes.put_slice(&fingerprint.0);
+                }
+                Mode::Items(items) => {
+                    bytes.put_u8(Mode::ITEMS);
+                    bytes.put_slice(&range.upper.0);
+                    put_items(bytes, items);
+                }
+                Mode::Want(items) => {
+                    bytes.put_u8(Mode::WANT);
+                    bytes.put_slice(&range.upper.0);
+                    put_items(bytes, items);
+                }
+            }
+        }
+    }
+
+    /// read the ranges of a reconciliation of the messages of `topic`
+    ///
+    /// the ranges need to be in order and to cover the whole space of
+    /// hashes.
+    pub(crate) fn read_ranges(topic: Topic, since: Time, mut bytes: &[u8]) -> Result<Self> {
+        let mut ranges = Vec::new();
+        let mut lower = MessageHash::MIN;
+
+        while !bytes.is_empty() {
+            ensure!(
+                bytes.len() >= Range::HEAD_SIZE,
+                "Not enough bytes for the range {} of the reconciliation",
+                ranges.len()
+            );
+            let mode = bytes[0];
+            let upper = MessageHash::try_from(&bytes[1..Range::HEAD_SIZE]).unwrap();
+            bytes = &bytes[Range::HEAD_SIZE..];
+
+            ensure!(
+                upper > lower,
+                "The ranges of the reconciliation are not in order"
+            );
+
+            let mode = match mode {
+                Mode::SKIP => Mode::Skip,
+                Mode::FINGERPRINT => {
+                    ensure!(
+                        bytes.len() >= MessageHash::SIZE,
+                        "Not enough bytes for the fingerprint of the range {}",
+                        ranges.len()
+                    );
+                    let (fingerprint, tail) = bytes.split_at(MessageHash::SIZE);
+                    bytes = tail;
+                    Mode::Fingerprint(Fingerprint(fingerprint.try_into().unwrap()))
+                }
+                Mode::ITEMS | Mode::WANT => {
+                    ensure!(
+                        bytes.len() >= 2,
+                        "Not enough bytes for the number of items of the range {}",
+                        ranges.len()
+                    );
+                    let len = u16::from_be_bytes(bytes[..2].try_into().unwrap()) as usize;
+                    bytes = &bytes[2..];
+                    ensure!(
+                        bytes.len() >= len * MessageHash::SIZE,
+                        "Not enough bytes for the items of the range {}",
+                        ranges.len()
+                    );
+                    let (head, tail) = bytes.split_at(len * MessageHash::SIZE);
+                    bytes = tail;
+                    let items: Vec<_> = head
+                        .chunks_exact(MessageHash::SIZE)
+                        .map(|item| MessageHash::try_from(item).unwrap())
+                        .collect();
+                    ensure!(
+                        items.windows(2).all(|w| w[0] < w[1]),
+                        "The items of the range {} are not sorted",
+                        ranges.len()
+                    );
+
+                    if mode == Mode::ITEMS {
+                        Mode::Items(items)
+                    } else {
+                        Mode::Want(items)
+                    }
+                }
+                mode => bail!("Unknown reconciliation mode {}", mode),
+            };
+
+            ranges.push(Range { upper, mode });
+            lower = upper;
+        }
+
+        ensure!(
+            lower == MessageHash::MAX,
+            "The ranges of the reconciliation do not cover all the messages"
+        );
+
+        Ok(Self {
+            topic,
+            since,
+            ranges,
+        })
+    }
+}
+
+/// the items within `lower` (included) and `upper` (excluded, unless it is
+/// [`MessageHash::MAX`])
+fn range_items<'a>(
+    items: &'a [MessageHash],
+    lower: &MessageHash,
+    upper: &MessageHash,
+) -> &'a [MessageHash] {
+    let start = items.partition_point(|item| item < lower);
+    let end = if upper == &MessageHash::MAX {
+        items.len()
+    } else {
+        items.partition_point(|item| item < upper)
+    };
+    &items[start..end]
+}
+
+fn put_items(bytes: &mut BytesMut, items: &[MessageHash]) {
+    bytes.put_u16(items.len() as u16);
+    for item in items {
+        bytes.put_slice(&item.0);
+    }
+}
+
+/// add the range to the reply, merging the consecutive reconciled ranges
+fn push(ranges: &mut Vec<Range>, range: Range) {
+    if let Some(last) = ranges.last_mut() {
+        if last.mode == Mode::Skip && range.mode == Mode::Skip {
+            last.upper = range.upper;
+            return;
+        }
+    }
+    ranges.push(range);
+}
+
+impl AsRef<[u8]> for MessageHash {
+    fn as_ref(&self) -> &[u8] {
+        self.0.as_ref()
+    }
+}
+
+impl From<[u8; Self::SIZE]> for MessageHash {
+    fn from(bytes: [u8; Self::SIZE]) -> Self {
+        Self(bytes)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for MessageHash {
+    type Error = std::array::TryFromSliceError;
+    fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
+        value.try_into().map(Self)
+    }
+}
+
+impl fmt::Debug for MessageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("MessageHash")
+            .field(&hex::encode(self.0))
+            .finish()
+    }
+}
+
+impl fmt::Display for MessageHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        hex::encode(self.0).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn hashes(range: std::ops::Range<u32>) -> BTreeSet<MessageHash> {
+        range.map(|i| MessageHash::new(i.to_be_bytes())).collect()
+    }
+
+    /// run the reconciliation between `a` and `b` until it is done and
+    /// returns the number of round trips
+    fn reconcile(a: &mut BTreeSet<MessageHash>, b: &mut BTreeSet<MessageHash>) -> usize {
+        reconcile_bounded(a, b, usize::MAX, usize::MAX)
+    }
+
+    fn reconcile_bounded(
+        a: &mut BTreeSet<MessageHash>,
+        b: &mut BTreeSet<MessageHash>,
+        max_ranges: usize,
+        max_messages: usize,
+    ) -> usize {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let items: Vec<_> = a.iter().copied().collect();
+        let mut next = Some(Reconciliation::new(topic, Time::from(0), &items));
+        let mut steps = 0;
+
+        let (mut from, mut to) = (a, b);
+        while let Some(reconciliation) = next {
+            // check the reconciliation survives the encoding
+            let message = Message::new_reconcile(&reconciliation);
+            assert!(message.as_ref().len() <= Message::MAX_SIZE);
+            let reconciliation = message.reconcile_checked().unwrap();
+
+            let items: Vec<_> = to.iter().copied().collect();
+            let (reply, send) = reconciliation.reply_bounded(&items, max_ranges, max_messages);
+            assert!(send.len() <= max_messages);
+            from.extend(send);
+
+            next = reply;
+            steps += 1;
+            std::mem::swap(&mut from, &mut to);
+        }
+
+        steps
+    }
+
+    #[test]
+    fn same_sets() {
+        let mut a = hashes(0..1_000);
+        let mut b = a.clone();
+
+        assert_eq!(reconcile(&mut a, &mut b), 1);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn small_sets() {
+        let mut a = hashes(0..10);
+        let mut b = hashes(5..20);
+
+        reconcile(&mut a, &mut b);
+        assert_eq!(a, hashes(0..20));
+        assert_eq!(b, hashes(0..20));
+    }
+
+    #[test]
+    fn empty_set() {
+        let mut a = BTreeSet::new();
+        let mut b = hashes(0..1_000);
+
+        reconcile(&mut a, &mut b);
+        assert_eq!(a, b);
+
+        let mut a = hashes(0..1_000);
+        let mut b = BTreeSet::new();
+
+        reconcile(&mut a, &mut b);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn large_sets_with_few_differences() {
+        let mut a = hashes(0..10_000);
+        let mut b = hashes(0..10_000);
+        a.extend(hashes(20_000..20_010));
+        b.extend(hashes(30_000..30_010));
+        a.remove(&MessageHash::new(42u32.to_be_bytes()));
+
+        let steps = reconcile(&mut a, &mut b);
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 10_020);
+        assert!(steps < 20, "took {} steps", steps);
+    }
+
+    #[test]
+    fn bounded_replies() {
+        let mut a = hashes(0..1_000);
+        let mut b = hashes(0..1_000);
+        a.extend(hashes(2_000..2_020));
+        b.extend(hashes(3_000..3_020));
+
+        reconcile_bounded(&mut a, &mut b, 4, 8);
+        assert_eq!(a, b);
+        assert_eq!(a.len(), 1_040);
+
+        let mut a = BTreeSet::new();
+        let mut b = hashes(0..100);
+        reconcile_bounded(&mut a, &mut b, 4, 8);
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn since_is_bounded() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let reconciliation = Reconciliation::new(topic, Time::from(10), &[]);
+
+        assert_eq!(
+            reconciliation.clone().not_before(Time::from(20)).since(),
+            Time::from(20)
+        );
+        assert_eq!(
+            reconciliation.not_before(Time::from(5)).since(),
+            Time::from(10)
+        );
+    }
+
+    #[test]
+    fn ranges_need_to_cover_all_hashes() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let reconciliation = Reconciliation {
+            topic,
+            since: Time::from(0),
+            ranges: vec![Range {
+                upper: MessageHash::from([0x80; MessageHash::SIZE]),
+                mode: Mode::Skip,
+            }],
+        };
+        let message = Message::new_reconcile(&reconciliation);
+
+        assert!(crate::MessageSlice::try_from_slice(message.as_ref()).is_err());
+    }
+}
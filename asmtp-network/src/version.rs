@@ -20,6 +20,40 @@ use std::{
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
 pub struct Version(u8);
 
+/// the optional features of the protocol supported by a peer
+///
+/// from [`Version::V3`], the peers exchange their capabilities during the
+/// handshake and only use the features both support (see
+/// [`intersection`]). The peers of the previous versions support all the
+/// features of their version (see [`of`]).
+///
+/// ```
+/// # use asmtp_network::{Capabilities, Version};
+/// let ours = Capabilities::ALL;
+/// let theirs = Capabilities::BATCH.union(Capabilities::REJECTION);
+/// let negotiated = ours.intersection(theirs);
+///
+/// assert!(negotiated.supports_batch());
+/// assert!(!negotiated.supports_reconciliation());
+/// assert!(Capabilities::of(Version::V2).supports_reconciliation());
+/// ```
+///
+/// [`intersection`]: Capabilities::intersection
+/// [`of`]: Capabilities::of
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Capabilities(u32);
+
+/// the responder does not support the [`Version`] the initiator opened
+/// the connection with
+///
+/// the responder tells so before any Diffie-Hellman operation, along with
+/// the maximal version it supports.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct UnsupportedVersion {
+    /// the maximal version the responder supports
+    pub supported: Version,
+}
+
 impl Version {
     /// the encoded size of the [`Version`].
     ///
@@ -34,11 +68,51 @@ impl Version {
     /// Support syncing passports between the nodes
     pub const V1: Self = Self(0x01);
 
+    /// version 2:
+    ///
+    /// * the handshake messages have a byte of flags after the version;
+    /// * the responder may ask the initiator to solve a [`Puzzle`] before
+    ///   processing the handshake;
+    /// * the initiator may open the connection without knowing the
+    ///   responder's static key (first contact with the Noise XX handshake);
+    /// * several messages may be packed in one frame (see
+    ///   [`MessageType::Batch`]);
+    /// * the topic message queries may be bounded and paginated (see
+    ///   [`TopicQuery`]);
+    /// * the peers may reconcile the messages of a topic (see
+    ///   [`Reconciliation`]);
+    /// * the peers may exchange only the new blocks of a passport (see
+    ///   [`MessageType::GetPassportBlocks`] and
+    ///   [`MessageType::PutPassportBlocks`]);
+    /// * the nodes may advertise several addresses along with their gossip
+    ///   (see [`PeerAddresses`]);
+    /// * the nodes may tell their peers a message was not accepted (see
+    ///   [`MessageType::Rejected`]);
+    /// * the peers may send cover traffic (see [`MessageType::Cover`]).
+    ///
+    /// [`Puzzle`]: crate::Puzzle
+    /// [`MessageType::Batch`]: crate::MessageType::Batch
+    /// [`TopicQuery`]: crate::TopicQuery
+    /// [`Reconciliation`]: crate::Reconciliation
+    /// [`MessageType::GetPassportBlocks`]: crate::MessageType::GetPassportBlocks
+    /// [`MessageType::PutPassportBlocks`]: crate::MessageType::PutPassportBlocks
+    /// [`PeerAddresses`]: crate::PeerAddresses
+    /// [`MessageType::Rejected`]: crate::MessageType::Rejected
+    /// [`MessageType::Cover`]: crate::MessageType::Cover
+    pub const V2: Self = Self(0x02);
+
+    /// version 3:
+    ///
+    /// the handshake messages are followed by the [`Capabilities`] of the
+    /// peers, the features of the version 2 are only used if both peers
+    /// support them.
+    pub const V3: Self = Self(0x03);
+
     /// get the minimal supported version supported by this implementation
     pub const MIN: Self = Self::V1;
 
     /// get the current version implemented by this implementation
-    pub const CURRENT: Self = Self::V1;
+    pub const CURRENT: Self = Self::V3;
 
     /// get the maximal supported version supported by this implementation
     pub const MAX: Self = Self::CURRENT;
@@ -60,6 +134,21 @@ impl Version {
         Self::MIN <= self && self <= Self::MAX
     }
 
+    /// returns if the version supports the [`Puzzle`] challenge
+    ///
+    /// [`Puzzle`]: crate::Puzzle
+    #[inline]
+    pub fn supports_puzzle(self) -> bool {
+        self >= Self::V2
+    }
+
+    /// returns if the version supports the first contact handshake (when
+    /// the initiator does not know the responder's static key)
+    #[inline]
+    pub fn supports_first_contact(self) -> bool {
+        self >= Self::V2
+    }
+
     #[inline]
     pub(crate) const fn from_u8(version: u8) -> Self {
         Self(version)
@@ -71,6 +160,157 @@ impl Version {
     }
 }
 
+impl Capabilities {
+    /// the encoded size of the [`Capabilities`] in the handshake
+    pub const SIZE: usize = std::mem::size_of::<u32>();
+
+    /// no optional feature
+    pub const NONE: Self = Self(0);
+
+    /// packing several messages in one frame (see [`MessageType::Batch`])
+    ///
+    /// [`MessageType::Batch`]: crate::MessageType::Batch
+    pub const BATCH: Self = Self(0b0000_0001);
+
+    /// the bounded and paginated topic message queries (see
+    /// [`TopicQuery`])
+    ///
+    /// [`TopicQuery`]: crate::TopicQuery
+    pub const PAGINATED_QUERY: Self = Self(0b0000_0010);
+
+    /// the set reconciliation of the messages of a topic (see
+    /// [`Reconciliation`])
+    ///
+    /// [`Reconciliation`]: crate::Reconciliation
+    pub const RECONCILIATION: Self = Self(0b0000_0100);
+
+    /// exchanging only the new blocks of a passport (see
+    /// [`MessageType::PutPassportBlocks`])
+    ///
+    /// [`MessageType::PutPassportBlocks`]: crate::MessageType::PutPassportBlocks
+    pub const PASSPORT_SYNC: Self = Self(0b0000_1000);
+
+    /// advertising several addresses (see [`PeerAddresses`])
+    ///
+    /// [`PeerAddresses`]: crate::PeerAddresses
+    pub const PEER_ADDRESSES: Self = Self(0b0001_0000);
+
+    /// being told a message was not accepted (see
+    /// [`MessageType::Rejected`])
+    ///
+    /// [`MessageType::Rejected`]: crate::MessageType::Rejected
+    pub const REJECTION: Self = Self(0b0010_0000);
+
+    /// receiving cover traffic (see [`MessageType::Cover`])
+    ///
+    /// [`MessageType::Cover`]: crate::MessageType::Cover
+    pub const COVER_TRAFFIC: Self = Self(0b0100_0000);
+
+    /// the features of the [`Version::V2`]
+    const V2: Self = Self::BATCH
+        .union(Self::PAGINATED_QUERY)
+        .union(Self::RECONCILIATION)
+        .union(Self::PASSPORT_SYNC)
+        .union(Self::PEER_ADDRESSES)
+        .union(Self::REJECTION)
+        .union(Self::COVER_TRAFFIC);
+
+    /// all the capabilities supported by this implementation
+    pub const ALL: Self = Self::V2;
+
+    /// the capabilities of the peers of the `version` which do not
+    /// exchange their capabilities during the handshake (before
+    /// [`Version::V3`])
+    pub fn of(version: Version) -> Self {
+        if version >= Version::V2 {
+            Self::V2
+        } else {
+            Self::NONE
+        }
+    }
+
+    /// the capabilities in `self` or in `other`
+    #[inline]
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    /// the capabilities both in `self` and in `other`
+    #[inline]
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// returns if all the capabilities of `other` are in `self`
+    #[inline]
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// returns if packing several messages in one frame is supported
+    /// (see [`Capabilities::BATCH`])
+    #[inline]
+    pub fn supports_batch(self) -> bool {
+        self.contains(Self::BATCH)
+    }
+
+    /// returns if the bounded and paginated topic message queries are
+    /// supported (see [`Capabilities::PAGINATED_QUERY`])
+    #[inline]
+    pub fn supports_paginated_query(self) -> bool {
+        self.contains(Self::PAGINATED_QUERY)
+    }
+
+    /// returns if the set reconciliation of the messages of a topic is
+    /// supported (see [`Capabilities::RECONCILIATION`])
+    #[inline]
+    pub fn supports_reconciliation(self) -> bool {
+        self.contains(Self::RECONCILIATION)
+    }
+
+    /// returns if exchanging only the new blocks of a passport is
+    /// supported (see [`Capabilities::PASSPORT_SYNC`])
+    #[inline]
+    pub fn supports_passport_sync(self) -> bool {
+        self.contains(Self::PASSPORT_SYNC)
+    }
+
+    /// returns if advertising several addresses is supported (see
+    /// [`Capabilities::PEER_ADDRESSES`])
+    #[inline]
+    pub fn supports_peer_addresses(self) -> bool {
+        self.contains(Self::PEER_ADDRESSES)
+    }
+
+    /// returns if being told a message was not accepted is supported
+    /// (see [`Capabilities::REJECTION`])
+    #[inline]
+    pub fn supports_rejection(self) -> bool {
+        self.contains(Self::REJECTION)
+    }
+
+    /// returns if receiving cover traffic is supported (see
+    /// [`Capabilities::COVER_TRAFFIC`])
+    #[inline]
+    pub fn supports_cover_traffic(self) -> bool {
+        self.contains(Self::COVER_TRAFFIC)
+    }
+
+    /// the capabilities as sent in the handshake
+    ///
+    /// the unknown capabilities are kept, they are removed when
+    /// negotiating with our own capabilities.
+    #[inline]
+    pub(crate) const fn from_bytes(bytes: [u8; Self::SIZE]) -> Self {
+        Self(u32::from_be_bytes(bytes))
+    }
+
+    #[inline]
+    pub(crate) const fn to_bytes(self) -> [u8; Self::SIZE] {
+        self.0.to_be_bytes()
+    }
+}
+
 impl Default for Version {
     fn default() -> Self {
         Self::CURRENT
@@ -83,6 +323,18 @@ impl fmt::Display for Version {
     }
 }
 
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the remote peer only supports the versions up to {}",
+            self.supported
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
+
 impl From<Version> for String {
     fn from(version: Version) -> Self {
         version.to_string()
@@ -120,6 +372,19 @@ mod tests {
         assert!(Version::CURRENT.is_supported())
     }
 
+    #[test]
+    fn capabilities_are_negotiated() {
+        let theirs = Capabilities::from_bytes([0xFF; Capabilities::SIZE]);
+        assert_eq!(Capabilities::ALL.intersection(theirs), Capabilities::ALL);
+        assert_eq!(
+            Capabilities::from_bytes(Capabilities::REJECTION.to_bytes()),
+            Capabilities::REJECTION
+        );
+
+        assert_eq!(Capabilities::of(Version::V1), Capabilities::NONE);
+        assert!(Capabilities::of(Version::V2).contains(Capabilities::ALL));
+    }
+
     #[test]
     fn parse_current_version() {
         let current = Version::CURRENT.0.to_string();
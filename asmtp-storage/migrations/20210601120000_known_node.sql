@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS known_node
+(
+    address     TEXT PRIMARY KEY NOT NULL,
+    key         BLOB             NOT NULL,
+    created_at  TEXT             NOT NULL DEFAULT (DATETIME('now')),
+    updated_at  TEXT             NOT NULL DEFAULT (DATETIME('now'))
+);
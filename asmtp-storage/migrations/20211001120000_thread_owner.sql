@@ -0,0 +1,5 @@
+ALTER TABLE thread ADD COLUMN owner TEXT;
+
+CREATE INDEX IF NOT EXISTS thread_owner ON thread (owner);
+
+CREATE INDEX IF NOT EXISTS message_thread ON message (thread, created_at);
@@ -0,0 +1,4 @@
+ALTER TABLE message ADD COLUMN hash BLOB;
+
+CREATE INDEX IF NOT EXISTS message_thread_hash ON message (thread, created_at, hash);
+CREATE INDEX IF NOT EXISTS message_hash ON message (thread, hash);
@@ -1,7 +1,9 @@
-use std::str::FromStr;
+use std::{convert::TryInto as _, str::FromStr};
 
 use anyhow::{bail, Context as _, Result};
+use futures::{Stream, StreamExt as _};
 use keynesis::{
+    hash::Blake2b,
     key::ed25519::PublicKey,
     passport::{block::Hash, PassportBlocks, PassportBlocksSlice},
 };
@@ -45,12 +47,44 @@ pub struct Message {
     pub read_at: Option<chrono::DateTime<chrono::Local>>,
 }
 
+/// a node we have connected to, identified by its address, and the
+/// public key it presented the first time (trust on first use)
+#[derive(sqlx::FromRow, Debug)]
+pub struct KnownNode {
+    pub address: String,
+    pub key: Vec<u8>,
+    pub created_at: chrono::DateTime<chrono::Local>,
+    pub updated_at: chrono::DateTime<chrono::Local>,
+}
+
 #[derive(sqlx::FromRow)]
 pub struct Thread {
     pub topic: Vec<u8>,
     pub created_at: chrono::DateTime<chrono::Local>,
 }
 
+/// how much the messages of one or several threads take in the database
+#[derive(sqlx::FromRow, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Usage {
+    /// the number of messages
+    pub messages: i64,
+    /// the size of the messages' content, in bytes
+    pub bytes: i64,
+}
+
+/// the size of the hash of a message's content (see [`message_hash`])
+pub const MESSAGE_HASH_SIZE: usize = 16;
+
+/// the hash the messages are indexed by in their thread
+///
+/// this is the same hash as the network's `MessageHash`, the messages of
+/// a thread are reconciled with the peers' on it.
+pub fn message_hash(content: impl AsRef<[u8]>) -> [u8; MESSAGE_HASH_SIZE] {
+    let mut hash = [0; MESSAGE_HASH_SIZE];
+    Blake2b::blake2b(&mut hash, content.as_ref(), &[]);
+    hash
+}
+
 pub enum StorageOptions {
     Sqlite { uri: String },
 }
@@ -79,7 +113,9 @@ impl Storage {
                     .await
                     .context("Failed to run migration script")?;
 
-                Ok(Self { backend })
+                let storage = Self { backend };
+                storage.hash_messages().await?;
+                Ok(storage)
             }
         }
     }
@@ -165,6 +201,14 @@ impl Storage {
         .context("Failed to list all passports")
     }
 
+    /// number of passports in the database
+    pub async fn number_passports(&self) -> Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM passport")
+            .fetch_one(&self.backend)
+            .await
+            .context("Failed to count the passports")
+    }
+
     pub async fn new_passport(&self, blocks: PassportBlocksSlice<'_>) -> Result<Hash> {
         let id = if let Some(block) = blocks.iter().next() {
             block.header().hash()
@@ -429,6 +473,14 @@ impl Storage {
         .context("Failed to list all threads")
     }
 
+    /// number of threads in the database
+    pub async fn number_threads(&self) -> Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM thread")
+            .fetch_one(&self.backend)
+            .await
+            .context("Failed to count the threads")
+    }
+
     pub async fn new_thread(&self, topic: &Topic) -> Result<()> {
         sqlx::query(
             r#"
@@ -443,6 +495,186 @@ impl Storage {
         .map(|_| ())
     }
 
+    /// create a new thread, keeping its messages on behalf of `owner`
+    ///
+    /// the owner is opaque to the storage, it is only used to account the
+    /// messages kept on behalf of the same owner (see [`Storage::owner_usage`]).
+    ///
+    /// a thread already kept is left unchanged: a thread shared by several
+    /// owners stays accounted to the owner it was created for, or to none if
+    /// it was created without one.
+    pub async fn new_owned_thread(&self, topic: &Topic, owner: &str) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO thread (topic, owner)
+            VALUES ( ?1, ?2 )
+            ON CONFLICT (topic) DO NOTHING
+            "#,
+        )
+        .bind(topic.as_ref())
+        .bind(owner)
+        .execute(&self.backend)
+        .await
+        .context("Failed to create new topic thread")
+        .map(|_| ())
+    }
+
+    /// the owner of the thread, if the thread exists and has one
+    pub async fn thread_owner(&self, topic: &Topic) -> Result<Option<String>> {
+        sqlx::query_scalar::<_, Option<String>>("SELECT owner FROM thread WHERE topic = ?1")
+            .bind(topic.as_ref())
+            .fetch_optional(&self.backend)
+            .await
+            .context("Failed to query the owner of the thread")
+            .map(Option::flatten)
+    }
+
+    /// all the owners of threads
+    pub async fn thread_owners(&self) -> Result<Vec<String>> {
+        sqlx::query_scalar("SELECT DISTINCT owner FROM thread WHERE owner IS NOT NULL")
+            .fetch_all(&self.backend)
+            .await
+            .context("Failed to list the owners of the threads")
+    }
+
+    /// the threads kept on behalf of the `owner`
+    pub async fn threads_of_owner(&self, owner: &str) -> Result<Vec<Thread>> {
+        sqlx::query_as(
+            r#"
+                SELECT topic, created_at
+                FROM thread
+                WHERE owner = ?1
+                ORDER BY created_at ASC NULLS LAST
+            "#,
+        )
+        .bind(owner)
+        .fetch_all(&self.backend)
+        .await
+        .context("Failed to list the threads of the owner")
+    }
+
+    /// the messages kept in the thread
+    pub async fn thread_usage(&self, topic: &Topic) -> Result<Usage> {
+        sqlx::query_as(
+            r#"
+                SELECT COUNT(*) AS messages, COALESCE(SUM(LENGTH(content)), 0) AS bytes
+                FROM message
+                WHERE thread = ?1
+            "#,
+        )
+        .bind(topic.as_ref())
+        .fetch_one(&self.backend)
+        .await
+        .context("Failed to query the usage of the thread")
+    }
+
+    /// the messages kept in all the threads of the `owner`
+    pub async fn owner_usage(&self, owner: &str) -> Result<Usage> {
+        sqlx::query_as(
+            r#"
+                SELECT COUNT(*) AS messages, COALESCE(SUM(LENGTH(content)), 0) AS bytes
+                FROM message
+                WHERE thread IN (SELECT topic FROM thread WHERE owner = ?1)
+            "#,
+        )
+        .bind(owner)
+        .fetch_one(&self.backend)
+        .await
+        .context("Failed to query the usage of the owner")
+    }
+
+    /// delete the messages of the thread received more than `max_age` ago
+    ///
+    /// returns the number of deleted messages
+    pub async fn delete_messages_older_than(
+        &self,
+        topic: &Topic,
+        max_age: std::time::Duration,
+    ) -> Result<u64> {
+        sqlx::query(
+            r#"
+            DELETE FROM message
+            WHERE thread = ?1 AND created_at < DATETIME('now', '-' || ?2 || ' seconds')
+            "#,
+        )
+        .bind(topic.as_ref())
+        .bind(max_age.as_secs() as i64)
+        .execute(&self.backend)
+        .await
+        .context("Failed to delete the old messages of the thread")
+        .map(|r| r.rows_affected())
+    }
+
+    /// delete the oldest messages of the thread so at most `max_messages`
+    /// messages and `max_bytes` bytes remain
+    ///
+    /// returns the number of deleted messages
+    pub async fn truncate_thread(
+        &self,
+        topic: &Topic,
+        max_messages: Option<i64>,
+        max_bytes: Option<i64>,
+    ) -> Result<u64> {
+        sqlx::query(
+            r#"
+            DELETE FROM message
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id,
+                        ROW_NUMBER() OVER newest AS position,
+                        SUM(LENGTH(content)) OVER newest AS total
+                    FROM message
+                    WHERE thread = ?1
+                    WINDOW newest AS (ORDER BY created_at DESC, id DESC)
+                )
+                WHERE position > ?2 OR total > ?3
+            )
+            "#,
+        )
+        .bind(topic.as_ref())
+        .bind(max_messages.unwrap_or(i64::MAX))
+        .bind(max_bytes.unwrap_or(i64::MAX))
+        .execute(&self.backend)
+        .await
+        .context("Failed to truncate the thread")
+        .map(|r| r.rows_affected())
+    }
+
+    /// delete the oldest messages of all the threads of the `owner` so at
+    /// most `max_messages` messages and `max_bytes` bytes remain
+    ///
+    /// returns the number of deleted messages
+    pub async fn truncate_owner(
+        &self,
+        owner: &str,
+        max_messages: Option<i64>,
+        max_bytes: Option<i64>,
+    ) -> Result<u64> {
+        sqlx::query(
+            r#"
+            DELETE FROM message
+            WHERE id IN (
+                SELECT id FROM (
+                    SELECT id,
+                        ROW_NUMBER() OVER newest AS position,
+                        SUM(LENGTH(content)) OVER newest AS total
+                    FROM message
+                    WHERE thread IN (SELECT topic FROM thread WHERE owner = ?1)
+                    WINDOW newest AS (ORDER BY created_at DESC, id DESC)
+                )
+                WHERE position > ?2 OR total > ?3
+            )
+            "#,
+        )
+        .bind(owner)
+        .bind(max_messages.unwrap_or(i64::MAX))
+        .bind(max_bytes.unwrap_or(i64::MAX))
+        .execute(&self.backend)
+        .await
+        .context("Failed to truncate the threads of the owner")
+        .map(|r| r.rows_affected())
+    }
+
     pub async fn delete_thread(&self, topic: &Topic) -> Result<()> {
         sqlx::query(
             r#"
@@ -478,7 +710,7 @@ impl Storage {
             r#"
                 SELECT thread, content, created_at, read_at
                 FROM message
-                ORDER BY created_at ASC
+                ORDER BY created_at ASC, id ASC
             "#,
         )
         .fetch_all(&self.backend)
@@ -492,18 +724,132 @@ impl Storage {
     {
         sqlx::query(
             r#"
-            INSERT INTO message (thread, content)
-            VALUES ( ?1, ?2 )
+            INSERT INTO message (thread, content, hash)
+            VALUES ( ?1, ?2, ?3 )
             "#,
         )
         .bind(thread.as_ref())
         .bind(message.as_ref())
+        .bind(message_hash(&message).to_vec())
         .execute(&self.backend)
         .await
         .context("Failed to store message")
         .map(|p| p.last_insert_rowid())
     }
 
+    /// hash the messages stored before the messages were indexed by
+    /// their hash (see [`message_hash`])
+    async fn hash_messages(&self) -> Result<()> {
+        let unhashed: Vec<(i64, Vec<u8>)> = sqlx::query_as(
+            r#"
+                SELECT id, content
+                FROM message
+                WHERE hash IS NULL
+            "#,
+        )
+        .fetch_all(&self.backend)
+        .await
+        .context("Failed to list the messages without hash")?;
+
+        for (id, content) in unhashed {
+            sqlx::query(
+                r#"
+                UPDATE message
+                SET hash = ?2
+                WHERE id = ?1"#,
+            )
+            .bind(id)
+            .bind(message_hash(&content).to_vec())
+            .execute(&self.backend)
+            .await
+            .context("Failed to set the hash of the message")?;
+        }
+
+        Ok(())
+    }
+
+    /// the thread has a message with the given content's hash (see
+    /// [`message_hash`])
+    pub async fn contains_message(
+        &self,
+        thread: &Topic,
+        hash: &[u8; MESSAGE_HASH_SIZE],
+    ) -> Result<bool> {
+        let opt = sqlx::query(
+            r#"
+                SELECT id
+                FROM message
+                WHERE thread = ?1 AND hash = ?2
+                LIMIT 1
+            "#,
+        )
+        .bind(thread.as_ref())
+        .bind(hash.as_ref())
+        .fetch_optional(&self.backend)
+        .await
+        .context("Failed to look up the message by hash")?;
+
+        Ok(opt.is_some())
+    }
+
+    /// the hashes of the messages of the thread stored since the given
+    /// time, without loading their content
+    pub async fn message_hashes_of_thread_since(
+        &self,
+        thread: &Topic,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Vec<[u8; MESSAGE_HASH_SIZE]>> {
+        let hashes: Vec<(Vec<u8>,)> = sqlx::query_as(
+            r#"
+                SELECT hash
+                FROM message
+                WHERE thread = ?1 AND created_at >= ?2 AND hash IS NOT NULL
+            "#,
+        )
+        .bind(thread.as_ref())
+        .bind(since)
+        .fetch_all(&self.backend)
+        .await
+        .context("Failed to list the hashes of the messages of the thread")?;
+
+        hashes
+            .into_iter()
+            .map(|(hash,)| {
+                hash.as_slice()
+                    .try_into()
+                    .context("Invalid hash of message")
+            })
+            .collect()
+    }
+
+    /// the content of the messages of the thread with the given hashes
+    ///
+    /// the hashes of no message of the thread are skipped.
+    pub async fn messages_of_thread_by_hash(
+        &self,
+        thread: &Topic,
+        hashes: &[[u8; MESSAGE_HASH_SIZE]],
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut contents = Vec::with_capacity(hashes.len());
+        for hash in hashes {
+            let content: Option<(Vec<u8>,)> = sqlx::query_as(
+                r#"
+                    SELECT content
+                    FROM message
+                    WHERE thread = ?1 AND hash = ?2
+                    LIMIT 1
+                "#,
+            )
+            .bind(thread.as_ref())
+            .bind(hash.as_ref())
+            .fetch_optional(&self.backend)
+            .await
+            .context("Failed to get the message by hash")?;
+            contents.extend(content.map(|(content,)| content));
+        }
+        Ok(contents)
+    }
+
     pub async fn mark_message_read(&self, id: i64) -> Result<()> {
         sqlx::query(
             r#"
@@ -547,7 +893,7 @@ impl Storage {
                 SELECT id, thread, content, created_at, read_at
                 FROM message
                 WHERE thread = ?1
-                ORDER BY created_at ASC
+                ORDER BY created_at ASC, id ASC
             "#,
         )
         .bind(id.as_ref())
@@ -566,7 +912,7 @@ impl Storage {
                 SELECT id, thread, content, created_at, read_at
                 FROM message
                 WHERE thread = ?1 AND created_at > ?2
-                ORDER BY created_at ASC
+                ORDER BY created_at ASC, id ASC
             "#,
         )
         .bind(id.as_ref())
@@ -576,6 +922,36 @@ impl Storage {
         .context("Failed to list all messages for topic")
     }
 
+    /// stream the messages of the thread received in the given time range
+    /// (`since` included, `until` excluded) by order of arrival
+    ///
+    /// if `after` is set, only the messages stored after the message with
+    /// this [`Message::id`] are returned. The messages are fetched from the
+    /// database as the stream is polled
+    pub fn messages_of_thread_between(
+        &self,
+        id: &Topic,
+        since: chrono::DateTime<chrono::Utc>,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+        after: Option<i64>,
+    ) -> impl Stream<Item = Result<Message>> + '_ {
+        sqlx::query_as(
+            r#"
+                SELECT id, thread, content, created_at, read_at
+                FROM message
+                WHERE thread = ?1 AND created_at >= ?2 AND (?3 IS NULL OR created_at < ?3)
+                    AND (?4 IS NULL OR id > ?4)
+                ORDER BY id ASC
+            "#,
+        )
+        .bind(id.as_ref().to_vec())
+        .bind(since)
+        .bind(until)
+        .bind(after)
+        .fetch(&self.backend)
+        .map(|r| r.context("Failed to list the messages for topic"))
+    }
+
     pub async fn messages_of_key(&self, key: &PublicKey) -> Result<Vec<Message>> {
         sqlx::query_as(
             r#"
@@ -591,6 +967,47 @@ impl Storage {
         .await
         .context("Failed to list all messages for topic")
     }
+
+    /// get the public key pinned for the node at the given address
+    pub async fn known_node<A>(&self, address: A) -> Result<Option<KnownNode>>
+    where
+        A: AsRef<str>,
+    {
+        sqlx::query_as(
+            r#"
+                SELECT address, key, created_at, updated_at
+                FROM known_node
+                WHERE address = ?1
+            "#,
+        )
+        .bind(address.as_ref())
+        .fetch_optional(&self.backend)
+        .await
+        .context("Failed to find the known node")
+    }
+
+    /// pin the public key of the node at the given address, replacing
+    /// the previously pinned key if any
+    pub async fn pin_known_node<A>(&self, address: A, key: &PublicKey) -> Result<()>
+    where
+        A: AsRef<str>,
+    {
+        sqlx::query(
+            r#"
+            INSERT INTO known_node (address, key)
+            VALUES ( ?1, ?2 )
+            ON CONFLICT (address) DO UPDATE
+            SET key        = excluded.key,
+                updated_at = DATETIME('now')
+            "#,
+        )
+        .bind(address.as_ref())
+        .bind(key.as_ref())
+        .execute(&self.backend)
+        .await
+        .context("Failed to pin the known node's key")
+        .map(|_| ())
+    }
 }
 
 #[cfg(test)]
@@ -723,6 +1140,156 @@ mod tests {
         assert!(list[0].name == "Alice");
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn known_nodes() {
+        let mut rng = Seed::from([0; Seed::SIZE]).into_rand_chacha();
+        let key1 = SecretKey::new(&mut rng).public_key();
+        let key2 = SecretKey::new(&mut rng).public_key();
+
+        let storage = Storage::new(StorageOptions::Sqlite {
+            uri: ":memory:".to_owned(),
+        })
+        .await
+        .expect("Create the storage");
+
+        let address = "tcp://[::1]:9800";
+        assert!(storage.known_node(address).await.unwrap().is_none());
+
+        storage.pin_known_node(address, &key1).await.unwrap();
+        let node = storage.known_node(address).await.unwrap().unwrap();
+        assert_eq!(node.key.as_slice(), key1.as_ref());
+
+        storage.pin_known_node(address, &key2).await.unwrap();
+        let node = storage.known_node(address).await.unwrap().unwrap();
+        assert_eq!(node.key.as_slice(), key2.as_ref());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn thread_retention() {
+        let storage = Storage::new(StorageOptions::Sqlite {
+            uri: ":memory:".to_owned(),
+        })
+        .await
+        .expect("Create the storage");
+
+        let a = Topic::new([1; Topic::SIZE]);
+        let b = Topic::new([2; Topic::SIZE]);
+        let c = Topic::new([3; Topic::SIZE]);
+        storage.new_owned_thread(&a, "alice").await.unwrap();
+        storage.new_owned_thread(&b, "alice").await.unwrap();
+        storage.new_thread(&c).await.unwrap();
+        storage.new_owned_thread(&a, "bob").await.unwrap();
+        storage.new_owned_thread(&c, "bob").await.unwrap();
+        assert_eq!(storage.thread_owner(&a).await.unwrap().unwrap(), "alice");
+        assert!(storage.thread_owner(&c).await.unwrap().is_none());
+        assert_eq!(storage.thread_owners().await.unwrap(), vec!["alice"]);
+        assert_eq!(storage.threads_of_owner("alice").await.unwrap().len(), 2);
+
+        for topic in [a, b, c].iter() {
+            for content in [b"1".as_ref(), b"22", b"333"].iter() {
+                storage.new_message(topic, content).await.unwrap();
+            }
+        }
+        let usage = Usage {
+            messages: 3,
+            bytes: 6,
+        };
+        assert_eq!(storage.thread_usage(&a).await.unwrap(), usage);
+        let usage = Usage {
+            messages: 6,
+            bytes: 12,
+        };
+        assert_eq!(storage.owner_usage("alice").await.unwrap(), usage);
+
+        // the oldest messages are deleted first
+        assert_eq!(storage.truncate_thread(&c, Some(2), None).await.unwrap(), 1);
+        let messages = storage.messages_of_thread(&c).await.unwrap();
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_slice()).collect();
+        assert_eq!(contents, vec![b"22".as_ref(), b"333"]);
+        assert_eq!(storage.truncate_thread(&c, None, Some(3)).await.unwrap(), 1);
+
+        // the messages of all the threads of the owner are accounted together
+        assert_eq!(
+            storage
+                .truncate_owner("alice", None, Some(6))
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(storage.thread_usage(&a).await.unwrap(), Usage::default());
+        assert_eq!(storage.thread_usage(&b).await.unwrap().bytes, 6);
+
+        sqlx::query(
+            "UPDATE message SET created_at = DATETIME('now', '-2 days') WHERE content = ?1",
+        )
+        .bind(b"22".as_ref())
+        .execute(&storage.backend)
+        .await
+        .unwrap();
+        let day = std::time::Duration::from_secs(24 * 3600);
+        assert_eq!(
+            storage.delete_messages_older_than(&b, day).await.unwrap(),
+            1
+        );
+        let messages = storage.messages_of_thread(&b).await.unwrap();
+        let contents: Vec<_> = messages.iter().map(|m| m.content.as_slice()).collect();
+        assert_eq!(contents, vec![b"1".as_ref(), b"333"]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn messages_by_hash() {
+        let storage = Storage::new(StorageOptions::Sqlite {
+            uri: ":memory:".to_owned(),
+        })
+        .await
+        .expect("Create the storage");
+
+        let a = Topic::new([1; Topic::SIZE]);
+        let b = Topic::new([2; Topic::SIZE]);
+        storage.new_thread(&a).await.unwrap();
+        storage.new_thread(&b).await.unwrap();
+        storage.new_message(&a, b"1").await.unwrap();
+        storage.new_message(&a, b"2").await.unwrap();
+        storage.new_message(&b, b"3").await.unwrap();
+
+        assert!(storage
+            .contains_message(&a, &message_hash(b"1"))
+            .await
+            .unwrap());
+        assert!(!storage
+            .contains_message(&a, &message_hash(b"3"))
+            .await
+            .unwrap());
+
+        let since = chrono::Utc::now() - chrono::Duration::days(1);
+        let mut hashes = storage
+            .message_hashes_of_thread_since(&a, since)
+            .await
+            .unwrap();
+        hashes.sort_unstable();
+        let mut expected = vec![message_hash(b"1"), message_hash(b"2")];
+        expected.sort_unstable();
+        assert_eq!(hashes, expected);
+
+        // the hashes of the messages of other threads are skipped
+        let contents = storage
+            .messages_of_thread_by_hash(&a, &[message_hash(b"2"), message_hash(b"3")])
+            .await
+            .unwrap();
+        assert_eq!(contents, vec![b"2".to_vec()]);
+
+        // the messages stored before they were hashed are hashed on opening
+        sqlx::query("UPDATE message SET hash = NULL")
+            .execute(&storage.backend)
+            .await
+            .unwrap();
+        storage.hash_messages().await.unwrap();
+        assert!(storage
+            .contains_message(&b, &message_hash(b"3"))
+            .await
+            .unwrap());
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn passport() {
         let mut sims = Sims::new();
@@ -762,6 +1329,7 @@ mod tests {
         assert!(passports.len() == 2);
         assert!(passports[0].id.as_slice() == alice_id.as_ref());
         assert!(passports[1].id.as_slice() == bob_id.as_ref());
+        assert_eq!(storage.number_passports().await.unwrap(), 2);
 
         storage
             .delete_passport(&bob_id)
@@ -0,0 +1,76 @@
+use crate::admin::{cookie_path, rpc};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use futures::prelude::*;
+use serde_json::{json, Value};
+use std::path::Path;
+use tokio::net::UnixStream;
+use tokio_util::codec::{Framed, LinesCodec};
+
+/// a client of the admin API of a local node
+pub struct Client {
+    lines: Framed<UnixStream, LinesCodec>,
+    next_id: u64,
+}
+
+impl Client {
+    /// connect to the admin API listening on `socket`
+    ///
+    /// the client authenticates with the cookie next to the socket.
+    pub async fn connect(socket: impl AsRef<Path>) -> Result<Self> {
+        let socket = socket.as_ref();
+        let cookie_path = cookie_path(socket);
+        let cookie = std::fs::read_to_string(&cookie_path)
+            .with_context(|| format!("Cannot read the admin cookie: {}", cookie_path.display()))?;
+
+        let stream = UnixStream::connect(socket)
+            .await
+            .with_context(|| format!("Cannot connect to the admin socket: {}", socket.display()))?;
+        let mut client = Self {
+            lines: Framed::new(
+                stream,
+                LinesCodec::new_with_max_length(super::MAX_LINE_LENGTH),
+            ),
+            next_id: 0,
+        };
+
+        client
+            .call("authenticate", json!({ "cookie": cookie.trim() }))
+            .await
+            .context("Cannot authenticate with the node")?;
+
+        Ok(client)
+    }
+
+    /// call the `method` of the admin API with the given `params`
+    pub async fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let request = serde_json::to_string(&rpc::Request::new(id, method, params))?;
+        self.lines
+            .send(request)
+            .await
+            .context("Cannot send the admin request")?;
+
+        let response = self
+            .lines
+            .next()
+            .await
+            .ok_or_else(|| anyhow!("The node closed the admin connection"))?
+            .context("Cannot read the admin response")?;
+        let response: rpc::Response =
+            serde_json::from_str(&response).context("Invalid admin response")?;
+        ensure!(
+            response.id == id,
+            "Unexpected response to request {}",
+            response.id
+        );
+
+        match (response.result, response.error) {
+            (_, Some(error)) => bail!(error),
+            (Some(result), None) => Ok(result),
+            // a `null` result
+            (None, None) => Ok(Value::Null),
+        }
+    }
+}
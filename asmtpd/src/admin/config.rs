@@ -0,0 +1,18 @@
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// the local control API of the node
+#[derive(Debug, PartialEq, Eq, Hash, Clone, StructOpt, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// path of the unix socket to listen for admin requests on
+    ///
+    /// the requests are authenticated with the cookie the node writes
+    /// next to the socket (with the `.cookie` extension) when it starts.
+    /// If not set, or not on a unix platform, the node does not accept
+    /// admin requests.
+    #[structopt(long = "admin-socket")]
+    #[serde(default)]
+    pub socket: Option<PathBuf>,
+}
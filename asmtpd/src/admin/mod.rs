@@ -0,0 +1,46 @@
+/*!
+local control API of the node
+
+The node listens for [JSON-RPC 2.0] requests on a unix socket (see
+[`Config::socket`]), one request per line. The API is only available on
+unix platforms. Only the local users who can read
+the cookie the node writes next to the socket when it starts can use the
+API: the first request of a connection must be `authenticate` with the
+content of the cookie file.
+
+| method        | parameters                            | result                         |
+|---------------|---------------------------------------|--------------------------------|
+| `authenticate`| `{ "cookie": string }`                | `true`                         |
+| `connections` |                                       | the opened connections         |
+| `topology`    |                                       | the peers of the topology      |
+| `topics`      |                                       | the topics the node keeps      |
+| `users`       |                                       | the users of the node          |
+| `gossip`      |                                       | `null`                         |
+| `ban`         | `{ "id": string, "duration": secs? }` | `null`                         |
+| `unban`       | `{ "id": string }`                    | `false` if the peer was not banned |
+| `log_level`   | `{ "level": string }`                 | `null`                         |
+
+The `level` of `log_level` is one of `off`, `error`, `warn`, `info`, `debug`
+or `trace`.
+
+[JSON-RPC 2.0]: https://www.jsonrpc.org/specification
+*/
+
+#[cfg(unix)]
+mod client;
+mod config;
+#[cfg(unix)]
+pub mod rpc;
+#[cfg(unix)]
+mod server;
+
+pub use self::config::Config;
+#[cfg(unix)]
+pub use self::{
+    client::Client,
+    server::{cookie_path, Admin, ReloadLogFilter},
+};
+
+/// maximum size of a request or a response (in bytes)
+#[cfg(unix)]
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
@@ -0,0 +1,91 @@
+/*!
+the JSON-RPC 2.0 messages of the admin API
+
+one request or response per line.
+*/
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{self, Formatter};
+
+pub const VERSION: &str = "2.0";
+
+/// the request could not be parsed
+pub const PARSE_ERROR: i64 = -32700;
+/// the method does not exist
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// the parameters of the method are invalid
+pub const INVALID_PARAMS: i64 = -32602;
+/// the node failed to process the request
+pub const INTERNAL_ERROR: i64 = -32603;
+/// the connection is not authenticated (see `authenticate`)
+pub const UNAUTHORIZED: i64 = -32001;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Request {
+    pub jsonrpc: String,
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub jsonrpc: String,
+    pub id: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<Error>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Error {
+    pub code: i64,
+    pub message: String,
+}
+
+impl Request {
+    pub fn new(id: u64, method: impl Into<String>, params: Value) -> Self {
+        Self {
+            jsonrpc: VERSION.to_owned(),
+            id: id.into(),
+            method: method.into(),
+            params,
+        }
+    }
+}
+
+impl Response {
+    pub fn new(id: Value, result: Result<Value, Error>) -> Self {
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+
+        Self {
+            jsonrpc: VERSION.to_owned(),
+            id,
+            result,
+            error,
+        }
+    }
+}
+
+impl Error {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (code {})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for Error {}
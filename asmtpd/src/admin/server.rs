@@ -0,0 +1,386 @@
+use super::{rpc, MAX_LINE_LENGTH};
+use crate::{network::Control, storage::Storage};
+use anyhow::{Context as _, Result};
+use futures::prelude::*;
+use keynesis::key::ed25519::PublicKey;
+use rand::{rngs::OsRng, RngCore as _};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    io::Write as _,
+    os::unix::fs::{OpenOptionsExt as _, PermissionsExt as _},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{UnixListener, UnixStream},
+    task::JoinHandle,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+use tracing_subscriber::filter::LevelFilter;
+
+/// change the log level of the running node
+pub type ReloadLogFilter = Box<dyn Fn(LevelFilter) -> Result<()> + Send + Sync>;
+
+/// the admin API server, see the [module documentation](super)
+pub struct Admin {
+    socket: PathBuf,
+    cookie: PathBuf,
+    handle: JoinHandle<()>,
+}
+
+struct State {
+    cookie: String,
+    control: Control,
+    storage: Storage,
+    log_filter: ReloadLogFilter,
+}
+
+#[derive(Deserialize)]
+struct Authenticate {
+    cookie: String,
+}
+
+#[derive(Deserialize)]
+struct Ban {
+    id: String,
+    #[serde(default)]
+    duration: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Unban {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct LogLevel {
+    level: String,
+}
+
+/// the path of the cookie of the admin API listening on `socket`
+pub fn cookie_path(socket: &Path) -> PathBuf {
+    socket.with_extension("cookie")
+}
+
+impl Admin {
+    /// listen for admin requests on the `socket`
+    ///
+    /// a new cookie is written next to the socket.
+    pub async fn start(
+        socket: &Path,
+        control: Control,
+        storage: Storage,
+        log_filter: ReloadLogFilter,
+    ) -> Result<Self> {
+        let mut bytes = [0; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let cookie = hex::encode(bytes);
+
+        let cookie_path = cookie_path(socket);
+        // the permissions are only set when the file is created
+        let _ = std::fs::remove_file(&cookie_path);
+        std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&cookie_path)
+            .and_then(|mut file| file.write_all(cookie.as_bytes()))
+            .with_context(|| format!("Cannot write the admin cookie: {}", cookie_path.display()))?;
+
+        // remove the socket of a previous run
+        let _ = std::fs::remove_file(socket);
+        let listener = UnixListener::bind(socket)
+            .with_context(|| format!("Cannot listen on the admin socket: {}", socket.display()))?;
+        std::fs::set_permissions(socket, std::fs::Permissions::from_mode(0o600))
+            .with_context(|| format!("Cannot restrict the admin socket: {}", socket.display()))?;
+
+        tracing::info!(socket = %socket.display(), "listening for admin requests");
+
+        let state = Arc::new(State {
+            cookie,
+            control,
+            storage,
+            log_filter,
+        });
+        let handle = tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let state = Arc::clone(&state);
+                        tokio::spawn(async move {
+                            if let Err(error) = state.serve(stream).await {
+                                tracing::warn!(reason = ?error, "admin connection failed");
+                            }
+                        });
+                    }
+                    Err(error) => {
+                        tracing::error!(reason = ?error, "Cannot accept admin connection");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            socket: socket.to_owned(),
+            cookie: cookie_path,
+            handle,
+        })
+    }
+
+    /// stop listening for admin requests, the socket and the cookie are
+    /// removed
+    pub fn shutdown(self) {
+        self.handle.abort();
+        let _ = std::fs::remove_file(&self.socket);
+        let _ = std::fs::remove_file(&self.cookie);
+    }
+}
+
+impl State {
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let mut lines = Framed::new(stream, LinesCodec::new_with_max_length(MAX_LINE_LENGTH));
+        let mut authenticated = false;
+
+        while let Some(line) = lines.next().await {
+            let line = line.context("Cannot read the admin request")?;
+
+            let response = match serde_json::from_str::<rpc::Request>(&line) {
+                Ok(request) => {
+                    tracing::debug!(method = %request.method, "admin request");
+                    let result = self
+                        .handle(&mut authenticated, &request.method, request.params)
+                        .await;
+                    rpc::Response::new(request.id, result)
+                }
+                Err(error) => rpc::Response::new(
+                    Value::Null,
+                    Err(rpc::Error::new(rpc::PARSE_ERROR, error.to_string())),
+                ),
+            };
+
+            let response = serde_json::to_string(&response)?;
+            lines
+                .send(response)
+                .await
+                .context("Cannot send the admin response")?;
+        }
+
+        Ok(())
+    }
+
+    async fn handle(
+        &self,
+        authenticated: &mut bool,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, rpc::Error> {
+        if method == "authenticate" {
+            let Authenticate { cookie } = parse(params)?;
+            return if constant_time_eq(cookie.as_bytes(), self.cookie.as_bytes()) {
+                *authenticated = true;
+                Ok(Value::Bool(true))
+            } else {
+                Err(rpc::Error::new(rpc::UNAUTHORIZED, "Invalid cookie"))
+            };
+        }
+
+        if !*authenticated {
+            return Err(rpc::Error::new(rpc::UNAUTHORIZED, "Not authenticated"));
+        }
+
+        match method {
+            "connections" => {
+                let connections = self.control.connections().await.map_err(internal)?;
+                Ok(connections
+                    .into_iter()
+                    .map(|connection| {
+                        let received = connection.received.snapshot();
+                        let sent = connection.sent.snapshot();
+                        json!({
+                            "id": connection.id.to_string(),
+                            "remote_address": connection.remote_address.to_string(),
+                            "inbound": connection.inbound,
+                            "version": connection.version.to_string(),
+                            "received": {
+                                "frames": received.frames,
+                                "bytes": received.bytes,
+                                "errors": received.errors,
+                            },
+                            "sent": {
+                                "frames": sent.frames,
+                                "bytes": sent.bytes,
+                                "errors": sent.errors,
+                            },
+                        })
+                    })
+                    .collect())
+            }
+            "topology" => Ok(self
+                .control
+                .view()
+                .into_iter()
+                .map(|profile| {
+                    json!({
+                        "id": profile.id().to_string(),
+                        "address": profile.address().to_string(),
+                        "last_update": u32::from(profile.last_update()),
+                    })
+                })
+                .collect()),
+            "topics" => {
+                let topics = self.storage.topic_subscriptions().await.map_err(internal)?;
+                Ok(topics.iter().map(|topic| topic.to_string()).collect())
+            }
+            "users" => Ok(self
+                .storage
+                .users()
+                .iter()
+                .map(|user| user.to_string())
+                .collect()),
+            "gossip" => {
+                self.control.gossip().await.map_err(internal)?;
+                Ok(Value::Null)
+            }
+            "ban" => {
+                let Ban { id, duration } = parse(params)?;
+                let id = parse_id(&id)?;
+                self.control
+                    .ban(id, duration.map(Duration::from_secs))
+                    .await
+                    .map_err(internal)?;
+                Ok(Value::Null)
+            }
+            "unban" => {
+                let Unban { id } = parse(params)?;
+                let id = parse_id(&id)?;
+                Ok(Value::Bool(self.control.unban(&id)))
+            }
+            "log_level" => {
+                let LogLevel { level } = parse(params)?;
+                let level: LevelFilter = level.parse().map_err(|_| {
+                    rpc::Error::new(rpc::INVALID_PARAMS, format!("Invalid log level: {}", level))
+                })?;
+                tracing::info!(%level, "changing the log level");
+                (self.log_filter)(level).map_err(internal)?;
+                Ok(Value::Null)
+            }
+            method => Err(rpc::Error::new(
+                rpc::METHOD_NOT_FOUND,
+                format!("Unknown method: {}", method),
+            )),
+        }
+    }
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, rpc::Error> {
+    serde_json::from_value(params)
+        .map_err(|error| rpc::Error::new(rpc::INVALID_PARAMS, error.to_string()))
+}
+
+fn parse_id(id: &str) -> Result<PublicKey, rpc::Error> {
+    id.parse().map_err(|error| {
+        rpc::Error::new(
+            rpc::INVALID_PARAMS,
+            format!("Invalid public identity: {}", error),
+        )
+    })
+}
+
+fn internal(error: anyhow::Error) -> rpc::Error {
+    rpc::Error::new(rpc::INTERNAL_ERROR, format!("{:#}", error))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{admin::Client, simulation::Simulation};
+    use std::sync::Mutex;
+
+    #[tokio::test]
+    async fn requests_are_authenticated() {
+        let simulation = Simulation::builder(2).build().await.unwrap();
+        simulation.advance(Duration::from_millis(300)).await;
+        let node = simulation.node(0);
+
+        let directory = tempfile::tempdir().unwrap();
+        let socket = directory.path().join("admin.sock");
+
+        let filters = Arc::new(Mutex::new(Vec::new()));
+        let log_filter = {
+            let filters = Arc::clone(&filters);
+            Box::new(move |level: LevelFilter| {
+                filters.lock().unwrap().push(level);
+                Ok(())
+            })
+        };
+        let admin = Admin::start(
+            &socket,
+            node.network().control().clone(),
+            node.storage().clone(),
+            log_filter,
+        )
+        .await
+        .unwrap();
+
+        // without the cookie
+        let stream = UnixStream::connect(&socket).await.unwrap();
+        let mut lines = Framed::new(stream, LinesCodec::new());
+        let request = rpc::Request::new(0, "connections", Value::Null);
+        lines
+            .send(serde_json::to_string(&request).unwrap())
+            .await
+            .unwrap();
+        let response = lines.next().await.unwrap().unwrap();
+        let response: rpc::Response = serde_json::from_str(&response).unwrap();
+        assert_eq!(response.error.unwrap().code, rpc::UNAUTHORIZED);
+
+        let mut client = Client::connect(&socket).await.unwrap();
+
+        let connections = client.call("connections", Value::Null).await.unwrap();
+        let connections = connections.as_array().unwrap();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0]["id"], simulation.node(1).id().to_string());
+
+        let topology = client.call("topology", Value::Null).await.unwrap();
+        assert_eq!(topology[0]["id"], simulation.node(1).id().to_string());
+
+        let error = client.call("unknown", Value::Null).await.unwrap_err();
+        assert_eq!(
+            error.downcast::<rpc::Error>().unwrap().code,
+            rpc::METHOD_NOT_FOUND
+        );
+
+        let id = simulation.node(1).id().to_string();
+        client
+            .call("ban", json!({ "id": id, "duration": 60 }))
+            .await
+            .unwrap();
+        assert_eq!(
+            client.call("unban", json!({ "id": id })).await.unwrap(),
+            Value::Bool(true)
+        );
+
+        client
+            .call("log_level", json!({ "level": "debug" }))
+            .await
+            .unwrap();
+        assert!(client
+            .call("log_level", json!({ "level": "verbose" }))
+            .await
+            .is_err());
+        assert_eq!(*filters.lock().unwrap(), vec![LevelFilter::DEBUG]);
+
+        admin.shutdown();
+        assert!(!socket.exists());
+        assert!(!cookie_path(&socket).exists());
+
+        simulation.shutdown().await.unwrap();
+    }
+}
@@ -1,8 +1,12 @@
 use anyhow::{Context as _, Result};
 use asmtp_lib::Entropy;
+#[cfg(unix)]
+use asmtpd::admin;
 use asmtpd::{secret::Secret, Config};
 use keynesis::{key::ed25519, Seed};
 use poldercast::{Gossip, Subscriptions};
+#[cfg(unix)]
+use serde_json::json;
 use std::path::PathBuf;
 use structopt::StructOpt;
 use tracing::Level;
@@ -51,6 +55,58 @@ enum Command {
         #[structopt(long = "config")]
         config: PathBuf,
     },
+
+    /// control a running node with its admin API
+    #[cfg(unix)]
+    Admin {
+        /// path of the admin socket of the node
+        #[structopt(long = "socket")]
+        socket: PathBuf,
+
+        #[structopt(subcommand)]
+        cmd: AdminCommand,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Debug, StructOpt)]
+enum AdminCommand {
+    /// list the opened connections
+    Connections,
+
+    /// list the peers of the topology
+    Topology,
+
+    /// list the topics the node keeps
+    Topics,
+
+    /// list the users of the node
+    Users,
+
+    /// gossip with the peers now
+    Gossip,
+
+    /// stop talking to a peer
+    Ban {
+        /// the public identity of the peer
+        id: ed25519::PublicKey,
+
+        /// for how long (in seconds), forever if not set
+        #[structopt(long = "duration")]
+        duration: Option<u64>,
+    },
+
+    /// lift the ban of a peer
+    Unban {
+        /// the public identity of the peer
+        id: ed25519::PublicKey,
+    },
+
+    /// change the log level of the node
+    LogLevel {
+        /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+        level: String,
+    },
 }
 
 #[tokio::main]
@@ -72,6 +128,10 @@ async fn main() {
         Command::MakeGossip { password, config } => make_gossip(password, config)
             .await
             .context("Cannot make gossip"),
+        #[cfg(unix)]
+        Command::Admin { socket, cmd } => admin(socket, cmd)
+            .await
+            .context("Cannot perform the admin request"),
     };
 
     if let Err(error) = result {
@@ -85,6 +145,29 @@ async fn default_config() -> Result<()> {
     Ok(())
 }
 
+#[cfg(unix)]
+async fn admin(socket: PathBuf, cmd: AdminCommand) -> Result<()> {
+    let (method, params) = match cmd {
+        AdminCommand::Connections => ("connections", json!(null)),
+        AdminCommand::Topology => ("topology", json!(null)),
+        AdminCommand::Topics => ("topics", json!(null)),
+        AdminCommand::Users => ("users", json!(null)),
+        AdminCommand::Gossip => ("gossip", json!(null)),
+        AdminCommand::Ban { id, duration } => {
+            ("ban", json!({ "id": id.to_string(), "duration": duration }))
+        }
+        AdminCommand::Unban { id } => ("unban", json!({ "id": id.to_string() })),
+        AdminCommand::LogLevel { level } => ("log_level", json!({ "level": level })),
+    };
+
+    let mut client = admin::Client::connect(socket).await?;
+    let result = client.call(method, params).await?;
+
+    println!("{}", serde_json::to_string_pretty(&result)?);
+
+    Ok(())
+}
+
 async fn make_gossip(password: Option<String>, config: PathBuf) -> Result<()> {
     let mut config = Config::from_file(config)?;
 
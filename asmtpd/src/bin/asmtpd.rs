@@ -1,18 +1,29 @@
 use anyhow::Context as _;
-use asmtpd::{network::Network, secret::Secret, storage::Storage, Config};
+#[cfg(unix)]
+use asmtpd::admin::Admin;
+use asmtpd::{
+    metrics::Exporter,
+    network::{Control, Network},
+    secret::Secret,
+    storage::{Compaction, Settings, Storage},
+    Config,
+};
 use std::path::PathBuf;
 use structopt::StructOpt;
 use tracing::Level;
-use tracing_subscriber::FmtSubscriber;
+use tracing_subscriber::{filter::LevelFilter, fmt, prelude::*, reload, Registry};
+
+type LogFilter = reload::Handle<LevelFilter, Registry>;
 
 #[derive(StructOpt, Debug)]
 struct Args {
     /// set log levels
     ///
     /// useful for trying to debug some operations happening
-    /// while executing some of the commands
-    #[structopt(long = "log-level", default_value = "info", global = true)]
-    log_level: Level,
+    /// while executing some of the commands. Defaults to the `log_level`
+    /// of the configuration file, or `info`.
+    #[structopt(long = "log-level", global = true)]
+    log_level: Option<Level>,
 
     /// path of the configuration file of the server
     #[structopt(long = "config")]
@@ -34,36 +45,106 @@ async fn main() {
 async fn main_run() -> anyhow::Result<()> {
     let args = Args::from_args();
 
-    // a builder for `FmtSubscriber`.
-    let subscriber = FmtSubscriber::builder()
-        // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
-        .with_max_level(args.log_level)
-        // completes the builder.
-        .finish();
+    let mut config = Config::from_file(&args.config).context("cannot load initial settings")?;
+    config.secret.password = args.password.clone();
+
+    // all spans/events with a level higher than the given log level (e.g, debug,
+    // info, warn, etc.) will be written to stdout. The level can be changed with
+    // the admin API or by reloading the configuration.
+    let level = match (args.log_level, config.log_level) {
+        (Some(level), _) => LevelFilter::from_level(level),
+        (None, Some(level)) => level.0,
+        (None, None) => LevelFilter::INFO,
+    };
+    let (filter, log_filter) = reload::Layer::new(level);
+    let subscriber = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer());
 
     tracing::subscriber::set_global_default(subscriber)
         .context("setting default subscriber failed")?;
 
-    let mut config = Config::from_file(args.config).context("cannot load initial settings")?;
-    config.secret.password = args.password;
-
-    let secret = Secret::new(config.secret).context("Cannot start the secret Key Manager")?;
-    let storage = Storage::new(config.storage, config.users)
+    let secret =
+        Secret::new(config.secret.clone()).context("Cannot start the secret Key Manager")?;
+    let storage = Storage::new(config.storage.clone(), config.users.clone())
         .await
         .context("Cannot load storage")?;
-    let network = Network::new(secret.clone(), storage.clone(), config.network)
+    let network = Network::new(secret.clone(), storage.clone(), config.network.clone())
         .await
         .context("Cannot load the network task")?;
 
-    println!("ctrl-c to stop the node...");
+    #[cfg(unix)]
+    let admin = match &config.admin.socket {
+        Some(socket) => {
+            let log_filter = log_filter.clone();
+            let log_filter = Box::new(move |filter| Ok(log_filter.reload(filter)?));
+            let admin = Admin::start(
+                socket,
+                network.control().clone(),
+                storage.clone(),
+                log_filter,
+            )
+            .await
+            .context("Cannot start the admin API")?;
+            Some(admin)
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    if config.admin.socket.is_some() {
+        tracing::warn!("the admin API is only available on unix platforms");
+    }
+
+    let exporter = match config.metrics.listen_address {
+        Some(address) => {
+            let exporter = Exporter::start(address, network.control().metrics().clone())
+                .context("Cannot start the metrics exporter")?;
+            Some(exporter)
+        }
+        None => None,
+    };
+
+    let compaction = if config.storage.compaction_interval.is_zero() {
+        None
+    } else {
+        Some(Compaction::start(
+            storage.clone(),
+            config.storage.compaction_interval,
+        ))
+    };
+
+    if cfg!(unix) {
+        println!("ctrl-c to stop the node, SIGHUP to reload the configuration...");
+    } else {
+        println!("ctrl-c to stop the node...");
+    }
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            tracing::info!("shuting down via CTRL-C instruction")
+    let mut hangup = Hangup::new().context("Cannot listen for SIGHUP")?;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                tracing::info!("shuting down via CTRL-C instruction");
+                break;
+            }
+            _ = hangup.recv() => {
+                if let Err(error) = reload(&args, &config, &storage, network.control(), &log_filter).await {
+                    tracing::error!(reason = ?error, "Cannot reload the configuration, keeping the running one");
+                }
+            }
         }
     }
 
+    #[cfg(unix)]
+    if let Some(admin) = admin {
+        admin.shutdown();
+    }
+    if let Some(exporter) = exporter {
+        exporter.shutdown();
+    }
+    if let Some(compaction) = compaction {
+        compaction.shutdown();
+    }
+
     network
         .shutdown()
         .await
@@ -74,3 +155,80 @@ async fn main_run() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// the SIGHUP signals asking to reload the configuration, the platforms
+/// without signals never ask
+#[cfg(unix)]
+struct Hangup(tokio::signal::unix::Signal);
+#[cfg(not(unix))]
+struct Hangup;
+
+impl Hangup {
+    #[cfg(unix)]
+    fn new() -> std::io::Result<Self> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        signal(SignalKind::hangup()).map(Self)
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    #[cfg(unix)]
+    async fn recv(&mut self) -> Option<()> {
+        self.0.recv().await
+    }
+
+    #[cfg(not(unix))]
+    async fn recv(&mut self) -> Option<()> {
+        futures::future::pending().await
+    }
+}
+
+/// apply the settings of the configuration file that can be changed while
+/// the node is running
+///
+/// nothing is changed if the configuration file is invalid: all the
+/// settings are checked before any is applied. The network settings are
+/// applied first, the only step that can fail (if the network stopped),
+/// so the node does not end up with half of the new settings. The settings that differ
+/// from the `running` configuration (the one the node started with) but
+/// cannot be changed are reported.
+async fn reload(
+    args: &Args,
+    running: &Config,
+    storage: &Storage,
+    control: &Control,
+    log_filter: &LogFilter,
+) -> anyhow::Result<()> {
+    let mut config = Config::from_file(&args.config).context("cannot load the settings")?;
+    config.secret.password = args.password.clone();
+
+    let settings = Settings::new(&config.storage, &config.users)
+        .context("cannot load the users and the storage limits")?;
+
+    tracing::info!(config = %args.config.display(), "reloading the configuration");
+
+    control
+        .reload(config.network.clone())
+        .await
+        .context("Cannot update the network settings")?;
+    storage.reload(settings);
+    if let Some(level) = config.log_level {
+        // only fails if the logs are no longer collected
+        if let Err(error) = log_filter.reload(level.0) {
+            tracing::warn!(reason = ?error, "Cannot change the log level");
+        }
+    }
+
+    for setting in running.restart_required(&config) {
+        tracing::warn!(
+            setting,
+            "the setting changed but requires a restart to be applied"
+        );
+    }
+
+    Ok(())
+}
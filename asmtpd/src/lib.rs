@@ -1,7 +1,11 @@
+pub mod admin;
 mod config;
+pub mod metrics;
 pub mod network;
 pub mod secret;
 mod session_id;
+#[cfg(any(test, feature = "simulation"))]
+pub mod simulation;
 pub mod storage;
 
 pub use self::{config::Config, session_id::SessionId};
@@ -0,0 +1,17 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use structopt::StructOpt;
+
+/// the metrics exporter of the node
+#[derive(Debug, PartialEq, Eq, Hash, Clone, StructOpt, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// address to serve the metrics on, in the Prometheus text format
+    ///
+    /// the metrics are served over HTTP at `/metrics`. There is no
+    /// authentication: prefer a local address. If not set, the metrics are
+    /// not exported.
+    #[structopt(long = "metrics-listen-address")]
+    #[serde(default)]
+    pub listen_address: Option<SocketAddr>,
+}
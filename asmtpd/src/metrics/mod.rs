@@ -0,0 +1,351 @@
+/*!
+metrics of the node
+
+Every node has its own [`Metrics`] (see
+[`Control::metrics`](crate::network::Control::metrics)). They can be
+exported in the [Prometheus text format] over HTTP with the [`Exporter`]
+(see [`Config::listen_address`]).
+
+| metric                                | labels             | description                                   |
+|---------------------------------------|--------------------|-----------------------------------------------|
+| `asmtpd_connections`                  | `direction`        | the opened connections                        |
+| `asmtpd_handshake_failures_total`     | `direction`        | the connections that failed to be established |
+| `asmtpd_messages_received_total`      | `type`             | the messages received from the peers          |
+| `asmtpd_messages_sent_total`          | `type`             | the messages sent to the peers                |
+| `asmtpd_message_cache_lookups_total`  | `result`           | the topic messages already seen (`hit`) or not (`miss`) |
+| `asmtpd_gossip_rounds_total`          |                    | the gossips sent to a peer                    |
+| `asmtpd_passports`                    |                    | the passports the node keeps                  |
+| `asmtpd_threads`                      |                    | the topics the node keeps the messages of     |
+| `asmtpd_queue_depth`                  | `queue`            | the items waiting in the node's queues        |
+
+The `direction` is `inbound` or `outbound`, the `type` is the type of the
+message (`Topic`, `Gossip`...) and the `queue` is either `messages` (the
+messages received from the peers, waiting to be handled) or `gossip` (the
+peers to gossip with).
+
+The storage metrics and the queue depths are updated on every heart beat
+of the network.
+
+[Prometheus text format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+*/
+
+mod config;
+
+pub use self::config::Config;
+use anyhow::{Context as _, Result};
+use asmtp_network::MessageType;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use prometheus::{
+    Encoder as _, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::{convert::Infallible, net::SocketAddr};
+use tokio::task::JoinHandle;
+
+/// the metrics of a node
+///
+/// this object can be cheaply cloned, all the clones update the same
+/// metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    connections: IntGaugeVec,
+    handshake_failures: IntCounterVec,
+    messages_received: IntCounterVec,
+    messages_sent: IntCounterVec,
+    message_cache: IntCounterVec,
+    gossip_rounds: IntCounter,
+    passports: IntGauge,
+    threads: IntGauge,
+    queue_depth: IntGaugeVec,
+}
+
+/// serve the [`Metrics`] over HTTP, see the [module documentation](self)
+pub struct Exporter {
+    address: SocketAddr,
+    handle: JoinHandle<()>,
+}
+
+fn direction(inbound: bool) -> &'static str {
+    if inbound {
+        "inbound"
+    } else {
+        "outbound"
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let connections = IntGaugeVec::new(
+            Opts::new("asmtpd_connections", "the opened connections"),
+            &["direction"],
+        )
+        .expect("valid metric");
+        let handshake_failures = IntCounterVec::new(
+            Opts::new(
+                "asmtpd_handshake_failures_total",
+                "the connections that failed to be established",
+            ),
+            &["direction"],
+        )
+        .expect("valid metric");
+        let messages_received = IntCounterVec::new(
+            Opts::new(
+                "asmtpd_messages_received_total",
+                "the messages received from the peers",
+            ),
+            &["type"],
+        )
+        .expect("valid metric");
+        let messages_sent = IntCounterVec::new(
+            Opts::new(
+                "asmtpd_messages_sent_total",
+                "the messages sent to the peers",
+            ),
+            &["type"],
+        )
+        .expect("valid metric");
+        let message_cache = IntCounterVec::new(
+            Opts::new(
+                "asmtpd_message_cache_lookups_total",
+                "the topic messages already seen (hit) or not (miss)",
+            ),
+            &["result"],
+        )
+        .expect("valid metric");
+        let gossip_rounds =
+            IntCounter::new("asmtpd_gossip_rounds_total", "the gossips sent to a peer")
+                .expect("valid metric");
+        let passports = IntGauge::new("asmtpd_passports", "the passports the node keeps")
+            .expect("valid metric");
+        let threads = IntGauge::new(
+            "asmtpd_threads",
+            "the topics the node keeps the messages of",
+        )
+        .expect("valid metric");
+        let queue_depth = IntGaugeVec::new(
+            Opts::new(
+                "asmtpd_queue_depth",
+                "the items waiting in the node's queues",
+            ),
+            &["queue"],
+        )
+        .expect("valid metric");
+
+        let metrics = Self {
+            registry,
+            connections,
+            handshake_failures,
+            messages_received,
+            messages_sent,
+            message_cache,
+            gossip_rounds,
+            passports,
+            threads,
+            queue_depth,
+        };
+        metrics.register().expect("metrics registered only once");
+        metrics
+    }
+
+    fn register(&self) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.connections.clone()))?;
+        self.registry
+            .register(Box::new(self.handshake_failures.clone()))?;
+        self.registry
+            .register(Box::new(self.messages_received.clone()))?;
+        self.registry
+            .register(Box::new(self.messages_sent.clone()))?;
+        self.registry
+            .register(Box::new(self.message_cache.clone()))?;
+        self.registry
+            .register(Box::new(self.gossip_rounds.clone()))?;
+        self.registry.register(Box::new(self.passports.clone()))?;
+        self.registry.register(Box::new(self.threads.clone()))?;
+        self.registry.register(Box::new(self.queue_depth.clone()))?;
+        Ok(())
+    }
+
+    pub fn connections(&self, inbound: usize, outbound: usize) {
+        self.connections
+            .with_label_values(&[direction(true)])
+            .set(inbound as i64);
+        self.connections
+            .with_label_values(&[direction(false)])
+            .set(outbound as i64);
+    }
+
+    pub fn handshake_failed(&self, inbound: bool) {
+        self.handshake_failures
+            .with_label_values(&[direction(inbound)])
+            .inc()
+    }
+
+    pub fn message_received(&self, message_type: MessageType) {
+        self.messages_received
+            .with_label_values(&[&format!("{:?}", message_type)])
+            .inc()
+    }
+
+    pub fn message_sent(&self, message_type: MessageType) {
+        self.messages_sent
+            .with_label_values(&[&format!("{:?}", message_type)])
+            .inc()
+    }
+
+    /// a topic message was looked up in the cache of the known messages
+    pub fn message_cache(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.message_cache.with_label_values(&[result]).inc()
+    }
+
+    pub fn gossip_round(&self) {
+        self.gossip_rounds.inc()
+    }
+
+    pub fn stored(&self, passports: usize, threads: usize) {
+        self.passports.set(passports as i64);
+        self.threads.set(threads as i64);
+    }
+
+    pub fn queue_depth(&self, queue: &str, depth: usize) {
+        self.queue_depth
+            .with_label_values(&[queue])
+            .set(depth as i64)
+    }
+
+    /// the metrics in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics are valid UTF-8");
+        String::from_utf8(buffer).expect("metrics are valid UTF-8")
+    }
+
+    fn respond(&self, request: Request<Body>) -> Response<Body> {
+        let mut response = Response::default();
+        match (request.method(), request.uri().path()) {
+            (&Method::GET, "/metrics") => {
+                response.headers_mut().insert(
+                    CONTENT_TYPE,
+                    TextEncoder::new()
+                        .format_type()
+                        .parse()
+                        .expect("valid content type"),
+                );
+                *response.body_mut() = Body::from(self.encode());
+            }
+            _ => *response.status_mut() = StatusCode::NOT_FOUND,
+        }
+        response
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Exporter {
+    /// serve the `metrics` on the given `address`
+    pub fn start(address: SocketAddr, metrics: Metrics) -> Result<Self> {
+        let make_service = make_service_fn(move |_| {
+            let metrics = metrics.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request| {
+                    let response = metrics.respond(request);
+                    async move { Ok::<_, Infallible>(response) }
+                }))
+            }
+        });
+
+        let server = Server::try_bind(&address)
+            .with_context(|| format!("Cannot listen for the metrics on {}", address))?
+            .serve(make_service);
+        let address = server.local_addr();
+
+        tracing::info!(%address, "exporting the metrics");
+
+        let handle = tokio::spawn(async move {
+            if let Err(error) = server.await {
+                tracing::error!(reason = ?error, "Cannot serve the metrics");
+            }
+        });
+
+        Ok(Self { address, handle })
+    }
+
+    /// the address the metrics are served on
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// stop serving the metrics
+    pub fn shutdown(self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt as _, AsyncWriteExt as _},
+        net::TcpStream,
+    };
+
+    async fn get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn metrics_are_encoded() {
+        let metrics = Metrics::new();
+        metrics.message_received(MessageType::Topic);
+        metrics.message_received(MessageType::Topic);
+        metrics.message_cache(true);
+        metrics.stored(3, 2);
+
+        let encoded = metrics.encode();
+        assert!(encoded.contains(r#"asmtpd_messages_received_total{type="Topic"} 2"#));
+        assert!(encoded.contains(r#"asmtpd_message_cache_lookups_total{result="hit"} 1"#));
+        assert!(encoded.contains("asmtpd_passports 3"));
+        assert!(encoded.contains("asmtpd_threads 2"));
+    }
+
+    #[tokio::test]
+    async fn metrics_are_exported() {
+        let metrics = Metrics::new();
+        metrics.gossip_round();
+        let exporter = Exporter::start(([127, 0, 0, 1], 0).into(), metrics.clone()).unwrap();
+
+        let response = get(exporter.address(), "/metrics").await;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("asmtpd_gossip_rounds_total 1"));
+
+        let response = get(exporter.address(), "/").await;
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found"),
+            "{}",
+            response
+        );
+
+        exporter.shutdown();
+    }
+}
@@ -1,4 +1,7 @@
+use crate::network::policy::IpRange;
 use anyhow::{Context as _, Result};
+use asmtp_network::net::Socks5Proxy;
+use keynesis::key::ed25519::PublicKey;
 use poldercast::GossipSlice;
 use serde::{Deserialize, Serialize};
 use std::{
@@ -27,12 +30,62 @@ pub struct Config {
     /// port forwarding and other internal work.
     pub public_address: SocketAddr,
 
+    /// more addresses to listen for incoming connections
+    ///
+    /// to listen on several interfaces, or on both IPv4 and IPv6, at the
+    /// same time. If the node listens on an IPv4 and an IPv6 address with
+    /// the same port, the IPv6 address only accepts IPv6 connections.
+    #[structopt(long = "additional-listen-address")]
+    #[serde(default)]
+    pub additional_listen_addresses: Vec<SocketAddr>,
+
+    /// more addresses that can be used to reach to us
+    ///
+    /// they are advertised to the other nodes after the `public_address`,
+    /// in this order of preference. The other nodes connect to the first
+    /// address they can reach.
+    #[structopt(long = "additional-public-address")]
+    #[serde(default)]
+    pub additional_public_addresses: Vec<SocketAddr>,
+
+    /// optional address to listen for inbound connections carried
+    /// over WebSocket
+    ///
+    /// this allows clients sitting behind proxies that only let HTTP(S)
+    /// through to reach the node. The node keeps listening for TCP
+    /// connections on `listen_address` at the same time.
+    #[structopt(long = "websocket-listen-address")]
+    #[serde(default)]
+    pub websocket_listen_address: Option<SocketAddr>,
+
+    /// optional SOCKS5 proxy to open the outbound connections through
+    ///
+    /// of the form `socks5://[username:password@]host:port`. The
+    /// peers' addresses are resolved by the proxy.
+    #[structopt(long = "socks5-proxy")]
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+
     /// the maximal number of opened connections
     ///
     /// set the maximum value of default connections
     #[serde(default = "default_max_opened_connections")]
     pub max_opened_connections: usize,
 
+    /// the time the inbound peers have to complete their handshake (in
+    /// seconds), solving the puzzle included
+    #[structopt(long = "handshake-timeout", parse(try_from_str = duration))]
+    #[serde(default = "default_handshake_timeout")]
+    pub handshake_timeout: Duration,
+
+    /// the maximal number of inbound handshakes in progress
+    ///
+    /// the new inbound connections are closed straight away until the
+    /// number of handshakes in progress goes down.
+    #[structopt(long = "max-pending-handshakes")]
+    #[serde(default = "default_max_pending_handshakes")]
+    pub max_pending_handshakes: usize,
+
     /// the inbound message queue size
     ///
     /// this is the number of entries that can be queued in the
@@ -40,10 +93,12 @@ pub struct Config {
     #[serde(default = "default_message_queue_size")]
     pub message_queue_size: usize,
 
-    /// the maximum size of the cache of known messages
+    /// the maximum size of the in-memory cache of known messages
     ///
     /// we are keeping a hash of the messages who passed by
-    /// so we don't re-propagate messages we already visited
+    /// so we don't re-propagate messages we already visited. The
+    /// older messages are looked up in the seen messages of the
+    /// storage (see `storage.seen_messages_window`).
     #[serde(default = "default_known_message_cache_size")]
     pub known_message_cache_size: usize,
 
@@ -51,6 +106,26 @@ pub struct Config {
     #[serde(default)]
     pub gossiping: Gossip,
 
+    #[structopt(flatten)]
+    #[serde(default)]
+    pub puzzle: Puzzle,
+
+    #[structopt(flatten)]
+    #[serde(default)]
+    pub cover_traffic: CoverTraffic,
+
+    #[structopt(flatten)]
+    #[serde(default)]
+    pub reconciliation: Reconciliation,
+
+    #[structopt(flatten)]
+    #[serde(default)]
+    pub policy: Policy,
+
+    #[structopt(flatten)]
+    #[serde(default)]
+    pub scoring: Scoring,
+
     /// the heart beat of the network (in seconds).
     ///
     /// make sure to wake up the network every `heart_beat`
@@ -79,6 +154,62 @@ fn default_max_opened_connections() -> usize {
     128
 }
 
+fn default_handshake_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_max_pending_handshakes() -> usize {
+    256
+}
+
+fn default_puzzle_difficulty() -> u8 {
+    16
+}
+
+fn default_puzzle_pending_handshakes() -> usize {
+    32
+}
+
+fn default_puzzle_max_difficulty() -> u8 {
+    asmtp_network::Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY
+}
+
+fn default_cover_traffic_max_size() -> usize {
+    1_024
+}
+
+fn default_reconciliation_interval() -> Duration {
+    Duration::from_secs(300)
+}
+
+fn default_reconciliation_window() -> Duration {
+    Duration::from_secs(7 * 24 * 3_600)
+}
+
+fn default_reconciliation_max_ranges() -> usize {
+    256
+}
+
+fn default_reconciliation_max_messages() -> usize {
+    256
+}
+
+fn default_reconciliation_max_requests() -> usize {
+    2
+}
+
+fn default_scoring_threshold() -> u32 {
+    100
+}
+
+fn default_scoring_half_life() -> Duration {
+    Duration::from_secs(600)
+}
+
+fn default_scoring_ban_duration() -> Duration {
+    Duration::from_secs(3_600)
+}
+
 fn default_gossiping_history_size() -> usize {
     10_240
 }
@@ -122,10 +253,260 @@ pub struct Gossip {
     pub history_size: usize,
 }
 
+/// the proof of work required from the inbound peers when the node
+/// is under load
+#[derive(StructOpt, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Puzzle {
+    /// the difficulty of the puzzle (number of leading zero bits)
+    ///
+    /// every extra bit doubles the work of the inbound peers. Set to
+    /// `0` to never challenge the inbound peers.
+    #[structopt(long = "puzzle-difficulty")]
+    #[serde(default = "default_puzzle_difficulty")]
+    pub difficulty: u8,
+
+    /// the number of inbound handshakes in progress from which the
+    /// node is considered under load
+    ///
+    /// the new inbound peers will be required to solve the puzzle
+    /// until the number of pending handshakes goes down.
+    ///
+    /// the puzzle is bound to the IP address of the peer. Behind a
+    /// reverse proxy (in front of the `websocket_listen_address` for
+    /// example) all the peers share the address of the proxy: a solution
+    /// is then only bound to the handshake it was found for.
+    #[structopt(long = "puzzle-pending-handshakes")]
+    #[serde(default = "default_puzzle_pending_handshakes")]
+    pub pending_handshakes: usize,
+
+    /// the maximum difficulty of the puzzles the node solves when
+    /// connecting to the other peers
+    ///
+    /// the connections to the peers asking for more fail: the expected
+    /// work doubles with every bit of difficulty.
+    #[structopt(long = "puzzle-max-difficulty")]
+    #[serde(default = "default_puzzle_max_difficulty")]
+    pub max_difficulty: u8,
+}
+
+/// dummy messages sent to the peers at random times to make the traffic
+/// analysis harder
+#[derive(StructOpt, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CoverTraffic {
+    /// the mean time between 2 dummy messages on a connection (in seconds)
+    ///
+    /// set to `0` (the default) to not send dummy messages.
+    #[structopt(long = "cover-traffic-interval", parse(try_from_str = duration))]
+    #[serde(default)]
+    pub interval: Duration,
+
+    /// the maximum size of the dummy messages (in bytes)
+    #[structopt(long = "cover-traffic-max-size")]
+    #[serde(default = "default_cover_traffic_max_size")]
+    pub max_size: usize,
+}
+
+/// periodic reconciliation of the messages of the topics we keep with
+/// the peers subscribed to the same topics
+#[derive(StructOpt, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Reconciliation {
+    /// the time between 2 reconciliations (in seconds)
+    ///
+    /// every time, one of the topics and one of the peers subscribed to
+    /// it are picked at random. Set to `0` to not start reconciliations
+    /// (the node still replies to the peers' reconciliations).
+    #[structopt(long = "reconciliation-interval", parse(try_from_str = duration))]
+    #[serde(default = "default_reconciliation_interval")]
+    pub interval: Duration,
+
+    /// how far back in time the messages are reconciled (in seconds)
+    ///
+    /// the peers' reconciliations of older messages are bound to the
+    /// window.
+    ///
+    /// the window is of the time the messages were received, which is not
+    /// the same for the two peers: the messages received close to the
+    /// start of the window may be sent to the peer again, their content is
+    /// kept only once.
+    #[structopt(long = "reconciliation-window", parse(try_from_str = duration))]
+    #[serde(default = "default_reconciliation_window")]
+    pub window: Duration,
+
+    /// the maximum number of ranges of a peer's reconciliation answered
+    /// in one round
+    ///
+    /// the ranges left are reconciled in the following rounds.
+    #[structopt(long = "reconciliation-max-ranges")]
+    #[serde(default = "default_reconciliation_max_ranges")]
+    pub max_ranges: usize,
+
+    /// the maximum number of messages sent to a peer in one round of
+    /// reconciliation
+    #[structopt(long = "reconciliation-max-messages")]
+    #[serde(default = "default_reconciliation_max_messages")]
+    pub max_messages: usize,
+
+    /// the maximum number of reconciliations of a peer answered at the
+    /// same time, the peer's other reconciliations are ignored
+    #[structopt(long = "reconciliation-max-requests")]
+    #[serde(default = "default_reconciliation_max_requests")]
+    pub max_requests: usize,
+}
+
+/// the misbehaviour scores of the peers
+///
+/// every offence of a peer (malformed frames, unknown or invalid
+/// messages, rejected passports, messages beyond the rate limit) adds to
+/// the peer's score. Past the threshold the peer is demoted from the
+/// topology, disconnected and banned for a while.
+#[derive(StructOpt, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scoring {
+    /// the score from which a peer is banned
+    ///
+    /// a malformed frame is worth 20 points, an unknown or invalid
+    /// message 10, a rejected passport 25 and a message beyond the rate
+    /// limit 5. Set to `0` to never ban the misbehaving peers.
+    #[structopt(long = "scoring-threshold")]
+    #[serde(default = "default_scoring_threshold")]
+    pub threshold: u32,
+
+    /// the time for the scores to be halved (in seconds)
+    #[structopt(long = "scoring-half-life", parse(try_from_str = duration))]
+    #[serde(default = "default_scoring_half_life")]
+    pub half_life: Duration,
+
+    /// how long the peers reaching the threshold are banned (in seconds)
+    #[structopt(long = "scoring-ban-duration", parse(try_from_str = duration))]
+    #[serde(default = "default_scoring_ban_duration")]
+    pub ban_duration: Duration,
+
+    /// the maximum number of frames per second to accept from a peer
+    ///
+    /// the messages beyond the limit are dropped. The messages packed in
+    /// one batch count as one frame. Set to `0` (the default) to not
+    /// limit the peers.
+    #[structopt(long = "scoring-rate-limit")]
+    #[serde(default)]
+    pub rate_limit: u32,
+}
+
+/// the peers the node accepts to talk to, inbound and outbound
+///
+/// a peer denied by its identity or by its address is never talked to.
+/// When an allow list is not empty, only the peers in it are talked to.
+#[derive(StructOpt, Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// the only peers (public identities) to talk to
+    #[structopt(long = "allow-peer")]
+    #[serde(default)]
+    pub allowed_peers: Vec<PeerId>,
+
+    /// the peers (public identities) to never talk to
+    #[structopt(long = "deny-peer")]
+    #[serde(default)]
+    pub denied_peers: Vec<PeerId>,
+
+    /// the only addresses to talk to (`address` or `address/prefix`)
+    #[structopt(long = "allow-address")]
+    #[serde(default)]
+    pub allowed_addresses: Vec<IpRange>,
+
+    /// the addresses to never talk to (`address` or `address/prefix`)
+    #[structopt(long = "deny-address")]
+    #[serde(default)]
+    pub denied_addresses: Vec<IpRange>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(into = "String", try_from = "String")]
 pub struct KnownGossip(pub(crate) poldercast::Gossip);
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Proxy(pub(crate) Socks5Proxy);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct PeerId(pub(crate) PublicKey);
+
+impl Config {
+    /// all the addresses to listen for incoming connections
+    pub fn listen_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = vec![self.listen_address];
+        for address in self.additional_listen_addresses.iter() {
+            if !addresses.contains(address) {
+                addresses.push(*address);
+            }
+        }
+        addresses
+    }
+
+    /// all the addresses to reach to us, in order of preference
+    pub fn public_addresses(&self) -> Vec<SocketAddr> {
+        let mut addresses = vec![self.public_address];
+        for address in self.additional_public_addresses.iter() {
+            if !addresses.contains(address) {
+                addresses.push(*address);
+            }
+        }
+        addresses
+    }
+
+    /// the settings that differ in `other` and cannot be changed while
+    /// the network is running
+    ///
+    /// the other settings are applied by
+    /// [`Control::reload`](crate::network::Control::reload).
+    pub fn restart_required(&self, other: &Self) -> Vec<&'static str> {
+        let changes = [
+            (
+                "network.listen_address",
+                self.listen_address != other.listen_address,
+            ),
+            (
+                "network.public_address",
+                self.public_address != other.public_address,
+            ),
+            (
+                "network.additional_listen_addresses",
+                self.additional_listen_addresses != other.additional_listen_addresses,
+            ),
+            (
+                "network.additional_public_addresses",
+                self.additional_public_addresses != other.additional_public_addresses,
+            ),
+            (
+                "network.websocket_listen_address",
+                self.websocket_listen_address != other.websocket_listen_address,
+            ),
+            ("network.proxy", self.proxy != other.proxy),
+            (
+                "network.message_queue_size",
+                self.message_queue_size != other.message_queue_size,
+            ),
+            (
+                "network.puzzle.difficulty",
+                self.puzzle.difficulty != other.puzzle.difficulty,
+            ),
+            (
+                "network.cover_traffic",
+                self.cover_traffic != other.cover_traffic,
+            ),
+        ];
+
+        changes
+            .iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| *setting)
+            .collect()
+    }
+}
+
 fn duration(s: &str) -> Result<Duration> {
     let i = s
         .parse()
@@ -138,16 +519,38 @@ impl Default for Config {
         Self {
             listen_address: "[::1]:9876".parse().unwrap(),
             public_address: "[::1]:9876".parse().unwrap(),
+            additional_listen_addresses: Vec::new(),
+            additional_public_addresses: Vec::new(),
+            websocket_listen_address: None,
+            proxy: None,
             max_opened_connections: default_max_opened_connections(),
+            handshake_timeout: default_handshake_timeout(),
+            max_pending_handshakes: default_max_pending_handshakes(),
             message_queue_size: default_message_queue_size(),
             known_message_cache_size: default_known_message_cache_size(),
             gossiping: Gossip::default(),
+            puzzle: Puzzle::default(),
+            cover_traffic: CoverTraffic::default(),
+            reconciliation: Reconciliation::default(),
+            policy: Policy::default(),
+            scoring: Scoring::default(),
             heart_beat: default_heart_beat(),
             known_gossips: Vec::new(),
         }
     }
 }
 
+impl Default for Scoring {
+    fn default() -> Self {
+        Self {
+            threshold: default_scoring_threshold(),
+            half_life: default_scoring_half_life(),
+            ban_duration: default_scoring_ban_duration(),
+            rate_limit: 0,
+        }
+    }
+}
+
 impl Default for Gossip {
     fn default() -> Self {
         Self {
@@ -158,6 +561,37 @@ impl Default for Gossip {
     }
 }
 
+impl Default for Puzzle {
+    fn default() -> Self {
+        Self {
+            difficulty: default_puzzle_difficulty(),
+            pending_handshakes: default_puzzle_pending_handshakes(),
+            max_difficulty: default_puzzle_max_difficulty(),
+        }
+    }
+}
+
+impl Default for CoverTraffic {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(0),
+            max_size: default_cover_traffic_max_size(),
+        }
+    }
+}
+
+impl Default for Reconciliation {
+    fn default() -> Self {
+        Self {
+            interval: default_reconciliation_interval(),
+            window: default_reconciliation_window(),
+            max_ranges: default_reconciliation_max_ranges(),
+            max_messages: default_reconciliation_max_messages(),
+            max_requests: default_reconciliation_max_requests(),
+        }
+    }
+}
+
 impl From<KnownGossip> for String {
     fn from(known_gossip: KnownGossip) -> Self {
         known_gossip.to_string()
@@ -185,3 +619,57 @@ impl FromStr for KnownGossip {
         Ok(KnownGossip(gossip.to_owned()))
     }
 }
+
+impl From<Proxy> for String {
+    fn from(proxy: Proxy) -> Self {
+        proxy.0.to_url()
+    }
+}
+
+impl TryFrom<String> for Proxy {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for Proxy {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Proxy).context("Invalid SOCKS5 proxy")
+    }
+}
+
+impl From<PeerId> for String {
+    fn from(id: PeerId) -> Self {
+        id.to_string()
+    }
+}
+
+impl TryFrom<String> for PeerId {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse()
+            .map(PeerId)
+            .context("Invalid peer public identity")
+    }
+}
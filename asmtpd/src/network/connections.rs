@@ -1,23 +1,104 @@
 use crate::{
-    network::{Config, Topology},
+    metrics::Metrics,
+    network::{Config, Offence, Policy, Topology},
     secret::Secret,
+    storage::Peers,
 };
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, ensure, Context as _, Result};
+use asmtp_lib::passport_topic;
 use asmtp_network::{
-    net::{Accepting, Connection, ConnectionReader, ConnectionWriter},
-    Message,
+    net::{
+        Accepting, Connection, ConnectionReader, ConnectionWriter, MemoryNetwork, Socks5Proxy,
+        TrafficMeter,
+    },
+    Capabilities, CoverTraffic, GoodbyeReason, Message, MessageType, PeerAddresses, Puzzle,
+    SessionId, Version,
 };
 use futures::prelude::*;
 use keynesis::key::ed25519::{PublicKey, SecretKey};
 use lru::LruCache;
 use poldercast::{Gossip, Profile};
 use rand::rngs::OsRng;
-use std::sync::{Arc, Mutex};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 use tokio::sync::mpsc;
 
 enum Command {
     Send(Message),
-    Gossips(Vec<Gossip>),
+    /// send all the messages, packed in batches if the peer supports it
+    SendAll(Vec<Message>),
+    /// the gossips and the addresses advertised by the gossiped peers
+    Gossips(Vec<Gossip>, Vec<PeerAddresses>),
+    /// tell the peer why we are closing the connection and close it
+    Goodbye {
+        reason: GoodbyeReason,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// an opened connection with a peer
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    /// the peer's public identity
+    pub id: PublicKey,
+    pub remote_address: SocketAddr,
+    /// the session of the connection, shared with the peer
+    pub session_id: SessionId,
+    /// the peer opened the connection
+    pub inbound: bool,
+    /// the version of the protocol agreed with the peer
+    pub version: Version,
+    pub received: TrafficMeter,
+    pub sent: TrafficMeter,
+}
+
+struct Entry {
+    command: mpsc::Sender<Command>,
+    info: ConnectionInfo,
+}
+
+type Entries = Arc<Mutex<LruCache<PublicKey, Entry>>>;
+
+/// sends messages to a peer from a task of its own, the work for the
+/// peer is not done on the network's task (see [`Connections::sender`])
+#[derive(Clone)]
+pub struct PeerSender {
+    id: PublicKey,
+    command: mpsc::Sender<Command>,
+}
+
+/// a message received from a peer or the peer's offence
+type Received = (PublicKey, Result<Inbound, Offence>);
+
+/// a message received from a peer
+pub struct Inbound {
+    pub message: Message,
+    /// the message is the first of its frame: the other messages unpacked
+    /// from the same [`MessageType::Batch`] are not
+    pub new_frame: bool,
+}
+
+/// how long to wait for a connection attempt before trying the next
+/// address of the peer as well (see [`Dialer::connect`])
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// how the outbound connections are opened
+#[derive(Clone)]
+pub enum Dialer {
+    /// connect to the peers with TCP, optionally through a SOCKS5 proxy
+    Tcp { proxy: Option<Socks5Proxy> },
+    /// connect to the peers on an in-memory network from the first of the
+    /// given addresses of the same family (IPv4 or IPv6) as the peer's
+    Memory {
+        network: MemoryNetwork,
+        addresses: Vec<SocketAddr>,
+    },
 }
 
 pub struct Connections {
@@ -31,41 +112,179 @@ pub struct Connections {
     ///
     // todo: pub this in a type so it is easier to change
     // behavior with time
-    to: Arc<Mutex<LruCache<PublicKey, mpsc::Sender<Command>>>>,
+    to: Entries,
     topology: Topology,
     secret: Secret,
-
-    message_sender: mpsc::Sender<(PublicKey, Message)>,
-    message_receiver: mpsc::Receiver<(PublicKey, Message)>,
+    dialer: Dialer,
+
+    /// the peers we accept to talk to
+    policy: Policy,
+
+    /// where the outcome of the connection attempts is recorded
+    peers: Peers,
+
+    metrics: Metrics,
+
+    /// the puzzle to challenge the inbound peers with when there are
+    /// more than `pending_handshakes` handshakes in progress
+    puzzle: Option<Puzzle>,
+    pending_handshakes: usize,
+    /// the maximum difficulty of the puzzles solved when connecting to
+    /// the peers
+    max_difficulty: u8,
+    handshakes: Arc<AtomicUsize>,
+    /// the inbound connections are closed straight away when there are
+    /// that many handshakes in progress
+    max_pending_handshakes: usize,
+    handshake_timeout: Duration,
+
+    /// dummy messages to send on every connections
+    cover: Option<CoverTraffic>,
+
+    message_queue_size: usize,
+    message_sender: mpsc::Sender<Received>,
+    message_receiver: mpsc::Receiver<Received>,
 }
 
 struct Runtime {
     inbound: ConnectionReader,
     outbound: ConnectionWriter,
+    cover: Option<CoverTraffic>,
+    metrics: Metrics,
 
     command_receiver: mpsc::Receiver<Command>,
-    message_sender: mpsc::Sender<(PublicKey, Message)>,
+    message_sender: mpsc::Sender<Received>,
+}
+
+/// an inbound handshake in progress, counted until it is dropped
+struct PendingHandshake(Arc<AtomicUsize>);
+
+impl Drop for PendingHandshake {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 impl Connections {
-    pub fn new(secret: Secret, topology: Topology, config: &Config) -> Self {
+    pub fn new(
+        secret: Secret,
+        topology: Topology,
+        policy: Policy,
+        peers: Peers,
+        metrics: Metrics,
+        dialer: Dialer,
+        config: &Config,
+    ) -> Result<Self> {
         let (message_sender, message_receiver) = mpsc::channel(config.message_queue_size);
 
-        Self {
+        let puzzle = if config.puzzle.difficulty == 0 {
+            None
+        } else {
+            Some(Puzzle::new(OsRng, config.puzzle.difficulty)?)
+        };
+
+        let cover = if config.cover_traffic.interval == Duration::from_secs(0) {
+            None
+        } else {
+            Some(CoverTraffic::new(
+                config.cover_traffic.interval,
+                config.cover_traffic.max_size,
+            ))
+        };
+
+        Ok(Self {
             to: Arc::new(Mutex::new(LruCache::new(config.max_opened_connections))),
             topology,
             secret,
+            dialer,
+            policy,
+            peers,
+            metrics,
+
+            puzzle,
+            pending_handshakes: config.puzzle.pending_handshakes,
+            max_difficulty: config.puzzle.max_difficulty,
+            handshakes: Arc::new(AtomicUsize::new(0)),
+            max_pending_handshakes: config.max_pending_handshakes,
+            handshake_timeout: config.handshake_timeout,
 
+            cover,
+
+            message_queue_size: config.message_queue_size,
             message_sender,
             message_receiver,
+        })
+    }
+
+    /// apply the new limits of the `config`
+    ///
+    /// if there are too many opened connections, the least recently used
+    /// are closed (the peers are told the node is overloaded).
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.pending_handshakes = config.puzzle.pending_handshakes;
+        self.max_difficulty = config.puzzle.max_difficulty;
+        self.max_pending_handshakes = config.max_pending_handshakes;
+        self.handshake_timeout = config.handshake_timeout;
+
+        let mut entries = self.to.lock().unwrap();
+        while entries.len() > config.max_opened_connections {
+            if let Some((evicted, entry)) = entries.pop_lru() {
+                tracing::debug!(id = %evicted, "too many connections, closing least recently used");
+                let _ = entry.command.try_send(Command::Goodbye {
+                    reason: GoodbyeReason::Overloaded,
+                    retry_after: None,
+                });
+            }
+        }
+        entries.resize(config.max_opened_connections);
+    }
+
+    /// the number of received messages waiting to be handled
+    pub fn queue_depth(&self) -> usize {
+        self.message_queue_size
+            .saturating_sub(self.message_sender.capacity())
+    }
+
+    /// the opened connections, the most recently used first
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.to
+            .lock()
+            .expect("valid lock")
+            .iter()
+            .map(|(_, entry)| entry.info.clone())
+            .collect()
+    }
+
+    /// close all the opened connections, telling the peers why
+    pub fn goodbye_all(&mut self, reason: GoodbyeReason, retry_after: Option<Duration>) {
+        let mut entries = self.to.lock().unwrap();
+        while let Some((_, entry)) = entries.pop_lru() {
+            let _ = entry.command.try_send(Command::Goodbye {
+                reason,
+                retry_after,
+            });
         }
     }
 
-    pub fn number_connections(&self) -> usize {
-        self.to.lock().expect("valid lock").len()
+    /// close the connection with the peer `id`, if any, telling the
+    /// peer why
+    pub fn goodbye(
+        &mut self,
+        id: &PublicKey,
+        reason: GoodbyeReason,
+        retry_after: Option<Duration>,
+    ) {
+        if let Some(entry) = self.to.lock().unwrap().pop(id) {
+            let _ = entry.command.try_send(Command::Goodbye {
+                reason,
+                retry_after,
+            });
+        }
     }
 
-    pub async fn receive(&mut self) -> (PublicKey, Message) {
+    /// the next message received from the peers, or the next offence of
+    /// the peers while receiving messages (see [`Offence::of_receive_error`])
+    pub async fn receive(&mut self) -> Received {
         // we own at least one `message_sender` so there is always
         // a sender available
         self.message_receiver
@@ -74,15 +293,54 @@ impl Connections {
             .expect("We should always receive something or wait indefinitely")
     }
 
-    pub async fn accept(&mut self, accepting: Accepting<OsRng, SecretKey>) {
+    pub async fn accept(&mut self, mut accepting: Accepting<OsRng, SecretKey>) {
+        let remote_address = accepting.remote_address();
+        if !self.policy.allows_address(remote_address.ip()) {
+            tracing::debug!(%remote_address, "refusing inbound connection denied by the policy");
+            return;
+        }
+
         let message_sender = self.message_sender.clone();
 
         let secret = self.secret.clone();
 
         let entries = self.to.clone();
+        let policy = self.policy.clone();
+
+        let pending = self.handshakes.fetch_add(1, Ordering::SeqCst);
+        let handshake = PendingHandshake(self.handshakes.clone());
+        if pending >= self.max_pending_handshakes {
+            tracing::debug!(
+                pending,
+                "too many handshakes in progress, closing inbound connection"
+            );
+            return;
+        }
+
+        let timeout = self.handshake_timeout;
+        let cover = self.cover;
+        let metrics = self.metrics.clone();
+        if let Some(puzzle) = &self.puzzle {
+            if pending >= self.pending_handshakes {
+                tracing::debug!(pending, "under load, challenging inbound connection");
+                accepting = accepting.challenge(puzzle.clone());
+            }
+        }
 
         let _ = tokio::spawn(async move {
-            if let Err(error) = accept(message_sender, secret, entries, accepting).await {
+            if let Err(error) = accept(
+                message_sender,
+                secret,
+                entries,
+                policy,
+                handshake,
+                timeout,
+                cover,
+                metrics,
+                accepting,
+            )
+            .await
+            {
                 tracing::warn!(reason = ?error, "Cannot accept inbound connection");
             }
         });
@@ -90,9 +348,20 @@ impl Connections {
 
     fn get_or_connect(&mut self, node: Arc<Profile>) -> Result<mpsc::Sender<Command>> {
         let id = node.id();
+        ensure!(
+            self.policy.allows_peer(&id),
+            "The peer {} is denied by the policy",
+            id
+        );
 
         loop {
-            match self.to.lock().unwrap().get(&id).cloned() {
+            let entry = self
+                .to
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map(|entry| entry.command.clone());
+            match entry {
                 Some(entry) => {
                     if entry.is_closed() {
                         self.to.lock().unwrap().pop(&id);
@@ -106,18 +375,30 @@ impl Connections {
 
                     let secret = self.secret.clone();
                     let entries = self.to.clone();
+                    let cover = self.cover;
+                    let max_difficulty = self.max_difficulty;
 
                     {
                         let command_sender = command_sender.clone();
                         let topology = self.topology.clone();
+                        let policy = self.policy.clone();
+                        let peers = self.peers.clone();
+                        let metrics = self.metrics.clone();
+                        let dialer = self.dialer.clone();
                         let _ = tokio::spawn(async move {
                             if let Err(error) = connect(
                                 topology,
+                                policy,
+                                peers,
+                                metrics,
                                 message_sender,
                                 command_sender.clone(),
                                 command_receiver,
                                 secret,
+                                dialer,
+                                max_difficulty,
                                 entries,
+                                cover,
                                 node,
                             )
                             .await
@@ -134,7 +415,40 @@ impl Connections {
     }
 
     pub async fn send_to_peer(&mut self, id: &PublicKey, message: Message) {
-        let entry = match self.to.lock().unwrap().get(id).cloned() {
+        self.command_peer(id, Command::Send(message)).await
+    }
+
+    /// send all the `messages` to the peer, in as few frames as possible
+    pub async fn send_all_to_peer(&mut self, id: &PublicKey, messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+
+        self.command_peer(id, Command::SendAll(messages)).await
+    }
+
+    /// the sender of the messages to the `node`, connecting to it if
+    /// needed
+    pub fn sender(&mut self, node: Arc<Profile>) -> Result<PeerSender> {
+        let id = node.id();
+        let command = self.get_or_connect(node)?;
+        Ok(PeerSender { id, command })
+    }
+
+    /// the sender of the messages to the peer `id`, if connected
+    pub fn peer_sender(&self, id: &PublicKey) -> Option<PeerSender> {
+        let command = self.to.lock().unwrap().peek(id)?.command.clone();
+        Some(PeerSender { id: *id, command })
+    }
+
+    async fn command_peer(&mut self, id: &PublicKey, command: Command) {
+        let entry = self
+            .to
+            .lock()
+            .unwrap()
+            .get(id)
+            .map(|entry| entry.command.clone());
+        let entry = match entry {
             Some(entry) => {
                 if entry.is_closed() {
                     self.to.lock().unwrap().pop(id);
@@ -151,7 +465,7 @@ impl Connections {
         };
 
         let r = entry
-            .send(Command::Send(message))
+            .send(command)
             .await
             .map_err(|_| anyhow!("Cannot send message to peer"));
 
@@ -169,10 +483,14 @@ impl Connections {
     }
 
     pub async fn send_gossips(&mut self, peer: Arc<Profile>, gossips: Vec<Gossip>) -> Result<()> {
+        let addresses = gossips
+            .iter()
+            .filter_map(|gossip| self.topology.addresses_of(&gossip.id()))
+            .collect();
         let sender = self.get_or_connect(peer)?;
 
         sender
-            .send(Command::Gossips(gossips))
+            .send(Command::Gossips(gossips, addresses))
             .await
             .map_err(|_| anyhow!("Cannot send gossips to peer"))
     }
@@ -187,22 +505,152 @@ impl Connections {
     }
 }
 
+impl PeerSender {
+    pub async fn send(&self, message: Message) {
+        self.command(Command::Send(message)).await
+    }
+
+    /// send all the `messages` to the peer, in as few frames as possible
+    pub async fn send_all(&self, messages: Vec<Message>) {
+        if messages.is_empty() {
+            return;
+        }
+
+        self.command(Command::SendAll(messages)).await
+    }
+
+    async fn command(&self, command: Command) {
+        if self.command.send(command).await.is_err() {
+            tracing::warn!(id = %self.id, "connection was recently closed");
+        }
+    }
+}
+
+impl Dialer {
+    /// connect to the first reachable of the `addresses` of the peer `id`
+    ///
+    /// the addresses are tried in order, happy eyeballs style: the next
+    /// address is tried as soon as the previous attempt failed or after
+    /// [`CONNECTION_ATTEMPT_DELAY`]. The first connection established wins.
+    ///
+    /// the peers challenging us with a puzzle more difficult than
+    /// `max_difficulty` are not connected to.
+    async fn connect(
+        &self,
+        secret: &Secret,
+        id: PublicKey,
+        addresses: Vec<SocketAddr>,
+        max_difficulty: u8,
+    ) -> Result<Connection> {
+        let mut addresses = addresses.into_iter();
+        let mut attempts = stream::FuturesUnordered::new();
+        let mut last_error = None;
+
+        attempts.extend(
+            addresses
+                .next()
+                .map(|address| self.connect_to(secret, id, address, max_difficulty)),
+        );
+        while !attempts.is_empty() {
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(connection) => return Ok(connection),
+                    Err(error) => {
+                        tracing::debug!(reason = ?error, "connection attempt failed");
+                        last_error = Some(error);
+                        attempts.extend(addresses.next().map(|address| self.connect_to(secret, id, address, max_difficulty)));
+                    }
+                },
+                () = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY) => {
+                    attempts.extend(addresses.next().map(|address| self.connect_to(secret, id, address, max_difficulty)));
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow!("No address to connect to")))
+    }
+
+    async fn connect_to(
+        &self,
+        secret: &Secret,
+        id: PublicKey,
+        address: SocketAddr,
+        max_difficulty: u8,
+    ) -> Result<Connection> {
+        let connection = match self {
+            Self::Tcp { proxy: None } => {
+                Connection::connect_to(OsRng, secret, address, id, max_difficulty).await
+            }
+            Self::Tcp { proxy: Some(proxy) } => {
+                let target = address.to_string();
+                Connection::connect_through(OsRng, secret, proxy, &target, id, max_difficulty).await
+            }
+            Self::Memory { network, addresses } => {
+                let local_address = addresses
+                    .iter()
+                    .find(|local| local.is_ipv4() == address.is_ipv4())
+                    .or_else(|| addresses.first())
+                    .copied()
+                    .context("No address to connect from")?;
+                Connection::connect_memory(
+                    OsRng,
+                    secret,
+                    network,
+                    local_address,
+                    address,
+                    id,
+                    max_difficulty,
+                )
+                .await
+            }
+        };
+
+        connection.with_context(|| format!("Cannot connect to {}", address))
+    }
+}
+
 impl Runtime {
     fn new(
         connection: Connection,
         command_receiver: mpsc::Receiver<Command>,
-        message_sender: mpsc::Sender<(PublicKey, Message)>,
+        message_sender: mpsc::Sender<Received>,
+        cover: Option<CoverTraffic>,
+        metrics: Metrics,
     ) -> Self {
+        // the older peers would drop the connection on the cover messages
+        let cover = cover.filter(|_| connection.capabilities().supports_cover_traffic());
         let (inbound, outbound) = connection.into_parts();
 
         Self {
             outbound,
             inbound,
+            cover,
+            metrics,
             command_receiver,
             message_sender,
         }
     }
 
+    fn info(&self, inbound: bool) -> ConnectionInfo {
+        ConnectionInfo {
+            id: *self.inbound.remote_public_identity(),
+            remote_address: self.inbound.remote_address(),
+            session_id: *self.inbound.session_id(),
+            inbound,
+            version: self.inbound.version(),
+            received: self.inbound.traffic_meter(),
+            sent: self.outbound.traffic_meter(),
+        }
+    }
+
+    /// hand the commands not processed yet over to the connection kept
+    /// instead of this one (see [`insert`])
+    fn forward(mut self, kept: &mpsc::Sender<Command>) {
+        while let Some(Some(command)) = self.command_receiver.recv().now_or_never() {
+            let _ = kept.try_send(command);
+        }
+    }
+
     #[tracing::instrument(
         skip(self),
         fields(
@@ -217,27 +665,65 @@ impl Runtime {
         let Self {
             mut inbound,
             mut outbound,
+            cover,
+            metrics,
             mut command_receiver,
             message_sender,
         } = self;
 
         tracing::info!("connected");
 
+        // when the cover traffic is disabled the timer is never polled
+        let next_cover = || {
+            let delay = cover
+                .map(|cover| cover.next_delay(OsRng))
+                .unwrap_or(Duration::from_secs(3_600));
+            tokio::time::Instant::now() + delay
+        };
+        let cover_timer = tokio::time::sleep_until(next_cover());
+        tokio::pin!(cover_timer);
+
         loop {
             tokio::select! {
+                () = &mut cover_timer, if cover.is_some() => {
+                    let message = cover.expect("cover traffic enabled").message(OsRng);
+                    let message_type = message.message_type();
+                    match outbound.send(message).await {
+                        Ok(()) => metrics.message_sent(message_type),
+                        Err(error) => tracing::debug!(reason = ?error, "cannot send cover traffic"),
+                    }
+                    cover_timer.as_mut().reset(next_cover());
+                }
                 result = inbound.next() => {
                     match result {
                         None => {
                             // disconnected
                             break;
                         }
-                        Some((_peer, Err(error))) => {
-                            // TODO: disconnect the whole node maybe?
+                        Some((id, Err(error))) => {
                             tracing::warn!(reason = ?error, "Error while receiving message from peer");
+                            // the network decides whether to disconnect the
+                            // peer from its misbehaviour score
+                            if let Some(offence) = Offence::of_receive_error(&error) {
+                                if let Err(error) = message_sender.send((id, Err(offence))).await {
+                                    tracing::error!(reason = %error, "Cannot report the peer's offence");
+                                    bail!("Error while sending offence to rest of the node: {}", error)
+                                }
+                            }
+                        }
+                        Some((_id, Ok(message))) if message.message_type() == MessageType::Goodbye => {
+                            let (reason, retry_after) = message.goodbye_checked().expect("already know it is a goodbye");
+                            tracing::info!(%reason, ?retry_after, "peer is closing the connection");
+                            break;
+                        }
+                        Some((_id, Ok(message))) if message.message_type() == MessageType::Cover => {
+                            tracing::trace!("discarding cover traffic");
                         }
                         Some((id, Ok(message))) => {
                             tracing::debug!("received new message");
-                            if let Err(error) = message_sender.send((id, message)).await {
+                            let new_frame = inbound.first_of_frame();
+                            let inbound = Inbound { message, new_frame };
+                            if let Err(error) = message_sender.send((id, Ok(inbound))).await {
                                 tracing::error!(reason = %error, "Cannot handle inbound message");
                                 bail!("Error while sending message to rest of the node: {}", error)
                             }
@@ -249,18 +735,54 @@ impl Runtime {
                         None => break,
                         Some(Command::Send(message)) => {
                             tracing::debug!("sending message");
-                            if let Err(error) = outbound.send(message).await {
-                                tracing::warn!(reason = ?error, "cannot forward message message");
+                            let mut messages = downgrade(outbound.capabilities(), message);
+                            let message_types = message_types(&messages);
+                            let result = if messages.len() == 1 {
+                                outbound.send(messages.pop().unwrap()).await
+                            } else {
+                                outbound.send_all(messages).await
+                            };
+                            match result {
+                                Ok(()) => message_types.into_iter().for_each(|message_type| metrics.message_sent(message_type)),
+                                Err(error) => tracing::warn!(reason = ?error, "cannot forward message message"),
+                            }
+                        }
+                        Some(Command::SendAll(messages)) => {
+                            let capabilities = outbound.capabilities();
+                            let messages: Vec<_> = messages
+                                .into_iter()
+                                .flat_map(|message| downgrade(capabilities, message))
+                                .collect();
+                            tracing::debug!(num_messages = messages.len(), "sending messages");
+                            let message_types = message_types(&messages);
+                            match outbound.send_all(messages).await {
+                                Ok(()) => message_types.into_iter().for_each(|message_type| metrics.message_sent(message_type)),
+                                Err(error) => tracing::warn!(reason = ?error, "cannot forward messages"),
                             }
                         }
-                        Some(Command::Gossips(gossips)) => {
+                        Some(Command::Goodbye { reason, retry_after }) => {
+                            tracing::info!(%reason, ?retry_after, "closing connection");
+                            let message = Message::new_goodbye(reason, retry_after);
+                            let message_type = message.message_type();
+                            match outbound.send(message).await {
+                                Ok(()) => metrics.message_sent(message_type),
+                                Err(error) => tracing::debug!(reason = ?error, "cannot send goodbye message"),
+                            }
+                            break;
+                        }
+                        Some(Command::Gossips(gossips, addresses)) => {
                             tracing::debug!(num_gossips = gossips.len(), "sending gossips");
-                            for gossip in gossips {
-                                let message = Message::new_gossip(gossip.as_slice());
-                                if let Err(error) = outbound.send(message).await {
-                                    tracing::warn!(reason = ?error, "cannot forward gossip message");
-                                    break;
-                                }
+                            let capabilities = outbound.capabilities();
+                            let messages: Vec<_> = gossips
+                                .iter()
+                                .map(|gossip| Message::new_gossip(gossip.as_slice()))
+                                .chain(addresses.iter().map(Message::new_addresses))
+                                .flat_map(|message| downgrade(capabilities, message))
+                                .collect();
+                            let message_types = message_types(&messages);
+                            match outbound.send_all(messages).await {
+                                Ok(()) => message_types.into_iter().for_each(|message_type| metrics.message_sent(message_type)),
+                                Err(error) => tracing::warn!(reason = ?error, "cannot forward gossip message"),
                             }
                         }
                     }
@@ -268,63 +790,219 @@ impl Runtime {
             }
         }
 
-        tracing::info!("shutting down");
+        let (received, sent) = (inbound.traffic(), outbound.traffic());
+        tracing::info!(
+            received_frames = received.frames,
+            received_bytes = received.bytes,
+            received_errors = received.errors,
+            sent_frames = sent.frames,
+            sent_bytes = sent.bytes,
+            sent_errors = sent.errors,
+            "shutting down"
+        );
 
         Ok(())
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn connect(
     topology: Topology,
-    message_sender: mpsc::Sender<(PublicKey, Message)>,
+    policy: Policy,
+    peers: Peers,
+    metrics: Metrics,
+    message_sender: mpsc::Sender<Received>,
     command_sender: mpsc::Sender<Command>,
     command_receiver: mpsc::Receiver<Command>,
     secret: Secret,
-    entries: Arc<Mutex<LruCache<PublicKey, mpsc::Sender<Command>>>>,
+    dialer: Dialer,
+    max_difficulty: u8,
+    entries: Entries,
+    cover: Option<CoverTraffic>,
     node: Arc<Profile>,
 ) -> Result<()> {
     let id = node.id();
-    let address = node.address();
+    let mut addresses = topology.dial_addresses(&node);
+    addresses.retain(|address| policy.allows_address(address.ip()));
+    if addresses.is_empty() {
+        topology.demote_peer(&id);
+        bail!("All the addresses of {} are denied by the policy", id)
+    }
 
-    let connection = match Connection::connect_to(OsRng, &secret, address, id).await {
+    let connection = match dialer.connect(&secret, id, addresses, max_difficulty).await {
         Err(error) => {
+            metrics.handshake_failed(false);
             topology.demote_peer(&id);
+            if let Err(error) = peers.failed(node.gossip()) {
+                tracing::warn!(reason = ?error, "Cannot record the failed connection");
+            }
             bail!(error)
         }
         Ok(connection) => connection,
     };
+    if let Err(error) = peers.connected(node.gossip()) {
+        tracing::warn!(reason = ?error, "Cannot record the connection");
+    }
 
-    entries.lock().unwrap().put(id, command_sender);
-    let runtime = Runtime::new(connection, command_receiver, message_sender);
+    let runtime = Runtime::new(connection, command_receiver, message_sender, cover, metrics);
+    let info = runtime.info(false);
+    if let Err(kept) = insert(&entries, &secret, command_sender, info.clone()) {
+        tracing::debug!(peer = %id, "already connected to the peer, closing the new connection");
+        runtime.forward(&kept);
+        return Ok(());
+    }
 
     let r = runtime.run().await;
 
-    entries.lock().unwrap().pop(&id);
+    remove(&entries, &info);
 
     r
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn accept(
-    message_sender: mpsc::Sender<(PublicKey, Message)>,
+    message_sender: mpsc::Sender<Received>,
     secret: Secret,
-    entries: Arc<Mutex<LruCache<PublicKey, mpsc::Sender<Command>>>>,
+    entries: Entries,
+    policy: Policy,
+    handshake: PendingHandshake,
+    timeout: Duration,
+    cover: Option<CoverTraffic>,
+    metrics: Metrics,
     accepting: Accepting<OsRng, SecretKey>,
 ) -> Result<()> {
     let (command_sender, command_receiver) = mpsc::channel(8);
 
-    let connection = accepting
-        .handshake(secret.secret(), |pk| !entries.lock().unwrap().contains(pk))
-        .await?;
+    let connection = tokio::time::timeout(
+        timeout,
+        accepting.handshake(secret.secret(), |pk| {
+            policy.allows_peer(pk) && !entries.lock().unwrap().contains(pk)
+        }),
+    )
+    .await
+    .unwrap_or_else(|_| Err(anyhow!("The handshake timed out")));
+    drop(handshake);
+    if connection.is_err() {
+        metrics.handshake_failed(true);
+    }
+    let connection = connection?;
+
+    let runtime = Runtime::new(connection, command_receiver, message_sender, cover, metrics);
+    let info = runtime.info(true);
+    if let Err(kept) = insert(&entries, &secret, command_sender, info.clone()) {
+        tracing::debug!(peer = %info.id, "already connected to the peer, closing the new connection");
+        runtime.forward(&kept);
+        return Ok(());
+    }
 
-    let id = *connection.remote_public_identity();
+    let r = runtime.run().await;
 
-    entries.lock().unwrap().put(id, command_sender);
+    remove(&entries, &info);
 
-    let runtime = Runtime::new(connection, command_receiver, message_sender);
+    r
+}
 
-    let r = runtime.run().await;
+/// the types of the `messages`, kept to count them once they are sent
+fn message_types(messages: &[Message]) -> Vec<MessageType> {
+    messages.iter().map(Message::message_type).collect()
+}
 
-    entries.lock().unwrap().pop(&id);
+/// adapt the message to the `capabilities` agreed with the peer
+///
+/// the messages the peer does not support are translated to their legacy
+/// equivalent or dropped.
+fn downgrade(capabilities: Capabilities, message: Message) -> Vec<Message> {
+    match message.message_type() {
+        MessageType::Reconcile if !capabilities.supports_reconciliation() => {
+            tracing::debug!(?capabilities, "peer does not support reconciliation");
+            Vec::new()
+        }
+        MessageType::Addresses if !capabilities.supports_peer_addresses() => Vec::new(),
+        MessageType::Rejected if !capabilities.supports_rejection() => Vec::new(),
+        MessageType::QueryTopicMessagesNext if !capabilities.supports_paginated_query() => {
+            tracing::debug!(
+                ?capabilities,
+                "peer does not support paginated queries, reply truncated"
+            );
+            Vec::new()
+        }
+        MessageType::GetPassportBlocks if !capabilities.supports_passport_sync() => {
+            let (id, _) = message
+                .get_passport_blocks_checked()
+                .expect("already know it is a get passport blocks");
+            vec![Message::new_get_passport(id)]
+        }
+        MessageType::PutPassportBlocks if !capabilities.supports_passport_sync() => {
+            let (id, _, blocks) = message
+                .put_passport_blocks_checked()
+                .expect("already know it is a put passport blocks");
+            let topic = passport_topic(&id);
+            blocks
+                .iter()
+                .map(|block| Message::new_topic(topic, block))
+                .collect()
+        }
+        _ => vec![message],
+    }
+}
 
-    r
+/// add the connection to the entries
+///
+/// if both nodes connected to each other at the same time, only the
+/// connection opened by the node with the smallest identity is kept, on
+/// both ends. If the new connection is the one to close, it is not added
+/// and the command sender of the connection kept is returned instead.
+///
+/// if there are already too many connections opened, the least recently
+/// used one is closed.
+fn insert(
+    entries: &Entries,
+    secret: &Secret,
+    command_sender: mpsc::Sender<Command>,
+    info: ConnectionInfo,
+) -> Result<(), mpsc::Sender<Command>> {
+    let mut entries = entries.lock().unwrap();
+    let id = info.id;
+
+    if let Some(entry) = entries.peek(&id) {
+        let opened_by_smallest = info.inbound == (id < secret.secret().public_key());
+        if entry.info.inbound != info.inbound && !opened_by_smallest {
+            return Err(entry.command.clone());
+        }
+    }
+
+    if !entries.contains(&id) && entries.len() >= entries.cap() {
+        if let Some((evicted, entry)) = entries.pop_lru() {
+            tracing::debug!(id = %evicted, "too many connections, closing least recently used");
+            let _ = entry.command.try_send(Command::Goodbye {
+                reason: GoodbyeReason::Idle,
+                retry_after: None,
+            });
+        }
+    }
+
+    entries.put(
+        id,
+        Entry {
+            command: command_sender,
+            info,
+        },
+    );
+
+    Ok(())
+}
+
+/// remove the connection from the entries
+///
+/// the entry may have been replaced by a newer connection with the same
+/// peer already (see [`insert`]), it is then left alone.
+fn remove(entries: &Entries, info: &ConnectionInfo) {
+    let mut entries = entries.lock().unwrap();
+    let replaced = matches!(
+        entries.peek(&info.id),
+        Some(entry) if entry.info.session_id != info.session_id
+    );
+    if !replaced {
+        entries.pop(&info.id);
+    }
 }
@@ -1,41 +1,108 @@
 pub mod config;
 mod connections;
+mod policy;
+mod scoring;
 mod topology;
 
-pub use self::config::Config;
-use self::{connections::Connections, topology::Topology};
-use crate::{secret::Secret, storage::Storage};
+pub use self::{config::Config, connections::ConnectionInfo, policy::IpRange};
+use self::{
+    connections::{Connections, Dialer, Inbound},
+    policy::Policy,
+    scoring::{Offence, Scores},
+    topology::Topology,
+};
+use crate::{
+    metrics::Metrics,
+    secret::Secret,
+    storage::{seen_hash, PassportUpdate, QuotaExceeded, Refused, SeenHash, Storage},
+};
 use anyhow::{anyhow, bail, Context as _, Result};
-use asmtp_network::{net::Listener, Message};
+use asmtp_lib::{passport_id, passport_topic};
+use asmtp_network::{
+    net::{Accepting, Listener, MemoryNetwork},
+    GoodbyeReason, Message, PeerAddresses, Reconciliation, RejectReason,
+};
 use bytes::Bytes;
+use futures::future;
 use indexmap::IndexSet;
 use keynesis::{
-    hash::Blake2b,
     key::{ed25519, Dh as _},
+    passport::{
+        block::{BlockSlice, Hash, Previous, Time},
+        PassportBlocks, PassportBlocksSlice,
+    },
 };
 use lru::LruCache;
-use poldercast::{layer::Selection, Topic};
+use poldercast::{layer::Selection, Profile, Topic};
 use rand::{rngs::OsRng, RngCore as _};
-use std::time::Duration;
-use tokio::{sync::mpsc, task::JoinHandle, time::Instant};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+
+/// the minimal time between two notifications of the same user its quota
+/// is exceeded (see [`Runner::quota_exceeded`])
+const QUOTA_NOTIFICATION_INTERVAL: Duration = Duration::from_secs(10);
+
+/// the number of users remembered as recently notified their quota is
+/// exceeded
+const QUOTA_NOTIFICATION_HISTORY: usize = 256;
 
 pub struct Network {
-    command: mpsc::Sender<Command>,
+    control: Control,
     handle: JoinHandle<Result<()>>,
 }
 
+/// control the running network (see [`Network::control`])
+///
+/// this object can be cheaply cloned and used from other tasks.
+#[derive(Clone)]
+pub struct Control {
+    command: mpsc::Sender<Command>,
+    policy: Policy,
+    topology: Topology,
+    metrics: Metrics,
+}
+
 struct Runner {
     topology: Topology,
+    policy: Policy,
     storage: Storage,
     connections: Connections,
-    listener: Listener,
+    listeners: Vec<Listener>,
     command: mpsc::Receiver<Command>,
     known_cache: MessageCache,
+    scores: Scores,
     gossipers: GossipCache,
+    next_reconciliation: Instant,
+    quota_notified: LruCache<String, Instant>,
+    reconciling: Reconciling,
+    metrics: Metrics,
     config: Config,
     id: ed25519::PublicKey,
 }
 
+/// the number of reconciliations of each peer being answered (see
+/// [`config::Reconciliation::max_requests`])
+///
+/// the reconciliations are answered on tasks of their own, the storage
+/// work is not done on the network's task.
+#[derive(Clone, Default)]
+struct Reconciling(Arc<Mutex<HashMap<ed25519::PublicKey, usize>>>);
+
+/// a reconciliation of the peer being answered, until dropped
+struct ReconcilingGuard {
+    reconciling: Reconciling,
+    peer: ed25519::PublicKey,
+}
+
 struct GossipCache {
     queue_size: usize,
     min_elapsed: Duration,
@@ -45,33 +112,136 @@ struct GossipCache {
 
 enum Command {
     Shutdown,
-    Subscriptions { add: Vec<Topic>, remove: Vec<Topic> },
+    Subscriptions {
+        add: Vec<Topic>,
+        remove: Vec<Topic>,
+    },
+    Ban {
+        id: ed25519::PublicKey,
+        duration: Option<Duration>,
+    },
+    Connections(oneshot::Sender<Vec<ConnectionInfo>>),
+    Gossip,
+    Reload(Box<Config>),
 }
 
+/// the error handling a message of a peer
+///
+/// only the peer's faults add to its misbehaviour score, the node failing
+/// to handle a valid message (storage errors...) is not the peer's fault.
+#[derive(Debug, Error)]
+enum HandleError {
+    /// the message breaks the protocol or is refused by the storage (see
+    /// [`Refused`])
+    #[error("{0:#}")]
+    Peer(anyhow::Error),
+    #[error("{0:#}")]
+    Local(anyhow::Error),
+}
+
+impl From<anyhow::Error> for HandleError {
+    fn from(error: anyhow::Error) -> Self {
+        if error.downcast_ref::<Refused>().is_some() {
+            Self::Peer(error)
+        } else {
+            Self::Local(error)
+        }
+    }
+}
+
+/// the messages seen the most recently, in front of the
+/// [`SeenMessages`](crate::storage::SeenMessages) of the storage
 pub struct MessageCache {
-    max: usize,
-    messages: IndexSet<[u8; 32]>,
+    messages: LruCache<SeenHash, ()>,
 }
 
 impl MessageCache {
     pub fn new(config: &Config) -> Self {
         Self {
-            max: config.known_message_cache_size,
-            messages: IndexSet::with_capacity(config.known_message_cache_size),
+            messages: LruCache::new(config.known_message_cache_size.max(1)),
+        }
+    }
+
+    /// remember the message, returns `true` if it was not in the cache
+    pub fn check(&mut self, hash: SeenHash) -> bool {
+        if self.messages.contains(&hash) {
+            return false;
         }
+
+        self.messages.put(hash, ());
+        true
     }
 
-    pub fn check(&mut self, obj: impl AsRef<[u8]>) -> bool {
-        let obj = obj.as_ref();
-        let mut hash = [0; 32];
-        Blake2b::blake2b(&mut hash, obj, &[]);
+    /// change the maximum number of known messages
+    ///
+    /// the oldest messages are forgotten right away if there are too many.
+    pub fn resize(&mut self, config: &Config) {
+        self.messages.resize(config.known_message_cache_size.max(1));
+    }
+}
+
+impl Reconciling {
+    /// start answering a reconciliation of the `peer`, unless `max` of its
+    /// reconciliations are being answered already
+    fn start(&self, peer: ed25519::PublicKey, max: usize) -> Option<ReconcilingGuard> {
+        let mut peers = self.0.lock().unwrap();
+        let count = peers.get(&peer).copied().unwrap_or(0);
+        if count >= max {
+            return None;
+        }
+        peers.insert(peer, count + 1);
+
+        Some(ReconcilingGuard {
+            reconciling: self.clone(),
+            peer,
+        })
+    }
+}
 
-        while self.messages.len() >= self.max {
-            self.messages.pop();
+impl Drop for ReconcilingGuard {
+    fn drop(&mut self) {
+        let mut peers = self.reconciling.0.lock().unwrap();
+        if let Some(count) = peers.get_mut(&self.peer) {
+            *count -= 1;
+            if *count == 0 {
+                peers.remove(&self.peer);
+            }
         }
+    }
+}
 
-        self.messages.insert(hash)
+/// the messages replying to the peer's `reconciliation`
+///
+/// only the hashes of our messages are loaded to compute the reply, then
+/// the content of the messages to send to the peer.
+async fn reply_reconciliation(
+    storage: &Storage,
+    config: &config::Reconciliation,
+    reconciliation: Reconciliation,
+) -> Result<Vec<Message>> {
+    let topic = *reconciliation.topic();
+    if !storage.contains_messages_of(&topic).await? {
+        tracing::debug!(topic = ?topic, "not keeping the messages of the topic to reconcile");
+        return Ok(Vec::new());
     }
+
+    let items = storage
+        .message_hashes(&topic, reconciliation.since())
+        .await?;
+    let (reply, send) =
+        reconciliation.reply_bounded(&items, config.max_ranges, config.max_messages);
+
+    let contents = storage.messages_by_hash(&topic, &send).await?;
+    let mut messages = Vec::with_capacity(contents.len() + 1);
+    for content in contents {
+        // the peer may relay the message back to us
+        storage.seen_messages().check(&seen_hash(&content))?;
+        messages.push(Message::new_topic(topic, &content));
+    }
+    if let Some(reply) = reply {
+        messages.push(Message::new_reconcile(&reply));
+    }
+    Ok(messages)
 }
 
 impl GossipCache {
@@ -84,6 +254,15 @@ impl GossipCache {
         }
     }
 
+    fn reconfigure(&mut self, config: &Config) {
+        self.queue_size = config.gossiping.queue_size;
+        self.min_elapsed = config.gossiping.minimum_time_elapsed;
+        self.has_gossiped.resize(config.gossiping.history_size);
+        while self.will_gossip.len() > self.queue_size {
+            self.will_gossip.pop();
+        }
+    }
+
     fn is_empty(&self) -> bool {
         self.will_gossip.is_empty()
     }
@@ -112,23 +291,98 @@ impl GossipCache {
 
         self.will_gossip.insert(id);
     }
+
+    /// register the interest in gossiping with the peer even if we
+    /// gossiped with it recently: our profile changed since
+    fn renew_interest(&mut self, id: ed25519::PublicKey) {
+        self.has_gossiped.pop(&id);
+        self.register_interest(id);
+    }
 }
 
 impl Network {
     pub async fn new(secret: Secret, storage: Storage, config: Config) -> Result<Self> {
+        let listen_addresses = config.listen_addresses();
+        tracing::info!(
+            listen_addresses = ?listen_addresses,
+            public_addresses = ?config.public_addresses(),
+            "listening for inbound connections"
+        );
+        let mut listeners = Vec::with_capacity(listen_addresses.len());
+        for address in listen_addresses.iter().copied() {
+            // leave the IPv4 connections to the IPv4 listener on the same port
+            let dual_stack = address.is_ipv6()
+                && listen_addresses
+                    .iter()
+                    .any(|other| other.is_ipv4() && other.port() == address.port());
+            let listener = if dual_stack {
+                Listener::ipv6_only(address)?
+            } else {
+                Listener::new(address).await?
+            };
+            listeners.push(listener);
+        }
+        if let Some(websocket_listen_address) = config.websocket_listen_address {
+            tracing::info!(
+                websocket_listen_address = %websocket_listen_address,
+                "listening for inbound WebSocket connections"
+            );
+            listeners.push(Listener::websocket(websocket_listen_address).await?);
+        }
+
+        let dialer = Dialer::Tcp {
+            proxy: config.proxy.clone().map(|proxy| proxy.0),
+        };
+
+        Self::start(secret, storage, config, listeners, dialer).await
+    }
+
+    /// start the network on the given in-memory network instead of TCP
+    ///
+    /// the node listens at the listen addresses of the `config` and opens
+    /// the outbound connections from its public addresses. This is useful
+    /// to run multiple nodes within the same process (see
+    /// [`simulation`](crate::simulation)).
+    pub async fn new_in_memory(
+        secret: Secret,
+        storage: Storage,
+        config: Config,
+        network: MemoryNetwork,
+    ) -> Result<Self> {
+        let listeners = config
+            .listen_addresses()
+            .into_iter()
+            .map(|address| Listener::memory(&network, address))
+            .collect::<Result<_>>()?;
+        let dialer = Dialer::Memory {
+            network,
+            addresses: config.public_addresses(),
+        };
+
+        Self::start(secret, storage, config, listeners, dialer).await
+    }
+
+    async fn start(
+        secret: Secret,
+        storage: Storage,
+        config: Config,
+        listeners: Vec<Listener>,
+        dialer: Dialer,
+    ) -> Result<Self> {
         let (command_sender, command_receiver) = mpsc::channel(8);
 
         let id = secret.public();
-
-        let listen_address = config.listen_address;
         let public_address = config.public_address;
-        tracing::info!(
-            listen_address = %listen_address,
-            public_address = %public_address,
-            "listening for inbound connections"
-        );
-        let listener = Listener::new(listen_address).await?;
-        let topology = Topology::new(public_address, secret.clone());
+
+        let policy = Policy::new(&config.policy);
+        let metrics = Metrics::new();
+        let topology = Topology::new(public_address, secret.clone(), policy.clone());
+        if !config.additional_public_addresses.is_empty() {
+            let addresses =
+                PeerAddresses::new(secret.secret(), Time::now(), &config.public_addresses())
+                    .context("Cannot advertise the public addresses")?;
+            topology.advertise(addresses);
+        }
 
         // load the initial subscriptions from the storage
         //
@@ -148,13 +402,37 @@ impl Network {
             topology.accept_gossip(gossip);
         }
 
-        let runner = Runner {
+        let connections = Connections::new(
+            secret,
+            topology.clone(),
+            policy.clone(),
+            storage.peers().clone(),
+            metrics.clone(),
+            dialer,
+            &config,
+        )
+        .context("Cannot set the network's connections")?;
+
+        let control = Control {
+            command: command_sender,
+            policy: policy.clone(),
             topology: topology.clone(),
+            metrics: metrics.clone(),
+        };
+
+        let runner = Runner {
+            topology,
+            policy,
             storage,
-            connections: Connections::new(secret, topology, &config),
+            connections,
             known_cache: MessageCache::new(&config),
+            scores: Scores::new(&config.scoring),
             gossipers: GossipCache::new(&config),
-            listener,
+            next_reconciliation: Instant::now() + config.reconciliation.interval,
+            quota_notified: LruCache::new(QUOTA_NOTIFICATION_HISTORY),
+            reconciling: Reconciling::default(),
+            metrics,
+            listeners,
             command: command_receiver,
             config,
             id,
@@ -165,14 +443,17 @@ impl Network {
             runner.run().await
         });
 
-        Ok(Self {
-            command: command_sender,
-            handle,
-        })
+        Ok(Self { control, handle })
+    }
+
+    /// get a handle to control the network while it is running
+    pub fn control(&self) -> &Control {
+        &self.control
     }
 
     pub async fn update_subscriptions(&self, add: Vec<Topic>, remove: Vec<Topic>) -> Result<()> {
-        self.command
+        self.control
+            .command
             .send(Command::Subscriptions { add, remove })
             .await
             .map_err(|_| anyhow!("Cannot subscriptions update command to the network"))?;
@@ -181,7 +462,8 @@ impl Network {
     }
 
     pub async fn shutdown(self) -> Result<()> {
-        self.command
+        self.control
+            .command
             .send(Command::Shutdown)
             .await
             .map_err(|_| anyhow!("Cannot send shutdown command to the network"))?;
@@ -203,6 +485,81 @@ impl Network {
     }
 }
 
+impl Control {
+    /// the opened connections, the most recently used first
+    pub async fn connections(&self) -> Result<Vec<ConnectionInfo>> {
+        let (reply, connections) = oneshot::channel();
+        self.command
+            .send(Command::Connections(reply))
+            .await
+            .map_err(|_| anyhow!("Cannot send connections command to the network"))?;
+
+        connections
+            .await
+            .map_err(|_| anyhow!("The network did not reply with the connections"))
+    }
+
+    /// the metrics of the node
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// the peers of the topology
+    pub fn view(&self) -> Vec<Arc<Profile>> {
+        self.topology.view_for(None, Selection::Any)
+    }
+
+    /// gossip with the peers of the topology without waiting for the
+    /// next gossiping round
+    ///
+    /// the peers we gossiped with recently (see
+    /// [`minimum_time_elapsed`](config::Gossip::minimum_time_elapsed)) are
+    /// skipped.
+    pub async fn gossip(&self) -> Result<()> {
+        self.command
+            .send(Command::Gossip)
+            .await
+            .map_err(|_| anyhow!("Cannot send gossip command to the network"))?;
+
+        Ok(())
+    }
+
+    /// stop talking to the peer `id`, for the given duration or forever
+    ///
+    /// the connection with the peer is closed and the peer is removed from
+    /// the topology.
+    pub async fn ban(&self, id: ed25519::PublicKey, duration: Option<Duration>) -> Result<()> {
+        self.command
+            .send(Command::Ban { id, duration })
+            .await
+            .map_err(|_| anyhow!("Cannot send ban command to the network"))?;
+
+        Ok(())
+    }
+
+    /// apply the settings of the `config` that can be changed while the
+    /// network is running
+    ///
+    /// the other settings (see [`Config::restart_required`]) are ignored.
+    /// The peers the new policy denies are disconnected and removed from
+    /// the topology, the new known gossips are added to the topology.
+    pub async fn reload(&self, config: Config) -> Result<()> {
+        self.command
+            .send(Command::Reload(Box::new(config)))
+            .await
+            .map_err(|_| anyhow!("Cannot send reload command to the network"))?;
+
+        Ok(())
+    }
+
+    /// lift the ban of the peer `id`
+    ///
+    /// returns `false` if the peer was not banned with [`Control::ban`].
+    pub fn unban(&self, id: &ed25519::PublicKey) -> bool {
+        self.policy.unban(id)
+    }
+}
+
 impl Runner {
     #[tracing::instrument(
         skip(self),
@@ -220,7 +577,13 @@ impl Runner {
             self.gossipers.register_interest(profile.id());
         }
 
+        let mut heart_beat = self.heart_beat();
+
         loop {
+            if heart_beat.period() != self.heart_beat_period() {
+                heart_beat = self.heart_beat();
+            }
+
             if let Some(id) = self.gossipers.next_gossip_peer() {
                 if let Some(gossiper) = self.topology.get(&id) {
                     let gossips = self.topology.gossips_for(&id);
@@ -229,6 +592,7 @@ impl Runner {
                         num_gossips = gossips.len(),
                         "sending gossips"
                     );
+                    self.metrics.gossip_round();
                     if let Err(error) = self.connections.send_gossips(gossiper, gossips).await {
                         tracing::warn!(reason = %error, peer = %id, "Cannot send gossip to peer")
                     }
@@ -252,28 +616,56 @@ impl Runner {
                 self.storage.update_known_gossips(gossips)?;
             }
 
+            let interval = self.config.reconciliation.interval;
+            if !interval.is_zero() && self.next_reconciliation <= Instant::now() {
+                self.next_reconciliation = Instant::now() + interval;
+                if let Err(error) = self.start_reconciliation().await {
+                    tracing::warn!(reason = %error, "Cannot start reconciliation")
+                }
+            }
+
             tokio::select! {
-                _ = tokio::time::sleep(self.config.heart_beat) => {
-                    self.beat()
+                _ = heart_beat.tick() => {
+                    if self.topology.commit_profile() {
+                        self.gossip_profile();
+                    }
+                    self.beat().await
                 }
                 // handle receiving commands
                 command = self.command.recv() => {
                     let stop = self.handle_command(command).await?;
                     if stop { break; }
                 }
-                // accept new connections from the listener
+                // accept new connections from the listeners
                 //
                 // new connections handshake will run within another task
                 // and will be queued until completion in the `accepting_tasks`
-                accepting = self.listener.accept::<_, ed25519::SecretKey>(OsRng) => {
+                accepting = accept(&self.listeners) => {
                     let accepting = accepting.context("failed to accept a new connection")?;
                     self.connections.accept(accepting).await;
                 }
 
                 // receiving messages from the connections
-                (peer, message) = self.connections.receive() => {
-                    if let Err(error) = self.handle_message(peer, message).await {
-                        tracing::warn!(reason = %error, peer = %peer, "failed to handle peer's message")
+                (peer, received) = self.connections.receive() => {
+                    match received {
+                        // the whole frame is dropped, the peer is penalized once
+                        Ok(inbound) if !self.scores.received(peer, inbound.new_frame) => {
+                            tracing::debug!(peer = %peer, "dropping message beyond the rate limit");
+                            if inbound.new_frame {
+                                self.misbehaved(peer, Offence::RateLimited);
+                            }
+                        }
+                        Ok(Inbound { message, .. }) => match self.handle_message(peer, message).await {
+                            Ok(()) => {}
+                            Err(HandleError::Peer(error)) => {
+                                tracing::warn!(reason = %error, peer = %peer, "invalid message from the peer");
+                                self.misbehaved(peer, Offence::InvalidMessage);
+                            }
+                            Err(HandleError::Local(error)) => {
+                                tracing::warn!(reason = %error, peer = %peer, "failed to handle peer's message");
+                            }
+                        },
+                        Err(offence) => self.misbehaved(peer, offence),
                     }
                 }
             }
@@ -282,29 +674,296 @@ impl Runner {
         Ok(())
     }
 
-    fn beat(&self) {
-        let number_connections = self.connections.number_connections();
+    /// record the `offence` of the `peer`
+    ///
+    /// the peer reaching the misbehaviour threshold is demoted from the
+    /// topology, disconnected and banned for a while. The ban is recorded
+    /// in the peers database so the peer is not favoured on the next
+    /// start (see [`PeerRecord::misbehaviour`]).
+    ///
+    /// [`PeerRecord::misbehaviour`]: crate::storage::PeerRecord::misbehaviour
+    fn misbehaved(&mut self, peer: ed25519::PublicKey, offence: Offence) {
+        if self.scores.penalize(peer, offence) {
+            let duration = Some(self.config.scoring.ban_duration);
+            tracing::warn!(peer = %peer, %offence, ?duration, "banning misbehaving peer");
+            if let Err(error) = self.storage.peers().misbehaved(&peer, 1) {
+                tracing::warn!(reason = ?error, peer = %peer, "Cannot record the peer's misbehaviour")
+            }
+            self.policy.ban(peer, duration);
+            self.connections
+                .goodbye(&peer, GoodbyeReason::Banned, duration);
+            self.topology.demote_peer(&peer);
+        } else {
+            let score = self.scores.score(&peer);
+            tracing::debug!(peer = %peer, %offence, score, "peer misbehaved");
+        }
+    }
+
+    /// the interval driving [`Runner::beat`], first ticking one
+    /// `heart_beat` from now. A late tick delays the next ones rather
+    /// than bursting to catch up.
+    fn heart_beat(&self) -> tokio::time::Interval {
+        let period = self.heart_beat_period();
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    }
+
+    /// `heart_beat`, at least a millisecond as an interval cannot be zero
+    fn heart_beat_period(&self) -> Duration {
+        self.config.heart_beat.max(Duration::from_millis(1))
+    }
+
+    /// update the metrics that are not updated as the events happen
+    async fn beat(&mut self) {
+        self.scores.forget_decayed();
+
+        let connections = self.connections.connections();
+        let number_connections = connections.len();
+        let inbound = connections.iter().filter(|info| info.inbound).count();
+        self.metrics
+            .connections(inbound, number_connections - inbound);
+
+        self.metrics
+            .queue_depth("messages", self.connections.queue_depth());
+        self.metrics
+            .queue_depth("gossip", self.gossipers.will_gossip.len());
+
+        let stored = future::try_join(
+            self.storage.number_passports(),
+            self.storage.number_threads(),
+        );
+        match stored.await {
+            Ok((passports, threads)) => self.metrics.stored(passports, threads),
+            Err(error) => tracing::warn!(reason = ?error, "Cannot count the stored items"),
+        }
 
-        // TODO:
-        // this is an opportunity to start displaying some useful information
-        // such as the number of opened connections or the number of messages
-        // the network sent or received etc...
         tracing::info!(number_connections, "beat");
     }
 
+    /// start the reconciliation of the messages of a random topic with
+    /// a random peer subscribed to it
+    async fn start_reconciliation(&mut self) -> Result<()> {
+        let topics = self.storage.message_topics().await?;
+        if topics.is_empty() {
+            return Ok(());
+        }
+        let topic = topics[OsRng.next_u32() as usize % topics.len()];
+
+        let peers = self.topology.view_for(None, Selection::Topic { topic });
+        if peers.is_empty() {
+            return Ok(());
+        }
+        let peer = peers[OsRng.next_u32() as usize % peers.len()].clone();
+
+        let since = self.reconciliation_since();
+        let id = peer.id();
+        let sender = self.connections.sender(peer)?;
+        let storage = self.storage.clone();
+        tokio::spawn(async move {
+            let items = match storage.message_hashes(&topic, since).await {
+                Ok(items) => items,
+                Err(error) => {
+                    tracing::warn!(reason = ?error, "Cannot start the reconciliation");
+                    return;
+                }
+            };
+
+            tracing::debug!(topic = ?topic, peer = %id, num_messages = items.len(), "starting reconciliation");
+            let reconciliation = Reconciliation::new(topic, since, &items);
+            sender.send(Message::new_reconcile(&reconciliation)).await
+        });
+        Ok(())
+    }
+
+    /// tell the clients of the user connected to the node that a message
+    /// was not kept as the user's quota is exceeded
+    ///
+    /// the peer the message came from is not told unless it acts for the
+    /// user: the author of the message, or the node relaying it, cannot
+    /// do anything about it.
+    ///
+    /// finding the clients of the user may load its passport from the
+    /// storage, so a user is told at most once every
+    /// [`QUOTA_NOTIFICATION_INTERVAL`].
+    async fn quota_exceeded(&mut self, exceeded: &QuotaExceeded) -> Result<()> {
+        let now = Instant::now();
+        if let Some(notified) = self.quota_notified.get(&exceeded.user) {
+            if now.duration_since(*notified) < QUOTA_NOTIFICATION_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.quota_notified.put(exceeded.user.clone(), now);
+
+        for connection in self.connections.connections() {
+            if self
+                .storage
+                .is_acting_for(&connection.id, &exceeded.user)
+                .await?
+            {
+                let message = Message::new_rejected(RejectReason::QuotaExceeded, exceeded.topic);
+                self.connections.send_to_peer(&connection.id, message).await
+            }
+        }
+        Ok(())
+    }
+
+    /// the oldest messages to reconcile (see [`config::Reconciliation::window`])
+    fn reconciliation_since(&self) -> Time {
+        let window = self.config.reconciliation.window.as_secs();
+        Time::from(Time::now().saturating_sub(window.min(u32::MAX as u64) as u32))
+    }
+
+    /// apply the new blocks of the passport `id` received from the `peer`
+    ///
+    /// the blocks we did not have yet are sent to the other peers subscribed
+    /// to the passport. If we are missing blocks, they are queried from
+    /// the `peer`.
+    async fn put_passport_blocks(
+        &mut self,
+        peer: ed25519::PublicKey,
+        id: Hash,
+        head: Hash,
+        blocks: PassportBlocksSlice<'_>,
+    ) -> Result<()> {
+        match self
+            .storage
+            .handle_passport_blocks(id, head, blocks)
+            .await?
+        {
+            PassportUpdate::UpToDate => {}
+            PassportUpdate::Behind { head } => {
+                tracing::debug!(passport = %id, peer = %peer, "missing passport blocks");
+                self.connections
+                    .send_to_peer(&peer, Message::new_get_passport_blocks(id, head))
+                    .await
+            }
+            PassportUpdate::Updated { head, blocks } => {
+                tracing::info!(passport = %id, peer = %peer, "received new passport blocks");
+                let topic = passport_topic(&id);
+                let view = self
+                    .topology
+                    .view_for(Some(&peer), Selection::Topic { topic });
+                let message = Message::new_put_passport_blocks(id, head, blocks.as_slice());
+
+                self.connections.send_all(view, message).await;
+            }
+        }
+
+        Ok(())
+    }
+
     async fn handle_command(&mut self, command: Option<Command>) -> Result<bool> {
         match command {
             None => bail!("failed to receive anymore commands"),
-            Some(Command::Shutdown) => Ok(true),
+            Some(Command::Shutdown) => {
+                self.connections.goodbye_all(GoodbyeReason::Shutdown, None);
+                Ok(true)
+            }
             Some(Command::Subscriptions { add, remove }) => {
-                self.topology.subscriptions(add, remove);
+                self.update_subscriptions(add, remove);
+                Ok(false)
+            }
+            Some(Command::Ban { id, duration }) => {
+                tracing::info!(id = %id, ?duration, "banning peer");
+                self.policy.ban(id, duration);
+                self.connections
+                    .goodbye(&id, GoodbyeReason::Banned, duration);
+                self.topology.demote_peer(&id);
+                Ok(false)
+            }
+            Some(Command::Connections(reply)) => {
+                let _ = reply.send(self.connections.connections());
+                Ok(false)
+            }
+            Some(Command::Gossip) => {
+                for profile in self.topology.view_for(None, Selection::Any) {
+                    self.gossipers.register_interest(profile.id());
+                }
                 Ok(false)
             }
+            Some(Command::Reload(config)) => {
+                self.reload(*config);
+                Ok(false)
+            }
+        }
+    }
+
+    /// update our subscriptions and gossip our new profile to the peers
+    /// of our view right away, so they learn of the change without
+    /// waiting for the next gossip rounds
+    ///
+    /// the profile committed within the second of the one gossiped is
+    /// committed on a following heart beat instead (see
+    /// [`Topology::subscriptions`]).
+    fn update_subscriptions(&mut self, add: Vec<Topic>, remove: Vec<Topic>) {
+        if self.topology.subscriptions(add, remove) {
+            self.gossip_profile();
+        }
+    }
+
+    /// gossip our new profile to the peers of our view
+    fn gossip_profile(&mut self) {
+        for profile in self.topology.view_for(None, Selection::Any) {
+            self.gossipers.renew_interest(profile.id());
+        }
+    }
+
+    fn reload(&mut self, config: Config) {
+        tracing::info!("reloading the network settings");
+
+        self.policy.update(&config.policy);
+        for info in self.connections.connections() {
+            if !self.policy.allows_peer(&info.id)
+                || !self.policy.allows_address(info.remote_address.ip())
+            {
+                tracing::info!(id = %info.id, "closing connection denied by the policy");
+                self.connections
+                    .goodbye(&info.id, GoodbyeReason::Banned, None);
+            }
         }
+        for profile in self.topology.view_for(None, Selection::Any) {
+            if !self.policy.allows_gossip(profile.gossip()) {
+                self.topology.demote_peer(&profile.id());
+            }
+        }
+
+        self.connections.reconfigure(&config);
+        self.known_cache.resize(&config);
+        self.scores.reconfigure(&config.scoring);
+        self.gossipers.reconfigure(&config);
+
+        for gossip in config.known_gossips.iter() {
+            if !self.config.known_gossips.contains(gossip) {
+                self.topology.accept_gossip(gossip.0.clone());
+            }
+        }
+
+        if config.reconciliation.interval != self.config.reconciliation.interval {
+            self.next_reconciliation = Instant::now() + config.reconciliation.interval;
+        }
+
+        self.config.max_opened_connections = config.max_opened_connections;
+        self.config.handshake_timeout = config.handshake_timeout;
+        self.config.max_pending_handshakes = config.max_pending_handshakes;
+        self.config.known_message_cache_size = config.known_message_cache_size;
+        self.config.gossiping = config.gossiping;
+        self.config.puzzle.pending_handshakes = config.puzzle.pending_handshakes;
+        self.config.puzzle.max_difficulty = config.puzzle.max_difficulty;
+        self.config.reconciliation = config.reconciliation;
+        self.config.policy = config.policy;
+        self.config.scoring = config.scoring;
+        self.config.heart_beat = config.heart_beat;
+        self.config.known_gossips = config.known_gossips;
     }
 
-    async fn handle_message(&mut self, peer: ed25519::PublicKey, message: Message) -> Result<()> {
+    async fn handle_message(
+        &mut self,
+        peer: ed25519::PublicKey,
+        message: Message,
+    ) -> Result<(), HandleError> {
         tracing::debug!(message = ?message.message_type(), peer = %peer, "Handling incoming message");
+        self.metrics.message_received(message.message_type());
 
         // ********************************************************************
         //
@@ -316,40 +975,153 @@ impl Runner {
         if let Some(gossip) = message.gossip_checked() {
             self.gossipers.register_interest(peer);
             self.topology.accept_gossip(gossip.to_owned());
+        } else if let Some(addresses) = message.addresses_checked() {
+            let id = addresses.id();
+            if !self.topology.accept_addresses(addresses) {
+                tracing::trace!(id = %id, "ignoring outdated addresses or addresses of an unknown peer");
+            }
+        } else if let Some((reason, topic)) = message.rejected_checked() {
+            tracing::debug!(peer = %peer, topic = ?topic, %reason, "peer rejected our message");
         } else if let Some((topic, content)) = message.topic_checked() {
-            if !self.known_cache.check(&content) {
+            let hash = seen_hash(content);
+            if !self.known_cache.check(hash) {
+                self.metrics.message_cache(true);
                 return Ok(());
             }
-            tracing::debug!(topic = ?topic, "received original message");
 
-            // propagate the topic message to other services
-            self.storage
+            if let Some(id) = passport_id(&topic) {
+                if self.storage.contains_passport(id).await? {
+                    let original = self.storage.seen_messages().check(&hash)?;
+                    self.metrics.message_cache(!original);
+                    if !original {
+                        return Ok(());
+                    }
+                    tracing::debug!(topic = ?topic, "received original message");
+
+                    // the peers not supporting the passport sync send the
+                    // new blocks of the passports one by one
+                    let block = BlockSlice::try_from_slice(content)
+                        .with_context(|| format!("cannot handle new block for passport {}", id))
+                        .map_err(HandleError::Peer)?;
+                    let head = match block.header().previous() {
+                        Previous::Previous(head) => head,
+                        Previous::None => {
+                            return Err(HandleError::Peer(anyhow!(
+                                "Unexpected genesis block for passport {}",
+                                id
+                            )))
+                        }
+                    };
+                    let blocks: PassportBlocks<Vec<u8>> = std::iter::once(block).collect();
+                    self.put_passport_blocks(peer, id, head, blocks.as_slice())
+                        .await?;
+                    return Ok(());
+                }
+            }
+
+            // propagate the topic message to other services, the storage
+            // tells if the message was seen already
+            let original = match self
+                .storage
                 .handle_incoming_message(topic, Bytes::from(content.to_vec()))
-                .await?;
+                .await
+            {
+                Ok(original) => original,
+                // the message is still relayed, the other nodes may
+                // have room for it
+                Err(error) => match error.downcast_ref::<QuotaExceeded>() {
+                    Some(exceeded) => {
+                        tracing::info!(peer = %peer, reason = %exceeded, "rejecting message");
+                        self.quota_exceeded(exceeded).await?;
+                        true
+                    }
+                    None => return Err(error.into()),
+                },
+            };
+            self.metrics.message_cache(!original);
+            if !original {
+                return Ok(());
+            }
+            tracing::debug!(topic = ?topic, "received original message");
 
             let view = self
                 .topology
                 .view_for(Some(&peer), Selection::Topic { topic });
 
             self.connections.send_all(view, message).await;
-        } else if let Some((topic, time)) = message.query_topic_messages_checked() {
-            let messages = self.storage.messages(topic, time).await?;
+        } else if let Some(query) = message.topic_query_checked() {
+            let topic = *query.topic();
+            let (messages, next) = self.storage.messages(&query).await?;
             // todo: here we are blocking the current task by
             // processing all the messages. This is a bit non
             // productive, instead we should do that in a separate
             // threads/task : `task::spawn` and forget with a clone
-            for message in messages {
-                self.connections
-                    .send_to_peer(&peer, Message::new_topic(topic, &message))
-                    .await
+            let mut messages: Vec<_> = messages
+                .iter()
+                .map(|message| Message::new_topic(topic, message))
+                .collect();
+            // the reply is truncated to the query limit: the cursor tells
+            // the peer, even if it did not paginate its query (the peers not
+            // supporting paginated queries are not sent it, see
+            // `connections::downgrade`)
+            if let Some(cursor) = next {
+                messages.push(Message::new_query_topic_messages_next(topic, cursor));
             }
+            self.connections.send_all_to_peer(&peer, messages).await
+        } else if let Some(reconciliation) = message.reconcile_checked() {
+            let reconciliation = reconciliation.not_before(self.reconciliation_since());
+            let max_requests = self.config.reconciliation.max_requests;
+            let guard = if let Some(guard) = self.reconciling.start(peer, max_requests) {
+                guard
+            } else {
+                tracing::debug!(peer = %peer, "too many reconciliations of the peer, ignored");
+                return Ok(());
+            };
+            let sender = if let Some(sender) = self.connections.peer_sender(&peer) {
+                sender
+            } else {
+                return Ok(());
+            };
+
+            let storage = self.storage.clone();
+            let config = self.config.reconciliation.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                let reply = reply_reconciliation(&storage, &config, reconciliation).await;
+                match reply {
+                    Ok(messages) => sender.send_all(messages).await,
+                    Err(error) => {
+                        tracing::warn!(reason = ?error, "Cannot reply to the reconciliation")
+                    }
+                }
+            });
         }
         // ********************************************************************
         //
         // the following operations are additions from the poldercast protocol
         // and are used to exchange passport across the network as requested
         //
-        else if let Some(id) = message.get_passport_checked() {
+        else if let Some((id, head)) = message.get_passport_blocks_checked() {
+            match self.storage.passport_blocks_since(id, head).await? {
+                Some(blocks) if blocks.as_slice().is_empty() => {}
+                Some(blocks) => {
+                    let message = Message::new_put_passport_blocks(id, head, blocks.as_slice());
+                    self.connections.send_to_peer(&peer, message).await
+                }
+                None => {
+                    // the peer's head is not one of our blocks, send the
+                    // whole passport instead
+                    if let Some(blocks) = self.storage.handle_get_passport(id).await? {
+                        let message = Message::new_put_passport(id, blocks.as_slice());
+                        self.connections.send_to_peer(&peer, message).await
+                    }
+                }
+            }
+        } else if let Some((id, head, blocks)) = message.put_passport_blocks_checked() {
+            if self.storage.contains_passport(id).await? {
+                self.put_passport_blocks(peer, id, head, blocks).await?
+            }
+        } else if let Some(id) = message.get_passport_checked() {
             let blocks = self
                 .storage
                 .handle_get_passport(id)
@@ -366,26 +1138,61 @@ impl Runner {
             // sent passport to that peer specifically.
             //
 
-            if let Err(error) = self
+            if self.storage.contains_passport(id).await? {
+                // the first block of the passport is the one identifying it
+                let genesis = slice.iter().next().map(|block| block.header().hash());
+                if genesis != Some(id) {
+                    return Err(HandleError::Peer(anyhow!(
+                        "the passport does not match the expected given hash"
+                    )));
+                }
+                let blocks: PassportBlocks<Vec<u8>> = slice.iter().skip(1).collect();
+                self.put_passport_blocks(peer, id, id, blocks.as_slice())
+                    .await?;
+                return Ok(());
+            }
+
+            match self
                 .storage
                 .handle_put_passport(peer, id, slice.to_blocks())
                 .await
             {
-                tracing::warn!(peer = %peer, passport = %id, reason = %error, "cannot accept new passport")
+                Ok(()) => self.update_subscriptions(vec![passport_topic(&id)], Vec::new()),
+                Err(error) if error.downcast_ref::<Refused>().is_some() => {
+                    tracing::warn!(peer = %peer, passport = %id, reason = %error, "cannot accept new passport");
+                    self.misbehaved(peer, Offence::RejectedPassport);
+                }
+                Err(error) => return Err(error.into()),
             }
         } else if let Some(topic) = message.register_topic_checked() {
-            self.storage.put_topic(peer, topic).await?
+            self.storage.put_topic(peer, topic).await?;
+            self.update_subscriptions(vec![topic], Vec::new());
         } else if let Some(topic) = message.deregister_topic_checked() {
-            self.storage.remove_topic(peer, topic).await?
+            self.storage.remove_topic(peer, topic).await?;
+            self.update_subscriptions(Vec::new(), vec![topic]);
         }
         // ********************************************************************
         //
         // None of the commands we received are handled by our node
         //
         else {
-            bail!("Unknown message type: {:?}", message.message_type());
+            return Err(HandleError::Peer(anyhow!(
+                "Unknown message type: {:?}",
+                message.message_type()
+            )));
         }
 
         Ok(())
     }
 }
+
+/// accept the next inbound connection from any of the listeners
+async fn accept(listeners: &[Listener]) -> Result<Accepting<OsRng, ed25519::SecretKey>> {
+    let accepting = listeners
+        .iter()
+        .map(|listener| Box::pin(listener.accept(OsRng)));
+
+    let (accepting, _, _) = future::select_all(accepting).await;
+
+    accepting
+}
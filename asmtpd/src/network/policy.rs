@@ -0,0 +1,337 @@
+/*!
+the peers the node accepts to talk to
+
+The [`Policy`] is checked before accepting an inbound connection (the
+remote address first, then the remote identity during the handshake),
+before opening an outbound connection and before adding a gossiped peer
+to the [`Topology`](super::Topology).
+
+A peer is allowed if it is not denied (statically in the configuration or
+banned at runtime) and if it is in the allow lists, when they are not empty.
+*/
+
+use crate::network::config;
+use anyhow::{ensure, Context as _, Result};
+use keynesis::key::ed25519::PublicKey;
+use poldercast::Gossip;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    fmt::{self, Formatter},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// a range of IP addresses, `address/prefix` (`192.168.0.0/16`, `fd00::/8`)
+///
+/// a single address (without prefix) is the range of just this address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct IpRange {
+    address: IpAddr,
+    prefix: u8,
+}
+
+/// the allow and deny lists of the node
+///
+/// this object can be cheaply cloned, all the clones share the same
+/// runtime bans.
+#[derive(Clone)]
+pub struct Policy {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    allowed_peers: HashSet<PublicKey>,
+    denied_peers: HashSet<PublicKey>,
+    allowed_addresses: Vec<IpRange>,
+    denied_addresses: Vec<IpRange>,
+    /// the peers banned at runtime, and until when (if not forever)
+    bans: HashMap<PublicKey, Option<Instant>>,
+}
+
+impl IpRange {
+    pub fn new(address: IpAddr, prefix: u8) -> Result<Self> {
+        let max = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        ensure!(
+            prefix <= max,
+            "The prefix of {} cannot be longer than {} bits",
+            address,
+            max
+        );
+
+        let address = match address {
+            IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask32(prefix))),
+            IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask128(prefix))),
+        };
+
+        Ok(Self { address, prefix })
+    }
+
+    /// check the `address` is within the range
+    ///
+    /// the IPv4 addresses mapped in IPv6 (`::ffff:a.b.c.d`) are checked
+    /// as IPv4 addresses.
+    pub fn contains(&self, address: IpAddr) -> bool {
+        let address = match address {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+            address => address,
+        };
+
+        match (self.address, address) {
+            (IpAddr::V4(range), IpAddr::V4(ip)) => {
+                u32::from(ip) & mask32(self.prefix) == u32::from(range)
+            }
+            (IpAddr::V6(range), IpAddr::V6(ip)) => {
+                u128::from(ip) & mask128(self.prefix) == u128::from(range)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn mask32(prefix: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+}
+
+fn mask128(prefix: u8) -> u128 {
+    u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+}
+
+impl Inner {
+    fn allows_peer(&mut self, id: &PublicKey) -> bool {
+        if let Some(until) = self.bans.get(id) {
+            match until {
+                Some(until) if *until <= Instant::now() => {
+                    self.bans.remove(id);
+                }
+                _ => return false,
+            }
+        }
+
+        !self.denied_peers.contains(id)
+            && (self.allowed_peers.is_empty() || self.allowed_peers.contains(id))
+    }
+
+    fn allows_address(&self, address: IpAddr) -> bool {
+        !self
+            .denied_addresses
+            .iter()
+            .any(|range| range.contains(address))
+            && (self.allowed_addresses.is_empty()
+                || self
+                    .allowed_addresses
+                    .iter()
+                    .any(|range| range.contains(address)))
+    }
+}
+
+impl Policy {
+    pub fn new(config: &config::Policy) -> Self {
+        let inner = Inner {
+            allowed_peers: config.allowed_peers.iter().map(|id| id.0).collect(),
+            denied_peers: config.denied_peers.iter().map(|id| id.0).collect(),
+            allowed_addresses: config.allowed_addresses.clone(),
+            denied_addresses: config.denied_addresses.clone(),
+            bans: HashMap::new(),
+        };
+
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+        }
+    }
+
+    /// check we accept to talk to the peer `id`
+    pub fn allows_peer(&self, id: &PublicKey) -> bool {
+        self.inner.lock().unwrap().allows_peer(id)
+    }
+
+    /// check we accept to talk to a peer at the given `address`
+    pub fn allows_address(&self, address: IpAddr) -> bool {
+        self.inner.lock().unwrap().allows_address(address)
+    }
+
+    /// check we accept to add the gossiped peer to the topology
+    pub fn allows_gossip(&self, gossip: &Gossip) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allows_peer(&gossip.id()) && inner.allows_address(gossip.address().ip())
+    }
+
+    /// replace the allow and deny lists with the ones of the `config`
+    ///
+    /// the runtime bans are kept.
+    pub fn update(&self, config: &config::Policy) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.allowed_peers = config.allowed_peers.iter().map(|id| id.0).collect();
+        inner.denied_peers = config.denied_peers.iter().map(|id| id.0).collect();
+        inner.allowed_addresses = config.allowed_addresses.clone();
+        inner.denied_addresses = config.denied_addresses.clone();
+    }
+
+    /// stop talking to the peer `id`, for the given duration or forever
+    pub fn ban(&self, id: PublicKey, duration: Option<Duration>) {
+        let until = duration.map(|duration| Instant::now() + duration);
+        self.inner.lock().unwrap().bans.insert(id, until);
+    }
+
+    /// lift the runtime ban of the peer `id`
+    ///
+    /// returns `false` if the peer was not banned. The peers denied in the
+    /// configuration remain denied.
+    pub fn unban(&self, id: &PublicKey) -> bool {
+        self.inner.lock().unwrap().bans.remove(id).is_some()
+    }
+}
+
+impl From<IpAddr> for IpRange {
+    fn from(address: IpAddr) -> Self {
+        let prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { address, prefix }
+    }
+}
+
+impl From<IpRange> for String {
+    fn from(range: IpRange) -> Self {
+        range.to_string()
+    }
+}
+
+impl TryFrom<String> for IpRange {
+    type Error = anyhow::Error;
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("Invalid IP address: {}", address))?;
+        match prefix {
+            Some(prefix) => {
+                let prefix = prefix
+                    .parse()
+                    .with_context(|| format!("Invalid prefix length: {}", prefix))?;
+                Self::new(address, prefix)
+            }
+            None => Ok(Self::from(address)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::{key::ed25519::SecretKey, Seed};
+
+    fn id(seed: u8) -> PublicKey {
+        let mut rng = Seed::from([seed; Seed::SIZE]).into_rand_chacha();
+        SecretKey::new(&mut rng).public_key()
+    }
+
+    #[test]
+    fn ip_ranges() {
+        let range: IpRange = "192.168.12.34/16".parse().unwrap();
+        assert_eq!(range.to_string(), "192.168.0.0/16");
+        assert!(range.contains("192.168.1.2".parse().unwrap()));
+        assert!(range.contains("::ffff:192.168.1.2".parse().unwrap()));
+        assert!(!range.contains("192.169.1.2".parse().unwrap()));
+        assert!(!range.contains("fd00::1".parse().unwrap()));
+
+        let range: IpRange = "fd00::/8".parse().unwrap();
+        assert!(range.contains("fd12::1".parse().unwrap()));
+        assert!(!range.contains("fe80::1".parse().unwrap()));
+
+        let range: IpRange = "10.0.0.1".parse().unwrap();
+        assert_eq!(range.to_string(), "10.0.0.1/32");
+        assert!(range.contains("10.0.0.1".parse().unwrap()));
+        assert!(!range.contains("10.0.0.2".parse().unwrap()));
+
+        let any: IpRange = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.7".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("10.0.0/8".parse::<IpRange>().is_err());
+    }
+
+    #[test]
+    fn deny_takes_precedence() {
+        let config = config::Policy {
+            allowed_peers: vec![config::PeerId(id(1)), config::PeerId(id(2))],
+            denied_peers: vec![config::PeerId(id(2))],
+            allowed_addresses: vec!["10.0.0.0/8".parse().unwrap()],
+            denied_addresses: vec!["10.0.0.2".parse().unwrap()],
+        };
+        let policy = Policy::new(&config);
+
+        assert!(policy.allows_peer(&id(1)));
+        assert!(!policy.allows_peer(&id(2)));
+        assert!(!policy.allows_peer(&id(3)));
+
+        assert!(policy.allows_address("10.0.0.1".parse().unwrap()));
+        assert!(!policy.allows_address("10.0.0.2".parse().unwrap()));
+        assert!(!policy.allows_address("192.168.0.1".parse().unwrap()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn runtime_bans() {
+        let policy = Policy::new(&config::Policy::default());
+        assert!(policy.allows_peer(&id(1)));
+
+        policy.ban(id(1), Some(Duration::from_secs(60)));
+        policy.ban(id(2), None);
+        assert!(!policy.allows_peer(&id(1)));
+        assert!(!policy.allows_peer(&id(2)));
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(policy.allows_peer(&id(1)));
+        assert!(!policy.allows_peer(&id(2)));
+
+        assert!(policy.unban(&id(2)));
+        assert!(!policy.unban(&id(2)));
+        assert!(policy.allows_peer(&id(2)));
+    }
+
+    #[test]
+    fn updates_keep_the_bans() {
+        let policy = Policy::new(&config::Policy::default());
+        policy.ban(id(1), None);
+
+        policy.update(&config::Policy {
+            denied_peers: vec![config::PeerId(id(2))],
+            denied_addresses: vec!["10.0.0.0/8".parse().unwrap()],
+            ..config::Policy::default()
+        });
+        assert!(!policy.allows_peer(&id(1)));
+        assert!(!policy.allows_peer(&id(2)));
+        assert!(policy.allows_peer(&id(3)));
+        assert!(!policy.allows_address("10.0.0.1".parse().unwrap()));
+
+        policy.update(&config::Policy::default());
+        assert!(!policy.allows_peer(&id(1)));
+        assert!(policy.allows_peer(&id(2)));
+        assert!(policy.allows_address("10.0.0.1".parse().unwrap()));
+    }
+}
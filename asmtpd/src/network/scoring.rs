@@ -0,0 +1,304 @@
+/*!
+the misbehaviour scores of the peers
+
+Every [`Offence`] of a peer adds its points to the peer's score. The
+scores decay over time (halved every `half_life`) so the occasional
+offence is forgiven. When the score of a peer reaches the `threshold` the
+peer is to be demoted from the topology, disconnected and banned for the
+`ban_duration` (see [`Scores::penalize`]).
+*/
+
+use crate::network::config;
+use asmtp_network::{InvalidMessage, UnknownMessageType};
+use keynesis::key::ed25519::PublicKey;
+use std::{collections::HashMap, fmt, io, time::Duration};
+use tokio::time::Instant;
+
+/// the ways a peer can misbehave
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Offence {
+    /// a frame that cannot be decrypted or decoded
+    MalformedFrame,
+    /// a message of a type we do not know of
+    UnknownMessage,
+    /// a message we failed to handle
+    InvalidMessage,
+    /// a passport we did not accept
+    RejectedPassport,
+    /// a message beyond the rate limit of the peer
+    RateLimited,
+}
+
+/// the misbehaviour scores and the message rates of the peers
+pub struct Scores {
+    threshold: u32,
+    half_life: Duration,
+    rate_limit: u32,
+    scores: HashMap<PublicKey, Score>,
+    /// the frames received from the peers in the current second
+    rates: HashMap<PublicKey, (Instant, u32)>,
+}
+
+struct Score {
+    points: f64,
+    updated: Instant,
+}
+
+impl Offence {
+    /// the points added to the score of the peer for the offence
+    pub fn points(self) -> u32 {
+        match self {
+            Self::MalformedFrame => 20,
+            Self::UnknownMessage => 10,
+            Self::InvalidMessage => 10,
+            Self::RejectedPassport => 25,
+            Self::RateLimited => 5,
+        }
+    }
+
+    /// the offence of the peer behind the `error` receiving a message
+    /// from the connection, if the peer is to blame
+    ///
+    /// only the known violations of the protocol are offences: the frames
+    /// failing to be decrypted and the invalid messages (see
+    /// [`InvalidMessage`]). The errors of the connection itself (closed,
+    /// reset...) and the unexpected errors are not.
+    pub fn of_receive_error(error: &anyhow::Error) -> Option<Self> {
+        for cause in error.chain() {
+            if cause.is::<UnknownMessageType>() {
+                return Some(Self::UnknownMessage);
+            }
+            if let Some(error) = cause.downcast_ref::<io::Error>() {
+                return if error.kind() == io::ErrorKind::InvalidData {
+                    Some(Self::MalformedFrame)
+                } else {
+                    None
+                };
+            }
+        }
+
+        if error.downcast_ref::<InvalidMessage>().is_some() {
+            Some(Self::MalformedFrame)
+        } else {
+            None
+        }
+    }
+}
+
+impl Scores {
+    pub fn new(config: &config::Scoring) -> Self {
+        Self {
+            threshold: config.threshold,
+            half_life: config.half_life,
+            rate_limit: config.rate_limit,
+            scores: HashMap::new(),
+            rates: HashMap::new(),
+        }
+    }
+
+    /// apply the new settings, the current scores are kept
+    pub fn reconfigure(&mut self, config: &config::Scoring) {
+        self.threshold = config.threshold;
+        self.half_life = config.half_life;
+        self.rate_limit = config.rate_limit;
+    }
+
+    /// the current (decayed) score of the peer `id`
+    pub fn score(&self, id: &PublicKey) -> u32 {
+        self.scores
+            .get(id)
+            .map(|score| decay(score, self.half_life, Instant::now()) as u32)
+            .unwrap_or(0)
+    }
+
+    /// add the points of the `offence` to the score of the peer `id`
+    ///
+    /// returns `true` if the score reached the threshold: the score is
+    /// then reset and the peer is to be banned. Never returns `true` if
+    /// the threshold is `0` (scoring disabled).
+    pub fn penalize(&mut self, id: PublicKey, offence: Offence) -> bool {
+        let now = Instant::now();
+        let points = match self.scores.get(&id) {
+            Some(score) => decay(score, self.half_life, now),
+            None => 0.0,
+        } + f64::from(offence.points());
+
+        if self.threshold != 0 && points >= f64::from(self.threshold) {
+            self.scores.remove(&id);
+            true
+        } else {
+            self.scores.insert(
+                id,
+                Score {
+                    points,
+                    updated: now,
+                },
+            );
+            false
+        }
+    }
+
+    /// count a message received from the peer `id`
+    ///
+    /// only the frames are counted: the messages unpacked from a batch after
+    /// the first one (not `new_frame`) share the fate of their frame, so the
+    /// replies packed in batches (reconciliation...) are one frame each.
+    ///
+    /// returns `false` if the peer sent more frames than the rate limit
+    /// within the last second. Always returns `true` if the rate limit is
+    /// `0` (unlimited).
+    pub fn received(&mut self, id: PublicKey, new_frame: bool) -> bool {
+        if self.rate_limit == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let (since, count) = self.rates.entry(id).or_insert((now, 0));
+        if new_frame {
+            if now.duration_since(*since) >= Duration::from_secs(1) {
+                *since = now;
+                *count = 0;
+            }
+            *count = count.saturating_add(1);
+        }
+
+        *count <= self.rate_limit
+    }
+
+    /// forget the scores that decayed to nothing and the rates of the
+    /// peers that did not send any message in the last second
+    pub fn forget_decayed(&mut self) {
+        let now = Instant::now();
+        let half_life = self.half_life;
+        self.scores
+            .retain(|_, score| decay(score, half_life, now) >= 1.0);
+        self.rates
+            .retain(|_, (since, _)| now.duration_since(*since) < Duration::from_secs(1));
+    }
+}
+
+/// the points of the `score` once decayed until `now`
+fn decay(score: &Score, half_life: Duration, now: Instant) -> f64 {
+    if half_life.is_zero() {
+        return score.points;
+    }
+
+    let elapsed = now.duration_since(score.updated).as_secs_f64();
+    score.points * 0.5f64.powf(elapsed / half_life.as_secs_f64())
+}
+
+impl fmt::Display for Offence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedFrame => f.write_str("malformed frame"),
+            Self::UnknownMessage => f.write_str("unknown message type"),
+            Self::InvalidMessage => f.write_str("invalid message"),
+            Self::RejectedPassport => f.write_str("rejected passport"),
+            Self::RateLimited => f.write_str("rate limit exceeded"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context as _;
+    use keynesis::{key::ed25519::SecretKey, Seed};
+
+    fn id(seed: u8) -> PublicKey {
+        let mut rng = Seed::from([seed; Seed::SIZE]).into_rand_chacha();
+        SecretKey::new(&mut rng).public_key()
+    }
+
+    fn scoring(threshold: u32, rate_limit: u32) -> config::Scoring {
+        config::Scoring {
+            threshold,
+            half_life: Duration::from_secs(60),
+            ban_duration: Duration::from_secs(3_600),
+            rate_limit,
+        }
+    }
+
+    #[test]
+    fn receive_errors() {
+        let error = anyhow::Error::new(UnknownMessageType(0)).context("Invalid message");
+        assert_eq!(
+            Offence::of_receive_error(&error),
+            Some(Offence::UnknownMessage)
+        );
+
+        let error = anyhow::anyhow!("Not enough bytes").context(InvalidMessage);
+        assert_eq!(
+            Offence::of_receive_error(&error),
+            Some(Offence::MalformedFrame)
+        );
+
+        // the unexpected errors are not blamed on the peer
+        let error = anyhow::anyhow!("Not enough bytes").context("Invalid message");
+        assert_eq!(Offence::of_receive_error(&error), None);
+
+        let error = Err::<(), _>(io::Error::new(io::ErrorKind::InvalidData, "invalid tag"))
+            .context("Invalid frame")
+            .unwrap_err();
+        assert_eq!(
+            Offence::of_receive_error(&error),
+            Some(Offence::MalformedFrame)
+        );
+
+        let error = Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionReset))
+            .context("Invalid frame")
+            .unwrap_err();
+        assert_eq!(Offence::of_receive_error(&error), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scores_reach_the_threshold() {
+        let mut scores = Scores::new(&scoring(50, 0));
+
+        assert!(!scores.penalize(id(1), Offence::RejectedPassport));
+        assert_eq!(scores.score(&id(1)), 25);
+        assert_eq!(scores.score(&id(2)), 0);
+        assert!(scores.penalize(id(1), Offence::RejectedPassport));
+        // the score is reset once the peer is to be banned
+        assert_eq!(scores.score(&id(1)), 0);
+
+        let mut scores = Scores::new(&scoring(0, 0));
+        for _ in 0..10 {
+            assert!(!scores.penalize(id(1), Offence::MalformedFrame));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn scores_decay() {
+        let mut scores = Scores::new(&scoring(50, 0));
+
+        assert!(!scores.penalize(id(1), Offence::RejectedPassport));
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert_eq!(scores.score(&id(1)), 12);
+        assert!(!scores.penalize(id(1), Offence::RejectedPassport));
+
+        tokio::time::advance(Duration::from_secs(600)).await;
+        scores.forget_decayed();
+        assert!(scores.scores.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rates_are_limited() {
+        let mut scores = Scores::new(&scoring(50, 2));
+
+        assert!(scores.received(id(1), true));
+        assert!(scores.received(id(1), true));
+        // the rest of the batch of the frame within the limit
+        assert!((0..10).all(|_| scores.received(id(1), false)));
+        assert!(!scores.received(id(1), true));
+        assert!(!scores.received(id(1), false));
+        assert!(scores.received(id(2), true));
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(!scores.received(id(1), false));
+        assert!(scores.received(id(1), true));
+
+        let mut scores = Scores::new(&scoring(50, 0));
+        assert!((0..100).all(|_| scores.received(id(1), true)));
+    }
+}
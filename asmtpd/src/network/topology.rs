@@ -1,7 +1,9 @@
-use crate::secret::Secret;
-use keynesis::key::ed25519::PublicKey;
+use crate::{network::Policy, secret::Secret};
+use asmtp_network::PeerAddresses;
+use keynesis::{key::ed25519::PublicKey, passport::block::Time};
 use poldercast::{layer::Selection, Gossip, Profile, Topic};
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     sync::{Arc, Mutex},
 };
@@ -14,14 +16,31 @@ pub struct Topology {
 struct Inner {
     secret: Secret,
     topology: poldercast::Topology,
+    /// the gossiped peers denied by the policy are not added
+    policy: Policy,
+    /// the addresses advertised by the peers (and us), when they have
+    /// more than the one of their gossip
+    addresses: HashMap<PublicKey, PeerAddresses>,
+    /// our subscriptions changed since our profile was committed
+    profile_outdated: bool,
+    /// our profile was gossiped since it was committed: the peers only
+    /// accept a more recent profile, to the second
+    profile_gossiped: bool,
 }
 
 impl Inner {
-    fn new(secret: Secret, topology: poldercast::Topology) -> Self {
-        Self { secret, topology }
+    fn new(secret: Secret, topology: poldercast::Topology, policy: Policy) -> Self {
+        Self {
+            secret,
+            topology,
+            policy,
+            addresses: HashMap::new(),
+            profile_outdated: false,
+            profile_gossiped: false,
+        }
     }
 
-    fn subscriptions(&mut self, add: Vec<Topic>, remove: Vec<Topic>) {
+    fn subscriptions(&mut self, add: Vec<Topic>, remove: Vec<Topic>) -> bool {
         for topic in add {
             self.topology.subscribe_topic(topic);
         }
@@ -29,18 +48,67 @@ impl Inner {
             self.topology.unsubscribe_topic(&topic)
         }
 
+        self.profile_outdated = true;
+        self.commit_profile()
+    }
+
+    fn commit_profile(&mut self) -> bool {
+        if !self.profile_outdated
+            || (self.profile_gossiped && Time::now() <= self.topology.self_profile().last_update())
+        {
+            return false;
+        }
+
         self.topology
             .update_profile_subscriptions(self.secret.secret());
+        self.profile_outdated = false;
+        self.profile_gossiped = false;
+        true
     }
 
     fn accept_gossip(&mut self, gossip: Gossip) {
+        if !self.policy.allows_gossip(&gossip) {
+            tracing::debug!(id = %gossip.id(), address = %gossip.address(), "ignoring gossip denied by the policy");
+            return;
+        }
+
         let peer = Profile::from_gossip(gossip);
 
         self.topology.add_peer(peer);
     }
 
+    fn accept_addresses(&mut self, addresses: PeerAddresses) -> bool {
+        let id = addresses.id();
+        if self.topology.get(&id).is_none() {
+            return false;
+        }
+
+        match self.addresses.get(&id) {
+            Some(known) if known.time() >= addresses.time() => false,
+            _ => {
+                self.addresses.insert(id, addresses);
+                true
+            }
+        }
+    }
+
+    fn dial_addresses(&self, peer: &Profile) -> Vec<SocketAddr> {
+        let mut addresses = self
+            .addresses
+            .get(&peer.id())
+            .map(PeerAddresses::addresses)
+            .unwrap_or_default();
+        if !addresses.contains(&peer.address()) {
+            addresses.push(peer.address());
+        }
+        addresses
+    }
+
     fn gossips_for(&mut self, recipient: &PublicKey) -> Vec<Gossip> {
-        self.topology.gossips_for(recipient)
+        let gossips = self.topology.gossips_for(recipient);
+        // our profile is always part of the gossips, if any
+        self.profile_gossiped |= !gossips.is_empty();
+        gossips
     }
 
     fn view_for(&mut self, from: Option<&PublicKey>, selection: Selection) -> Vec<Arc<Profile>> {
@@ -53,15 +121,16 @@ impl Inner {
     }
 
     fn demote(&mut self, peer: &PublicKey) {
-        self.topology.remove_peer(peer)
+        self.topology.remove_peer(peer);
+        self.addresses.remove(peer);
     }
 }
 
 impl Topology {
-    pub fn new(address: SocketAddr, secret: Secret) -> Self {
+    pub fn new(address: SocketAddr, secret: Secret, policy: Policy) -> Self {
         let topology = poldercast::Topology::new(address, secret.secret());
 
-        let inner = Inner::new(secret, topology);
+        let inner = Inner::new(secret, topology, policy);
 
         Self {
             inner: Arc::new(Mutex::new(inner)),
@@ -73,11 +142,25 @@ impl Topology {
         inner.topology.get(id).cloned()
     }
 
-    pub fn subscriptions(&self, add: Vec<Topic>, remove: Vec<Topic>) {
+    /// update our subscriptions and commit our profile
+    ///
+    /// returns `false` if the profile is not committed yet: the profile we
+    /// gossiped was committed within the same second, the peers would not
+    /// take the new one. It is committed by [`Topology::commit_profile`]
+    /// once the second has rolled over, with all the changes made
+    /// meanwhile.
+    pub fn subscriptions(&self, add: Vec<Topic>, remove: Vec<Topic>) -> bool {
         let mut inner = self.inner.lock().unwrap();
         inner.subscriptions(add, remove)
     }
 
+    /// commit our profile if our subscriptions changed since it was last
+    /// committed (see [`Topology::subscriptions`]), returns `true` if it
+    /// was
+    pub fn commit_profile(&self) -> bool {
+        self.inner.lock().unwrap().commit_profile()
+    }
+
     pub fn accept_gossip(&self, gossip: Gossip) {
         self.inner.lock().unwrap().accept_gossip(gossip)
     }
@@ -90,6 +173,31 @@ impl Topology {
         self.inner.lock().unwrap().gossips_for(recipient)
     }
 
+    /// advertise all the addresses we can be reached at along with
+    /// our gossip
+    pub fn advertise(&self, addresses: PeerAddresses) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.addresses.insert(addresses.id(), addresses);
+    }
+
+    /// keep the addresses advertised by a known peer
+    ///
+    /// returns `false` if the peer is unknown or if we already have
+    /// more recent addresses for the peer.
+    pub fn accept_addresses(&self, addresses: PeerAddresses) -> bool {
+        self.inner.lock().unwrap().accept_addresses(addresses)
+    }
+
+    /// the addresses advertised by the peer, if any
+    pub fn addresses_of(&self, id: &PublicKey) -> Option<PeerAddresses> {
+        self.inner.lock().unwrap().addresses.get(id).cloned()
+    }
+
+    /// the addresses to try to connect to the peer, in order of preference
+    pub fn dial_addresses(&self, peer: &Profile) -> Vec<SocketAddr> {
+        self.inner.lock().unwrap().dial_addresses(peer)
+    }
+
     #[allow(dead_code)]
     pub fn promote_peer(&self, peer: &PublicKey) {
         self.inner.lock().unwrap().promote(peer)
@@ -0,0 +1,1172 @@
+/*!
+multi-node simulation harness
+
+spin up N nodes within the same process, connected together with an
+in-memory network ([`MemoryNetwork`]). The latency of the links and the
+partitions between the nodes can be changed during the simulation.
+
+The time is driven by [`tokio::time`] (heart beat, gossiping, latency of
+the links). The simulation is meant to run with the clock paused (see
+[`tokio::time::pause`] or `#[tokio::test(start_paused = true)]`) and
+[`Simulation::advance`] lets the runtime move the clock forward from one
+timer of the nodes to the next, once the nodes are idle. The storage of
+the nodes runs on its own threads, the clock is not moved forward while
+one of its requests is pending (see the `simulation` feature).
+
+The wall clock is not controlled: the gossips and the passport blocks are
+timestamped to the second with the wall clock.
+*/
+
+use crate::{
+    network::{self, Network},
+    secret::Secret,
+    storage::{self, Storage},
+};
+use anyhow::{Context as _, Result};
+use asmtp_lib::passport_topic;
+use asmtp_network::{
+    net::{Connection, MemoryNetwork},
+    Message, Puzzle,
+};
+use futures::StreamExt as _;
+use keynesis::{
+    key::{ed25519, Dh as _},
+    passport::PassportBlocks,
+    Seed,
+};
+use poldercast::{Gossip, Topic};
+use rand::rngs::OsRng;
+use std::{
+    collections::HashSet,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+/// the port every simulated node listens to
+const PORT: u16 = 9800;
+
+/// the address the clients connect from
+const CLIENT_ADDRESS: ([u8; 4], u16) = ([192, 168, 0, 1], 9800);
+
+/// build a [`Simulation`]
+pub struct Builder {
+    nodes: usize,
+    seed: Seed,
+    topics: Vec<Topic>,
+    passports: Vec<PassportBlocks<Vec<u8>>>,
+    latency: Duration,
+    dual_stack: bool,
+    config: network::Config,
+    storage: storage::Config,
+}
+
+/// a set of nodes connected together with an in-memory network
+pub struct Simulation {
+    network: MemoryNetwork,
+    nodes: Vec<SimulatedNode>,
+}
+
+/// a node running within the [`Simulation`]
+pub struct SimulatedNode {
+    secret: Secret,
+    address: SocketAddr,
+    ipv6_address: Option<SocketAddr>,
+    storage: Storage,
+    network: Network,
+}
+
+impl Builder {
+    fn new(nodes: usize) -> Self {
+        let config = network::Config {
+            gossiping: network::config::Gossip {
+                minimum_time_elapsed: Duration::from_secs(1),
+                ..network::config::Gossip::default()
+            },
+            ..network::Config::default()
+        };
+
+        let storage = storage::Config {
+            path: ":memory:".into(),
+            peers_path: None,
+            peers_seed: 64,
+            gossip_refresh_rate: Duration::from_secs(60),
+            passport_cache_size: 256,
+            query_limit: 512,
+            thread_retention: storage::Retention::default(),
+            user_retention: storage::Retention::default(),
+            quota: storage::Quota::default(),
+            user_quotas: Default::default(),
+            compaction_interval: Duration::from_secs(600),
+            seen_messages_window: Duration::from_secs(24 * 60 * 60),
+            seen_messages_size: 1_048_576,
+        };
+
+        Self {
+            nodes,
+            seed: Seed::from([0; Seed::SIZE]),
+            topics: Vec::new(),
+            passports: Vec::new(),
+            latency: Duration::from_millis(0),
+            dual_stack: false,
+            config,
+            storage,
+        }
+    }
+
+    /// the seed used to generate the nodes' keys
+    pub fn seed(mut self, seed: Seed) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// make every node subscribe to the given topic
+    ///
+    /// the nodes keep the messages of this topic in their storage.
+    pub fn subscribe(mut self, topic: Topic) -> Self {
+        self.topics.push(topic);
+        self
+    }
+
+    /// make every node keep the given passport
+    ///
+    /// the nodes subscribe to the passport's topic to receive its new
+    /// blocks.
+    pub fn passport(mut self, blocks: PassportBlocks<Vec<u8>>) -> Self {
+        self.passports.push(blocks);
+        self
+    }
+
+    /// the initial latency of all the links of the network
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// make every node listen to an IPv6 address too
+    ///
+    /// the IPv6 address is advertised after the IPv4 address (see
+    /// [`SimulatedNode::ipv6_address`]).
+    pub fn dual_stack(mut self) -> Self {
+        self.dual_stack = true;
+        self
+    }
+
+    /// the network configuration of the nodes
+    ///
+    /// the addresses and the known gossips are set by the simulation.
+    pub fn network_config(mut self, config: network::Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// the storage configuration of the nodes
+    ///
+    /// the nodes always keep their messages in memory.
+    pub fn storage_config(mut self, config: storage::Config) -> Self {
+        self.storage = storage::Config {
+            path: ":memory:".into(),
+            peers_path: None,
+            ..config
+        };
+        self
+    }
+
+    /// start the nodes
+    ///
+    /// the nodes are started one after the other, every node knows about
+    /// the nodes started before it (as if joining the network through them).
+    pub async fn build(self) -> Result<Simulation> {
+        let network = MemoryNetwork::new();
+        network.set_latency(self.latency);
+
+        let mut topics = self.topics.clone();
+        for blocks in self.passports.iter() {
+            let genesis = blocks.iter().next().context("Empty passport")?;
+            topics.push(passport_topic(&genesis.header().hash()));
+        }
+
+        let mut rng = self.seed.clone().into_rand_chacha();
+        let peers: Vec<(Secret, SocketAddr)> = (0..self.nodes)
+            .map(|index| (Secret::generate(&mut rng), node_address(index)))
+            .collect();
+
+        let gossips: Vec<Gossip> = peers
+            .iter()
+            .map(|(secret, address)| {
+                let mut topology = poldercast::Topology::new(*address, secret.secret());
+                for topic in topics.iter().copied() {
+                    topology.subscribe_topic(topic);
+                }
+                topology.update_profile_subscriptions(secret.secret());
+                topology.self_profile().gossip().clone()
+            })
+            .collect();
+
+        let mut nodes = Vec::with_capacity(self.nodes);
+        for (index, (secret, address)) in peers.into_iter().enumerate() {
+            let storage = async {
+                let storage = Storage::new(self.storage.clone(), HashSet::new()).await?;
+                for topic in self.topics.iter().copied() {
+                    storage.subscribe_message(topic).await?;
+                }
+                for blocks in self.passports.iter() {
+                    storage.put_passport(blocks.as_slice()).await?;
+                }
+                Result::<_>::Ok(storage)
+            }
+            .await
+            .with_context(|| format!("Cannot create the storage of node {}", index))?;
+
+            let ipv6_address = if self.dual_stack {
+                Some(node_ipv6_address(index))
+            } else {
+                None
+            };
+
+            let mut config = self.config.clone();
+            config.listen_address = address;
+            config.public_address = address;
+            config.additional_listen_addresses = ipv6_address.into_iter().collect();
+            config.additional_public_addresses = ipv6_address.into_iter().collect();
+            config.known_gossips = gossips[..index]
+                .iter()
+                .cloned()
+                .map(network::config::KnownGossip)
+                .collect();
+
+            let node =
+                Network::new_in_memory(secret.clone(), storage.clone(), config, network.clone())
+                    .await
+                    .with_context(|| format!("Cannot start node {}", index))?;
+
+            nodes.push(SimulatedNode {
+                secret,
+                address,
+                ipv6_address,
+                storage,
+                network: node,
+            });
+        }
+
+        Ok(Simulation { network, nodes })
+    }
+}
+
+impl Simulation {
+    /// how long [`Self::receive`] waits for a message
+    pub const RECEIVE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// prepare a simulation of `nodes` nodes
+    pub fn builder(nodes: usize) -> Builder {
+        Builder::new(nodes)
+    }
+
+    pub fn nodes(&self) -> &[SimulatedNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, index: usize) -> &SimulatedNode {
+        &self.nodes[index]
+    }
+
+    /// the in-memory network connecting the nodes
+    pub fn network(&self) -> &MemoryNetwork {
+        &self.network
+    }
+
+    /// set the latency of the link between the nodes `a` and `b`
+    pub fn set_latency(&self, a: usize, b: usize, latency: Duration) {
+        self.network
+            .set_link_latency(self.nodes[a].address, self.nodes[b].address, latency)
+    }
+
+    /// partition the nodes `a` and `b`
+    ///
+    /// only their IPv4 addresses are partitioned, see
+    /// [`network`](Self::network) to partition other addresses.
+    pub fn partition(&self, a: usize, b: usize) {
+        self.network
+            .partition(self.nodes[a].address, self.nodes[b].address)
+    }
+
+    /// remove the partition between the nodes `a` and `b`
+    pub fn heal(&self, a: usize, b: usize) {
+        self.network
+            .heal(self.nodes[a].address, self.nodes[b].address)
+    }
+
+    /// let the simulation run for the given duration
+    ///
+    /// with the clock paused, the runtime moves the clock forward to the
+    /// next timer of the nodes once they are all idle, up to the end of
+    /// the `duration`.
+    pub async fn advance(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+
+    /// connect to the given node as a client would, authenticating
+    /// with the key `client`
+    pub async fn connect(&self, node: usize, client: &ed25519::SecretKey) -> Result<Connection> {
+        let node = &self.nodes[node];
+
+        Connection::connect_memory(
+            OsRng,
+            client,
+            &self.network,
+            SocketAddr::from(CLIENT_ADDRESS),
+            node.address,
+            node.id(),
+            Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY,
+        )
+        .await
+    }
+
+    /// receive the next message from the `connection`, letting the
+    /// simulation run until it arrives (see [`Self::advance`])
+    ///
+    /// fails if the connection is closed or if nothing is received within
+    /// [`Self::RECEIVE_TIMEOUT`].
+    pub async fn receive(&self, connection: &mut Connection) -> Result<Message> {
+        let received = tokio::time::timeout(Self::RECEIVE_TIMEOUT, connection.next())
+            .await
+            .with_context(|| format!("Nothing received after {:?}", Self::RECEIVE_TIMEOUT))?;
+        let (_, message) = received.context("Connection closed")?;
+        message
+    }
+
+    /// stop all the nodes
+    pub async fn shutdown(self) -> Result<()> {
+        for node in self.nodes {
+            node.network.shutdown().await?;
+        }
+        Ok(())
+    }
+}
+
+impl SimulatedNode {
+    pub fn id(&self) -> ed25519::PublicKey {
+        self.secret.public()
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// the IPv6 address of the node, if the simulation is
+    /// [`dual_stack`](Builder::dual_stack)
+    pub fn ipv6_address(&self) -> Option<SocketAddr> {
+        self.ipv6_address
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+}
+
+fn node_address(index: usize) -> SocketAddr {
+    let index = index as u32 + 1;
+    SocketAddr::from((Ipv4Addr::from(0x0a00_0000 | index), PORT))
+}
+
+fn node_ipv6_address(index: usize) -> SocketAddr {
+    let index = index as u128 + 1;
+    SocketAddr::from((Ipv6Addr::from(0xfd00 << 112 | index), PORT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asmtp_network::{Message, MessageType, TopicQuery};
+    use bytes::Bytes;
+    use futures::prelude::*;
+    use keynesis::passport::{block::Time, Passport};
+
+    /// long enough for a message to go around the simulated network
+    const ROUND: Duration = Duration::from_millis(300);
+
+    /// the key of the client connecting to the nodes
+    fn client() -> ed25519::SecretKey {
+        let mut rng = Seed::from([1; Seed::SIZE]).into_rand_chacha();
+        ed25519::SecretKey::new(&mut rng)
+    }
+
+    /// connect the [`client`] to the given node as one of its users
+    async fn connect_user(simulation: &Simulation, node: usize) -> Connection {
+        let user = client();
+        let users = std::iter::once(user.public_key().to_string()).collect();
+        simulation.node(node).storage().set_users(&users).unwrap();
+        simulation.connect(node, &user).await.unwrap()
+    }
+
+    async fn send_topics<I>(connection: &mut Connection, topic: Topic, contents: I)
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        for content in contents {
+            connection
+                .send(Message::new_topic(topic, content))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn messages(node: &SimulatedNode, topic: Topic) -> Vec<Vec<u8>> {
+        let query = TopicQuery::new(topic, Time::from(0));
+        node.storage().messages(&query).await.unwrap().0
+    }
+
+    /// check the node `node` knows the node `peer` subscribed to `topic`
+    fn knows_subscription(simulation: &Simulation, node: usize, peer: usize, topic: Topic) -> bool {
+        let view = simulation.node(node).network().control().view();
+        let peer = simulation.node(peer).id();
+        view.iter()
+            .filter(|profile| profile.id() == peer)
+            .any(|profile| profile.subscriptions().iter().any(|s| s.topic() == topic))
+    }
+
+    /// advance the simulation until the `condition` holds
+    ///
+    /// the profiles are timestamped to the second of the wall clock: the
+    /// profile a node updates within the second of the one it gossiped is
+    /// gossiped on a heart beat once the second has rolled over.
+    async fn advance_until(simulation: &Simulation, condition: impl Fn() -> bool) {
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            assert!(std::time::Instant::now() < deadline, "condition not met");
+            simulation.advance(ROUND).await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn topic_message_is_relayed_unless_partitioned() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .latency(Duration::from_millis(20))
+            .build()
+            .await
+            .unwrap();
+        // let the nodes gossip with each other
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+
+        send_topics(&mut connection, topic, &[b"before"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"before"]);
+
+        simulation.partition(0, 1);
+        send_topics(&mut connection, topic, &[b"during"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(messages(simulation.node(0), topic).await.len(), 2);
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"before"]);
+
+        simulation.heal(0, 1);
+        send_topics(&mut connection, topic, &[b"after"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(
+            messages(simulation.node(1), topic).await,
+            vec![b"before".to_vec(), b"after".to_vec()]
+        );
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simultaneous_connections_are_deduplicated() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(2).config;
+        config.gossiping.minimum_time_elapsed = Duration::from_millis(100);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .latency(Duration::from_millis(20))
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        // the connection between the nodes breaks on the next message
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        simulation.partition(0, 1);
+        send_topics(&mut connection, topic, &[b"message"]).await;
+        simulation.advance(ROUND).await;
+        simulation.heal(0, 1);
+
+        // both nodes connect to each other at once
+        for node in simulation.nodes() {
+            node.network().control().gossip().await.unwrap();
+        }
+        simulation.advance(ROUND).await;
+
+        let ids = [simulation.node(1).id(), simulation.node(0).id()];
+        let mut sessions = Vec::new();
+        for (node, id) in simulation.nodes().iter().zip(ids.iter()) {
+            let connections = node.network().control().connections().await;
+            let connections: Vec<_> = connections
+                .unwrap()
+                .into_iter()
+                .filter(|info| &info.id == id)
+                .collect();
+            assert_eq!(connections.len(), 1);
+            sessions.push(connections[0].session_id);
+        }
+        // both ends kept the same connection
+        assert!(sessions[0] == sessions[1]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missed_messages_are_reconciled() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(2).config;
+        config.reconciliation.interval = Duration::from_millis(200);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        // the message reaches node 0 without being relayed, as if node 1
+        // had been unreachable at the time
+        let mut storage = simulation.node(0).storage().clone();
+        storage
+            .handle_incoming_message(topic, Bytes::from_static(b"missed"))
+            .await
+            .unwrap();
+        assert!(messages(simulation.node(1), topic).await.is_empty());
+
+        simulation.advance(Duration::from_millis(1_000)).await;
+        assert_eq!(messages(simulation.node(0), topic).await, vec![b"missed"]);
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"missed"]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconciliation_replies_are_within_the_rate_limit() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(2).config;
+        config.reconciliation.interval = Duration::from_secs(1);
+        // the received messages are relayed back one frame each, some beyond
+        // the rate limit: not enough to ban the peer
+        config.scoring.threshold = 100;
+        config.scoring.rate_limit = 10;
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        // more missed messages than the rate limit, sent back in one batch
+        let mut storage = simulation.node(0).storage().clone();
+        for i in 0..20u8 {
+            let message = Bytes::from(format!("missed {}", i));
+            storage
+                .handle_incoming_message(topic, message)
+                .await
+                .unwrap();
+        }
+
+        simulation.advance(Duration::from_secs(5)).await;
+        assert_eq!(messages(simulation.node(1), topic).await.len(), 20);
+        for node in simulation.nodes() {
+            let connections = node.network().control().connections().await.unwrap();
+            assert_eq!(connections.len(), 1);
+        }
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn new_passport_blocks_are_synced() {
+        let mut rng = Seed::from([1; Seed::SIZE]).into_rand_chacha();
+        let author = ed25519::SecretKey::new(&mut rng);
+        let passphrase = Seed::generate(&mut rng);
+        let mut passport =
+            Passport::create(&mut rng, "device", &author, passphrase.clone()).unwrap();
+        let id = passport.id();
+        let genesis = passport.blocks().to_blocks();
+
+        // keynesis only accepts new entries created within the second of the
+        // previous block, so the update is prepared before starting the nodes
+        let mut mutation = passport.as_mut();
+        mutation.rotate_shared_key(&mut rng, passphrase).unwrap();
+        mutation.finalize(&author).unwrap();
+        let block = passport.blocks().iter().last().unwrap();
+        let head = block.header().hash();
+
+        let simulation = Simulation::builder(2)
+            .passport(genesis)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        // send the new block the way the legacy clients do
+        let client = ed25519::SecretKey::new(&mut rng);
+        let mut connection = simulation.connect(0, &client).await.unwrap();
+        connection
+            .send(Message::new_topic(passport_topic(&id), block))
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        for node in simulation.nodes() {
+            let blocks = node.storage().get_passport_blocks(id).await.unwrap();
+            assert_eq!(blocks.unwrap().iter().count(), 2);
+        }
+
+        // only the new block is sent back
+        let mut connection = simulation.connect(1, &client).await.unwrap();
+        connection
+            .send(Message::new_get_passport_blocks(id, id))
+            .await
+            .unwrap();
+        let message = simulation.receive(&mut connection).await.unwrap();
+        let (i, h, blocks) = message.put_passport_blocks_checked().unwrap();
+        assert_eq!((i, h), (id, id));
+        let blocks: Vec<_> = blocks.iter().map(|b| b.header().hash()).collect();
+        assert_eq!(blocks, vec![head]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_solve_the_puzzle_under_load() {
+        let mut config = Builder::new(1).config;
+        config.puzzle.difficulty = 8;
+        // always considered under load
+        config.puzzle.pending_handshakes = 0;
+        let simulation = Simulation::builder(1)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+
+        let connection = simulation.connect(0, &client()).await.unwrap();
+        assert_eq!(
+            connection.remote_public_identity(),
+            &simulation.node(0).id()
+        );
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_handshakes_time_out() {
+        let mut config = Builder::new(1).config;
+        config.max_pending_handshakes = 1;
+        config.handshake_timeout = Duration::from_secs(5);
+        let simulation = Simulation::builder(1)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+
+        // a peer so slow its handshake cannot complete in time
+        let node = simulation.node(0);
+        let stalled = SocketAddr::from(([192, 168, 0, 2], 9800));
+        let network = simulation.network().clone();
+        network.set_link_latency(stalled, node.address(), Duration::from_secs(60));
+        let (address, id) = (node.address(), node.id());
+        tokio::spawn(async move {
+            let max_difficulty = Puzzle::DEFAULT_MAX_SOLVED_DIFFICULTY;
+            Connection::connect_memory(
+                OsRng,
+                &client(),
+                &network,
+                stalled,
+                address,
+                id,
+                max_difficulty,
+            )
+            .await
+        });
+        simulation.advance(ROUND).await;
+        assert!(simulation.connect(0, &client()).await.is_err());
+
+        // the stalled peer retries with the version 1 once dropped (as it
+        // would do with a node of the version 1) and stalls again
+        simulation.advance(Duration::from_secs(10)).await;
+        assert!(simulation.connect(0, &client()).await.is_ok());
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn cover_traffic_is_discarded() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(2).config;
+        config.cover_traffic.interval = Duration::from_millis(10);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+
+        // the node sends dummy messages to the client too
+        let message = simulation.receive(&mut connection).await;
+        assert_eq!(message.unwrap().message_type(), MessageType::Cover);
+
+        connection.send(Message::new_cover(64)).await.unwrap();
+        send_topics(&mut connection, topic, &[b"hello"]).await;
+        connection.send(Message::new_cover(64)).await.unwrap();
+        simulation.advance(ROUND).await;
+
+        assert_eq!(messages(simulation.node(0), topic).await, vec![b"hello"]);
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"hello"]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn denied_and_banned_peers_are_not_talked_to() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(3).config;
+        config.policy.denied_addresses = vec![node_address(2).ip().into()];
+        let simulation = Simulation::builder(3)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+
+        send_topics(&mut connection, topic, &[b"denied"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"denied"]);
+        assert!(messages(simulation.node(2), topic).await.is_empty());
+
+        simulation
+            .node(0)
+            .network()
+            .control()
+            .ban(simulation.node(1).id(), None)
+            .await
+            .unwrap();
+        send_topics(&mut connection, topic, &[b"banned"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(messages(simulation.node(0), topic).await.len(), 2);
+        assert_eq!(messages(simulation.node(1), topic).await, vec![b"denied"]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloaded_policy_disconnects_denied_peers() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let control = simulation.node(0).network().control();
+        let denied = simulation.node(1).id();
+        let connections = control.connections().await.unwrap();
+        assert!(connections.iter().any(|info| info.id == denied));
+
+        let mut config = Builder::new(2).config;
+        config.policy.denied_peers = vec![network::config::PeerId(denied)];
+        control.reload(config).await.unwrap();
+        simulation.advance(Duration::from_millis(100)).await;
+
+        let connections = control.connections().await.unwrap();
+        assert!(connections.iter().all(|info| info.id != denied));
+        assert!(control.view().iter().all(|peer| peer.id() != denied));
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        send_topics(&mut connection, topic, &[b"denied"]).await;
+        simulation.advance(ROUND).await;
+        assert_eq!(messages(simulation.node(0), topic).await, vec![b"denied"]);
+        assert!(messages(simulation.node(1), topic).await.is_empty());
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn advertised_addresses_are_used_when_unreachable() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .dual_stack()
+            .build()
+            .await
+            .unwrap();
+        // let the nodes gossip with each other (and share their addresses)
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+
+        // the established connection is closed and the nodes cannot reach
+        // each other on their IPv4 addresses anymore
+        simulation.partition(0, 1);
+        send_topics(&mut connection, topic, &[b"during"]).await;
+        simulation.advance(ROUND).await;
+
+        send_topics(&mut connection, topic, &[b"after"]).await;
+        simulation.advance(Duration::from_millis(500)).await;
+        assert!(messages(simulation.node(1), topic)
+            .await
+            .contains(&b"after".to_vec()));
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn topic_messages_are_paginated() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(1)
+            .subscribe(topic)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        assert!(connection.capabilities().supports_paginated_query());
+
+        let sent: Vec<_> = (0..5u8).map(|i| vec![i; 8]).collect();
+        send_topics(&mut connection, topic, &sent).await;
+        simulation.advance(ROUND).await;
+
+        let mut received = Vec::new();
+        let mut query = TopicQuery::new(topic, Time::from(0)).limit(2);
+        for _ in 0..3 {
+            connection
+                .send(Message::new_topic_query(&query))
+                .await
+                .unwrap();
+
+            let mut next = None;
+            loop {
+                let message = simulation.receive(&mut connection).await.unwrap();
+                if let Some((_, content)) = message.topic_checked() {
+                    received.push(content.to_vec());
+                    if received.len() == sent.len() {
+                        break;
+                    }
+                } else if let Some((_, cursor)) = message.query_topic_messages_next_checked() {
+                    next = Some(cursor);
+                    break;
+                }
+            }
+
+            match next {
+                Some(cursor) => query = query.after(cursor),
+                None => break,
+            }
+        }
+
+        assert_eq!(received, sent);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_are_told_about_shutdown() {
+        let simulation = Simulation::builder(1).build().await.unwrap();
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        // let the node register the connection
+        simulation.advance(Duration::from_millis(100)).await;
+
+        simulation.shutdown().await.unwrap();
+
+        let (_, message) = connection.next().await.unwrap();
+        assert_eq!(
+            message.unwrap().goodbye_checked(),
+            Some((asmtp_network::GoodbyeReason::Shutdown, None))
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn clients_are_told_about_overload() {
+        let simulation = Simulation::builder(1).build().await.unwrap();
+
+        let mut rng = Seed::from([2; Seed::SIZE]).into_rand_chacha();
+        let other = ed25519::SecretKey::new(&mut rng);
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        let _other = simulation.connect(0, &other).await.unwrap();
+        simulation.advance(Duration::from_millis(100)).await;
+
+        // the least recently used connection is closed
+        let mut config = Builder::new(1).config;
+        config.max_opened_connections = 1;
+        let control = simulation.node(0).network().control();
+        control.reload(config).await.unwrap();
+
+        let message = simulation.receive(&mut connection).await;
+        assert_eq!(
+            message.unwrap().goodbye_checked(),
+            Some((asmtp_network::GoodbyeReason::Overloaded, None))
+        );
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn misbehaving_peers_are_banned() {
+        let mut config = Builder::new(1).config;
+        config.scoring.threshold = 25;
+        config.scoring.rate_limit = 100;
+        let simulation = Simulation::builder(1)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+
+        let client = client();
+        let mut connection = simulation.connect(0, &client).await.unwrap();
+        // the client is not a user of the node: every registration is an
+        // invalid message (10 points, a bit less once decayed)
+        for seed in 0..3 {
+            let topic = Topic::new([seed; Topic::SIZE]);
+            connection
+                .send(Message::new_register_topic(topic))
+                .await
+                .unwrap();
+        }
+        simulation.advance(ROUND).await;
+
+        let message = simulation.receive(&mut connection).await;
+        let (reason, retry_after) = message.unwrap().goodbye_checked().unwrap();
+        assert_eq!(reason, asmtp_network::GoodbyeReason::Banned);
+        assert_eq!(retry_after, Some(Duration::from_secs(3_600)));
+        assert!(simulation
+            .node(0)
+            .network()
+            .control()
+            .unban(&client.public_key()));
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn peers_beyond_the_rate_limit_are_banned() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(1).config;
+        config.scoring.threshold = 30;
+        config.scoring.rate_limit = 4;
+        let simulation = Simulation::builder(1)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        // the messages beyond the limit are dropped (5 points each)
+        send_topics(&mut connection, topic, (0..12u8).map(|i| [i])).await;
+
+        // banned once the messages within the limit are stored
+        let message = simulation.receive(&mut connection).await;
+        assert_eq!(
+            message.unwrap().goodbye_checked().map(|(reason, _)| reason),
+            Some(asmtp_network::GoodbyeReason::Banned)
+        );
+        assert_eq!(messages(simulation.node(0), topic).await.len(), 4);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn heart_beat_follows_the_reloaded_period() {
+        let simulation = Simulation::builder(1).build().await.unwrap();
+        let control = simulation.node(0).network().control();
+
+        let mut config = Builder::new(1).config;
+        config.heart_beat = Duration::from_secs(2);
+        control.reload(config).await.unwrap();
+        let _connection = simulation.connect(0, &client()).await.unwrap();
+
+        // the commands keep waking up the network more often than it beats
+        for _ in 0..6 {
+            control.connections().await.unwrap();
+            simulation.advance(Duration::from_millis(500)).await;
+        }
+
+        let metrics = control.metrics().encode();
+        assert!(metrics.contains(r#"asmtpd_connections{direction="inbound"} 1"#));
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn relayed_messages_are_measured() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        send_topics(&mut connection, topic, &[b"measured"; 2]).await;
+        simulation.advance(ROUND).await;
+
+        let metrics = simulation.node(0).network().control().metrics().encode();
+        // the duplicate is neither stored nor relayed again
+        assert!(metrics.contains(r#"asmtpd_message_cache_lookups_total{result="miss"} 1"#));
+        assert!(metrics.contains(r#"asmtpd_messages_sent_total{type="Topic"} 1"#));
+
+        let metrics = simulation.node(1).network().control().metrics().encode();
+        assert!(metrics.contains(r#"asmtpd_messages_received_total{type="Topic"} 1"#));
+        assert!(metrics.contains(r#"asmtpd_message_cache_lookups_total{result="miss"} 1"#));
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn seen_messages_outlive_the_message_cache() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut config = Builder::new(2).config;
+        config.known_message_cache_size = 1;
+        let simulation = Simulation::builder(2)
+            .subscribe(topic)
+            .network_config(config)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let mut connection = simulation.connect(0, &client()).await.unwrap();
+        // the first message is out of the in-memory cache when it comes back
+        send_topics(&mut connection, topic, &[b"first", b"other", b"first"]).await;
+        simulation.advance(ROUND).await;
+
+        for node in simulation.nodes() {
+            assert_eq!(
+                messages(node, topic).await,
+                vec![b"first".to_vec(), b"other".to_vec()]
+            );
+        }
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn registered_topics_are_gossiped() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let simulation = Simulation::builder(2).build().await.unwrap();
+        simulation.advance(ROUND).await;
+
+        // the profile of node 0 is updated within the second of the one
+        // gossiped on startup
+        let mut connection = connect_user(&simulation, 0).await;
+        connection
+            .send(Message::new_register_topic(topic))
+            .await
+            .unwrap();
+        advance_until(&simulation, || knows_subscription(&simulation, 1, 0, topic)).await;
+
+        connection
+            .send(Message::new_deregister_topic(topic))
+            .await
+            .unwrap();
+        advance_until(&simulation, || {
+            !knows_subscription(&simulation, 1, 0, topic)
+        })
+        .await;
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exceeded_quota_is_rejected() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut storage = Builder::new(1).storage;
+        storage.quota.max_messages = Some(1);
+        let simulation = Simulation::builder(1)
+            .storage_config(storage)
+            .build()
+            .await
+            .unwrap();
+
+        let mut connection = connect_user(&simulation, 0).await;
+        connection
+            .send(Message::new_register_topic(topic))
+            .await
+            .unwrap();
+        send_topics(&mut connection, topic, &[b"kept", b"over"]).await;
+
+        let message = simulation.receive(&mut connection).await;
+        assert_eq!(
+            message.unwrap().rejected_checked(),
+            Some((asmtp_network::RejectReason::QuotaExceeded, topic))
+        );
+        assert_eq!(messages(simulation.node(0), topic).await, vec![b"kept"]);
+
+        simulation.shutdown().await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exceeded_quota_is_told_to_the_user_only() {
+        let topic = Topic::new([1; Topic::SIZE]);
+        let mut storage = Builder::new(2).storage;
+        storage.quota.max_messages = Some(1);
+        let simulation = Simulation::builder(2)
+            .storage_config(storage)
+            .build()
+            .await
+            .unwrap();
+        simulation.advance(ROUND).await;
+
+        let mut user = connect_user(&simulation, 0).await;
+        user.send(Message::new_register_topic(topic)).await.unwrap();
+        advance_until(&simulation, || knows_subscription(&simulation, 1, 0, topic)).await;
+
+        // the messages are relayed by the node 1 to the node 0
+        let mut rng = Seed::from([2; Seed::SIZE]).into_rand_chacha();
+        let author = ed25519::SecretKey::new(&mut rng);
+        let mut author = simulation.connect(1, &author).await.unwrap();
+        send_topics(&mut author, topic, &[b"kept", b"over"]).await;
+        simulation.advance(ROUND).await;
+
+        let message = simulation.receive(&mut user).await;
+        assert_eq!(
+            message.unwrap().rejected_checked(),
+            Some((asmtp_network::RejectReason::QuotaExceeded, topic))
+        );
+        assert_eq!(messages(simulation.node(0), topic).await, vec![b"kept"]);
+
+        // the user was told just now, it is not told again right away
+        send_topics(&mut author, topic, &[b"over and over"]).await;
+        assert!(simulation.receive(&mut user).await.is_err());
+
+        assert!(simulation.receive(&mut author).await.is_err());
+
+        // the peers sending the messages directly are not told either
+        let mut rng = Seed::from([3; Seed::SIZE]).into_rand_chacha();
+        let peer = ed25519::SecretKey::new(&mut rng);
+        let mut peer = simulation.connect(0, &peer).await.unwrap();
+        send_topics(&mut peer, topic, &[b"over again"]).await;
+
+        let message = simulation.receive(&mut user).await;
+        assert_eq!(
+            message.unwrap().rejected_checked(),
+            Some((asmtp_network::RejectReason::QuotaExceeded, topic))
+        );
+        assert!(simulation.receive(&mut peer).await.is_err());
+
+        simulation.shutdown().await.unwrap();
+    }
+}
@@ -0,0 +1,39 @@
+use crate::storage::Storage;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// enforce the retention policies of the [`Storage`] in the background
+/// (see [`Storage::compact`])
+pub struct Compaction {
+    handle: JoinHandle<()>,
+}
+
+impl Compaction {
+    /// compact the `storage` now and then every `interval`
+    ///
+    /// # Panics
+    ///
+    /// if the `interval` is zero
+    pub fn start(storage: Storage, interval: Duration) -> Self {
+        let mut interval = tokio::time::interval(interval);
+
+        let handle = tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+
+                match storage.compact().await {
+                    Ok(0) => {}
+                    Ok(deleted) => tracing::info!(deleted, "compacted the messages"),
+                    Err(error) => tracing::warn!(reason = ?error, "Cannot compact the messages"),
+                }
+            }
+        });
+
+        Self { handle }
+    }
+
+    /// stop compacting the storage
+    pub fn shutdown(self) {
+        self.handle.abort();
+    }
+}
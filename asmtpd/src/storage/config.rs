@@ -1,8 +1,12 @@
 use anyhow::{Context as _, Result};
+use asmtp_storage::Usage;
 use serde::{Deserialize, Serialize};
-use std::{path::PathBuf, time::Duration};
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
 use structopt::StructOpt;
 
+/// the path of the storage kept in memory
+const MEMORY: &str = ":memory:";
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, StructOpt, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -12,6 +16,24 @@ pub struct Config {
     #[structopt(long = "storage-path")]
     pub path: PathBuf,
 
+    /// path to the peers database
+    ///
+    /// the peers we know of, their latest gossip and how they behaved, are
+    /// kept there between restarts. If not set, the database is kept next
+    /// to the persistent storage file (see [`Config::peers_path`]).
+    #[serde(default)]
+    #[structopt(long = "storage-peers-path")]
+    pub peers_path: Option<PathBuf>,
+
+    /// number of peers from the peers database to start with
+    ///
+    /// on startup, the topology is seeded with the best known peers (by
+    /// reputation) in addition to the `known_gossips` of the network
+    /// configuration.
+    #[serde(default = "default_peers_seed")]
+    #[structopt(long = "storage-peers-seed", default_value = "64")]
+    pub peers_seed: usize,
+
     /// number of minutes to store gossips in the persistent storage
     ///
     /// this will set the storage rate refresh of the current state
@@ -27,12 +49,185 @@ pub struct Config {
     #[serde(default = "default_passport_cache_size")]
     #[structopt(long = "storage-passport-cache-size", default_value = "256")]
     pub passport_cache_size: usize,
+
+    /// maximum number of messages to send back for one topic query
+    ///
+    /// peers may query fewer messages. If more messages match the
+    /// query, the peers supporting paginated queries are sent a cursor
+    /// to query the next messages, the older peers only receive the first
+    /// messages.
+    #[serde(default = "default_query_limit")]
+    #[structopt(long = "storage-query-limit", default_value = "512")]
+    pub query_limit: usize,
+
+    /// how long and how many messages to keep in every thread
+    #[structopt(skip)]
+    #[serde(default)]
+    pub thread_retention: Retention,
+
+    /// how long and how many messages to keep in all the threads
+    /// registered by the same user
+    #[structopt(skip)]
+    #[serde(default)]
+    pub user_retention: Retention,
+
+    /// how many messages to accept in all the threads registered by the
+    /// same user
+    #[structopt(skip)]
+    #[serde(default)]
+    pub quota: Quota,
+
+    /// the quota of specific users (as listed in the `users`), in place
+    /// of the `quota`
+    #[structopt(skip)]
+    #[serde(default)]
+    pub user_quotas: BTreeMap<String, Quota>,
+
+    /// number of minutes between two compactions of the messages
+    ///
+    /// the retention policies (`thread_retention` and `user_retention`)
+    /// are enforced on every compaction. `0` disables the compaction.
+    #[serde(default = "default_compaction_interval")]
+    #[structopt(long = "storage-compaction-interval", parse(try_from_str = duration))]
+    pub compaction_interval: Duration,
+
+    /// number of minutes to remember the messages the node has seen
+    ///
+    /// the hashes of the messages are kept in the peers database, so a
+    /// message coming back within this window is neither relayed nor
+    /// stored again, even after a restart.
+    #[serde(default = "default_seen_messages_window")]
+    #[structopt(long = "storage-seen-messages-window", parse(try_from_str = duration))]
+    pub seen_messages_window: Duration,
+
+    /// maximum number of messages to remember (see `seen_messages_window`)
+    ///
+    /// the oldest messages are forgotten first if more messages are seen
+    /// within the window.
+    #[serde(default = "default_seen_messages_size")]
+    #[structopt(long = "storage-seen-messages-size", default_value = "1048576")]
+    pub seen_messages_size: usize,
+}
+
+impl Config {
+    /// check if the settings differ from the `other` settings in ways that
+    /// cannot be applied while the node is running
+    ///
+    /// the retention policies and the quotas can be changed (see
+    /// [`Storage::reload`](super::Storage::reload)).
+    pub fn restart_required(&self, other: &Self) -> bool {
+        let other = Self {
+            thread_retention: self.thread_retention,
+            user_retention: self.user_retention,
+            quota: self.quota,
+            user_quotas: self.user_quotas.clone(),
+            ..other.clone()
+        };
+        *self != other
+    }
+}
+
+/// how long and how many messages to keep
+///
+/// the messages beyond any of the limits are deleted on the next
+/// compaction, the oldest first. The limits that are not set are not
+/// enforced.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    /// the messages received longer ago than this are deleted
+    #[serde(default)]
+    pub max_age: Option<Duration>,
+
+    /// maximum number of messages to keep
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+
+    /// maximum size of the messages to keep, in bytes
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// how many messages to accept on behalf of a user
+///
+/// the new messages beyond any of the limits are rejected, the clients of
+/// the user connected to the node are told the quota is exceeded. The
+/// limits that are not set are not enforced.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Quota {
+    /// maximum number of messages
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+
+    /// maximum size of the messages, in bytes
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+impl Config {
+    /// the path to the peers database: the `peers_path` if set, otherwise
+    /// the `path` of the storage with the `peers` extension
+    ///
+    /// there is none if the storage is in memory (`:memory:`), the peers
+    /// are then forgotten when the node stops.
+    pub fn peers_path(&self) -> Option<PathBuf> {
+        match &self.peers_path {
+            Some(path) => Some(path.clone()),
+            None if self.path.as_os_str() == MEMORY => None,
+            None => Some(self.path.with_extension("peers")),
+        }
+    }
+}
+
+impl Retention {
+    /// check if any of the limits is set
+    pub fn is_limited(&self) -> bool {
+        self.max_age.is_some() || self.max_messages.is_some() || self.max_bytes.is_some()
+    }
+}
+
+impl Quota {
+    /// check if any of the limits is set
+    pub fn is_limited(&self) -> bool {
+        self.max_messages.is_some() || self.max_bytes.is_some()
+    }
+
+    /// check if one more message of `bytes` bytes fits in the quota given
+    /// the messages already kept (`usage`)
+    pub fn allows(&self, usage: Usage, bytes: usize) -> bool {
+        let messages = usage.messages as u64 + 1;
+        let bytes = usage.bytes as u64 + bytes as u64;
+
+        let exceeds = |max: Option<u64>, value: u64| matches!(max, Some(max) if value > max);
+        !exceeds(self.max_messages, messages) && !exceeds(self.max_bytes, bytes)
+    }
+}
+
+fn default_peers_seed() -> usize {
+    64
 }
 
 fn default_passport_cache_size() -> usize {
     256
 }
 
+fn default_query_limit() -> usize {
+    512
+}
+
+fn default_compaction_interval() -> Duration {
+    Duration::from_secs(10 * 60)
+}
+
+fn default_seen_messages_window() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn default_seen_messages_size() -> usize {
+    1_048_576
+}
+
 fn default_gossips_refresh_rate() -> Duration {
     Duration::from_secs(30)
 }
@@ -43,3 +238,55 @@ fn duration(s: &str) -> Result<Duration> {
         .context("expecting to parse a duration in minutes")?;
     Ok(Duration::from_secs(i * 60))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_are_kept_next_to_the_storage() {
+        let config = Config {
+            path: "/var/lib/asmtpd/storage.db".into(),
+            ..Config::default()
+        };
+        assert_eq!(
+            config.peers_path(),
+            Some("/var/lib/asmtpd/storage.peers".into())
+        );
+
+        let config = Config {
+            peers_path: Some("/var/lib/asmtpd/peers".into()),
+            ..config
+        };
+        assert_eq!(config.peers_path(), Some("/var/lib/asmtpd/peers".into()));
+
+        let config = Config {
+            path: MEMORY.into(),
+            peers_path: None,
+            ..config
+        };
+        assert_eq!(config.peers_path(), None);
+    }
+
+    #[test]
+    fn quota_allows() {
+        let usage = Usage {
+            messages: 2,
+            bytes: 10,
+        };
+        assert!(Quota::default().allows(usage, 1_000));
+
+        let quota = Quota {
+            max_messages: Some(3),
+            max_bytes: Some(12),
+        };
+        assert!(quota.allows(usage, 2));
+        assert!(!quota.allows(usage, 3));
+
+        let usage = Usage {
+            messages: 3,
+            bytes: 3,
+        };
+        assert!(!quota.allows(usage, 1));
+    }
+}
@@ -1,34 +1,137 @@
+mod compaction;
 mod config;
-mod gossips;
+mod peers;
+mod seen;
+mod user;
 
-pub use self::config::Config;
-use self::gossips::Gossips;
+pub use self::{
+    compaction::Compaction,
+    config::{Config, Quota, Retention},
+    peers::{PeerRecord, Peers},
+    seen::{seen_hash, SeenHash, SeenMessages},
+    user::User,
+};
 use anyhow::{ensure, Context as _, Result};
-use asmtp_lib::PassportImporter;
-use asmtp_storage::{Storage as Db, StorageOptions};
+use asmtp_lib::{passport_topic, MessageId, PassportImporter};
+use asmtp_network::{MessageHash, TopicQuery};
+use asmtp_storage::{Storage as Db, StorageOptions, Usage};
 use bytes::Bytes;
+use futures::prelude::*;
 use keynesis::{
     key::ed25519,
     passport::{
-        block::{BlockSlice, Hash, Time},
-        Passport, PassportBlocks, PassportBlocksSlice,
+        block::{Hash, Time},
+        LightPassport, PassportBlocks, PassportBlocksSlice,
     },
 };
 use poldercast::{Gossip, Topic};
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    convert::TryFrom as _,
+    sync::{Arc, Mutex},
+};
+use thiserror::Error;
+
+/// the outcome of receiving new blocks of a passport (see
+/// [`Storage::handle_passport_blocks`])
+pub enum PassportUpdate {
+    /// the passport was updated with the `blocks` following `head`
+    Updated {
+        head: Hash,
+        blocks: PassportBlocks<Vec<u8>>,
+    },
+    /// we already had all the blocks
+    UpToDate,
+    /// the blocks do not follow any of our blocks: we are missing the
+    /// blocks following our `head`
+    Behind { head: Hash },
+}
+
+/// a message was not kept because the user the messages of the topic are
+/// kept for is over its quota (see [`Config::quota`])
+#[derive(Debug, Error)]
+#[error("the quota of the user {user} is exceeded, not keeping the message of the topic {topic}")]
+pub struct QuotaExceeded {
+    pub user: String,
+    pub topic: Topic,
+}
+
+/// the request of a peer was refused, the peer is to blame: it does not
+/// act for one of the users or it sent invalid data
+///
+/// the other errors are failures of the node itself.
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct Refused(String);
+
+/// the settings of the storage that can be changed while the node is
+/// running, all checked before any is applied (see [`Storage::reload`])
+pub struct Settings {
+    users: HashSet<User>,
+    limits: Limits,
+}
+
+/// the retention policies and the quotas (see [`Config::thread_retention`]
+/// and [`Config::quota`])
+#[derive(Clone)]
+struct Limits {
+    thread_retention: Retention,
+    user_retention: Retention,
+    quota: Quota,
+    /// the quotas of specific users, by the users' canonical form
+    user_quotas: BTreeMap<String, Quota>,
+}
 
 #[derive(Clone)]
 pub struct Storage {
-    gossips: Gossips,
+    peers: Peers,
+    seen: SeenMessages,
     storage: Db,
     db: sled::Db,
+    query_limit: usize,
+    peers_seed: usize,
+    /// shared by all the clones so the retention policies and the quotas
+    /// can be changed while the node is running (see [`Storage::reload`])
+    limits: Arc<Mutex<Limits>>,
+
+    /// the messages kept on behalf of the users with a quota, counted as
+    /// they are stored rather than summed for every message. An owner is
+    /// counted from the database again after a compaction or once its
+    /// topics are removed.
+    usage: Arc<Mutex<HashMap<String, Usage>>>,
+
+    /// shared by all the clones so the users can be changed while the
+    /// node is running (see [`Storage::set_users`])
+    users: Arc<Mutex<HashSet<User>>>,
 
-    users: HashSet<ed25519::PublicKey>,
+    /// the active master keys of the passports, loaded the first time a
+    /// peer is checked against them (see [`Storage::is_master_key`]) and
+    /// replaced as the passports are stored or updated. A passport we do
+    /// not keep has no keys.
+    passport_keys: Arc<Mutex<HashMap<Hash, HashSet<ed25519::PublicKey>>>>,
+}
+
+impl Settings {
+    /// check the `users` and the limits of the `config`
+    pub fn new(config: &Config, users: &HashSet<String>) -> Result<Self> {
+        Ok(Self {
+            users: parse_users(users)?,
+            limits: Limits {
+                thread_retention: config.thread_retention,
+                user_retention: config.user_retention,
+                quota: config.quota,
+                user_quotas: parse_user_quotas(&config.user_quotas)?,
+            },
+        })
+    }
 }
 
 impl Storage {
     pub async fn new(config: Config, users_set: HashSet<String>) -> Result<Self> {
-        let sled_config = sled::Config::new().temporary(true);
+        let sled_config = match config.peers_path() {
+            Some(path) => sled::Config::new().path(path),
+            None => sled::Config::new().temporary(true),
+        };
 
         let sled_db = sled_config.open().with_context(|| {
             format!(
@@ -37,84 +140,227 @@ impl Storage {
             )
         })?;
 
-        let gossips = Gossips::new(&sled_db, config.gossip_refresh_rate)?;
+        let peers = Peers::new(&sled_db, config.gossip_refresh_rate)?;
+        let seen = SeenMessages::new(
+            &sled_db,
+            config.seen_messages_window,
+            config.seen_messages_size,
+        )?;
 
-        let mut users: HashSet<ed25519::PublicKey> = HashSet::new();
-        for user in users_set {
-            let k = user.parse().context("Invalid user")?;
-            users.insert(k);
-        }
+        let Settings { users, limits } = Settings::new(&config, &users_set)?;
 
-        let storage = Db::new(StorageOptions::Sqlite {
+        let storage = request(Db::new(StorageOptions::Sqlite {
             uri: config.path.display().to_string(),
-        })
+        }))
         .await?;
 
         Ok(Self {
-            users,
-            gossips,
+            users: Arc::new(Mutex::new(users)),
+            peers,
+            seen,
             db: sled_db,
+            query_limit: config.query_limit.max(1),
+            peers_seed: config.peers_seed,
+            limits: Arc::new(Mutex::new(limits)),
+            usage: Arc::new(Mutex::new(HashMap::new())),
+            passport_keys: Arc::new(Mutex::new(HashMap::new())),
             storage,
         })
     }
 
     pub async fn put_passport(&self, passport_blocks: PassportBlocksSlice<'_>) -> Result<Hash> {
-        self.storage.new_passport(passport_blocks).await
+        let id = request(self.storage.new_passport(passport_blocks)).await?;
+
+        match PassportImporter::from_blocks(passport_blocks.iter()) {
+            Ok(passport) => self.set_passport_keys(&passport),
+            Err(_) => {
+                self.passport_keys.lock().unwrap().remove(&id);
+            }
+        }
+
+        Ok(id)
     }
 
     pub async fn get_passport_blocks(&self, id: Hash) -> Result<Option<PassportBlocks<Vec<u8>>>> {
-        self.storage
-            .get_passport(&id)
+        request(self.storage.get_passport(&id))
             .await
             .context("Failed to get passport's block from persistent storage")
     }
 
-    pub async fn get_passport_from_topic(&self, topic: Topic) -> Result<Option<Passport>> {
-        let mut id = [0; Hash::SIZE];
-        if topic.as_ref()[..(Topic::SIZE - Hash::SIZE)] != [0; Topic::SIZE - Hash::SIZE] {
-            return Ok(None);
-        }
-
-        let hash = &topic.as_ref()[(Topic::SIZE - Hash::SIZE)..];
-        id.copy_from_slice(hash.as_ref());
-        let id = Hash::from(id);
-
+    /// load the passport `id`, if we keep it
+    ///
+    /// the [`LightPassport`] applies all the blocks to its ledger, unlike
+    /// the [`Passport`](keynesis::passport::Passport) that only follows
+    /// its genesis.
+    pub async fn get_passport(&self, id: Hash) -> Result<Option<LightPassport>> {
         if let Some(blocks) = self.get_passport_blocks(id).await? {
-            let passport = PassportImporter::from_blocks_owned(
-                blocks.as_slice().iter().map(|b| b.to_block()),
-            )?;
+            let passport = PassportImporter::from_blocks(blocks.iter())?;
             Ok(Some(passport))
         } else {
             Ok(None)
         }
     }
 
-    pub async fn handle_incoming_message(&mut self, topic: Topic, message: Bytes) -> Result<()> {
-        if let Some(mut passport) = self
-            .get_passport_from_topic(topic)
-            .await
-            .context("Error while handling incoming message")?
-        {
-            let block = BlockSlice::try_from_slice(message.as_ref()).with_context(|| {
-                format!("cannot handle new block for passport {}", passport.id())
-            })?;
-
-            passport.push(block)?;
-
-            self.storage.update_passport(passport.blocks()).await?;
-            Ok(())
-        } else if self.storage.contains_tread(&topic).await? {
-            let _message_id = self.storage.new_message(&topic, message).await?;
-            Ok(())
-        } else {
-            // simply ignore the message and relay anyway
-            Ok(())
+    pub async fn contains_passport(&self, id: Hash) -> Result<bool> {
+        Ok(self.get_passport_blocks(id).await?.is_some())
+    }
+
+    /// keep the message if we keep the messages of the topic
+    ///
+    /// returns `false` if the message is in the
+    /// [`seen_messages`](Self::seen_messages): it is not kept again, nor
+    /// to be relayed. Otherwise the message is remembered as seen once
+    /// it is kept.
+    ///
+    /// fails with [`QuotaExceeded`] if the user the messages of the topic
+    /// are kept for has no room left for the message (see
+    /// [`Config::quota`] and [`Config::user_quotas`]). The message is not
+    /// remembered as seen: it is kept if it comes back once the user has
+    /// room for it.
+    pub async fn handle_incoming_message(&mut self, topic: Topic, message: Bytes) -> Result<bool> {
+        let hash = seen_hash(&message);
+        if self.seen.contains(&hash)? {
+            return Ok(false);
+        }
+
+        if request(self.storage.contains_tread(&topic)).await? {
+            // the messages kept for longer than they are remembered as seen
+            // (sent again by a reconciliation for example)
+            let message_hash = asmtp_storage::message_hash(&message);
+            if request(self.storage.contains_message(&topic, &message_hash)).await? {
+                self.seen.check(&hash)?;
+                return Ok(false);
+            }
+
+            let mut owner = None;
+            if let Some(user) = request(self.storage.thread_owner(&topic)).await? {
+                let quota = self.quota_of(&user);
+                if quota.is_limited() {
+                    let usage = self.owner_usage(&user).await?;
+                    if !quota.allows(usage, message.len()) {
+                        return Err(QuotaExceeded { user, topic }.into());
+                    }
+                    owner = Some(user);
+                }
+            }
+
+            let bytes = message.len() as i64;
+            let _message_id = request(self.storage.new_message(&topic, message)).await?;
+            if let Some(owner) = owner {
+                if let Some(usage) = self.usage.lock().unwrap().get_mut(&owner) {
+                    usage.messages += 1;
+                    usage.bytes += bytes;
+                }
+            }
+        }
+        // the messages of the topics we do not keep are relayed anyway
+        self.seen.check(&hash)
+    }
+
+    /// the quota of the `owner` of threads (see [`Config::user_quotas`])
+    fn quota_of(&self, owner: &str) -> Quota {
+        let limits = self.limits.lock().unwrap();
+        limits
+            .user_quotas
+            .get(owner)
+            .copied()
+            .unwrap_or(limits.quota)
+    }
+
+    /// the messages kept on behalf of the `owner`, counted from the
+    /// database only the first time
+    async fn owner_usage(&self, owner: &str) -> Result<Usage> {
+        if let Some(usage) = self.usage.lock().unwrap().get(owner) {
+            return Ok(*usage);
+        }
+
+        let usage = request(self.storage.owner_usage(owner)).await?;
+        self.usage.lock().unwrap().insert(owner.to_owned(), usage);
+        Ok(usage)
+    }
+
+    /// apply the `blocks` following the block `head` to the passport `id`
+    ///
+    /// the blocks we already have are skipped. The blocks are validated
+    /// against the passport's ledger before being stored.
+    pub async fn handle_passport_blocks(
+        &self,
+        id: Hash,
+        head: Hash,
+        blocks: PassportBlocksSlice<'_>,
+    ) -> Result<PassportUpdate> {
+        let mut stored = self
+            .get_passport_blocks(id)
+            .await?
+            .with_context(|| format!("Unknown passport {}", id))?;
+        let mut passport = PassportImporter::from_blocks(stored.iter())?;
+
+        let known: Vec<Hash> = stored.iter().map(|block| block.header().hash()).collect();
+        let our_head = *known.last().expect("a passport has at least one block");
+        let position = match known.iter().position(|hash| hash == &head) {
+            Some(position) => position,
+            None => return Ok(PassportUpdate::Behind { head: our_head }),
+        };
+        let known = &known[position + 1..];
+
+        let mut added = PassportBlocks::new();
+        for (index, block) in blocks.iter().enumerate() {
+            if let Some(hash) = known.get(index) {
+                ensure!(
+                    &block.header().hash() == hash,
+                    Refused(format!(
+                        "the blocks do not match the known blocks of passport {}",
+                        id
+                    ))
+                );
+                continue;
+            }
+
+            passport
+                .update(block)
+                .with_context(|| Refused(format!("cannot handle new block for passport {}", id)))?;
+            added.push(block);
+        }
+
+        if added.as_slice().is_empty() {
+            return Ok(PassportUpdate::UpToDate);
+        }
+
+        for block in added.iter() {
+            stored.push(block);
+        }
+        request(self.storage.update_passport(stored.as_slice())).await?;
+        self.set_passport_keys(&passport);
+        Ok(PassportUpdate::Updated {
+            head: our_head,
+            blocks: added,
+        })
+    }
+
+    /// the blocks of the passport `id` following the block `head`
+    ///
+    /// returns `None` if we do not keep the passport or if `head` is not
+    /// one of its blocks
+    pub async fn passport_blocks_since(
+        &self,
+        id: Hash,
+        head: Hash,
+    ) -> Result<Option<PassportBlocks<Vec<u8>>>> {
+        let blocks = match self.get_passport_blocks(id).await? {
+            Some(blocks) => blocks,
+            None => return Ok(None),
+        };
+
+        let mut iter = blocks.iter();
+        if !iter.any(|block| block.header().hash() == head) {
+            return Ok(None);
         }
+
+        Ok(Some(iter.collect()))
     }
 
     pub async fn handle_get_passport(&self, id: Hash) -> Result<Option<PassportBlocks<Vec<u8>>>> {
-        self.storage
-            .get_passport(&id)
+        request(self.storage.get_passport(&id))
             .await
             .context("Failed to query passport from block")
     }
@@ -126,8 +372,11 @@ impl Storage {
         blocks: PassportBlocks<Vec<u8>>,
     ) -> Result<()> {
         ensure!(
-            self.users.contains(&peer),
-            "user needs to be registered in order to allow them to publish passports"
+            self.is_user(&peer).await? || self.is_passport_user(&peer, id, &blocks).await?,
+            Refused(
+                "user needs to be registered in order to allow them to publish passports"
+                    .to_owned()
+            )
         );
 
         tracing::info!(id = %id, peer = %peer, "received new passport blocks");
@@ -135,13 +384,13 @@ impl Storage {
 
         ensure!(
             resulted_id == id,
-            "the passport does not match the expected given hash"
+            Refused("the passport does not match the expected given hash".to_owned())
         );
         Ok(())
     }
 
     pub async fn subscribe_message(&self, topic: Topic) -> Result<()> {
-        self.storage.new_thread(&topic).await
+        request(self.storage.new_thread(&topic)).await
     }
 
     /*
@@ -151,73 +400,861 @@ impl Storage {
 
     /// list all the subscriptions we have in the storage
     pub async fn topic_subscriptions(&self) -> Result<Vec<Topic>> {
-        let mut topics: Vec<Topic> = self
-            .storage
-            .passports()
-            .await?
-            .into_iter()
-            .map(|p| {
-                let h = p.id;
-                let mut bytes = [0; Topic::SIZE];
-                let topic = &mut bytes[(Topic::SIZE - Hash::SIZE)..];
-                topic.copy_from_slice(&h);
-                Topic::new(bytes)
-            })
-            .collect();
+        let mut topics = Vec::new();
+        for passport in request(self.storage.passports()).await? {
+            let id = Hash::try_from(passport.id.as_slice()).context("Invalid passport id")?;
+            topics.push(passport_topic(&id));
+        }
 
-        topics.extend(self.storage.threads().await?.into_iter().map(|t| {
-            let mut topic = [0; Topic::SIZE];
-            topic.copy_from_slice(&t.topic);
-            Topic::new(topic)
-        }));
+        topics.extend(self.message_topics().await?);
 
         Ok(topics)
     }
 
-    /// list of the known gossips in the storage
+    /// the gossips of the best known peers in the storage
+    ///
+    /// at most the configured number of peers to seed the topology with
+    /// are returned, by order of reputation.
     pub fn known_gossips(&self) -> Result<Vec<Gossip>> {
-        self.gossips.gossips()
+        self.peers.seeds(self.peers_seed)
     }
 
     pub fn needs_update_known_gossips(&self) -> bool {
-        self.gossips.needs_updated()
+        self.peers.needs_updated()
     }
 
     /// update the known gossips
     pub fn update_known_gossips(&self, gossips: Vec<Gossip>) -> Result<()> {
-        self.gossips.update(gossips)
+        self.peers.update(gossips)
+    }
+
+    /// the users authorized to perform administrative operations on
+    /// the node
+    pub fn users(&self) -> Vec<User> {
+        self.users.lock().unwrap().iter().copied().collect()
+    }
+
+    /// replace the users authorized to perform administrative operations
+    /// on the node
+    ///
+    /// the users are left unchanged if any of the new users is invalid.
+    pub fn set_users(&self, users: &HashSet<String>) -> Result<()> {
+        let users = parse_users(users)?;
+        *self.users.lock().unwrap() = users;
+        Ok(())
+    }
+
+    /// apply the new users, retention policies and quotas (see
+    /// [`Settings::new`])
+    ///
+    /// the new retention policies are enforced on the next compaction.
+    pub fn reload(&self, settings: Settings) {
+        *self.users.lock().unwrap() = settings.users;
+        *self.limits.lock().unwrap() = settings.limits;
+    }
+
+    /// check the `peer` is one of the users: either its key is one of the
+    /// users or it is an active master key of one of the users' passports
+    /// we keep
+    async fn is_user(&self, peer: &ed25519::PublicKey) -> Result<bool> {
+        Ok(self.user_of(peer).await?.is_some())
+    }
+
+    /// check the `peer` acts for the `user` the messages of a thread are
+    /// kept for (see [`QuotaExceeded::user`])
+    pub async fn is_acting_for(&self, peer: &ed25519::PublicKey, user: &str) -> Result<bool> {
+        Ok(matches!(self.user_of(peer).await?, Some(u) if u.to_string() == user))
+    }
+
+    /// find the user the `peer` acts for (see [`Storage::is_user`])
+    async fn user_of(&self, peer: &ed25519::PublicKey) -> Result<Option<User>> {
+        let passports: Vec<Hash> = {
+            let users = self.users.lock().unwrap();
+            let user = User::Key(*peer);
+            if users.contains(&user) {
+                return Ok(Some(user));
+            }
+            users
+                .iter()
+                .filter_map(|user| match user {
+                    User::Passport(id) => Some(*id),
+                    User::Key(_) => None,
+                })
+                .collect()
+        };
+
+        for id in passports {
+            if self.is_master_key(id, peer).await? {
+                return Ok(Some(User::Passport(id)));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// check the `peer` is an active master key of the passport `id`
+    ///
+    /// the keys are loaded from the database only the first time.
+    async fn is_master_key(&self, id: Hash, peer: &ed25519::PublicKey) -> Result<bool> {
+        if let Some(keys) = self.passport_keys.lock().unwrap().get(&id) {
+            return Ok(keys.contains(peer));
+        }
+
+        let keys: HashSet<_> = match self.get_passport(id).await? {
+            Some(passport) => passport
+                .active_master_keys()
+                .iter()
+                .map(|key| **key)
+                .collect(),
+            None => HashSet::new(),
+        };
+        let found = keys.contains(peer);
+        // the keys of a passport stored or updated meanwhile are newer
+        self.passport_keys.lock().unwrap().entry(id).or_insert(keys);
+        Ok(found)
+    }
+
+    /// replace the active master keys of the `passport`
+    fn set_passport_keys(&self, passport: &LightPassport) {
+        let keys = passport
+            .active_master_keys()
+            .iter()
+            .map(|key| **key)
+            .collect();
+        self.passport_keys
+            .lock()
+            .unwrap()
+            .insert(passport.id(), keys);
     }
 
-    pub async fn messages(&self, topic: Topic, since: Time) -> Result<Vec<Vec<u8>>> {
+    /// check the `peer` is an active master key of the passport `id` it is
+    /// publishing and that this passport is one of the users
+    ///
+    /// this is how a user publishes its passport the first time or
+    /// publishes the blocks registering a new master key. The `blocks`
+    /// are applied to the passport we keep, if any, so the keys the
+    /// blocks we have already revoke are not accepted.
+    async fn is_passport_user(
+        &self,
+        peer: &ed25519::PublicKey,
+        id: Hash,
+        blocks: &PassportBlocks<Vec<u8>>,
+    ) -> Result<bool> {
+        if !self.users.lock().unwrap().contains(&User::Passport(id)) {
+            return Ok(false);
+        }
+
+        let passport = match self.get_passport_blocks(id).await? {
+            Some(stored) => {
+                let mut passport = PassportImporter::from_blocks(stored.iter())?;
+                let known: Vec<Hash> = stored.iter().map(|block| block.header().hash()).collect();
+                for (index, block) in blocks.iter().enumerate() {
+                    match known.get(index) {
+                        Some(hash) => ensure!(
+                            &block.header().hash() == hash,
+                            Refused(format!(
+                                "the blocks do not match the known blocks of passport {}",
+                                id
+                            ))
+                        ),
+                        None => passport.update(block).with_context(|| {
+                            Refused(format!("cannot handle new block for passport {}", id))
+                        })?,
+                    }
+                }
+                passport
+            }
+            None => PassportImporter::from_blocks(blocks.iter())
+                .with_context(|| Refused(format!("invalid blocks for passport {}", id)))?,
+        };
+        Ok(passport.id() == id && passport.active_master_keys().contains(peer))
+    }
+
+    /// the messages the node has seen recently
+    ///
+    /// the network checks the incoming messages against it, so the
+    /// messages seen within the window are neither relayed nor stored again.
+    pub fn seen_messages(&self) -> &SeenMessages {
+        &self.seen
+    }
+
+    /// the peers database, to record how the peers behave
+    pub fn peers(&self) -> &Peers {
+        &self.peers
+    }
+
+    /// the messages matching the `query`, by order of arrival
+    ///
+    /// at most the query's limit (bound to the configured query limit)
+    /// messages are returned. If more messages match the query a cursor
+    /// is returned too: it is the [`MessageId`] to query the next messages
+    /// with (see [`TopicQuery::after`]).
+    ///
+    /// The cursor is opaque to the peers: it carries the time the last
+    /// returned message was received at and where it is stored, so the next
+    /// page starts right after it even if several messages were received
+    /// within the same second.
+    pub async fn messages(&self, query: &TopicQuery) -> Result<(Vec<Vec<u8>>, Option<MessageId>)> {
+        let limit = query
+            .max_messages()
+            .map(usize::from)
+            .unwrap_or(usize::MAX)
+            .min(self.query_limit);
+
+        let since = chrono::DateTime::from(query.since().to_system_time());
+        let until = query
+            .until_time()
+            .map(|until| chrono::DateTime::from(until.to_system_time()));
+        let after = query.cursor().map(stored_id);
+
+        let messages = self
+            .storage
+            .messages_of_thread_between(query.topic(), since, until, after);
+        futures::pin_mut!(messages);
+
+        let mut page = Vec::with_capacity(limit.min(1024));
+        let mut next = None;
+        while let Some(message) = request(messages.next()).await {
+            let message = message?;
+            if page.len() == limit {
+                next = page.last().map(|(id, _)| *id);
+                break;
+            }
+
+            let time = Time::from(
+                (message.created_at.timestamp() as u64).saturating_sub(Time::COVID_EPOCH) as u32,
+            );
+            page.push((cursor(time, message.id), message.content));
+        }
+
+        Ok((page.into_iter().map(|(_, content)| content).collect(), next))
+    }
+
+    /// the [`MessageHash`]es of the messages of the `topic` received since
+    /// the given time, sorted (see [`Reconciliation`])
+    ///
+    /// the content of the messages is not loaded, see
+    /// [`Storage::messages_by_hash`].
+    ///
+    /// [`Reconciliation`]: asmtp_network::Reconciliation
+    pub async fn message_hashes(&self, topic: &Topic, since: Time) -> Result<Vec<MessageHash>> {
         let since = chrono::DateTime::from(since.to_system_time());
-        self.storage
-            .messages_of_thread_since(&topic, since)
-            .await
-            .map(|r| r.into_iter().map(|m| m.content).collect())
+        let hashes = request(self.storage.message_hashes_of_thread_since(topic, since)).await?;
+        let mut hashes: Vec<_> = hashes.into_iter().map(MessageHash::from).collect();
+        // the same content may have been kept twice before the messages
+        // were indexed by their hash
+        hashes.sort_unstable();
+        hashes.dedup();
+        Ok(hashes)
+    }
+
+    /// the content of the messages of the `topic` with the given hashes,
+    /// the hashes of no message of the topic are skipped
+    pub async fn messages_by_hash(
+        &self,
+        topic: &Topic,
+        hashes: &[MessageHash],
+    ) -> Result<Vec<Vec<u8>>> {
+        let hashes: Vec<_> = hashes
+            .iter()
+            .map(|hash| {
+                let mut bytes = [0; MessageHash::SIZE];
+                bytes.copy_from_slice(hash.as_ref());
+                bytes
+            })
+            .collect();
+        request(self.storage.messages_of_thread_by_hash(topic, &hashes)).await
+    }
+
+    /// list the topics we are keeping the messages of
+    pub async fn message_topics(&self) -> Result<Vec<Topic>> {
+        Ok(request(self.storage.threads())
+            .await?
+            .into_iter()
+            .map(|t| {
+                let mut topic = [0; Topic::SIZE];
+                topic.copy_from_slice(&t.topic);
+                Topic::new(topic)
+            })
+            .collect())
+    }
+
+    /// enforce the retention policies (see [`Config::thread_retention`] and
+    /// [`Config::user_retention`]), deleting the oldest messages first
+    ///
+    /// returns the number of deleted messages
+    pub async fn compact(&self) -> Result<u64> {
+        let (thread_retention, user_retention) = {
+            let limits = self.limits.lock().unwrap();
+            (limits.thread_retention, limits.user_retention)
+        };
+        let mut deleted = 0;
+
+        if thread_retention.is_limited() {
+            for topic in self.message_topics().await? {
+                deleted += self.apply_retention(&topic, &thread_retention).await?;
+            }
+        }
+
+        if user_retention.is_limited() {
+            let Retention {
+                max_age,
+                max_messages,
+                max_bytes,
+            } = user_retention;
+
+            for user in request(self.storage.thread_owners()).await? {
+                if let Some(max_age) = max_age {
+                    for thread in request(self.storage.threads_of_owner(&user)).await? {
+                        let topic = Topic::try_from(thread.topic.as_slice())
+                            .context("Invalid thread topic")?;
+                        deleted +=
+                            request(self.storage.delete_messages_older_than(&topic, max_age))
+                                .await?;
+                    }
+                }
+                deleted += request(self.storage.truncate_owner(
+                    &user,
+                    limit(max_messages),
+                    limit(max_bytes),
+                ))
+                .await?;
+            }
+        }
+
+        if deleted > 0 {
+            self.usage.lock().unwrap().clear();
+        }
+
+        Ok(deleted)
+    }
+
+    async fn apply_retention(&self, topic: &Topic, retention: &Retention) -> Result<u64> {
+        let mut deleted = 0;
+        if let Some(max_age) = retention.max_age {
+            deleted += request(self.storage.delete_messages_older_than(topic, max_age)).await?;
+        }
+        deleted += request(self.storage.truncate_thread(
+            topic,
+            limit(retention.max_messages),
+            limit(retention.max_bytes),
+        ))
+        .await?;
+        Ok(deleted)
+    }
+
+    /// the number of passports the node keeps
+    pub async fn number_passports(&self) -> Result<usize> {
+        Ok(request(self.storage.number_passports()).await? as usize)
+    }
+
+    /// the number of topics the node keeps the messages of
+    pub async fn number_threads(&self) -> Result<usize> {
+        Ok(request(self.storage.number_threads()).await? as usize)
     }
 
+    pub async fn contains_messages_of(&self, topic: &Topic) -> Result<bool> {
+        request(self.storage.contains_tread(topic)).await
+    }
+
+    /// keep the messages of the `topic` on behalf of the user the `peer`
+    /// acts for
+    ///
+    /// the messages are accounted to the user (see [`Config::quota`] and
+    /// [`Config::user_retention`]). A topic already kept stays accounted
+    /// to the user it was first kept for, if any.
     pub async fn put_topic(&self, peer: ed25519::PublicKey, topic: Topic) -> Result<()> {
-        ensure!(
-            self.users.contains(&peer),
-            "user needs to be registered in order to allow them to subscribe to topics"
-        );
+        let user = self.user_of(&peer).await?.ok_or_else(|| {
+            Refused(
+                "user needs to be registered in order to allow them to subscribe to topics"
+                    .to_owned(),
+            )
+        })?;
 
-        self.storage
-            .new_thread(&topic)
+        request(self.storage.new_owned_thread(&topic, &user.to_string()))
             .await
             .context("Failed to store updated information in the persistent storage")?;
 
         Ok(())
     }
 
+    /// stop keeping the messages of the `topic`
+    ///
+    /// only the user the messages are kept for may remove the topic (see
+    /// [`Storage::put_topic`]), the topics kept without an owner are left
+    /// to the node's operator.
     pub async fn remove_topic(&self, peer: ed25519::PublicKey, topic: Topic) -> Result<()> {
+        let user = self.user_of(&peer).await?.ok_or_else(|| {
+            Refused(
+                "user needs to be registered in order to allow them to unsubscribe from topics"
+                    .to_owned(),
+            )
+        })?;
+
+        if !request(self.storage.contains_tread(&topic)).await? {
+            return Ok(());
+        }
+
+        let owner = request(self.storage.thread_owner(&topic)).await?;
         ensure!(
-            self.users.contains(&peer),
-            "user needs to be registered in order to allow them to unsubscribe from topics"
+            owner.as_deref() == Some(user.to_string().as_str()),
+            Refused(format!(
+                "the messages of the topic {} are not kept for the user {}",
+                topic, user
+            ))
         );
 
-        self.storage.delete_thread(&topic).await?;
+        request(self.storage.delete_thread(&topic)).await?;
+        if let Some(owner) = owner {
+            self.usage.lock().unwrap().remove(&owner);
+        }
 
         Ok(())
     }
 }
+
+/// the limit as stored in the database
+fn limit(limit: Option<u64>) -> Option<i64> {
+    limit.map(|limit| limit.min(i64::MAX as u64) as i64)
+}
+
+/// await a request to the database
+///
+/// the database runs on its own threads, the runtime of the
+/// [`simulation`](crate::simulation) would consider the node idle and move
+/// its paused clock forward while a request is pending. A blocking task
+/// is kept running until the request completes: the runtime does not move
+/// a paused clock forward while there is a blocking task running.
+#[cfg(any(test, feature = "simulation"))]
+async fn request<F: Future>(future: F) -> F::Output {
+    let (pending, completed) = std::sync::mpsc::channel::<()>();
+    tokio::task::spawn_blocking(move || completed.recv());
+
+    let output = future.await;
+    drop(pending);
+    output
+}
+
+/// await a request to the database
+#[cfg(not(any(test, feature = "simulation")))]
+async fn request<F: Future>(future: F) -> F::Output {
+    future.await
+}
+
+fn parse_users(users: &HashSet<String>) -> Result<HashSet<User>> {
+    users
+        .iter()
+        .map(|user| {
+            user.parse()
+                .with_context(|| format!("Invalid user: {}", user))
+        })
+        .collect()
+}
+
+/// the quotas of the users, by the canonical form of the users as the
+/// threads are owned by (see [`Storage::put_topic`])
+fn parse_user_quotas(quotas: &BTreeMap<String, Quota>) -> Result<BTreeMap<String, Quota>> {
+    quotas
+        .iter()
+        .map(|(user, quota)| {
+            let user: User = user
+                .parse()
+                .with_context(|| format!("Invalid user: {}", user))?;
+            Ok((user.to_string(), *quota))
+        })
+        .collect()
+}
+
+/// the cursor pointing after the message stored with the `id` (see
+/// [`Storage::messages`])
+fn cursor(time: Time, id: i64) -> MessageId {
+    let mut cursor = [0; MessageId::SIZE];
+    cursor[..Time::SIZE].copy_from_slice(&time.to_be_bytes());
+    cursor[Time::SIZE..Time::SIZE + 8].copy_from_slice(&id.to_be_bytes());
+    MessageId::from(cursor)
+}
+
+/// the id of the stored message the `cursor` points after
+fn stored_id(cursor: &MessageId) -> i64 {
+    let mut id = [0; 8];
+    id.copy_from_slice(&cursor.hash()[..8]);
+    i64::from_be_bytes(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::{
+        key::ed25519::SecretKey,
+        passport::{
+            block::{EntryMut, EntryType},
+            Passport,
+        },
+        Seed,
+    };
+    use std::time::Duration;
+
+    fn secret_key(seed: u8) -> SecretKey {
+        let mut rng = Seed::from([seed; Seed::SIZE]).into_rand_chacha();
+        SecretKey::new(&mut rng)
+    }
+
+    fn user(seed: u8) -> ed25519::PublicKey {
+        secret_key(seed).public_key()
+    }
+
+    fn config() -> Config {
+        Config {
+            path: ":memory:".into(),
+            peers_path: None,
+            peers_seed: 64,
+            gossip_refresh_rate: Duration::from_secs(60),
+            passport_cache_size: 256,
+            query_limit: 512,
+            thread_retention: Retention::default(),
+            user_retention: Retention::default(),
+            quota: Quota::default(),
+            user_quotas: BTreeMap::new(),
+            compaction_interval: Duration::from_secs(600),
+            seen_messages_window: Duration::from_secs(24 * 60 * 60),
+            seen_messages_size: 1_048_576,
+        }
+    }
+
+    async fn storage_with(config: Config, users: &[User]) -> Storage {
+        let users = users.iter().map(|user| user.to_string()).collect();
+        Storage::new(config, users).await.unwrap()
+    }
+
+    async fn storage(users: &[User]) -> Storage {
+        storage_with(config(), users).await
+    }
+
+    async fn message(storage: &mut Storage, topic: Topic, content: &'static [u8]) -> Result<bool> {
+        storage
+            .handle_incoming_message(topic, Bytes::from_static(content))
+            .await
+    }
+
+    #[tokio::test]
+    async fn users_are_replaced() {
+        let storage = storage(&[User::Key(user(1))]).await;
+        let clone = storage.clone();
+        assert!(clone.is_user(&user(1)).await.unwrap());
+
+        let users = vec![user(2).to_string()].into_iter().collect();
+        storage.set_users(&users).unwrap();
+        assert!(!clone.is_user(&user(1)).await.unwrap());
+        assert!(clone.is_user(&user(2)).await.unwrap());
+
+        let users = vec![user(3).to_string(), "not a key".to_owned()]
+            .into_iter()
+            .collect();
+        assert!(storage.set_users(&users).is_err());
+        assert_eq!(clone.users(), vec![User::Key(user(2))]);
+    }
+
+    #[tokio::test]
+    async fn passport_users_are_authorized() {
+        let mut rng = Seed::from([4; Seed::SIZE]).into_rand_chacha();
+        let author = secret_key(1);
+        let passphrase = Seed::generate(&mut rng);
+        let passport = Passport::create(&mut rng, "device", &author, passphrase).unwrap();
+        let id = passport.id();
+        let blocks = passport.blocks().to_blocks();
+        let topic = Topic::new([5; Topic::SIZE]);
+
+        let storage = storage(&[User::Passport(id)]).await;
+
+        // the passport is not known yet: only its master keys may publish it
+        assert!(storage.put_topic(author.public_key(), topic).await.is_err());
+        assert!(storage
+            .handle_put_passport(user(2), id, blocks.clone())
+            .await
+            .is_err());
+        storage
+            .handle_put_passport(author.public_key(), id, blocks)
+            .await
+            .unwrap();
+
+        storage.put_topic(author.public_key(), topic).await.unwrap();
+        assert!(storage.put_topic(user(2), topic).await.is_err());
+        storage
+            .remove_topic(author.public_key(), topic)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn passport_users_follow_the_passport_updates() {
+        let mut rng = Seed::from([4; Seed::SIZE]).into_rand_chacha();
+        let author = secret_key(1);
+        let other = secret_key(2);
+        let passphrase = Seed::generate(&mut rng);
+        let mut passport = Passport::create(&mut rng, "device", &author, passphrase).unwrap();
+        let id = passport.id();
+
+        // keynesis only accepts new entries created within the second of the
+        // previous block, so all the blocks are prepared at once
+        let mut entry = vec![0; EntryType::RegisterMasterKey.size(&[])];
+        let entry = EntryMut::new_register_master_key(&mut entry, "other", id).unwrap();
+        let mut mutation = passport.as_mut();
+        mutation.push(entry.finalize(&other)).unwrap();
+        mutation.finalize(&author).unwrap();
+        let registered = passport.blocks().to_blocks();
+        let head = passport.blocks().iter().last().unwrap().header().hash();
+        let mut mutation = passport.as_mut();
+        mutation.remove_master_key(&other.public_key()).unwrap();
+        mutation.finalize(&author).unwrap();
+        let revoked = passport.blocks().iter().last().unwrap().to_block();
+
+        let storage = storage(&[User::Passport(id)]).await;
+        storage
+            .handle_put_passport(author.public_key(), id, registered.clone())
+            .await
+            .unwrap();
+        assert!(storage.is_user(&other.public_key()).await.unwrap());
+
+        let mut blocks = PassportBlocks::new();
+        blocks.push(revoked.as_slice());
+        storage
+            .handle_passport_blocks(id, head, blocks.as_slice())
+            .await
+            .unwrap();
+        assert!(!storage.is_user(&other.public_key()).await.unwrap());
+
+        // the revoked key cannot publish the blocks that registered it again
+        let error = storage
+            .handle_put_passport(other.public_key(), id, registered)
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<Refused>().is_some());
+    }
+
+    #[tokio::test]
+    async fn topics_are_removed_by_their_owner() {
+        let mut storage = storage(&[User::Key(user(1)), User::Key(user(2))]).await;
+        let (a, b) = (Topic::new([1; Topic::SIZE]), Topic::new([2; Topic::SIZE]));
+        storage.put_topic(user(1), a).await.unwrap();
+        storage.subscribe_message(b).await.unwrap();
+
+        // the shared topic stays accounted to its first user
+        storage.put_topic(user(2), a).await.unwrap();
+        message(&mut storage, a, b"1").await.unwrap();
+        assert_eq!(
+            storage
+                .storage
+                .owner_usage(&user(1).to_string())
+                .await
+                .unwrap()
+                .messages,
+            1
+        );
+
+        let error = storage.remove_topic(user(2), a).await.unwrap_err();
+        assert!(error.downcast_ref::<Refused>().is_some());
+        let error = storage.remove_topic(user(1), b).await.unwrap_err();
+        assert!(error.downcast_ref::<Refused>().is_some());
+
+        storage.remove_topic(user(1), a).await.unwrap();
+        assert!(!storage.contains_messages_of(&a).await.unwrap());
+        assert!(storage.contains_messages_of(&b).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn quota_is_enforced() {
+        let config = Config {
+            quota: Quota {
+                max_messages: Some(2),
+                max_bytes: None,
+            },
+            ..config()
+        };
+        let mut storage = storage_with(config, &[User::Key(user(1))]).await;
+        let (a, b, c) = (
+            Topic::new([1; Topic::SIZE]),
+            Topic::new([2; Topic::SIZE]),
+            Topic::new([3; Topic::SIZE]),
+        );
+        storage.put_topic(user(1), a).await.unwrap();
+        storage.put_topic(user(1), b).await.unwrap();
+        storage.subscribe_message(c).await.unwrap();
+
+        message(&mut storage, a, b"1").await.unwrap();
+        message(&mut storage, b, b"2").await.unwrap();
+        let error = message(&mut storage, b, b"3").await.unwrap_err();
+        let exceeded = error.downcast_ref::<QuotaExceeded>().unwrap();
+        assert_eq!(exceeded.user, User::Key(user(1)).to_string());
+        assert_eq!(exceeded.topic, b);
+
+        // the topics registered without a user are not accounted
+        for content in [b"4", b"5", b"6"].iter() {
+            assert!(message(&mut storage, c, *content).await.unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn quota_is_counted_between_compactions() {
+        let mut user_quotas = BTreeMap::new();
+        user_quotas.insert(
+            user(2).to_string(),
+            Quota {
+                max_messages: Some(3),
+                max_bytes: None,
+            },
+        );
+        let config = Config {
+            quota: Quota {
+                max_messages: Some(2),
+                max_bytes: None,
+            },
+            user_quotas,
+            thread_retention: Retention {
+                max_messages: Some(1),
+                ..Retention::default()
+            },
+            ..config()
+        };
+        let mut storage = storage_with(config, &[User::Key(user(1)), User::Key(user(2))]).await;
+        let (a, b) = (Topic::new([1; Topic::SIZE]), Topic::new([2; Topic::SIZE]));
+        storage.put_topic(user(1), a).await.unwrap();
+        storage.put_topic(user(2), b).await.unwrap();
+
+        message(&mut storage, a, b"1").await.unwrap();
+        message(&mut storage, a, b"2").await.unwrap();
+        assert!(message(&mut storage, a, b"3").await.is_err());
+
+        // the compaction makes room for new messages, the rejected message
+        // was not remembered as seen
+        assert_eq!(storage.compact().await.unwrap(), 1);
+        assert!(message(&mut storage, a, b"3").await.unwrap());
+        assert!(message(&mut storage, a, b"5").await.is_err());
+
+        // the user with its own quota is allowed more messages
+        for content in [b"6", b"7", b"8"].iter() {
+            message(&mut storage, b, *content).await.unwrap();
+        }
+        assert!(message(&mut storage, b, b"9").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn quota_is_reloaded() {
+        let mut storage = storage(&[User::Key(user(1))]).await;
+        let topic = Topic::new([1; Topic::SIZE]);
+        storage.put_topic(user(1), topic).await.unwrap();
+        message(&mut storage, topic, b"1").await.unwrap();
+
+        let config = Config {
+            quota: Quota {
+                max_messages: Some(1),
+                max_bytes: None,
+            },
+            ..config()
+        };
+        let users = vec![user(1).to_string(), "not a key".to_owned()]
+            .into_iter()
+            .collect();
+        assert!(Settings::new(&config, &users).is_err());
+
+        let users = vec![user(1).to_string()].into_iter().collect();
+        storage.reload(Settings::new(&config, &users).unwrap());
+        assert!(message(&mut storage, topic, b"2").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn seen_messages_are_not_kept_again() {
+        let mut storage = storage(&[]).await;
+        let topic = Topic::new([1; Topic::SIZE]);
+        storage.subscribe_message(topic).await.unwrap();
+
+        assert!(message(&mut storage, topic, b"1").await.unwrap());
+        assert!(!message(&mut storage, topic, b"1").await.unwrap());
+        assert_eq!(
+            storage.storage.thread_usage(&topic).await.unwrap().messages,
+            1
+        );
+
+        // the messages of the topics we do not keep are remembered too
+        let other = Topic::new([2; Topic::SIZE]);
+        assert!(message(&mut storage, other, b"2").await.unwrap());
+        assert!(!message(&mut storage, other, b"2").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn messages_are_paginated() {
+        let storage = storage(&[]).await;
+        let topic = Topic::new([1; Topic::SIZE]);
+        storage.subscribe_message(topic).await.unwrap();
+
+        // the same content stored twice within the same second
+        for content in [b"1", b"1", b"2"].iter() {
+            storage.storage.new_message(&topic, content).await.unwrap();
+        }
+
+        let mut query = TopicQuery::new(topic, Time::from(0)).limit(1);
+        let mut received = Vec::new();
+        loop {
+            let (messages, next) = storage.messages(&query).await.unwrap();
+            received.extend(messages);
+            match next {
+                Some(cursor) => query = query.after(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(received, vec![b"1".to_vec(), b"1".to_vec(), b"2".to_vec()]);
+
+        // the messages beyond the limit are paginated too
+        let query = TopicQuery::new(topic, Time::from(0));
+        let storage = Storage {
+            query_limit: 2,
+            ..storage
+        };
+        let (messages, next) = storage.messages(&query).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        let (messages, next) = storage.messages(&query.after(next.unwrap())).await.unwrap();
+        assert_eq!(messages, vec![b"2".to_vec()]);
+        assert!(next.is_none());
+    }
+
+    #[tokio::test]
+    async fn messages_are_compacted() {
+        let config = Config {
+            thread_retention: Retention {
+                max_messages: Some(2),
+                ..Retention::default()
+            },
+            user_retention: Retention {
+                max_bytes: Some(3),
+                ..Retention::default()
+            },
+            ..config()
+        };
+        let mut storage = storage_with(config, &[User::Key(user(1))]).await;
+        let (a, b, c) = (
+            Topic::new([1; Topic::SIZE]),
+            Topic::new([2; Topic::SIZE]),
+            Topic::new([3; Topic::SIZE]),
+        );
+        storage.put_topic(user(1), a).await.unwrap();
+        storage.put_topic(user(1), b).await.unwrap();
+        storage.subscribe_message(c).await.unwrap();
+
+        message(&mut storage, a, b"11").await.unwrap();
+        message(&mut storage, b, b"22").await.unwrap();
+        for content in [b"1", b"2", b"3"].iter() {
+            message(&mut storage, c, *content).await.unwrap();
+        }
+
+        // the oldest message of the thread and of the user are deleted
+        assert_eq!(storage.compact().await.unwrap(), 2);
+        assert_eq!(storage.compact().await.unwrap(), 0);
+
+        let query = |topic| TopicQuery::new(topic, Time::from(0));
+        let (messages, _) = storage.messages(&query(a)).await.unwrap();
+        assert!(messages.is_empty());
+        let (messages, _) = storage.messages(&query(b)).await.unwrap();
+        assert_eq!(messages, vec![b"22".to_vec()]);
+        let (messages, _) = storage.messages(&query(c)).await.unwrap();
+        assert_eq!(messages, vec![b"2".to_vec(), b"3".to_vec()]);
+    }
+}
@@ -0,0 +1,345 @@
+use anyhow::{ensure, Context as _, Result};
+use keynesis::{key::ed25519::PublicKey, passport::block::Time};
+use poldercast::{Gossip, GossipSlice};
+use std::{
+    cmp::Reverse,
+    convert::TryInto as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// the number of connection attempts in a row a peer may fail before
+/// it is forgotten
+const MAX_FAILURES: u32 = 16;
+
+/// the peers database
+///
+/// keep, for every peer we know of, its latest gossip and how it has
+/// behaved so far. The best peers are used to join the network again
+/// on restart (see [`Peers::seeds`]).
+#[derive(Clone)]
+pub struct Peers {
+    peers: sled::Tree,
+
+    min_gossip_refresh: Duration,
+    last_update: Arc<Mutex<Instant>>,
+}
+
+/// what we know about a peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerRecord {
+    /// the latest gossip of the peer
+    pub gossip: Gossip,
+    /// the last time we successfully connected to the peer
+    pub last_connected: Option<Time>,
+    /// the number of connection attempts that failed since the last
+    /// successful one
+    pub failures: u32,
+    /// how badly the peer behaved (invalid messages...), halved on
+    /// every successful connection so the peers are forgiven over time
+    pub misbehaviour: u32,
+}
+
+impl PeerRecord {
+    /// last connection (4 bytes), failures (4 bytes) and misbehaviour (4 bytes)
+    const HEAD_SIZE: usize = 3 * 4;
+
+    fn new(gossip: Gossip) -> Self {
+        Self {
+            gossip,
+            last_connected: None,
+            failures: 0,
+            misbehaviour: 0,
+        }
+    }
+
+    /// the key to sort the peers with, the best peers first
+    ///
+    /// the peers who never misbehaved come first, then the ones we fail
+    /// the least to connect to, then the ones we connected to the most
+    /// recently.
+    fn reputation(&self) -> (u32, u32, Reverse<u32>) {
+        let last_connected = self.last_connected.map(u32::from).unwrap_or(0);
+        (self.misbehaviour, self.failures, Reverse(last_connected))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let last_connected = self.last_connected.map(u32::from).unwrap_or(0);
+        let gossip = self.gossip.as_ref();
+
+        let mut bytes = Vec::with_capacity(Self::HEAD_SIZE + gossip.len());
+        bytes.extend_from_slice(&last_connected.to_be_bytes());
+        bytes.extend_from_slice(&self.failures.to_be_bytes());
+        bytes.extend_from_slice(&self.misbehaviour.to_be_bytes());
+        bytes.extend_from_slice(gossip);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        ensure!(
+            bytes.len() > Self::HEAD_SIZE,
+            "Not enough bytes for a peer record"
+        );
+
+        let last_connected = u32::from_be_bytes(bytes[0..4].try_into().unwrap());
+        let failures = u32::from_be_bytes(bytes[4..8].try_into().unwrap());
+        let misbehaviour = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+        let gossip = GossipSlice::try_from_slice(&bytes[Self::HEAD_SIZE..])
+            .context("Invalid gossip in the peer record")?
+            .to_owned();
+
+        Ok(Self {
+            gossip,
+            last_connected: if last_connected == 0 {
+                None
+            } else {
+                Some(Time::from(last_connected))
+            },
+            failures,
+            misbehaviour,
+        })
+    }
+}
+
+impl Peers {
+    pub(crate) fn new(db: &sled::Db, min_gossip_refresh: Duration) -> Result<Self> {
+        let peers = db
+            .open_tree("network::peers")
+            .context("Cannot open the peers sled tree")?;
+        Ok(Self {
+            peers,
+            min_gossip_refresh,
+            last_update: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    pub(crate) fn needs_updated(&self) -> bool {
+        self.last_update.lock().unwrap().elapsed() > self.min_gossip_refresh
+    }
+
+    /// get the record of the peer `id`, if we know of it
+    pub fn get(&self, id: &PublicKey) -> Result<Option<PeerRecord>> {
+        self.peers
+            .get(id.as_ref())?
+            .map(|bytes| PeerRecord::from_bytes(&bytes))
+            .transpose()
+    }
+
+    /// all the known peers, the best peers first
+    pub fn peers(&self) -> Result<Vec<PeerRecord>> {
+        let mut peers = Vec::new();
+
+        for entry in self.peers.iter() {
+            let (_, record) = entry?;
+            let record = PeerRecord::from_bytes(record.as_ref())
+                .context("Cannot retrieve peer from the storage")?;
+            peers.push(record);
+        }
+
+        peers.sort_by_key(PeerRecord::reputation);
+
+        Ok(peers)
+    }
+
+    /// the gossips of the `max` best known peers
+    pub fn seeds(&self, max: usize) -> Result<Vec<Gossip>> {
+        Ok(self
+            .peers()?
+            .into_iter()
+            .take(max)
+            .map(|record| record.gossip)
+            .collect())
+    }
+
+    /// keep the latest gossips of the peers
+    ///
+    /// the peers we did not know of are added.
+    pub(crate) fn update(&self, gossips: Vec<Gossip>) -> Result<()> {
+        let mut locked = self.last_update.lock().unwrap();
+
+        for gossip in gossips {
+            self.modify(&gossip.id(), |record| match record {
+                Some(mut record) => {
+                    if record.gossip.time() < gossip.time() {
+                        record.gossip = gossip.clone();
+                    }
+                    Some(record)
+                }
+                None => Some(PeerRecord::new(gossip.clone())),
+            })?;
+        }
+
+        self.peers
+            .flush()
+            .context("cannot save the peers persistently")?;
+
+        *locked = Instant::now();
+        Ok(())
+    }
+
+    /// we successfully connected to the peer
+    ///
+    /// the failures are reset and the misbehaviour decays.
+    pub fn connected(&self, gossip: &Gossip) -> Result<()> {
+        self.modify(&gossip.id(), |record| {
+            let mut record = record.unwrap_or_else(|| PeerRecord::new(gossip.clone()));
+            record.last_connected = Some(Time::now());
+            record.failures = 0;
+            record.misbehaviour /= 2;
+            Some(record)
+        })
+    }
+
+    /// we failed to connect to the peer
+    ///
+    /// the peer is forgotten after too many failures in a row.
+    pub fn failed(&self, gossip: &Gossip) -> Result<()> {
+        self.modify(&gossip.id(), |record| {
+            let mut record = record.unwrap_or_else(|| PeerRecord::new(gossip.clone()));
+            record.failures = record.failures.saturating_add(1);
+            if record.failures >= MAX_FAILURES {
+                None
+            } else {
+                Some(record)
+            }
+        })
+    }
+
+    /// the peer `id` misbehaved, add the `points` to its score
+    ///
+    /// nothing is recorded if we do not know of the peer (clients...)
+    pub fn misbehaved(&self, id: &PublicKey, points: u32) -> Result<()> {
+        self.modify(id, |record| {
+            let mut record = record?;
+            record.misbehaviour = record.misbehaviour.saturating_add(points);
+            Some(record)
+        })
+    }
+
+    fn modify<F>(&self, id: &PublicKey, f: F) -> Result<()>
+    where
+        F: Fn(Option<PeerRecord>) -> Option<PeerRecord>,
+    {
+        let mut error = None;
+        self.peers
+            .fetch_and_update(id.as_ref(), |bytes| {
+                let record = match bytes.map(PeerRecord::from_bytes).transpose() {
+                    Ok(record) => record,
+                    Err(e) => {
+                        // the invalid record is replaced
+                        error = Some(e);
+                        None
+                    }
+                };
+                f(record).map(|record| record.to_bytes())
+            })
+            .context("Cannot update the peer record")?;
+
+        if let Some(error) = error {
+            tracing::warn!(id = %id, reason = ?error, "replaced invalid peer record");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::{key::ed25519::SecretKey, Seed};
+
+    fn gossip(seed: u8) -> Gossip {
+        let mut rng = Seed::from([seed; Seed::SIZE]).into_rand_chacha();
+        let key = SecretKey::new(&mut rng);
+        let address = ([10, 0, 0, seed], 9800).into();
+        let topology = poldercast::Topology::new(address, &key);
+        topology.self_profile().gossip().clone()
+    }
+
+    fn ids(peers: &Peers) -> Vec<PublicKey> {
+        peers
+            .peers()
+            .unwrap()
+            .iter()
+            .map(|record| record.gossip.id())
+            .collect()
+    }
+
+    #[test]
+    fn peers_are_sorted_by_reputation() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let peers = Peers::new(&db, Duration::from_secs(60)).unwrap();
+        let (a, b, c) = (gossip(1), gossip(2), gossip(3));
+
+        peers.update(vec![a.clone(), b.clone(), c.clone()]).unwrap();
+        peers.connected(&b).unwrap();
+        peers.failed(&c).unwrap();
+        peers.misbehaved(&a.id(), 1).unwrap();
+        assert_eq!(ids(&peers), vec![b.id(), c.id(), a.id()]);
+
+        let record = peers.get(&b.id()).unwrap().unwrap();
+        assert_eq!(record.gossip, b);
+        assert!(record.last_connected.is_some());
+        assert_eq!(peers.seeds(1).unwrap(), vec![b]);
+
+        // unknown peers (clients) are not recorded
+        peers.misbehaved(&gossip(4).id(), 1).unwrap();
+        assert_eq!(peers.peers().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn misbehaviour_decays() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let peers = Peers::new(&db, Duration::from_secs(60)).unwrap();
+        let a = gossip(1);
+
+        peers.update(vec![a.clone()]).unwrap();
+        peers.misbehaved(&a.id(), 5).unwrap();
+        peers.connected(&a).unwrap();
+        assert_eq!(peers.get(&a.id()).unwrap().unwrap().misbehaviour, 2);
+        peers.connected(&a).unwrap();
+        peers.connected(&a).unwrap();
+        assert_eq!(peers.get(&a.id()).unwrap().unwrap().misbehaviour, 0);
+    }
+
+    #[test]
+    fn unreachable_peers_are_forgotten() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let peers = Peers::new(&db, Duration::from_secs(60)).unwrap();
+        let a = gossip(1);
+
+        for _ in 1..MAX_FAILURES {
+            peers.failed(&a).unwrap();
+        }
+        assert_eq!(
+            peers.get(&a.id()).unwrap().unwrap().failures,
+            MAX_FAILURES - 1
+        );
+
+        peers.failed(&a).unwrap();
+        assert!(peers.get(&a.id()).unwrap().is_none());
+    }
+
+    #[test]
+    fn peers_are_kept_between_restarts() {
+        let path = std::env::temp_dir().join(format!("asmtpd-peers-{}", std::process::id()));
+        let a = gossip(1);
+
+        {
+            let db = sled::Config::new().path(&path).open().unwrap();
+            let peers = Peers::new(&db, Duration::from_secs(60)).unwrap();
+            peers.update(vec![a.clone()]).unwrap();
+            peers.connected(&a).unwrap();
+            db.flush().unwrap();
+        }
+
+        let db = sled::Config::new().path(&path).open().unwrap();
+        let peers = Peers::new(&db, Duration::from_secs(60)).unwrap();
+        let record = peers.get(&a.id()).unwrap().unwrap();
+        assert_eq!(record.gossip, a);
+        assert!(record.last_connected.is_some());
+
+        drop(db);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,326 @@
+use anyhow::{Context as _, Result};
+use keynesis::{hash::Blake2b, passport::block::Time};
+use sled::{
+    transaction::{ConflictableTransactionError, TransactionError, TransactionalTree},
+    Transactional as _,
+};
+use std::{
+    convert::TryInto as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// the hash identifying a message in the [`SeenMessages`]
+pub type SeenHash = [u8; 32];
+
+/// compute the [`SeenHash`] of the given message
+pub fn seen_hash(message: impl AsRef<[u8]>) -> SeenHash {
+    let mut hash = [0; 32];
+    Blake2b::blake2b(&mut hash, message.as_ref(), &[]);
+    hash
+}
+
+/// the messages the node has seen recently, kept between restarts
+///
+/// a message is remembered for at least the `window` after it was first
+/// seen, so it is neither relayed nor stored again if it comes back. At
+/// most `max` messages are remembered, the oldest are forgotten first.
+#[derive(Clone)]
+pub struct SeenMessages {
+    /// the time each message was seen at, by hash
+    by_hash: sled::Tree,
+    /// the messages by the time they were seen at (the time then the hash)
+    /// so the oldest are forgotten first
+    by_time: sled::Tree,
+    /// the number of messages in `by_hash`, updated in the same
+    /// transactions as the other trees so it is not counted on startup
+    count: sled::Tree,
+    window: Duration,
+    max: usize,
+    /// the number of messages in `by_hash`, as persisted in `count`
+    len: Arc<AtomicUsize>,
+}
+
+impl SeenMessages {
+    /// the number of expired messages to forget on every check, so the
+    /// expired messages are forgotten faster than new ones are seen
+    const PURGE_PER_CHECK: usize = 2;
+
+    /// the key of the number of messages in the `count` tree
+    const LEN: &'static [u8] = b"len";
+
+    pub(crate) fn new(db: &sled::Db, window: Duration, max: usize) -> Result<Self> {
+        let by_hash = db
+            .open_tree("network::seen::by_hash")
+            .context("Cannot open the seen messages sled tree")?;
+        let by_time = db
+            .open_tree("network::seen::by_time")
+            .context("Cannot open the seen messages sled tree")?;
+        let count = db
+            .open_tree("network::seen::count")
+            .context("Cannot open the seen messages sled tree")?;
+
+        let len = match count.get(Self::LEN)? {
+            Some(len) => decode_len(&len),
+            None => {
+                // the messages were remembered before the count was
+                // persisted, they are counted only this once
+                let len = by_hash.len();
+                count.insert(Self::LEN, &(len as u64).to_be_bytes()[..])?;
+                len
+            }
+        };
+
+        Ok(Self {
+            by_hash,
+            by_time,
+            count,
+            window,
+            max: max.max(1),
+            len: Arc::new(AtomicUsize::new(len)),
+        })
+    }
+
+    /// the number of messages remembered
+    pub fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// check if the message was seen within the window
+    pub fn contains(&self, hash: &SeenHash) -> Result<bool> {
+        let now = Time::now();
+        match self.by_hash.get(hash)? {
+            Some(seen) => Ok(!self.is_expired(decode_time(&seen), now)),
+            None => Ok(false),
+        }
+    }
+
+    /// remember the message was seen now
+    ///
+    /// returns `true` if the message was not seen within the window, i.e.
+    /// the message is to be relayed and stored.
+    pub fn check(&self, hash: &SeenHash) -> Result<bool> {
+        self.check_at(hash, Time::now())
+    }
+
+    fn check_at(&self, hash: &SeenHash, now: Time) -> Result<bool> {
+        self.purge(now, Self::PURGE_PER_CHECK)?;
+
+        // `None` if the message was seen within the window, otherwise
+        // whether it was not remembered at all
+        let remembered = (&self.by_hash, &self.by_time, &self.count)
+            .transaction(|(by_hash, by_time, count)| {
+                let new = match by_hash.get(hash)? {
+                    Some(seen) => {
+                        let seen = decode_time(&seen);
+                        if !self.is_expired(seen, now) {
+                            return Ok(None);
+                        }
+                        by_time.remove(time_key(seen, hash))?;
+                        false
+                    }
+                    None => {
+                        add_len(count, 1)?;
+                        true
+                    }
+                };
+
+                by_hash.insert(&hash[..], &u32::from(now).to_be_bytes()[..])?;
+                by_time.insert(time_key(now, hash), &[][..])?;
+                Ok::<_, ConflictableTransactionError>(Some(new))
+            })
+            .map_err(transaction_error)?;
+
+        match remembered {
+            None => return Ok(false),
+            Some(true) => {
+                self.len.fetch_add(1, Ordering::SeqCst);
+            }
+            Some(false) => {}
+        }
+
+        while self.len() > self.max {
+            if !self.forget_oldest()? {
+                break;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// forget the messages seen before the window
+    pub fn forget_expired(&self) -> Result<usize> {
+        self.purge(Time::now(), usize::MAX)
+    }
+
+    fn purge(&self, now: Time, limit: usize) -> Result<usize> {
+        let mut forgotten = 0;
+
+        while forgotten < limit {
+            match self.by_time.first()? {
+                Some((key, _)) if self.is_expired(decode_time(&key), now) => {
+                    self.forget(&key)?;
+                    forgotten += 1;
+                }
+                _ => break,
+            }
+        }
+
+        Ok(forgotten)
+    }
+
+    fn forget_oldest(&self) -> Result<bool> {
+        if let Some((key, _)) = self.by_time.first()? {
+            self.forget(&key)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    fn forget(&self, key: &[u8]) -> Result<()> {
+        let removed = (&self.by_hash, &self.by_time, &self.count)
+            .transaction(|(by_hash, by_time, count)| {
+                by_time.remove(key)?;
+                let removed = by_hash.remove(&key[4..])?.is_some();
+                if removed {
+                    add_len(count, -1)?;
+                }
+                Ok::<_, ConflictableTransactionError>(removed)
+            })
+            .map_err(transaction_error)?;
+        if removed {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    fn is_expired(&self, seen: Time, now: Time) -> bool {
+        let elapsed = u32::from(now).saturating_sub(u32::from(seen));
+        Duration::from_secs(elapsed as u64) > self.window
+    }
+}
+
+fn transaction_error(error: TransactionError) -> anyhow::Error {
+    match error {
+        TransactionError::Abort(error) | TransactionError::Storage(error) => {
+            anyhow::Error::new(error).context("Cannot update the seen messages")
+        }
+    }
+}
+
+fn time_key(time: Time, hash: &SeenHash) -> Vec<u8> {
+    let mut key = Vec::with_capacity(4 + hash.len());
+    key.extend_from_slice(&u32::from(time).to_be_bytes());
+    key.extend_from_slice(hash);
+    key
+}
+
+/// add `delta` to the number of messages persisted in the `count` tree
+fn add_len(count: &TransactionalTree, delta: i64) -> Result<(), ConflictableTransactionError> {
+    let len = count
+        .get(SeenMessages::LEN)?
+        .map(|len| decode_len(&len))
+        .unwrap_or(0);
+    let len = (len as i64 + delta).max(0) as u64;
+    count.insert(SeenMessages::LEN, &len.to_be_bytes()[..])?;
+    Ok(())
+}
+
+fn decode_len(bytes: &[u8]) -> usize {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap()) as usize
+}
+
+fn decode_time(bytes: &[u8]) -> Time {
+    Time::from(u32::from_be_bytes(bytes[..4].try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seen_messages(window: Duration, max: usize) -> SeenMessages {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SeenMessages::new(&db, window, max).unwrap()
+    }
+
+    #[test]
+    fn duplicates_are_detected() {
+        let seen = seen_messages(Duration::from_secs(60), 10);
+        let hash = seen_hash(b"message");
+
+        assert!(!seen.contains(&hash).unwrap());
+        assert!(seen.check(&hash).unwrap());
+        assert!(seen.contains(&hash).unwrap());
+        assert!(!seen.check(&hash).unwrap());
+        assert!(seen.check(&seen_hash(b"another message")).unwrap());
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn expired_messages_are_forgotten() {
+        let seen = seen_messages(Duration::from_secs(60), 10);
+        let first = seen_hash(b"first");
+        let second = seen_hash(b"second");
+        let now = Time::now();
+        let later = Time::from(u32::from(now) + 61);
+
+        assert!(seen.check_at(&first, now).unwrap());
+        assert!(!seen
+            .check_at(&first, Time::from(u32::from(now) + 60))
+            .unwrap());
+
+        // the first message is forgotten on the next check after the window
+        assert!(seen.check_at(&second, later).unwrap());
+        assert_eq!(seen.len(), 1);
+        assert!(seen.check_at(&first, later).unwrap());
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn oldest_messages_are_forgotten_first() {
+        let seen = seen_messages(Duration::from_secs(60), 2);
+        let now = u32::from(Time::now());
+        let hashes: Vec<_> = (0..3u8).map(|i| seen_hash([i])).collect();
+
+        for (i, hash) in hashes.iter().enumerate() {
+            assert!(seen.check_at(hash, Time::from(now + i as u32)).unwrap());
+        }
+
+        assert_eq!(seen.len(), 2);
+        assert!(!seen.contains(&hashes[0]).unwrap());
+        assert!(seen.contains(&hashes[1]).unwrap());
+        assert!(seen.contains(&hashes[2]).unwrap());
+    }
+
+    #[test]
+    fn seen_messages_are_persisted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let hash = seen_hash(b"message");
+
+        // nothing but the trees is kept from one instance to the next
+        let seen = SeenMessages::new(&db, Duration::from_secs(60), 10).unwrap();
+        assert!(seen.check(&hash).unwrap());
+        drop(seen);
+
+        let seen = SeenMessages::new(&db, Duration::from_secs(60), 10).unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(!seen.check(&hash).unwrap());
+        drop(seen);
+
+        // the messages remembered before the count was persisted are
+        // counted on startup
+        db.drop_tree("network::seen::count").unwrap();
+        let seen = SeenMessages::new(&db, Duration::from_secs(60), 10).unwrap();
+        assert_eq!(seen.len(), 1);
+        assert!(seen.check(&seen_hash(b"another message")).unwrap());
+        assert_eq!(seen.len(), 2);
+    }
+}
@@ -0,0 +1,65 @@
+use anyhow::Context as _;
+use keynesis::{key::ed25519::PublicKey, passport::block::Hash};
+use std::{
+    fmt::{self, Formatter},
+    str::FromStr,
+};
+
+const PASSPORT_PREFIX: &str = "passport:";
+
+/// a user of the node, authorized to publish passports and to
+/// register topics
+///
+/// in the configuration file a user is either the hexadecimal public key
+/// of the user or `passport:` followed by the id of the user's passport.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum User {
+    /// the only key of the user
+    Key(PublicKey),
+    /// every active master key of the passport, the keys added to the
+    /// passport are authorized and the revoked ones lose their access
+    Passport(Hash),
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(key) => key.fmt(f),
+            Self::Passport(id) => write!(f, "{}{}", PASSPORT_PREFIX, id),
+        }
+    }
+}
+
+impl FromStr for User {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(id) = s.strip_prefix(PASSPORT_PREFIX) {
+            let id = id.parse().context("Invalid passport id")?;
+            Ok(Self::Passport(id))
+        } else {
+            let key = s.parse().context("Invalid public key")?;
+            Ok(Self::Key(key))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keynesis::{key::ed25519::SecretKey, Seed};
+
+    #[test]
+    fn parse_users() {
+        let mut rng = Seed::from([1; Seed::SIZE]).into_rand_chacha();
+        let key = SecretKey::new(&mut rng).public_key();
+        let id = Hash::from([2; Hash::SIZE]);
+
+        for user in [User::Key(key), User::Passport(id)].iter() {
+            assert_eq!(&user.to_string().parse::<User>().unwrap(), user);
+        }
+
+        assert!("passport:".parse::<User>().is_err());
+        assert!("passport:not a passport".parse::<User>().is_err());
+        assert!("not a key".parse::<User>().is_err());
+    }
+}